@@ -0,0 +1,190 @@
+//! Compares the work stealing ThreadPool against the previous design, where every worker
+//! blocked in recv on a single Arc<Mutex<mpsc::Receiver>>.
+//!
+//! Run with `cargo bench --bench threadpool`.
+
+use dash::threadpool::{ThreadPool, ThreadPoolJob};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WORKER_COUNTS: [usize; 3] = [2, 8, 16];
+const THROUGHPUT_JOBS: usize = 200_000;
+const LATENCY_JOBS: usize = 20_000;
+const LATENCY_JOB_WORK: Duration = Duration::from_micros(20);
+
+type BoxedFn = Box<dyn FnOnce() + Send + 'static>;
+
+/// The pool design Dash used before the work stealing scheduler.
+struct MutexReceiverPool {
+    sender: Option<mpsc::Sender<BoxedFn>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl MutexReceiverPool {
+    fn new(size: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<BoxedFn>();
+        let rx = Arc::new(Mutex::new(rx));
+        let workers = (0..size)
+            .map(|_| {
+                let rx = Arc::clone(&rx);
+                thread::spawn(move || loop {
+                    let job = match rx.lock().unwrap().recv() {
+                        Ok(j) => j,
+                        Err(_) => return,
+                    };
+                    job();
+                })
+            })
+            .collect();
+        MutexReceiverPool {
+            sender: Some(tx),
+            workers,
+        }
+    }
+
+    fn submit(&self, f: BoxedFn) {
+        self.sender.as_ref().unwrap().send(f).unwrap();
+    }
+}
+
+impl Drop for MutexReceiverPool {
+    fn drop(&mut self) {
+        self.sender.take();
+        for w in self.workers.drain(..) {
+            let _ = w.join();
+        }
+    }
+}
+
+struct ClosureJob(Mutex<Option<BoxedFn>>);
+
+impl ThreadPoolJob for ClosureJob {
+    fn run_job(&self) {
+        if let Some(f) = self.0.lock().unwrap().take() {
+            f();
+        }
+    }
+}
+
+trait BenchPool {
+    fn name(&self) -> &'static str;
+    fn submit(&self, f: BoxedFn);
+}
+
+impl BenchPool for MutexReceiverPool {
+    fn name(&self) -> &'static str {
+        "mutex-receiver"
+    }
+
+    fn submit(&self, f: BoxedFn) {
+        MutexReceiverPool::submit(self, f);
+    }
+}
+
+impl BenchPool for ThreadPool {
+    fn name(&self) -> &'static str {
+        "work-stealing"
+    }
+
+    fn submit(&self, f: BoxedFn) {
+        self.submit_job(Box::new(ClosureJob(Mutex::new(Some(f)))));
+    }
+}
+
+fn spin(d: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < d {
+        std::hint::spin_loop();
+    }
+}
+
+fn wait_for(counter: &AtomicUsize, target: usize) {
+    while counter.load(Ordering::SeqCst) < target {
+        thread::yield_now();
+    }
+}
+
+/// Submits many empty jobs as fast as possible, the cost is almost entirely dispatch.
+fn throughput(pool: &dyn BenchPool) -> f64 {
+    let done = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    for _ in 0..THROUGHPUT_JOBS {
+        let done = Arc::clone(&done);
+        pool.submit(Box::new(move || {
+            done.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+    wait_for(&done, THROUGHPUT_JOBS);
+    THROUGHPUT_JOBS as f64 / start.elapsed().as_secs_f64()
+}
+
+/// Submits short jobs from several producer threads and records how long each job waited
+/// between being submitted and starting to run.
+fn latency(pool: &(dyn BenchPool + Sync)) -> Vec<Duration> {
+    const PRODUCERS: usize = 4;
+    let done = Arc::new(AtomicUsize::new(0));
+    let latencies = Arc::new(Mutex::new(Vec::with_capacity(LATENCY_JOBS)));
+    let start_flag = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        for _ in 0..PRODUCERS {
+            s.spawn(|| {
+                while !start_flag.load(Ordering::SeqCst) {
+                    thread::yield_now();
+                }
+                for _ in 0..LATENCY_JOBS / PRODUCERS {
+                    let done = Arc::clone(&done);
+                    let latencies = Arc::clone(&latencies);
+                    let submitted = Instant::now();
+                    pool.submit(Box::new(move || {
+                        let waited = submitted.elapsed();
+                        spin(LATENCY_JOB_WORK);
+                        latencies.lock().unwrap().push(waited);
+                        done.fetch_add(1, Ordering::SeqCst);
+                    }));
+                }
+            });
+        }
+        start_flag.store(true, Ordering::SeqCst);
+    });
+    wait_for(&done, LATENCY_JOBS / PRODUCERS * PRODUCERS);
+
+    let mut latencies = std::mem::take(&mut *latencies.lock().unwrap());
+    latencies.sort();
+    latencies
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let index = ((sorted.len() as f64 - 1.0) * p).round() as usize;
+    sorted[index]
+}
+
+fn report(pool: &(dyn BenchPool + Sync), workers: usize) {
+    let jobs_per_sec = throughput(pool);
+    let latencies = latency(pool);
+    println!(
+        "{:<15} workers={:<3} throughput={:>12.0} jobs/s  p50={:>10?} p99={:>10?} p99.9={:>10?} max={:>10?}",
+        pool.name(),
+        workers,
+        jobs_per_sec,
+        percentile(&latencies, 0.50),
+        percentile(&latencies, 0.99),
+        percentile(&latencies, 0.999),
+        latencies.last().copied().unwrap_or_default(),
+    );
+}
+
+fn main() {
+    for workers in WORKER_COUNTS {
+        let baseline = MutexReceiverPool::new(workers);
+        report(&baseline, workers);
+        drop(baseline);
+
+        let pool = ThreadPool::new(workers, workers, workers, Duration::from_secs(5)).unwrap();
+        report(&pool, workers);
+        pool.shutdown();
+    }
+}
@@ -0,0 +1,92 @@
+use crate::cidr::Cidr;
+use crate::config::{AccessControlConfig, AclAction};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How often one rule decided about a query. The default action has clients None.
+#[derive(Debug, Clone)]
+pub struct AclRuleStats {
+    pub clients: Option<Vec<Cidr>>,
+    pub action: AclAction,
+    pub hits: u64,
+}
+
+#[derive(Debug)]
+struct Rule {
+    clients: Vec<Cidr>,
+    action: AclAction,
+    hits: AtomicU64,
+}
+
+/// The access control rules, checked on the client address of every query before a job is
+/// created for it.
+#[derive(Debug)]
+pub struct AccessControl {
+    rules: Vec<Rule>,
+    default_action: AclAction,
+    default_hits: AtomicU64,
+}
+
+impl AccessControl {
+    pub fn new(config: &AccessControlConfig) -> Self {
+        AccessControl {
+            rules: config
+                .rules
+                .iter()
+                .map(|r| Rule {
+                    clients: r.clients.clone(),
+                    action: r.action,
+                    hits: AtomicU64::new(0),
+                })
+                .collect(),
+            default_action: config.default_action,
+            default_hits: AtomicU64::new(0),
+        }
+    }
+
+    /// The action for client, from the rule with the most specific prefix containing it.
+    /// Of equally specific rules the first one configured wins. Counts the hit.
+    pub fn check(&self, client: IpAddr) -> AclAction {
+        let mut best: Option<(u8, &Rule)> = None;
+        for rule in &self.rules {
+            let matched = rule
+                .clients
+                .iter()
+                .filter(|c| c.contains(client))
+                .map(Cidr::prefix_len)
+                .max();
+            if let Some(len) = matched {
+                if best.is_none_or(|(best_len, _)| len > best_len) {
+                    best = Some((len, rule));
+                }
+            }
+        }
+        match best {
+            Some((_, rule)) => {
+                rule.hits.fetch_add(1, Ordering::Relaxed);
+                rule.action
+            }
+            None => {
+                self.default_hits.fetch_add(1, Ordering::Relaxed);
+                self.default_action
+            }
+        }
+    }
+
+    /// Every rule in the configured order, followed by the default action.
+    pub fn stats(&self) -> Vec<AclRuleStats> {
+        self.rules
+            .iter()
+            .map(|r| AclRuleStats {
+                clients: Some(r.clients.clone()),
+                action: r.action,
+                hits: r.hits.load(Ordering::Relaxed),
+            })
+            .chain(std::iter::once(AclRuleStats {
+                clients: None,
+                action: self.default_action,
+                hits: self.default_hits.load(Ordering::Relaxed),
+            }))
+            .collect()
+    }
+}
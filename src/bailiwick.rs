@@ -0,0 +1,109 @@
+//! Scrubbing of upstream responses before anything in them is used or cached.
+//!
+//! A server is only trusted for names in its bailiwick, the zone it was asked as a server
+//! for. Every record a response carries must also be relevant to the question: the answer
+//! section may only hold the CNAME chain starting at the query name, the authority section
+//! only NS and SOA records of zones containing that chain, and the additional section only
+//! addresses of names the other sections point at. Everything else is dropped, which stops
+//! a server for evil.example from planting records for bank.example.
+
+use crate::config::normalize_name;
+use crate::upstream::is_subdomain;
+use log::debug;
+use rustdns::{Message, Resource};
+
+/// Longest CNAME chain followed inside one response.
+const MAX_CHAIN_LENGTH: usize = 16;
+
+/// Removes every record from rsp that a server for zone has no business sending in
+/// response to a query for qname. Returns the number of records removed.
+pub fn scrub(rsp: &mut Message, qname: &str, zone: &str) -> usize {
+    let zone = normalize_name(zone);
+    let before = rsp.answers.len() + rsp.authoritys.len() + rsp.additionals.len();
+
+    // The answer: records of qname, then of each CNAME target in turn, as long as the
+    // chain stays in the bailiwick. A target outside it has to be asked of its own servers.
+    let mut chain = vec![normalize_name(qname)];
+    let mut answers = Vec::new();
+    let mut remaining = std::mem::take(&mut rsp.answers);
+    while chain.len() <= MAX_CHAIN_LENGTH {
+        let current = chain.last().unwrap().clone();
+        if !is_subdomain(&current, &zone) {
+            break;
+        }
+        let (owned, rest): (Vec<_>, Vec<_>) = remaining
+            .into_iter()
+            .partition(|r| normalize_name(&r.name) == current);
+        remaining = rest;
+        let target = owned.iter().find_map(|r| match &r.resource {
+            Resource::CNAME(target) => Some(normalize_name(target)),
+            _ => None,
+        });
+        answers.extend(owned);
+        match target {
+            Some(target) if !chain.contains(&target) => chain.push(target),
+            _ => break,
+        }
+    }
+    rsp.answers = answers;
+    let end = chain.last().unwrap().clone();
+
+    // The authority: the zone the end of the chain is in. A referral has to go down from
+    // zone, never sideways or back up.
+    let referral = !rsp.aa && rsp.answers.is_empty();
+    rsp.authoritys.retain(|r| {
+        let owner = normalize_name(&r.name);
+        let relevant = is_subdomain(&owner, &zone) && is_subdomain(&end, &owner);
+        match &r.resource {
+            Resource::NS(_) => relevant && !(referral && owner == zone),
+            Resource::SOA(_) => relevant,
+            _ => false,
+        }
+    });
+
+    // The additional section: addresses of the nameservers, mail exchangers and services
+    // named above
+    let mut targets: Vec<String> = Vec::new();
+    for record in rsp.answers.iter().chain(&rsp.authoritys) {
+        match &record.resource {
+            Resource::NS(name) => targets.push(normalize_name(name)),
+            Resource::MX(mx) => targets.push(normalize_name(&mx.exchange)),
+            Resource::SRV(srv) => targets.push(normalize_name(&srv.name)),
+            _ => (),
+        }
+    }
+    rsp.additionals.retain(|r| {
+        let owner = normalize_name(&r.name);
+        matches!(r.resource, Resource::A(_) | Resource::AAAA(_))
+            && is_subdomain(&owner, &zone)
+            && targets.contains(&owner)
+    });
+
+    let removed = before - (rsp.answers.len() + rsp.authoritys.len() + rsp.additionals.len());
+    if removed > 0 {
+        debug!(
+            "Scrubbed {} records out of bailiwick {:?} or unrelated to {} from a response",
+            removed, zone, qname
+        );
+    }
+    removed
+}
+
+/// The name the CNAME chain in the answer section of rsp leads to from qname, qname
+/// itself if there is none. Assumes rsp has been scrubbed.
+pub fn chain_end(rsp: &Message, qname: &str) -> String {
+    let mut current = normalize_name(qname);
+    for _ in 0..MAX_CHAIN_LENGTH {
+        let target = rsp.answers.iter().find_map(|r| match &r.resource {
+            Resource::CNAME(target) if normalize_name(&r.name) == current => {
+                Some(normalize_name(target))
+            }
+            _ => None,
+        });
+        match target {
+            Some(target) => current = target,
+            None => break,
+        }
+    }
+    current
+}
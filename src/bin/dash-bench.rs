@@ -0,0 +1,381 @@
+//! Load generator for Dash or any other DNS server reachable over UDP.
+//!
+//! Sends the names from a query list file at a target rate from several concurrent senders
+//! for a fixed duration, then reports throughput, latency percentiles and a breakdown of
+//! response codes.
+//!
+//! ```text
+//! dash-bench --queries queries.example.txt --server 127.0.0.1:50051 --qps 500 \
+//!     --concurrency 16 --duration 30 --qtype-mix A=80,AAAA=15,MX=5
+//! ```
+
+use clap::Parser;
+use rustdns::{Class, Extension, Message, Type};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const EDNS_RECCOMENDED_OCTETS: usize = 4096;
+
+#[derive(Parser, Debug)]
+#[command(name = "dash-bench", version)]
+/// Sends DNS queries to a server at a target rate and reports latency and response codes
+struct Args {
+    /// File with one query per line as "name" or "name TYPE", lines starting with # are
+    /// ignored. Names without a type get one from --qtype-mix
+    #[arg(short, long, value_name = "FILE")]
+    queries: PathBuf,
+
+    /// Server to query
+    #[arg(short, long, value_name = "ADDR", default_value = "127.0.0.1:50051")]
+    server: SocketAddr,
+
+    /// Target queries per second across all senders, 0 sends as fast as responses arrive
+    #[arg(long, default_value_t = 100)]
+    qps: u64,
+
+    /// Number of senders, each keeps one query outstanding at a time
+    #[arg(short, long, default_value_t = 10)]
+    concurrency: usize,
+
+    /// How long to send queries for
+    #[arg(short, long, value_name = "SECONDS", default_value_t = 10)]
+    duration: u64,
+
+    /// Weighted query types for names without an explicit type, e.g. A=80,AAAA=15,MX=5
+    #[arg(long, value_name = "MIX", default_value = "A=1", value_parser = parse_qtype_mix)]
+    qtype_mix: QtypeMix,
+
+    /// How long to wait for each response before counting it as timed out
+    #[arg(long, value_name = "MS", default_value_t = 2000)]
+    timeout_ms: u64,
+}
+
+/// Query types repeated according to their weight, so cycling through it gives the mix.
+#[derive(Debug, Clone)]
+struct QtypeMix(Vec<Type>);
+
+fn parse_qtype_mix(s: &str) -> Result<QtypeMix, String> {
+    let mut schedule = Vec::new();
+    for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (qtype, weight) = match entry.split_once('=') {
+            Some((t, w)) => (t.trim(), w.trim()),
+            None => (entry, "1"),
+        };
+        let qtype = qtype
+            .to_ascii_uppercase()
+            .parse::<Type>()
+            .map_err(|_| format!("unknown query type \"{}\"", qtype))?;
+        let weight = weight
+            .parse::<usize>()
+            .map_err(|_| format!("weight \"{}\" of {} is not a number", weight, qtype))?;
+        schedule.extend(std::iter::repeat_n(qtype, weight));
+    }
+    if schedule.is_empty() {
+        return Err("the mix needs at least one type with a weight above 0".to_string());
+    }
+    Ok(QtypeMix(schedule))
+}
+
+struct Query {
+    name: String,
+    qtype: Option<Type>,
+}
+
+fn read_queries(path: &Path) -> Result<Vec<Query>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("reading {}: {}", path.display(), e))?;
+    let mut queries = Vec::new();
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let name = fields.next().unwrap_or_default().to_string();
+        let qtype = match fields.next() {
+            Some(t) => Some(t.to_ascii_uppercase().parse::<Type>().map_err(|_| {
+                format!(
+                    "{}:{}: unknown query type \"{}\"",
+                    path.display(),
+                    line_number + 1,
+                    t
+                )
+            })?),
+            None => None,
+        };
+        queries.push(Query { name, qtype });
+    }
+    if queries.is_empty() {
+        return Err(format!("{} does not contain any queries", path.display()));
+    }
+    Ok(queries)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Outcome {
+    Rcode(String),
+    Timeout,
+    /// A datagram came back that is not a DNS message.
+    Malformed,
+    /// The response carried our ID but a different question.
+    Mismatched,
+    SendError,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Rcode(r) => write!(f, "{}", r),
+            Outcome::Timeout => write!(f, "Timeout"),
+            Outcome::Malformed => write!(f, "Malformed"),
+            Outcome::Mismatched => write!(f, "Mismatched"),
+            Outcome::SendError => write!(f, "SendError"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Results {
+    latencies: Vec<Duration>,
+    outcomes: BTreeMap<Outcome, u64>,
+    sent: u64,
+}
+
+impl Results {
+    fn merge(&mut self, other: Results) {
+        self.latencies.extend(other.latencies);
+        for (outcome, count) in other.outcomes {
+            *self.outcomes.entry(outcome).or_default() += count;
+        }
+        self.sent += other.sent;
+    }
+}
+
+/// Sends one query and waits for its response. Returns the outcome and, for anything that
+/// came back as a DNS message, the round trip time.
+fn send_query(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    name: &str,
+    qtype: Type,
+    timeout: Duration,
+) -> (Outcome, Option<Duration>) {
+    let mut msg = Message::default();
+    msg.add_question(name, qtype, Class::Internet);
+    msg.add_extension(Extension {
+        payload_size: EDNS_RECCOMENDED_OCTETS as u16,
+        ..Default::default()
+    });
+    let query = match msg.to_vec() {
+        Ok(q) => q,
+        Err(_) => return (Outcome::SendError, None),
+    };
+
+    let start = Instant::now();
+    if socket.send_to(&query, server).is_err() {
+        return (Outcome::SendError, None);
+    }
+
+    let mut buffer = [0; EDNS_RECCOMENDED_OCTETS];
+    loop {
+        let remaining = match timeout.checked_sub(start.elapsed()) {
+            Some(r) if !r.is_zero() => r,
+            _ => return (Outcome::Timeout, None),
+        };
+        if socket.set_read_timeout(Some(remaining)).is_err() {
+            return (Outcome::SendError, None);
+        }
+        let (length, from) = match socket.recv_from(&mut buffer) {
+            Ok(r) => r,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return (Outcome::Timeout, None)
+            }
+            Err(_) => return (Outcome::SendError, None),
+        };
+        if from != server {
+            // Stray datagram, keep waiting for the real answer
+            continue;
+        }
+        let rtt = start.elapsed();
+        let rsp = match Message::from_slice(&buffer[..length]) {
+            Ok(r) => r,
+            Err(_) => return (Outcome::Malformed, Some(rtt)),
+        };
+        if rsp.id != msg.id {
+            // Late answer to an earlier query that timed out
+            continue;
+        }
+        if rsp.questions != msg.questions {
+            return (Outcome::Mismatched, Some(rtt));
+        }
+        return (Outcome::Rcode(rsp.rcode.to_string()), Some(rtt));
+    }
+}
+
+fn run_sender(
+    sender: usize,
+    args: &Args,
+    queries: &[Query],
+    start: Instant,
+    deadline: Instant,
+) -> Results {
+    let mut results = Results::default();
+    let bind_address = if args.server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = match UdpSocket::bind(bind_address) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("sender {} could not bind a socket: {}", sender, e);
+            return results;
+        }
+    };
+
+    let timeout = Duration::from_millis(args.timeout_ms);
+    // Every sender gets an equal share of the target rate, staggered so they do not all
+    // fire at the same instant
+    let interval =
+        (args.qps > 0).then(|| Duration::from_secs_f64(args.concurrency as f64 / args.qps as f64));
+    let mut next_send =
+        start + interval.unwrap_or_default() * sender as u32 / args.concurrency as u32;
+
+    let mut k = sender;
+    while Instant::now() < deadline {
+        if let Some(interval) = interval {
+            let now = Instant::now();
+            if next_send > now {
+                thread::sleep(next_send - now);
+            }
+            next_send += interval;
+            if next_send >= deadline {
+                break;
+            }
+        }
+
+        let query = &queries[k % queries.len()];
+        let mix = &args.qtype_mix.0;
+        let qtype = query.qtype.unwrap_or(mix[k % mix.len()]);
+        k += args.concurrency;
+
+        let (outcome, rtt) = send_query(&socket, args.server, &query.name, qtype, timeout);
+        results.sent += 1;
+        if let Some(rtt) = rtt {
+            results.latencies.push(rtt);
+        }
+        *results.outcomes.entry(outcome).or_default() += 1;
+    }
+    results
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() as f64 - 1.0) * p).round() as usize;
+    sorted[index]
+}
+
+fn report(args: &Args, results: &mut Results, elapsed: Duration) {
+    results.latencies.sort();
+    let answered = results.latencies.len() as u64;
+    let seconds = elapsed.as_secs_f64();
+
+    println!("Server:       {}", args.server);
+    println!(
+        "Duration:     {:.2}s, {} senders, target {}",
+        seconds,
+        args.concurrency,
+        if args.qps > 0 {
+            format!("{} qps", args.qps)
+        } else {
+            "unlimited".to_string()
+        }
+    );
+    println!(
+        "Sent:         {} ({:.1} qps)",
+        results.sent,
+        results.sent as f64 / seconds
+    );
+    println!(
+        "Answered:     {} ({:.1} responses/s)",
+        answered,
+        answered as f64 / seconds
+    );
+
+    println!("\nLatency of answered queries");
+    for (label, p) in [
+        ("p50", 0.50),
+        ("p90", 0.90),
+        ("p99", 0.99),
+        ("p99.9", 0.999),
+    ] {
+        println!(
+            "  {:<6} {:>10.3}ms",
+            label,
+            percentile(&results.latencies, p).as_secs_f64() * 1000.0
+        );
+    }
+    println!(
+        "  {:<6} {:>10.3}ms",
+        "max",
+        results
+            .latencies
+            .last()
+            .copied()
+            .unwrap_or_default()
+            .as_secs_f64()
+            * 1000.0
+    );
+
+    println!("\nOutcomes");
+    for (outcome, count) in &results.outcomes {
+        println!(
+            "  {:<12} {:>10} {:>7.2}%",
+            outcome.to_string(),
+            count,
+            *count as f64 * 100.0 / results.sent.max(1) as f64
+        );
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    if args.concurrency == 0 {
+        eprintln!("--concurrency must be at least 1");
+        std::process::exit(2);
+    }
+    let queries = match read_queries(&args.queries) {
+        Ok(q) => q,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let results = Arc::new(Mutex::new(Results::default()));
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(args.duration);
+    thread::scope(|s| {
+        for sender in 0..args.concurrency {
+            let results = Arc::clone(&results);
+            let args = &args;
+            let queries = &queries;
+            s.spawn(move || {
+                let r = run_sender(sender, args, queries, start, deadline);
+                results.lock().unwrap().merge(r);
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+
+    let mut results = std::mem::take(&mut *results.lock().unwrap());
+    report(&args, &mut results, elapsed);
+}
@@ -0,0 +1,213 @@
+use crate::config::{normalize_name, BlockAction, BlocklistConfig};
+use crate::configerror::{ConfigError, ConfigErrorReason, Result};
+use crate::domaintrie::DomainTrie;
+use log::{debug, info};
+use rustdns::{Class, Message, Rcode, Record, Resource, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Names hosts files map to themselves, which are never worth blocking.
+const HOSTS_FILE_NAMES: [&str; 6] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+];
+
+/// Hits and size of one list.
+#[derive(Debug, Clone)]
+pub struct ListStats {
+    pub path: PathBuf,
+    pub entries: usize,
+    pub hits: u64,
+}
+
+#[derive(Debug)]
+struct List {
+    path: PathBuf,
+    entries: usize,
+    hits: AtomicU64,
+}
+
+/// A domain rule read from one line of a list.
+enum Rule {
+    Block(String),
+    Allow(String),
+}
+
+/// Reads one line in any of the supported formats: a plain domain, a hosts file entry or
+/// an adblock ||domain^ rule, where @@||domain^ is an exception. Adblock rules with paths
+/// or options are not about whole domains and are skipped.
+fn parse_line(line: &str) -> Vec<Rule> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with(['#', '!', '[']) {
+        return Vec::new();
+    }
+    if let Some(rule) = line.strip_prefix("@@||") {
+        return adblock_domain(rule).map(Rule::Allow).into_iter().collect();
+    }
+    if let Some(rule) = line.strip_prefix("||") {
+        return adblock_domain(rule).map(Rule::Block).into_iter().collect();
+    }
+
+    let line = line.split('#').next().unwrap_or_default();
+    let mut fields = line.split_whitespace();
+    let Some(first) = fields.next() else {
+        return Vec::new();
+    };
+    if first.parse::<IpAddr>().is_ok() {
+        return fields
+            .filter(|name| !HOSTS_FILE_NAMES.contains(&name.to_ascii_lowercase().as_str()))
+            .filter_map(valid_domain)
+            .map(Rule::Block)
+            .collect();
+    }
+    valid_domain(first.trim_start_matches("*."))
+        .map(Rule::Block)
+        .into_iter()
+        .collect()
+}
+
+fn adblock_domain(rule: &str) -> Option<String> {
+    let (domain, rest) = rule.split_once('^').unwrap_or((rule, ""));
+    if !rest.is_empty() {
+        return None;
+    }
+    valid_domain(domain)
+}
+
+fn valid_domain(domain: &str) -> Option<String> {
+    let domain = normalize_name(domain);
+    let valid = !domain.is_empty()
+        && domain.split('.').all(|l| {
+            !l.is_empty()
+                && l.bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        });
+    valid.then_some(domain)
+}
+
+fn read_list(path: &Path) -> Result<Vec<Rule>> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        ConfigError::new(ConfigErrorReason::Io(format!(
+            "reading {}: {}",
+            path.display(),
+            e
+        )))
+    })?;
+    Ok(contents.lines().flat_map(parse_line).collect())
+}
+
+/// The filtering stage run before resolution.
+#[derive(Debug)]
+pub struct Blocklist {
+    config: BlocklistConfig,
+    // Index of the list that blocked the domain
+    blocked: DomainTrie<usize>,
+    allowed: DomainTrie<()>,
+    lists: Vec<List>,
+}
+
+impl Blocklist {
+    pub fn new(config: &BlocklistConfig) -> Result<Self> {
+        let mut blocked = DomainTrie::new();
+        let mut allowed = DomainTrie::new();
+        let mut lists = Vec::with_capacity(config.lists.len());
+        for (index, path) in config.lists.iter().enumerate() {
+            let mut entries = 0;
+            for rule in read_list(path)? {
+                match rule {
+                    Rule::Block(domain) => {
+                        blocked.insert(&domain, index);
+                        entries += 1;
+                    }
+                    Rule::Allow(domain) => {
+                        allowed.insert(&domain, ());
+                    }
+                }
+            }
+            info!("Loaded {} blocked domains from {}", entries, path.display());
+            lists.push(List {
+                path: path.clone(),
+                entries,
+                hits: AtomicU64::new(0),
+            });
+        }
+        for domain in &config.allowlist {
+            allowed.insert(domain, ());
+        }
+        for path in &config.allowlist_files {
+            for rule in read_list(path)? {
+                let (Rule::Block(domain) | Rule::Allow(domain)) = rule;
+                allowed.insert(&domain, ());
+            }
+        }
+        Ok(Blocklist {
+            config: config.clone(),
+            blocked,
+            allowed,
+            lists,
+        })
+    }
+
+    /// Index of the list blocking name, if it is blocked and not allowlisted.
+    pub fn blocking_list(&self, name: &str) -> Option<usize> {
+        if self.blocked.is_empty() || self.allowed.longest_match(name).is_some() {
+            return None;
+        }
+        self.blocked.longest_match(name).map(|(_, list)| *list)
+    }
+
+    /// Answers query with the configured action if its name is blocked, counting the hit
+    /// against the list that blocked it.
+    pub fn check(&self, query: &Message) -> Option<Message> {
+        let question = query.questions.first()?;
+        let list = self.blocking_list(&question.name)?;
+        self.lists[list].hits.fetch_add(1, Ordering::Relaxed);
+        debug!(
+            "Blocked {} listed in {}",
+            question.name,
+            self.lists[list].path.display()
+        );
+
+        let mut rsp = Message::default();
+        let address = match (self.config.action, question.r#type) {
+            (BlockAction::Nxdomain, _) => {
+                rsp.rcode = Rcode::NXDomain;
+                None
+            }
+            (BlockAction::Null, Type::A) => Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            (BlockAction::Null, Type::AAAA) => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+            (BlockAction::Sinkhole, Type::A) => self.config.sinkhole_ipv4.map(IpAddr::V4),
+            (BlockAction::Sinkhole, Type::AAAA) => self.config.sinkhole_ipv6.map(IpAddr::V6),
+            _ => None,
+        };
+        if let Some(address) = address {
+            rsp.answers.push(Record {
+                name: question.name.clone(),
+                class: Class::Internet,
+                ttl: Duration::from_secs(self.config.ttl as u64),
+                resource: match address {
+                    IpAddr::V4(v4) => Resource::A(v4),
+                    IpAddr::V6(v6) => Resource::AAAA(v6),
+                },
+            });
+        }
+        Some(rsp)
+    }
+
+    pub fn stats(&self) -> Vec<ListStats> {
+        self.lists
+            .iter()
+            .map(|l| ListStats {
+                path: l.path.clone(),
+                entries: l.entries,
+                hits: l.hits.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,227 @@
+use crate::cidr::Cidr;
+use crate::config::{ClientLimitsConfig, LimitAction};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Clients without queries in flight that have been quiet this long are forgotten when the
+/// table is full. Their rate limit bucket has long been refilled by then.
+const IDLE_CLIENT: Duration = Duration::from_secs(10);
+
+/// Number of clients listed in [`ClientLimitStats::busiest_clients`].
+const BUSIEST_CLIENTS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Client(IpAddr),
+    /// Index of a rule, shared by every client it covers.
+    Rule(usize),
+}
+
+#[derive(Debug)]
+struct Usage {
+    tokens: f64,
+    updated: Instant,
+    in_flight: usize,
+}
+
+/// Whether a query may go ahead.
+#[derive(Debug)]
+pub enum Admission {
+    /// Holds the recursion slots of the query, if it needs recursion, until dropped.
+    Admitted(Option<InFlight>),
+    Limited(LimitAction),
+}
+
+/// A recursion counted against the limits of its client until dropped.
+#[derive(Debug)]
+pub struct InFlight {
+    limits: Arc<ClientLimits>,
+    keys: Vec<Key>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut usage = self
+            .limits
+            .usage
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for key in &self.keys {
+            if let Some(u) = usage.get_mut(key) {
+                u.in_flight = u.in_flight.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// Recursions in flight for the clients of one rule.
+#[derive(Debug, Clone)]
+pub struct RuleUsage {
+    pub clients: Vec<Cidr>,
+    pub in_flight: usize,
+    pub limited: u64,
+}
+
+/// Point in time snapshot of [`ClientLimits`].
+#[derive(Debug, Clone)]
+pub struct ClientLimitStats {
+    pub tracked_clients: usize,
+    /// Recursions in flight for all clients together.
+    pub in_flight: usize,
+    /// Clients with the most recursions in flight, most first.
+    pub busiest_clients: Vec<(IpAddr, usize)>,
+    pub rules: Vec<RuleUsage>,
+    pub dropped: u64,
+    pub refused: u64,
+}
+
+/// Per client and per network limits on query rate and recursions in flight.
+#[derive(Debug)]
+pub struct ClientLimits {
+    config: ClientLimitsConfig,
+    usage: Mutex<HashMap<Key, Usage>>,
+    rule_limited: Vec<AtomicU64>,
+    dropped: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl ClientLimits {
+    pub fn new(config: &ClientLimitsConfig) -> Self {
+        ClientLimits {
+            config: config.clone(),
+            usage: Mutex::new(HashMap::new()),
+            rule_limited: config.rules.iter().map(|_| AtomicU64::new(0)).collect(),
+            dropped: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.config.queries_per_second > 0
+            || self.config.max_in_flight > 0
+            || !self.config.rules.is_empty()
+    }
+
+    /// The rate and in flight limits of key.
+    fn limits(&self, key: Key) -> (u32, usize) {
+        match key {
+            Key::Client(_) => (self.config.queries_per_second, self.config.max_in_flight),
+            Key::Rule(i) => {
+                let rule = &self.config.rules[i];
+                (rule.queries_per_second, rule.max_in_flight)
+            }
+        }
+    }
+
+    /// Counts a query from client against every limit that applies to it. recursion is
+    /// whether the query will need recursion rather than being answered from the cache or
+    /// local data. A limited query is not counted at all.
+    pub fn admit(self: &Arc<Self>, client: IpAddr, recursion: bool) -> Admission {
+        if !self.is_enabled() {
+            return Admission::Admitted(None);
+        }
+        let mut keys = vec![Key::Client(client)];
+        keys.extend(
+            self.config
+                .rules
+                .iter()
+                .enumerate()
+                .filter(|(_, r)| r.clients.iter().any(|c| c.contains(client)))
+                .map(|(i, _)| Key::Rule(i)),
+        );
+
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap_or_else(PoisonError::into_inner);
+        if usage.len() >= self.config.max_clients && !usage.contains_key(&keys[0]) {
+            usage.retain(|key, u| {
+                matches!(key, Key::Rule(_))
+                    || u.in_flight > 0
+                    || now.duration_since(u.updated) < IDLE_CLIENT
+            });
+            if usage.len() >= self.config.max_clients {
+                // Rather let a new client through unlimited than grow without bound
+                keys.remove(0);
+            }
+        }
+
+        // Check every limit before counting against any, so a query limited by one of them
+        // uses up nothing of the others
+        for &key in &keys {
+            let (rate, max_in_flight) = self.limits(key);
+            let u = usage.entry(key).or_insert(Usage {
+                tokens: rate as f64,
+                updated: now,
+                in_flight: 0,
+            });
+            let elapsed = now.duration_since(u.updated).as_secs_f64();
+            u.tokens = (u.tokens + elapsed * rate as f64).min(rate as f64);
+            u.updated = now;
+            let over_rate = rate > 0 && u.tokens < 1.0;
+            let over_in_flight = recursion && max_in_flight > 0 && u.in_flight >= max_in_flight;
+            if over_rate || over_in_flight {
+                if let Key::Rule(i) = key {
+                    self.rule_limited[i].fetch_add(1, Ordering::Relaxed);
+                }
+                let counter = match self.config.action {
+                    LimitAction::Drop => &self.dropped,
+                    LimitAction::Refuse => &self.refused,
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+                return Admission::Limited(self.config.action);
+            }
+        }
+
+        for &key in &keys {
+            if let Some(u) = usage.get_mut(&key) {
+                if self.limits(key).0 > 0 {
+                    u.tokens -= 1.0;
+                }
+                if recursion {
+                    u.in_flight += 1;
+                }
+            }
+        }
+        Admission::Admitted(recursion.then(|| InFlight {
+            limits: self.clone(),
+            keys,
+        }))
+    }
+
+    pub fn stats(&self) -> ClientLimitStats {
+        let usage = self.usage.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut clients: Vec<(IpAddr, usize)> = usage
+            .iter()
+            .filter_map(|(key, u)| match key {
+                Key::Client(ip) => Some((*ip, u.in_flight)),
+                Key::Rule(_) => None,
+            })
+            .collect();
+        let tracked_clients = clients.len();
+        let in_flight = clients.iter().map(|(_, n)| n).sum();
+        clients.retain(|(_, n)| *n > 0);
+        clients.sort_by_key(|(_, n)| std::cmp::Reverse(*n));
+        clients.truncate(BUSIEST_CLIENTS);
+        let rules = self
+            .config
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(i, r)| RuleUsage {
+                clients: r.clients.clone(),
+                in_flight: usage.get(&Key::Rule(i)).map_or(0, |u| u.in_flight),
+                limited: self.rule_limited[i].load(Ordering::Relaxed),
+            })
+            .collect();
+        ClientLimitStats {
+            tracked_clients,
+            in_flight,
+            busiest_clients: clients,
+            rules,
+            dropped: self.dropped.load(Ordering::Relaxed),
+            refused: self.refused.load(Ordering::Relaxed),
+        }
+    }
+}
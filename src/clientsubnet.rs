@@ -0,0 +1,113 @@
+//! EDNS Client Subnet (RFC 7871).
+//!
+//! A client's address is cut to a prefix and sent along with recursive queries to the
+//! authoritative servers configured for it. Each answer says how much of the prefix it was
+//! chosen for, its scope, and is only cached for clients within that scope, so an answer
+//! meant for one network is not handed to clients in another.
+
+use crate::cidr::Cidr;
+use crate::config::ClientSubnetConfig;
+use std::cell::Cell;
+use std::net::{IpAddr, SocketAddr};
+
+/// The EDNS option code of a client subnet.
+pub const ECS_OPTION: u16 = 8;
+
+const FAMILY_IPV4: u16 = 1;
+const FAMILY_IPV6: u16 = 2;
+
+/// The subnet of the client a lookup is made for, and the longest scope any answer to it
+/// was given for so far.
+#[derive(Debug)]
+pub struct ClientSubnet {
+    subnet: Cidr,
+    // None until a server the subnet was sent to answers
+    scope: Cell<Option<u8>>,
+}
+
+impl ClientSubnet {
+    /// The subnet sent for client, None if client subnets are off. Clients in loopback,
+    /// private and link-local networks are sent as a prefix of length 0, which reveals
+    /// nothing and asks for an answer that suits every client (RFC 7871 7.1.2).
+    pub fn for_client(client: IpAddr, config: &ClientSubnetConfig) -> Option<ClientSubnet> {
+        if !config.enabled {
+            return None;
+        }
+        // An IPv4 client on a dual stack socket shows up as an IPv4 mapped IPv6 address
+        let client = match client {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(client, IpAddr::V4),
+            v4 => v4,
+        };
+        let prefix_len = match client {
+            IpAddr::V4(v4) if v4.is_private() || v4.is_loopback() || v4.is_link_local() => 0,
+            IpAddr::V6(v6)
+                if v6.is_loopback()
+                    || v6.segments()[0] & 0xfe00 == 0xfc00
+                    || v6.segments()[0] & 0xffc0 == 0xfe80 =>
+            {
+                0
+            }
+            IpAddr::V4(_) => config.ipv4_prefix_len,
+            IpAddr::V6(_) => config.ipv6_prefix_len,
+        };
+        Some(ClientSubnet {
+            subnet: Cidr::new(client, prefix_len)?,
+            scope: Cell::new(None),
+        })
+    }
+
+    /// Whether server is one client subnets are sent to.
+    pub fn sent_to(server: &SocketAddr, config: &ClientSubnetConfig) -> bool {
+        config.servers.iter().any(|s| s.contains(server.ip()))
+    }
+
+    /// The source prefix length, how much of the client address is sent.
+    pub fn source_prefix_len(&self) -> u8 {
+        self.subnet.prefix_len()
+    }
+
+    /// The data of the option sent with a query (RFC 7871 6): the family, the source prefix
+    /// length, a scope of 0 and as many octets of the address as the prefix covers.
+    pub fn option_data(&self) -> Vec<u8> {
+        let (family, octets) = match self.subnet.address() {
+            IpAddr::V4(v4) => (FAMILY_IPV4, v4.octets().to_vec()),
+            IpAddr::V6(v6) => (FAMILY_IPV6, v6.octets().to_vec()),
+        };
+        let source = self.source_prefix_len();
+        let mut data = family.to_be_bytes().to_vec();
+        data.extend([source, 0]);
+        data.extend_from_slice(&octets[..(source as usize).div_ceil(8)]);
+        data
+    }
+
+    /// The scope prefix length of a response's option, None if the option does not repeat
+    /// the family, source prefix and address that were sent, in which case the response
+    /// must be discarded (RFC 7871 7.3).
+    pub fn response_scope(&self, data: &[u8]) -> Option<u8> {
+        let sent = self.option_data();
+        if data.len() != sent.len() || data[..3] != sent[..3] || data[4..] != sent[4..] {
+            return None;
+        }
+        Some(data[3])
+    }
+
+    /// Remembers that an answer was given for scope, 0 for one without the option. A scope
+    /// longer than the source prefix only applies to the source prefix (RFC 7871 7.3.1).
+    pub fn record_scope(&self, scope: u8) {
+        let scope = scope.min(self.source_prefix_len());
+        self.scope
+            .set(Some(self.scope.get().map_or(scope, |s| s.max(scope))));
+    }
+
+    /// The longest scope the answers for the client were given for, 0 if they hold for
+    /// every client. None if the subnet was never sent.
+    pub fn scope(&self) -> Option<u8> {
+        self.scope.get()
+    }
+
+    /// The part of a cache key that keeps apart the answers given for the prefix of length
+    /// scope containing the client.
+    pub fn cache_key(&self, scope: u8) -> Option<String> {
+        Cidr::new(self.subnet.address(), scope).map(|prefix| format!("ecs/{}", prefix))
+    }
+}
@@ -0,0 +1,1026 @@
+use crate::cidr::Cidr;
+use crate::configerror::{ConfigError, ConfigErrorReason, Result};
+use crate::dnssec::TrustAnchor;
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+const DNS_PORT: u16 = 53;
+
+/// Everything the dash binary needs to start a server. Built from defaults, then a TOML file,
+/// then command line flags, and checked with [`Config::validate`] before use.
+///
+/// ```toml
+/// [server]
+/// listen = ["0.0.0.0", "::"]
+/// port = 53
+///
+/// [resolver]
+/// mode = "forward"
+/// forwarders = ["1.1.1.1", "9.9.9.9:53"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub pool: PoolConfig,
+    pub cache: CacheConfig,
+    pub resolver: ResolverConfig,
+    pub log: LogConfig,
+    pub zones: Vec<ZoneConfig>,
+    pub local_data: LocalDataConfig,
+    pub blocklist: BlocklistConfig,
+    /// Response policy zones, highest priority first.
+    pub rpz: Vec<ZoneConfig>,
+    pub access_control: AccessControlConfig,
+    pub response_rate_limit: ResponseRateLimitConfig,
+    pub client_limits: ClientLimitsConfig,
+    pub dnssec: DnssecConfig,
+    pub client_subnet: ClientSubnetConfig,
+    /// Client specific views, the first one matching a client is used. Clients matching
+    /// none get everything configured above.
+    pub views: Vec<ViewConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// A UDP socket is bound on port for every address.
+    pub listen: Vec<IpAddr>,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            port: 50051,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    pub size: usize,
+    pub min_size: usize,
+    pub max_size: usize,
+    pub max_exec_time_ms: u64,
+    /// A worker that serviced fewer jobs than this in the last window counts towards shrinking.
+    pub resize_lower_bound: usize,
+    /// A worker that serviced more jobs than this in the last window counts towards growing.
+    pub resize_upper_bound: usize,
+    /// Number of received queries between two dynamic resizing passes.
+    pub resize_interval: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            size: 10,
+            min_size: 5,
+            max_size: 15,
+            max_exec_time_ms: 5000,
+            resize_lower_bound: 3,
+            resize_upper_bound: 6,
+            resize_interval: 20,
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn max_exec_time(&self) -> Duration {
+        Duration::from_millis(self.max_exec_time_ms)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub capacity: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig { capacity: 100 }
+    }
+}
+
+/// How queries that cannot be answered from the cache are resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamMode {
+    /// Iterate from the root servers.
+    Recursive,
+    /// Send the query to one of the configured forwarders with recursion desired set.
+    Forward,
+}
+
+impl FromStr for UpstreamMode {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "recursive" => Ok(UpstreamMode::Recursive),
+            "forward" => Ok(UpstreamMode::Forward),
+            _ => Err(ConfigError::new(ConfigErrorReason::InvalidValue(format!(
+                "unknown upstream mode \"{}\", expected \"recursive\" or \"forward\"",
+                s
+            )))),
+        }
+    }
+}
+
+/// Order in which the healthy servers of an upstream pool are tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpstreamSelection {
+    /// Start at the next server for every query so the load is spread evenly.
+    RoundRobin,
+    /// Prefer the server with the lowest smoothed response time.
+    Fastest,
+}
+
+impl FromStr for UpstreamSelection {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "round-robin" => Ok(UpstreamSelection::RoundRobin),
+            "fastest" => Ok(UpstreamSelection::Fastest),
+            _ => Err(ConfigError::new(ConfigErrorReason::InvalidValue(format!(
+                "unknown upstream selection \"{}\", expected \"round-robin\" or \"fastest\"",
+                s
+            )))),
+        }
+    }
+}
+
+/// How much of a query name recursion reveals to the servers of the zones above it
+/// (RFC 9156).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QnameMinimization {
+    /// Every server is sent the full question.
+    Off,
+    /// Each zone is asked only about the name one label below it, with an A query. Servers
+    /// that fail such queries or deny names that exist are sent the full question instead.
+    Relaxed,
+    /// As relaxed, but a failed or denied minimized query is the answer.
+    Strict,
+}
+
+/// Queries for names at or below name are forwarded to these servers instead of being
+/// resolved the usual way, whatever the upstream mode.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardZoneConfig {
+    pub name: String,
+    #[serde(deserialize_with = "deserialize_server_addresses")]
+    pub forwarders: Vec<SocketAddr>,
+}
+
+/// Recursion for names at or below name starts at these nameservers instead of the root,
+/// for zones that are not delegated from the public tree. Unlike a forward zone the servers
+/// are queried iteratively and only need to be authoritative.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StubZoneConfig {
+    pub name: String,
+    #[serde(deserialize_with = "deserialize_server_addresses")]
+    pub nameservers: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolverConfig {
+    pub mode: UpstreamMode,
+    /// Tried in order until one answers, only used in recursive mode.
+    pub root_servers: Vec<Ipv4Addr>,
+    /// Upstream pool used in forward mode. The port defaults to 53.
+    #[serde(deserialize_with = "deserialize_server_addresses")]
+    pub forwarders: Vec<SocketAddr>,
+    /// Applies to the forwarders and to every forward zone.
+    pub selection: UpstreamSelection,
+    /// How often every forwarder is probed, 0 disables probing. Failed queries mark a
+    /// forwarder down either way, probing is what brings it back.
+    pub health_check_interval_ms: u64,
+    pub forward_zones: Vec<ForwardZoneConfig>,
+    pub stub_zones: Vec<StubZoneConfig>,
+    /// How long to wait for a single upstream server to answer.
+    pub query_timeout_ms: u64,
+    /// Sends query names to upstream servers in random mixed case and only accepts
+    /// responses that repeat it exactly. Only for servers known to copy the question as
+    /// they got it, responses from others are all discarded.
+    pub randomize_case: bool,
+    pub qname_minimization: QnameMinimization,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            mode: UpstreamMode::Recursive,
+            // a.root-servers.net
+            root_servers: vec![Ipv4Addr::new(198, 41, 0, 4)],
+            forwarders: Vec::new(),
+            selection: UpstreamSelection::RoundRobin,
+            health_check_interval_ms: 5000,
+            forward_zones: Vec::new(),
+            stub_zones: Vec::new(),
+            query_timeout_ms: 10_000,
+            randomize_case: false,
+            qname_minimization: QnameMinimization::Relaxed,
+        }
+    }
+}
+
+impl ResolverConfig {
+    pub fn query_timeout(&self) -> Duration {
+        Duration::from_millis(self.query_timeout_ms)
+    }
+
+    pub fn health_check_interval(&self) -> Option<Duration> {
+        (self.health_check_interval_ms > 0)
+            .then(|| Duration::from_millis(self.health_check_interval_ms))
+    }
+}
+
+/// A zone Dash answers authoritatively from an RFC 1035 zone file. Relative names in the
+/// file are relative to name until the file sets its own $ORIGIN.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    pub name: String,
+    pub file: PathBuf,
+}
+
+/// What clients in one of the clients prefixes see, for split-horizon DNS. Every section
+/// left out is taken from the top level configuration, and the view caches its answers
+/// apart from every other view.
+///
+/// ```toml
+/// [[views]]
+/// name = "office"
+/// clients = ["10.0.0.0/8", "fd00::/8"]
+///
+/// [[views.zones]]
+/// name = "example.com"
+/// file = "/etc/dash/example.com.internal.zone"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ViewConfig {
+    pub name: String,
+    pub clients: Vec<Cidr>,
+    pub zones: Option<Vec<ZoneConfig>>,
+    pub forward_zones: Option<Vec<ForwardZoneConfig>>,
+    pub blocklist: Option<BlocklistConfig>,
+    pub rpz: Option<Vec<ZoneConfig>>,
+}
+
+/// Lowercases a domain name and strips the trailing dot, the root becomes "".
+pub fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// One of off, error, warn, info, debug or trace.
+    #[serde(deserialize_with = "deserialize_level")]
+    pub level: LevelFilter,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: LevelFilter::Info,
+        }
+    }
+}
+
+/// Names pinned to addresses without running an authoritative server. They are answered
+/// before local zones, the cache and recursion.
+///
+/// ```toml
+/// [local_data]
+/// hosts_files = ["/etc/hosts"]
+/// records = ["db.dev.internal A 10.0.0.12", "10.0.0.99 PTR printer.dev.internal"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalDataConfig {
+    /// Files in /etc/hosts format, read again whenever they change.
+    pub hosts_files: Vec<PathBuf>,
+    /// A, AAAA and PTR records as "name TYPE data". A PTR may be given for an address
+    /// instead of its reverse name.
+    pub records: Vec<String>,
+    /// TTL of every local answer.
+    pub ttl: u32,
+    /// How often the hosts files are checked for changes, 0 disables it.
+    pub reload_interval_ms: u64,
+}
+
+impl Default for LocalDataConfig {
+    fn default() -> Self {
+        LocalDataConfig {
+            hosts_files: Vec::new(),
+            records: Vec::new(),
+            ttl: 3600,
+            reload_interval_ms: 2000,
+        }
+    }
+}
+
+impl LocalDataConfig {
+    pub fn reload_interval(&self) -> Option<Duration> {
+        (self.reload_interval_ms > 0).then(|| Duration::from_millis(self.reload_interval_ms))
+    }
+}
+
+/// What a blocked name is answered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockAction {
+    /// The name does not exist.
+    Nxdomain,
+    /// The name exists but has no records of any type.
+    Nodata,
+    /// A and AAAA queries get 0.0.0.0 and ::, anything else NODATA.
+    Null,
+    /// A and AAAA queries get the sinkhole addresses, anything else NODATA.
+    Sinkhole,
+}
+
+/// Domain lists filtered before resolution. A listed domain blocks every name below it
+/// too, unless the name is at or below an allowlisted domain.
+///
+/// ```toml
+/// [blocklist]
+/// lists = ["/etc/dash/ads.txt", "/etc/dash/malware.hosts"]
+/// allowlist = ["cdn.example.com"]
+/// action = "sinkhole"
+/// sinkhole_ipv4 = "10.0.0.250"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlocklistConfig {
+    /// Files with one domain per line, in hosts format or as adblock ||domain^ rules.
+    pub lists: Vec<PathBuf>,
+    pub allowlist: Vec<String>,
+    /// Files in any list format whose domains are never blocked.
+    pub allowlist_files: Vec<PathBuf>,
+    pub action: BlockAction,
+    pub sinkhole_ipv4: Option<Ipv4Addr>,
+    pub sinkhole_ipv6: Option<Ipv6Addr>,
+    /// TTL of the records in blocked answers.
+    pub ttl: u32,
+}
+
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        BlocklistConfig {
+            lists: Vec::new(),
+            allowlist: Vec::new(),
+            allowlist_files: Vec::new(),
+            action: BlockAction::Nxdomain,
+            sinkhole_ipv4: None,
+            sinkhole_ipv6: None,
+            ttl: 300,
+        }
+    }
+}
+
+/// What happens to queries from a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AclAction {
+    /// Queries asking for recursion are answered. Ones without the RD bit are only answered
+    /// from local data, local zones and the blocklist, and refused otherwise, so the cache
+    /// cannot be probed for what other clients looked up.
+    Allow,
+    /// Every query is answered, with or without the RD bit.
+    AllowSnoop,
+    /// Queries are dropped without an answer.
+    Deny,
+    /// Queries are answered with REFUSED.
+    Refuse,
+}
+
+impl fmt::Display for AclAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AclAction::Allow => write!(f, "allow"),
+            AclAction::AllowSnoop => write!(f, "allow-snoop"),
+            AclAction::Deny => write!(f, "deny"),
+            AclAction::Refuse => write!(f, "refuse"),
+        }
+    }
+}
+
+/// Clients in any of the clients prefixes get action.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclRuleConfig {
+    pub clients: Vec<Cidr>,
+    pub action: AclAction,
+}
+
+/// Who may use the resolver. The rule with the most specific prefix containing the client
+/// address applies, clients no rule covers get default_action. Without any rules
+/// configured loopback and private networks are allowed.
+///
+/// ```toml
+/// [access_control]
+/// default_action = "refuse"
+///
+/// [[access_control.rules]]
+/// clients = ["192.0.2.0/24"]
+/// action = "allow"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessControlConfig {
+    pub rules: Vec<AclRuleConfig>,
+    pub default_action: AclAction,
+}
+
+impl Default for AccessControlConfig {
+    fn default() -> Self {
+        let private = [
+            "127.0.0.0/8",
+            "::1",
+            "10.0.0.0/8",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            "169.254.0.0/16",
+            "fc00::/7",
+            "fe80::/10",
+        ];
+        AccessControlConfig {
+            rules: vec![AclRuleConfig {
+                clients: private.iter().map(|c| c.parse().unwrap()).collect(),
+                action: AclAction::Allow,
+            }],
+            default_action: AclAction::Refuse,
+        }
+    }
+}
+
+/// Response rate limiting, against the server being used to reflect traffic at a spoofed
+/// address. Responses are counted per client network and kind of response, each kind
+/// limited to its rate with bursts of up to one second worth of responses. Past the limit
+/// every slip-th response is sent truncated, so a real client retries over TCP, and the
+/// rest are dropped.
+///
+/// ```toml
+/// [response_rate_limit]
+/// responses_per_second = 20
+/// nxdomains_per_second = 5
+/// slip = 2
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResponseRateLimitConfig {
+    /// Rate of responses with answers, 0 turns rate limiting off.
+    pub responses_per_second: u32,
+    /// The other rates default to responses_per_second, 0 leaves that kind unlimited.
+    pub nodata_per_second: Option<u32>,
+    pub nxdomains_per_second: Option<u32>,
+    /// SERVFAIL, REFUSED, FORMERR and every other error.
+    pub errors_per_second: Option<u32>,
+    /// Every slip-th limited response is sent truncated instead of dropped. 0 drops them
+    /// all, 1 truncates them all.
+    pub slip: u32,
+    /// Only log which responses would have been limited, for tuning the rates.
+    pub log_only: bool,
+    /// Clients are counted together by network of these sizes.
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+    /// Upper bound on the networks tracked at once.
+    pub max_table_size: usize,
+}
+
+impl Default for ResponseRateLimitConfig {
+    fn default() -> Self {
+        ResponseRateLimitConfig {
+            responses_per_second: 0,
+            nodata_per_second: None,
+            nxdomains_per_second: None,
+            errors_per_second: None,
+            slip: 2,
+            log_only: false,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 56,
+            max_table_size: 100_000,
+        }
+    }
+}
+
+/// What happens to a query over a client limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitAction {
+    Drop,
+    Refuse,
+}
+
+/// Limits shared by every client in the clients prefixes together. 0 leaves a limit off.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientLimitRuleConfig {
+    pub clients: Vec<Cidr>,
+    #[serde(default)]
+    pub queries_per_second: u32,
+    #[serde(default)]
+    pub max_in_flight: usize,
+}
+
+/// Limits on how much of the server one client can take, checked as queries arrive. Every
+/// client address gets queries_per_second and max_in_flight recursions to itself, and each
+/// rule limits all of its clients together on top of that. 0 leaves a limit off.
+///
+/// ```toml
+/// [client_limits]
+/// queries_per_second = 200
+/// max_in_flight = 20
+/// action = "refuse"
+///
+/// [[client_limits.rules]]
+/// clients = ["10.42.0.0/16"]
+/// max_in_flight = 100
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientLimitsConfig {
+    pub queries_per_second: u32,
+    /// Queries needing recursion being worked on at once. Cache hits never count.
+    pub max_in_flight: usize,
+    pub rules: Vec<ClientLimitRuleConfig>,
+    pub action: LimitAction,
+    /// Upper bound on the client addresses tracked at once.
+    pub max_clients: usize,
+}
+
+impl Default for ClientLimitsConfig {
+    fn default() -> Self {
+        ClientLimitsConfig {
+            queries_per_second: 0,
+            max_in_flight: 0,
+            rules: Vec::new(),
+            action: LimitAction::Drop,
+            max_clients: 100_000,
+        }
+    }
+}
+
+/// EDNS Client Subnet (RFC 7871): recursive queries to the servers listed tell them the
+/// network of the client asking, cut to a prefix, so services that answer by location can
+/// answer for the client rather than for the resolver. Answers given for a scope are cached
+/// for clients in that scope only. Clients in loopback, private and link-local networks are
+/// never revealed, they are sent as a prefix of length 0.
+///
+/// ```toml
+/// [client_subnet]
+/// enabled = true
+/// servers = ["192.0.2.53", "2001:db8::/64"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSubnetConfig {
+    pub enabled: bool,
+    /// How much of an IPv4 client address is sent, at most 24 bits.
+    pub ipv4_prefix_len: u8,
+    /// How much of an IPv6 client address is sent, at most 56 bits.
+    pub ipv6_prefix_len: u8,
+    /// Addresses of the authoritative servers that are sent client subnets.
+    pub servers: Vec<Cidr>,
+}
+
+impl Default for ClientSubnetConfig {
+    fn default() -> Self {
+        // The longest prefixes RFC 7871 11.1 recommends sending
+        ClientSubnetConfig {
+            enabled: false,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 56,
+            servers: Vec::new(),
+        }
+    }
+}
+
+/// DNSSEC validation of recursive answers. Answers are validated from the trust anchors,
+/// those below one are either secure and get the AD bit, insecure, or bogus and answered
+/// with SERVFAIL. Forwarded answers are passed on unvalidated.
+///
+/// ```toml
+/// [dnssec]
+/// validation = true
+/// trust_anchors = [". DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D"]
+/// insecure_zones = ["corp.example"]
+/// trust_anchor_file = "/var/lib/dash/trust-anchors"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnssecConfig {
+    pub validation: bool,
+    /// DS or DNSKEY records in presentation format. Defaults to the root zone's keys.
+    pub trust_anchors: Vec<TrustAnchor>,
+    /// Zones left unvalidated although a trust anchor covers them (RFC 7646).
+    pub insecure_zones: Vec<String>,
+    /// Where the trust anchors are kept as RFC 5011 updates them. Once it exists it takes
+    /// precedence over trust_anchors for the zones it has anchors for.
+    pub trust_anchor_file: Option<PathBuf>,
+    /// How long a new key has to be seen before it is trusted.
+    pub add_hold_down_secs: u64,
+    /// How long a revoked key is remembered before it is forgotten.
+    pub remove_hold_down_secs: u64,
+    /// Answer for names that validated NSEC or NSEC3 records already prove nonexistent
+    /// without asking the zone again (RFC 8198).
+    pub aggressive_nsec: bool,
+}
+
+impl DnssecConfig {
+    pub fn add_hold_down(&self) -> Duration {
+        Duration::from_secs(self.add_hold_down_secs)
+    }
+
+    pub fn remove_hold_down(&self) -> Duration {
+        Duration::from_secs(self.remove_hold_down_secs)
+    }
+}
+
+impl Default for DnssecConfig {
+    fn default() -> Self {
+        DnssecConfig {
+            validation: true,
+            trust_anchors: ROOT_TRUST_ANCHORS
+                .iter()
+                .map(|a| a.parse().expect("root trust anchor"))
+                .collect(),
+            insecure_zones: Vec::new(),
+            trust_anchor_file: None,
+            // The 30 days of RFC 5011 2.4.1 and 6.6
+            add_hold_down_secs: 30 * 86400,
+            remove_hold_down_secs: 30 * 86400,
+            aggressive_nsec: true,
+        }
+    }
+}
+
+/// The DS records of the root zone's key signing keys, KSK-2017 and KSK-2024.
+const ROOT_TRUST_ANCHORS: [&str; 2] = [
+    ". DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+    ". DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+];
+
+/// Parses an upstream server given either as "ip" or "ip:port".
+pub fn parse_forwarder(s: &str) -> Result<SocketAddr> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    match s.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, DNS_PORT)),
+        Err(_) => Err(ConfigError::new(ConfigErrorReason::InvalidValue(format!(
+            "\"{}\" is not an IP address or IP:port",
+            s
+        )))),
+    }
+}
+
+fn deserialize_server_addresses<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Vec<SocketAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| parse_forwarder(s).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn deserialize_level<'de, D>(deserializer: D) -> std::result::Result<LevelFilter, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_log_level(&s).map_err(serde::de::Error::custom)
+}
+
+pub fn parse_log_level(s: &str) -> Result<LevelFilter> {
+    s.parse::<LevelFilter>().map_err(|_| {
+        ConfigError::new(ConfigErrorReason::InvalidValue(format!(
+            "unknown log level \"{}\", expected off, error, warn, info, debug or trace",
+            s
+        )))
+    })
+}
+
+/// Checks a list of (zone name, number of servers) and returns the normalized names.
+fn validate_zones<'a>(
+    kind: &str,
+    server_kind: &str,
+    zones: impl Iterator<Item = (&'a str, usize)>,
+) -> Result<HashSet<String>> {
+    let mut names = HashSet::new();
+    for (zone, servers) in zones {
+        let name = normalize_name(zone);
+        if !name.is_empty() && name.split('.').any(|label| label.is_empty()) {
+            return Err(invalid_value(format!(
+                "{} name \"{}\" has an empty label",
+                kind, zone
+            )));
+        }
+        if servers == 0 {
+            return Err(invalid_value(format!(
+                "{} \"{}\" needs at least one {}",
+                kind, zone, server_kind
+            )));
+        }
+        if !names.insert(name) {
+            return Err(invalid_value(format!(
+                "{} \"{}\" is configured more than once",
+                kind, zone
+            )));
+        }
+    }
+    Ok(names)
+}
+
+fn invalid_value(info: String) -> ConfigError {
+    ConfigError::new(ConfigErrorReason::InvalidValue(info))
+}
+
+fn invalid_combination(info: String) -> ConfigError {
+    ConfigError::new(ConfigErrorReason::InvalidCombination(info))
+}
+
+impl Config {
+    /// Parses a TOML document. Missing sections and keys keep their defaults, unknown keys
+    /// are rejected so typos do not silently fall back to a default.
+    pub fn from_toml(s: &str) -> Result<Config> {
+        toml::from_str(s).map_err(|e| ConfigError::new(ConfigErrorReason::Parse(e.to_string())))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            ConfigError::new(ConfigErrorReason::Io(format!(
+                "reading {}: {}",
+                path.display(),
+                e
+            )))
+        })?;
+        Config::from_toml(&contents).map_err(|e| match e.reason() {
+            ConfigErrorReason::Parse(s) => ConfigError::new(ConfigErrorReason::Parse(format!(
+                "{}: {}",
+                path.display(),
+                s
+            ))),
+            _ => e,
+        })
+    }
+
+    /// Checks the values and how they relate to each other. Run it after every source
+    /// (file, flags) has been applied.
+    pub fn validate(&self) -> Result<()> {
+        if self.server.listen.is_empty() {
+            return Err(invalid_value(
+                "server.listen needs at least one address".to_string(),
+            ));
+        }
+        if self.server.port == 0 {
+            return Err(invalid_value("server.port must not be 0".to_string()));
+        }
+
+        let pool = &self.pool;
+        if pool.min_size == 0 {
+            return Err(invalid_value(
+                "pool.min_size must be at least 1".to_string(),
+            ));
+        }
+        if pool.min_size > pool.max_size {
+            return Err(invalid_combination(format!(
+                "pool.min_size ({}) is larger than pool.max_size ({})",
+                pool.min_size, pool.max_size
+            )));
+        }
+        if pool.size < pool.min_size || pool.size > pool.max_size {
+            return Err(invalid_combination(format!(
+                "pool.size ({}) must be between pool.min_size ({}) and pool.max_size ({})",
+                pool.size, pool.min_size, pool.max_size
+            )));
+        }
+        if pool.max_exec_time_ms == 0 {
+            return Err(invalid_value(
+                "pool.max_exec_time_ms must not be 0".to_string(),
+            ));
+        }
+        if pool.resize_lower_bound > pool.resize_upper_bound {
+            return Err(invalid_combination(format!(
+                "pool.resize_lower_bound ({}) is larger than pool.resize_upper_bound ({})",
+                pool.resize_lower_bound, pool.resize_upper_bound
+            )));
+        }
+        if pool.resize_interval == 0 {
+            return Err(invalid_value(
+                "pool.resize_interval must be at least 1".to_string(),
+            ));
+        }
+
+        if self.cache.capacity == 0 {
+            return Err(invalid_value(
+                "cache.capacity must be at least 1".to_string(),
+            ));
+        }
+
+        let resolver = &self.resolver;
+        if resolver.query_timeout_ms == 0 {
+            return Err(invalid_value(
+                "resolver.query_timeout_ms must not be 0".to_string(),
+            ));
+        }
+        match resolver.mode {
+            UpstreamMode::Recursive => {
+                if resolver.root_servers.is_empty() {
+                    return Err(invalid_combination(
+                        "resolver.mode = \"recursive\" needs at least one resolver.root_servers entry"
+                            .to_string(),
+                    ));
+                }
+                if !resolver.forwarders.is_empty() {
+                    return Err(invalid_combination(
+                        "resolver.forwarders is set but resolver.mode is \"recursive\", set mode = \"forward\" to use them"
+                            .to_string(),
+                    ));
+                }
+            }
+            UpstreamMode::Forward => {
+                if resolver.forwarders.is_empty() {
+                    return Err(invalid_combination(
+                        "resolver.mode = \"forward\" needs at least one resolver.forwarders entry"
+                            .to_string(),
+                    ));
+                }
+            }
+        }
+
+        let forward_zones = resolver
+            .forward_zones
+            .iter()
+            .map(|z| (z.name.as_str(), z.forwarders.len()));
+        let forward_zone_names = validate_zones("forward zone", "forwarder", forward_zones)?;
+        let stub_zones = resolver
+            .stub_zones
+            .iter()
+            .map(|z| (z.name.as_str(), z.nameservers.len()));
+        let stub_zone_names = validate_zones("stub zone", "nameserver", stub_zones)?;
+        if let Some(name) = forward_zone_names.intersection(&stub_zone_names).next() {
+            return Err(invalid_combination(format!(
+                "\"{}\" is configured as both a forward zone and a stub zone",
+                name
+            )));
+        }
+
+        let zones = self.zones.iter().map(|z| (z.name.as_str(), 1));
+        validate_zones("zone", "file", zones)?;
+        let policy_zones = self.rpz.iter().map(|z| (z.name.as_str(), 1));
+        validate_zones("policy zone", "file", policy_zones)?;
+
+        let blocklist = &self.blocklist;
+        if blocklist.action == BlockAction::Sinkhole
+            && blocklist.sinkhole_ipv4.is_none()
+            && blocklist.sinkhole_ipv6.is_none()
+        {
+            return Err(invalid_combination(
+                "blocklist.action = \"sinkhole\" needs blocklist.sinkhole_ipv4 or blocklist.sinkhole_ipv6"
+                    .to_string(),
+            ));
+        }
+
+        if let Some(rule) = self
+            .access_control
+            .rules
+            .iter()
+            .find(|r| r.clients.is_empty())
+        {
+            return Err(invalid_value(format!(
+                "access_control rule with action {} needs at least one clients prefix",
+                rule.action
+            )));
+        }
+
+        let rrl = &self.response_rate_limit;
+        if rrl.ipv4_prefix_len > 32 || rrl.ipv6_prefix_len > 128 {
+            return Err(invalid_value(
+                "response_rate_limit prefix lengths must be at most 32 for IPv4 and 128 for IPv6"
+                    .to_string(),
+            ));
+        }
+        if rrl.max_table_size == 0 {
+            return Err(invalid_value(
+                "response_rate_limit.max_table_size must be at least 1".to_string(),
+            ));
+        }
+
+        if self
+            .client_limits
+            .rules
+            .iter()
+            .any(|r| r.clients.is_empty())
+        {
+            return Err(invalid_value(
+                "client_limits rules need at least one clients prefix".to_string(),
+            ));
+        }
+        if self.client_limits.max_clients == 0 {
+            return Err(invalid_value(
+                "client_limits.max_clients must be at least 1".to_string(),
+            ));
+        }
+
+        let dnssec = &self.dnssec;
+        if dnssec.validation && dnssec.trust_anchors.is_empty() {
+            return Err(invalid_value(
+                "dnssec.validation needs at least one dnssec.trust_anchors entry".to_string(),
+            ));
+        }
+        let insecure_zones = dnssec.insecure_zones.iter().map(|z| (z.as_str(), 1));
+        validate_zones("insecure zone", "entry", insecure_zones)?;
+
+        let client_subnet = &self.client_subnet;
+        // Longer prefixes than RFC 7871 11.1 recommends would all but name the client
+        if client_subnet.ipv4_prefix_len > 24 || client_subnet.ipv6_prefix_len > 56 {
+            return Err(invalid_value(
+                "client_subnet prefix lengths must be at most 24 for IPv4 and 56 for IPv6"
+                    .to_string(),
+            ));
+        }
+        if client_subnet.enabled && client_subnet.servers.is_empty() {
+            return Err(invalid_combination(
+                "client_subnet.enabled needs at least one client_subnet.servers entry".to_string(),
+            ));
+        }
+
+        let mut view_names = HashSet::new();
+        for view in &self.views {
+            if view.name.is_empty() {
+                return Err(invalid_value("views need a name".to_string()));
+            }
+            if !view_names.insert(view.name.as_str()) {
+                return Err(invalid_value(format!(
+                    "view \"{}\" is configured more than once",
+                    view.name
+                )));
+            }
+            if view.clients.is_empty() {
+                return Err(invalid_value(format!(
+                    "view \"{}\" needs at least one clients prefix",
+                    view.name
+                )));
+            }
+            self.for_view(view).validate().map_err(|e| {
+                let reason = match e.reason() {
+                    ConfigErrorReason::InvalidValue(s) => {
+                        ConfigErrorReason::InvalidValue(format!("view \"{}\": {}", view.name, s))
+                    }
+                    ConfigErrorReason::InvalidCombination(s) => {
+                        ConfigErrorReason::InvalidCombination(format!(
+                            "view \"{}\": {}",
+                            view.name, s
+                        ))
+                    }
+                    other => other.clone(),
+                };
+                ConfigError::new(reason)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// The configuration clients of view see: this one, with the sections the view sets
+    /// replaced and no views of its own.
+    pub fn for_view(&self, view: &ViewConfig) -> Config {
+        let mut config = self.clone();
+        config.views = Vec::new();
+        if let Some(zones) = &view.zones {
+            config.zones = zones.clone();
+        }
+        if let Some(forward_zones) = &view.forward_zones {
+            config.resolver.forward_zones = forward_zones.clone();
+        }
+        if let Some(blocklist) = &view.blocklist {
+            config.blocklist = blocklist.clone();
+        }
+        if let Some(rpz) = &view.rpz {
+            config.rpz = rpz.clone();
+        }
+        config
+    }
+}
@@ -0,0 +1,43 @@
+pub type Result<T> = std::result::Result<T, ConfigError>;
+
+#[derive(Debug, Clone)]
+pub enum ConfigErrorReason {
+    Io(String),
+    Parse(String),
+    InvalidValue(String),
+    InvalidCombination(String),
+}
+
+impl std::fmt::Display for ConfigErrorReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigErrorReason::Io(s) => write!(f, "Io: {}", s),
+            ConfigErrorReason::Parse(s) => write!(f, "Parse: {}", s),
+            ConfigErrorReason::InvalidValue(s) => write!(f, "InvalidValue: {}", s),
+            ConfigErrorReason::InvalidCombination(s) => write!(f, "InvalidCombination: {}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    reason: ConfigErrorReason,
+}
+
+impl ConfigError {
+    pub fn new(reason: ConfigErrorReason) -> ConfigError {
+        ConfigError { reason }
+    }
+
+    pub fn reason(&self) -> &ConfigErrorReason {
+        &self.reason
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ConfigError: {}", self.reason)
+    }
+}
+
+impl std::error::Error for ConfigError {}
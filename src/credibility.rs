@@ -0,0 +1,31 @@
+use rustdns::Message;
+
+/// How far cached data can be trusted, after RFC 2181 5.4.1, least trustworthy first. Data
+/// of a lower rank never replaces cached data of a higher one for the same name, so glue
+/// picked up along a referral cannot overwrite what a zone's own servers said.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Credibility {
+    /// The additional section of a response.
+    Additional,
+    /// NS records and glue of a referral, as the parent zone sees the child.
+    Referral,
+    /// The answer section of a response from a server without authority for it, such as a
+    /// forwarder.
+    NonAuthoritativeAnswer,
+    /// The authority section of an authoritative response.
+    AuthoritativeAuthority,
+    /// The answer section of an authoritative response.
+    AuthoritativeAnswer,
+}
+
+impl Credibility {
+    /// The rank of the answer to the question of rsp.
+    pub fn of_answer(rsp: &Message) -> Credibility {
+        match (rsp.aa, rsp.answers.is_empty()) {
+            (true, false) => Credibility::AuthoritativeAnswer,
+            // Negative answers come from the SOA in the authority section
+            (true, true) => Credibility::AuthoritativeAuthority,
+            (false, _) => Credibility::NonAuthoritativeAnswer,
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::accesscontrol::AccessControl;
+use crate::blocklist::Blocklist;
+use crate::cidr::Cidr;
+use crate::clientlimits::ClientLimits;
+use crate::config::{normalize_name, Config, ResolverConfig, ViewConfig};
+use crate::configerror::{ConfigError, ConfigErrorReason, Result};
+use crate::delegationcache::DelegationCache;
+use crate::dnssec::TrustAnchor;
+use crate::localdata::LocalDataStore;
+use crate::localzone::ZoneStore;
+use crate::nseccache::NsecCache;
+use crate::rpz::PolicyZones;
+use crate::rrl::ResponseRateLimiter;
+use crate::trustanchors::TrustAnchorStore;
+use crate::upstream::{is_subdomain, label_count, Upstreams};
+use crate::validator::KeyCache;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Everything a [`crate::dashjob::DashJob`] reads from the configuration.
+///
+/// The configuration of a context is never modified. Reloading the configuration builds a new one and
+/// hands it to jobs created from then on, while jobs already queued or running keep the
+/// `Arc` they were created with, so every job sees a single consistent configuration.
+#[derive(Debug)]
+pub struct DashContext {
+    config: Config,
+    // The upstreams, zones, blocklist and policy zones are shared with the views that do
+    // not override them
+    upstreams: Arc<Upstreams>,
+    // Most specific zone first
+    stub_zones: Vec<(String, Vec<SocketAddr>)>,
+    // Referrals depend on the root servers and stub zones, so they go with the context
+    delegations: DelegationCache,
+    // Validated DNSSEC keys, for the same reason
+    keys: KeyCache,
+    // Proven nonexistence, also validated with this context's anchors
+    nsec_cache: NsecCache,
+    // Shared with the views, the anchors are kept current for all of them at once
+    trust_anchors: Arc<TrustAnchorStore>,
+    zones: Arc<ZoneStore>,
+    local_data: Arc<LocalDataStore>,
+    blocklist: Arc<Blocklist>,
+    policy_zones: Arc<PolicyZones>,
+    access_control: AccessControl,
+    client_limits: Arc<ClientLimits>,
+    // Shared with the views, responses to a client count the same whichever view they
+    // come from
+    rate_limiter: Arc<ResponseRateLimiter>,
+    // None for the top level context
+    view: Option<String>,
+    views: Vec<(Vec<Cidr>, Arc<DashContext>)>,
+}
+
+impl DashContext {
+    /// Builds the context for config and its views, loading every local zone file, the local
+    /// data, the blocklists and the policy zones. Fails if one of the files cannot be read
+    /// or parsed.
+    pub fn new(config: Config) -> Result<Self> {
+        let local_data = Arc::new(LocalDataStore::new(&config.local_data)?);
+        let rate_limiter = Arc::new(ResponseRateLimiter::new(&config.response_rate_limit));
+        let trust_anchors = Arc::new(TrustAnchorStore::load(&config.dnssec)?);
+        let zones = Arc::new(load_zones(&config)?);
+        let blocklist = Arc::new(Blocklist::new(&config.blocklist)?);
+        let policy_zones = Arc::new(PolicyZones::load(&config.rpz)?);
+        let upstreams = Arc::new(Upstreams::new(&config.resolver));
+        let mut stub_zones: Vec<_> = config
+            .resolver
+            .stub_zones
+            .iter()
+            .map(|z| (normalize_name(&z.name), z.nameservers.clone()))
+            .collect();
+        stub_zones.sort_by_key(|(name, _)| std::cmp::Reverse(label_count(name)));
+        let mut context = DashContext {
+            access_control: AccessControl::new(&config.access_control),
+            client_limits: Arc::new(ClientLimits::new(&config.client_limits)),
+            config,
+            upstreams,
+            stub_zones,
+            delegations: DelegationCache::new(),
+            keys: KeyCache::new(),
+            nsec_cache: NsecCache::new(),
+            trust_anchors,
+            zones,
+            local_data,
+            blocklist,
+            policy_zones,
+            rate_limiter,
+            view: None,
+            views: Vec::new(),
+        };
+        context.views = context
+            .config
+            .views
+            .iter()
+            .map(|view| Ok((view.clients.clone(), Arc::new(context.build_view(view)?))))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(context)
+    }
+
+    /// The context of view. Only the sections the view sets are loaded again, everything
+    /// else is shared with this context: the local zones, blocklist, policy zones and
+    /// upstream servers it does not override, and always the local data, the rate limiter
+    /// and the trust anchors.
+    fn build_view(&self, view: &ViewConfig) -> Result<Self> {
+        let config = self.config.for_view(view);
+        let zones = match view.zones {
+            Some(_) => Arc::new(load_zones(&config)?),
+            None => Arc::clone(&self.zones),
+        };
+        let blocklist = match view.blocklist {
+            Some(_) => Arc::new(Blocklist::new(&config.blocklist)?),
+            None => Arc::clone(&self.blocklist),
+        };
+        let policy_zones = match view.rpz {
+            Some(_) => Arc::new(PolicyZones::load(&config.rpz)?),
+            None => Arc::clone(&self.policy_zones),
+        };
+        let upstreams = match view.forward_zones {
+            Some(_) => Arc::new(Upstreams::new(&config.resolver)),
+            None => Arc::clone(&self.upstreams),
+        };
+        Ok(DashContext {
+            access_control: AccessControl::new(&config.access_control),
+            client_limits: Arc::new(ClientLimits::new(&config.client_limits)),
+            config,
+            upstreams,
+            stub_zones: self.stub_zones.clone(),
+            delegations: DelegationCache::new(),
+            keys: KeyCache::new(),
+            nsec_cache: NsecCache::new(),
+            trust_anchors: Arc::clone(&self.trust_anchors),
+            zones,
+            local_data: Arc::clone(&self.local_data),
+            blocklist,
+            policy_zones,
+            rate_limiter: Arc::clone(&self.rate_limiter),
+            view: Some(view.name.clone()),
+            views: Vec::new(),
+        })
+    }
+
+    /// Starts probing the upstream servers of this context and its views, watching its
+    /// hosts files and refreshing its trust anchors. All stop by themselves once the context
+    /// is dropped.
+    pub fn start_background_tasks(self: &Arc<Self>) {
+        LocalDataStore::start_watcher(&self.local_data);
+        TrustAnchorStore::start_refresher(self);
+        // Views that forward like the top level context share its upstreams, which are
+        // probed once
+        let mut probed: Vec<&Arc<Upstreams>> = Vec::new();
+        for context in std::iter::once(self.as_ref()).chain(self.views()) {
+            if probed.iter().any(|u| Arc::ptr_eq(u, &context.upstreams)) {
+                continue;
+            }
+            probed.push(&context.upstreams);
+            if let Some(interval) = context.config.resolver.health_check_interval() {
+                Upstreams::start_health_checker(
+                    &context.upstreams,
+                    interval,
+                    context.config.resolver.query_timeout(),
+                );
+            }
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn resolver(&self) -> &ResolverConfig {
+        &self.config.resolver
+    }
+
+    pub fn upstreams(&self) -> &Upstreams {
+        &self.upstreams
+    }
+
+    /// The closest stub zone containing name, as the zone name and its nameservers.
+    pub fn stub_zone(&self, name: &str) -> Option<(&str, &[SocketAddr])> {
+        let name = normalize_name(name);
+        self.stub_zones
+            .iter()
+            .find(|(zone, _)| is_subdomain(&name, zone))
+            .map(|(zone, servers)| (zone.as_str(), servers.as_slice()))
+    }
+
+    pub fn delegations(&self) -> &DelegationCache {
+        &self.delegations
+    }
+
+    pub fn keys(&self) -> &KeyCache {
+        &self.keys
+    }
+
+    pub fn nsec_cache(&self) -> &NsecCache {
+        &self.nsec_cache
+    }
+
+    /// The DNSSEC trust anchors answers are validated from, as they are now.
+    pub fn trust_anchors(&self) -> Vec<TrustAnchor> {
+        self.trust_anchors.anchors()
+    }
+
+    pub fn trust_anchor_store(&self) -> &TrustAnchorStore {
+        &self.trust_anchors
+    }
+
+    pub fn zones(&self) -> &ZoneStore {
+        &self.zones
+    }
+
+    pub fn local_data(&self) -> &LocalDataStore {
+        &self.local_data
+    }
+
+    pub fn blocklist(&self) -> &Blocklist {
+        &self.blocklist
+    }
+
+    pub fn policy_zones(&self) -> &PolicyZones {
+        &self.policy_zones
+    }
+
+    pub fn access_control(&self) -> &AccessControl {
+        &self.access_control
+    }
+
+    pub fn client_limits(&self) -> &Arc<ClientLimits> {
+        &self.client_limits
+    }
+
+    pub fn rate_limiter(&self) -> &ResponseRateLimiter {
+        &self.rate_limiter
+    }
+
+    /// The name of the view this context belongs to, None at the top level.
+    pub fn view(&self) -> Option<&str> {
+        self.view.as_deref()
+    }
+
+    pub fn views(&self) -> impl Iterator<Item = &DashContext> {
+        self.views.iter().map(|(_, view)| view.as_ref())
+    }
+
+    /// The context of the first view whose clients include client, or this one if none do.
+    pub fn for_client(self: &Arc<Self>, client: &SocketAddr) -> Arc<DashContext> {
+        self.views
+            .iter()
+            .find(|(clients, _)| clients.iter().any(|c| c.contains(client.ip())))
+            .map_or_else(|| self.clone(), |(_, view)| view.clone())
+    }
+}
+
+fn load_zones(config: &Config) -> Result<ZoneStore> {
+    ZoneStore::load(&config.zones).map_err(|e| {
+        ConfigError::new(ConfigErrorReason::InvalidValue(format!(
+            "loading zones: {}",
+            e
+        )))
+    })
+}
@@ -1,72 +1,360 @@
-use crate::dnstools::{parse_ttl_from_answer, string_of_question};
+use crate::clientlimits::InFlight;
+use crate::clientsubnet::ClientSubnet;
+use crate::config::normalize_name;
+use crate::credibility::Credibility;
+use crate::dashcontext::DashContext;
+use crate::dnserror::{self, ExtendedError};
+use crate::dnstools::{parse_ttl_from_answer, response_to, string_of_question, udp_payload_limit};
 use crate::lru_ttl_cache::Cache;
-use crate::resolver::resolve_message_query;
-use crate::threadpool::ThreadPoolJob;
-use rustdns::Message;
-use rustdns::Resource::{A, AAAA, CNAME, NS, PTR};
+use crate::resolver::resolve_for_client;
+use crate::rpz::{local_data_answer, PolicyAction, PolicyHit};
+use crate::rrl::apply_verdict;
+use crate::threadpool::{JobPriority, ThreadPoolJob};
+use crate::wire::encode_response;
+use log::{debug, warn};
+use rustdns::{Message, Rcode, Record, Resource};
 use std::net::{SocketAddr, UdpSocket};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::time::SystemTime;
 
 pub struct DashJob {
     msg: Message,
     client: SocketAddr,
+    // The socket the query arrived on, so the response comes from the address it was sent to
+    socket: Arc<UdpSocket>,
     cache: Arc<Mutex<Cache<String, Message>>>,
+    context: Arc<DashContext>,
+    // Released when the job is dropped after running
+    in_flight: Option<InFlight>,
 }
 
 impl DashJob {
     pub fn new(
         msg: Message,
         client: SocketAddr,
+        socket: Arc<UdpSocket>,
         cache: Arc<Mutex<Cache<String, Message>>>,
+        context: Arc<DashContext>,
     ) -> Self {
-        DashJob { msg, client, cache }
+        let context = context.for_client(&client);
+        DashJob {
+            msg,
+            client,
+            socket,
+            cache,
+            context,
+            in_flight: None,
+        }
+    }
+
+    pub fn query(&self) -> &Message {
+        &self.msg
+    }
+
+    /// Keeps the recursion slots the query was admitted with until the job is done.
+    pub fn set_in_flight(&mut self, in_flight: Option<InFlight>) {
+        self.in_flight = in_flight;
+    }
+
+    /// Builds the response to the query: from local data, a local zone, the blocklist, the
+    /// cache or from resolving it, in that order, with the policy zones applied to the last
+    /// two, with the Extended DNS Error explaining a failure. None means no response is sent
+    /// at all.
+    fn respond(&self) -> Option<(Message, Option<ExtendedError>)> {
+        let mut rsp = response_to(&self.msg);
+        if self.msg.questions.is_empty() {
+            rsp.rcode = Rcode::FormErr;
+            return Some((rsp, None));
+        }
+        if let Some(local) = self.answer_locally(&self.msg) {
+            rsp.aa = local.aa;
+            copy_sections(&mut rsp, local);
+            return Some((rsp, None));
+        }
+
+        // A QNAME trigger decides before resolving unless a policy zone ahead of it has
+        // triggers that can only be checked against the response
+        let policy_zones = self.context.policy_zones();
+        let qname = &self.msg.questions[0].name;
+        let qname_hit = policy_zones.qname_hit(qname);
+        if let Some(hit) = &qname_hit {
+            if !policy_zones.has_response_triggers_before(hit.zone_index) {
+                return self.apply_policy(rsp, hit, || self.lookup(&self.msg));
+            }
+        }
+        let resolved = self.lookup(&self.msg);
+        let response_hit = match &resolved {
+            Ok(answer) if !policy_zones.is_empty() => {
+                let zone_limit = qname_hit.as_ref().map_or(usize::MAX, |h| h.zone_index);
+                policy_zones.response_hit(qname, answer, &self.context, zone_limit)
+            }
+            _ => None,
+        };
+        match response_hit.or(qname_hit) {
+            Some(hit) => self.apply_policy(rsp, &hit, || resolved),
+            None => {
+                let extended_error = finish(&mut rsp, resolved);
+                Some((rsp, extended_error))
+            }
+        }
+    }
+
+    /// The answer from local data, a local zone or the blocklist, in that order, which are
+    /// never cached or subject to the policy zones.
+    fn answer_locally(&self, query: &Message) -> Option<Message> {
+        self.context
+            .local_data()
+            .answer(query)
+            .or_else(|| self.context.zones().answer(query))
+            .or_else(|| self.context.blocklist().check(query))
+    }
+
+    /// The answer to query from the cache, or from resolving it and caching the result. An
+    /// answer given for the client's subnet is cached for the clients in its scope only.
+    fn lookup(&self, query: &Message) -> dnserror::Result<Message> {
+        let question_stringified = self.cache_key(query)?;
+        let subnet =
+            ClientSubnet::for_client(self.client.ip(), &self.context.config().client_subnet);
+        // A job that panicked while holding the cache must not take every later job down too.
+        // The lock is only held for the lookup itself so cache hits are never stuck behind a
+        // slow recursive resolution.
+        let cached = {
+            let cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+            cached_key(&cache, &question_stringified, subnet.as_ref()).and_then(|k| cache.get(&k))
+        };
+        if let Some(cache_value) = cached {
+            debug!("Cache hit for {}", query.questions[0].name);
+            return Ok(cache_value);
+        }
+        match resolve_for_client(query, &self.context, subnet.as_ref()) {
+            Ok(v) => {
+                let Ok(ttl) = parse_ttl_from_answer(&v) else {
+                    return Ok(v);
+                };
+                let scope = subnet.as_ref().and_then(|s| Some((s, s.scope()?)));
+                let question_stringified = match scope {
+                    // Asked without revealing the client, the answer may suit only the
+                    // resolver's own location and is kept from clients that were revealed
+                    Some((subnet, scope)) if scope > 0 || subnet.source_prefix_len() == 0 => {
+                        // A scope longer than the address itself is ignored
+                        scoped_key(&question_stringified, subnet, scope)
+                            .unwrap_or_else(|| question_stringified.clone())
+                    }
+                    _ => question_stringified.clone(),
+                };
+                let expires = SystemTime::now() + ttl;
+                let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+                // Another job may have cached an answer meanwhile, the more credible one stays
+                match cache.get(&question_stringified) {
+                    Some(cached)
+                        if Credibility::of_answer(&cached) > Credibility::of_answer(&v) => {}
+                    Some(_) => {
+                        cache.replace(&question_stringified, v.clone(), expires);
+                    }
+                    None => {
+                        cache.add(question_stringified, v.clone(), expires);
+                    }
+                }
+                Ok(v)
+            }
+            Err(dns_error) => {
+                warn!(
+                    "{} for client {}, with request: {}",
+                    dns_error, self.client, query
+                );
+                Err(dns_error)
+            }
+        }
+    }
+
+    /// Answers with the action of a matched policy. resolved is only called when the action
+    /// needs the real answer.
+    fn apply_policy(
+        &self,
+        mut rsp: Message,
+        hit: &PolicyHit,
+        resolved: impl FnOnce() -> dnserror::Result<Message>,
+    ) -> Option<(Message, Option<ExtendedError>)> {
+        let question = &self.msg.questions[0];
+        self.context.policy_zones().record_hit(hit);
+        debug!(
+            "Policy zone {} matched {} by {} trigger: {}",
+            hit.zone, question.name, hit.trigger, hit.action
+        );
+        match &hit.action {
+            PolicyAction::Passthru => {
+                let extended_error = finish(&mut rsp, resolved());
+                return Some((rsp, extended_error));
+            }
+            PolicyAction::Drop => return None,
+            PolicyAction::Nxdomain => rsp.rcode = Rcode::NXDomain,
+            PolicyAction::Nodata => (),
+            PolicyAction::TcpOnly => rsp.tc = true,
+            PolicyAction::Cname(record) => {
+                let Resource::CNAME(target) = &record.resource else {
+                    return Some((rsp, None));
+                };
+                // A target of *.example prepends the query name to example
+                let target = match normalize_name(target).strip_prefix("*.") {
+                    Some(suffix) => format!("{}.{}", normalize_name(&question.name), suffix),
+                    None => normalize_name(target),
+                };
+                rsp.answers.push(Record {
+                    name: question.name.clone(),
+                    resource: Resource::CNAME(format!("{}.", target)),
+                    ..record.clone()
+                });
+                // The target is answered like any other query, except that the policy
+                // zones are not applied to it again
+                let mut query = self.msg.clone();
+                query.questions[0].name = target;
+                let resolved = match self.answer_locally(&query) {
+                    Some(local) => Ok(local),
+                    None => self.lookup(&query),
+                };
+                match resolved {
+                    Ok(answer) => {
+                        rsp.rcode = answer.rcode;
+                        rsp.answers.extend(answer.answers);
+                    }
+                    Err(dns_error) => {
+                        rsp.rcode = dns_error.code();
+                        return Some((rsp, dns_error.extended().cloned()));
+                    }
+                }
+            }
+            PolicyAction::LocalData(records) => {
+                rsp.answers = local_data_answer(records, &question.name, question.r#type);
+            }
+        }
+        Some((rsp, None))
+    }
+
+    /// The question as the cache knows it. Every view has its own part of the cache, as the
+    /// same question can have a different answer in each. Queries with the CD bit get
+    /// answers that were not validated, which are kept apart too.
+    fn cache_key(&self, query: &Message) -> dnserror::Result<String> {
+        let mut question = string_of_question(query)?;
+        if query.cd {
+            question.push_str("/cd");
+        }
+        Ok(match self.context.view() {
+            Some(view) => format!("{}/{}", view, question),
+            None => question,
+        })
+    }
+
+    /// Whether the query is answered from local data, a local zone or the blocklist, which
+    /// tell nothing about what other clients have looked up.
+    pub fn answered_locally(&self) -> bool {
+        let Some(question) = self.msg.questions.first() else {
+            return false;
+        };
+        let name = &question.name;
+        self.context.local_data().contains(name)
+            || self.context.zones().zone_for(name).is_some()
+            || self.context.blocklist().blocking_list(name).is_some()
+    }
+
+    /// Queries that can be answered from the cache jump ahead of ones needing recursion.
+    pub fn priority(&self) -> JobPriority {
+        if self.msg.questions.is_empty() {
+            return JobPriority::ClientRecursion;
+        }
+        if self.answered_locally() {
+            return JobPriority::CacheHit;
+        }
+        let question_stringified = match self.cache_key(&self.msg) {
+            Ok(q) => q,
+            Err(_) => return JobPriority::ClientRecursion,
+        };
+        let subnet =
+            ClientSubnet::for_client(self.client.ip(), &self.context.config().client_subnet);
+        let cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        if cached_key(&cache, &question_stringified, subnet.as_ref()).is_some() {
+            JobPriority::CacheHit
+        } else {
+            JobPriority::ClientRecursion
+        }
     }
 }
 
 impl ThreadPoolJob for DashJob {
     fn run_job(&self) {
-        let question_stringified = string_of_question(&self.msg).unwrap();
-        let rsp;
-        let mut cache = self.cache.lock().unwrap();
-        if let Some(cache_value) = cache.get(&question_stringified) {
-            println!("Cache hit for {}", self.msg.questions.first().unwrap().name);
-            rsp = cache_value;
-        } else {
-            match resolve_message_query(&self.msg) {
-                Ok(v) => {
-                    rsp = v.clone();
-                    cache.add(
-                        question_stringified,
-                        v.clone(),
-                        SystemTime::now() + parse_ttl_from_answer(&v).unwrap(),
-                    );
-                }
-                // TODO make this return some sort of response to client
-                Err(dns_error) => {
-                    println!(
-                        "{} for client {}, with request: {}",
-                        dns_error, self.client, self.msg
-                    );
+        let Some((mut rsp, extended_error)) = self.respond() else {
+            return;
+        };
+        // The AD bit only goes to clients that show they understand it (RFC 6840 5.8)
+        let dnssec_ok = self.msg.extension.as_ref().is_some_and(|e| e.dnssec_ok);
+        rsp.ad &= self.msg.ad || dnssec_ok;
+        let verdict = self.context.rate_limiter().check(self.client.ip(), &rsp);
+        let Some(rsp) = apply_verdict(verdict, rsp) else {
+            return;
+        };
+        let encoded =
+            match encode_response(&rsp, udp_payload_limit(&self.msg), extended_error.as_ref()) {
+                Ok(e) => e,
+                Err(e) => {
+                    warn!("Could not encode the response for {}: {}", self.client, e);
                     return;
                 }
-            }
+            };
+        if let Err(e) = self.socket.send_to(&encoded, self.client) {
+            warn!("Could not send the response to {}: {}", self.client, e);
         }
+    }
+}
 
-        let answer_str = match &rsp.answers.first().unwrap().resource {
-            A(a) => a.to_string(),
-            AAAA(a) => a.to_string().to_string(),
-            CNAME(c) => c.clone(),
-            NS(ns) => ns.clone(),
-            PTR(ptr) => ptr.clone(),
-            _ => "Unhandled answer record resource type".to_string(),
-        };
+/// The key an answer to the question with key is cached under for a client in subnet: the
+/// one shared by every client, or else the one for the longest scope containing the client
+/// (RFC 7871 7.3.1).
+fn cached_key(
+    cache: &Cache<String, Message>,
+    key: &str,
+    subnet: Option<&ClientSubnet>,
+) -> Option<String> {
+    if cache.contains(&key.to_string()) {
+        return Some(key.to_string());
+    }
+    let subnet = subnet?;
+    let scopes = match subnet.source_prefix_len() {
+        0 => 0..=0,
+        source => 1..=source,
+    };
+    scopes
+        .rev()
+        .filter_map(|scope| scoped_key(key, subnet, scope))
+        .find(|scoped| cache.contains(scoped))
+}
+
+/// The key of an answer to the question with key given for the prefix of length scope
+/// containing the client in subnet.
+fn scoped_key(key: &str, subnet: &ClientSubnet, scope: u8) -> Option<String> {
+    subnet
+        .cache_key(scope)
+        .map(|scoped| format!("{}/{}", key, scoped))
+}
 
-        // // TODO error handling
-        let socket_for_sending = UdpSocket::bind("0.0.0.0:0").unwrap();
-        // TODO error handling
-        socket_for_sending
-            .send_to(answer_str.as_bytes(), self.client)
-            .unwrap();
+/// Fills the response from the result of a lookup, and returns the Extended DNS Error of a
+/// failed one.
+fn finish(rsp: &mut Message, resolved: dnserror::Result<Message>) -> Option<ExtendedError> {
+    match resolved {
+        Ok(answer) => {
+            rsp.ad = answer.ad;
+            copy_sections(rsp, answer);
+            None
+        }
+        Err(dns_error) => {
+            rsp.rcode = dns_error.code();
+            dns_error.extended().cloned()
+        }
     }
 }
+
+/// Moves the rcode and the record sections of an answer into the response being built.
+fn copy_sections(rsp: &mut Message, answer: Message) {
+    rsp.rcode = answer.rcode;
+    rsp.answers = answer.answers;
+    rsp.authoritys = answer.authoritys;
+    rsp.additionals = answer.additionals;
+}
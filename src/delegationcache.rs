@@ -0,0 +1,113 @@
+use crate::config::normalize_name;
+use crate::credibility::Credibility;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Delegations kept at most this many at once. Referrals are small, but a resolver sees one
+/// for nearly every zone it has been asked about.
+const MAX_DELEGATIONS: usize = 10_000;
+
+/// Upper bound on how long a referral is trusted, whatever TTL the parent zone gave it.
+const MAX_DELEGATION_TTL: Duration = Duration::from_secs(86_400);
+
+#[derive(Debug, Clone)]
+struct Delegation {
+    nameservers: Vec<String>,
+    servers: Vec<SocketAddr>,
+    expires: Instant,
+    credibility: Credibility,
+}
+
+/// Nameserver addresses learned from referrals during recursion, keyed by the zone they
+/// were delegated for. Lets a later query for a name in the same zone skip the walk down
+/// from the root.
+#[derive(Debug, Default)]
+pub struct DelegationCache {
+    entries: Mutex<HashMap<String, Delegation>>,
+}
+
+impl DelegationCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remembers that zone is served by the named nameservers, at servers, for ttl. An
+    /// unexpired delegation learned with a higher credibility is kept instead.
+    pub fn insert(
+        &self,
+        zone: &str,
+        nameservers: Vec<String>,
+        servers: Vec<SocketAddr>,
+        ttl: Duration,
+        credibility: Credibility,
+    ) {
+        if servers.is_empty() || ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() >= MAX_DELEGATIONS {
+            entries.retain(|_, d| d.expires > now);
+            if entries.len() >= MAX_DELEGATIONS {
+                return;
+            }
+        }
+        let zone = normalize_name(zone);
+        if entries
+            .get(&zone)
+            .is_some_and(|d| d.expires > now && d.credibility > credibility)
+        {
+            return;
+        }
+        entries.insert(
+            zone,
+            Delegation {
+                nameservers,
+                servers,
+                expires: now + ttl.min(MAX_DELEGATION_TTL),
+                credibility,
+            },
+        );
+    }
+
+    /// The deepest unexpired delegation containing name, as the zone name and its servers.
+    pub fn closest(&self, name: &str) -> Option<(String, Vec<SocketAddr>)> {
+        self.closest_with(name, |zone, d| (zone.to_string(), d.servers.clone()))
+    }
+
+    /// Names and addresses of the nameservers of the deepest unexpired delegation
+    /// containing name.
+    pub fn nameservers(&self, name: &str) -> Option<(Vec<String>, Vec<SocketAddr>)> {
+        self.closest_with(name, |_, d| (d.nameservers.clone(), d.servers.clone()))
+    }
+
+    fn closest_with<T>(&self, name: &str, f: impl Fn(&str, &Delegation) -> T) -> Option<T> {
+        let name = normalize_name(name);
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut zone = name.as_str();
+        loop {
+            if let Some(d) = entries.get(zone).filter(|d| d.expires > now) {
+                return Some(f(zone, d));
+            }
+            match zone.split_once('.') {
+                Some((_, parent)) => zone = parent,
+                None if !zone.is_empty() => zone = "",
+                None => return None,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -7,11 +7,16 @@ pub type Result<T> = std::result::Result<T, DnsError>;
 pub struct DnsError {
     code: Rcode,
     info: String,
+    extended: Option<ExtendedError>,
 }
 
 impl fmt::Display for DnsError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "DNS Error -- Code {}, Info: {}", self.code, self.info)
+        write!(f, "DNS Error -- Code {}, Info: {}", self.code, self.info)?;
+        match &self.extended {
+            Some(e) => write!(f, ", {}", e),
+            None => Ok(()),
+        }
     }
 }
 
@@ -32,11 +37,55 @@ impl DnsError {
         Self {
             code,
             info: String::new(),
+            extended: None,
         }
     }
 
+    pub fn code(&self) -> Rcode {
+        self.code
+    }
+
     pub fn with_info(mut self, info: String) -> Self {
         self.info = info;
         self
     }
+
+    /// Adds the Extended DNS Error sent to the client along with the rcode.
+    pub fn with_extended(mut self, extended: ExtendedError) -> Self {
+        self.extended = Some(extended);
+        self
+    }
+
+    pub fn extended(&self) -> Option<&ExtendedError> {
+        self.extended.as_ref()
+    }
+}
+
+/// An Extended DNS Error (RFC 8914), telling the client why a query failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedError {
+    pub code: u16,
+    pub text: String,
+}
+
+impl ExtendedError {
+    pub const UNSUPPORTED_DNSKEY_ALGORITHM: u16 = 1;
+    pub const UNSUPPORTED_DS_DIGEST_TYPE: u16 = 2;
+    pub const DNSSEC_BOGUS: u16 = 6;
+    pub const SIGNATURE_EXPIRED: u16 = 7;
+    pub const SIGNATURE_NOT_YET_VALID: u16 = 8;
+    pub const DNSKEY_MISSING: u16 = 9;
+    pub const RRSIGS_MISSING: u16 = 10;
+    pub const NSEC_MISSING: u16 = 12;
+    pub const UNSUPPORTED_NSEC3_ITERATIONS: u16 = 27;
+
+    pub fn new(code: u16, text: String) -> Self {
+        ExtendedError { code, text }
+    }
+}
+
+impl fmt::Display for ExtendedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EDE {}: {}", self.code, self.text)
+    }
 }
@@ -0,0 +1,696 @@
+//! The DNSSEC records (RFC 4034, RFC 5155) and the cryptography to check them.
+//!
+//! Records are read from the canonical RDATA of [`crate::wire::RawRecord`]. Signatures are
+//! checked with RSA/SHA-256, ECDSA P-256 with SHA-256, ECDSA P-384 with SHA-384 and
+//! Ed25519, the algorithms RFC 8624 requires a validator to support.
+
+use crate::config::normalize_name;
+use crate::wire::{canonical_name, name_labels};
+use ring::digest;
+use ring::signature::{self, UnparsedPublicKey};
+use serde::{Deserialize, Deserializer};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+pub const DS: u16 = 43;
+pub const RRSIG: u16 = 46;
+pub const NSEC: u16 = 47;
+pub const DNSKEY: u16 = 48;
+pub const NSEC3: u16 = 50;
+
+pub const RSASHA256: u8 = 8;
+pub const ECDSAP256SHA256: u8 = 13;
+pub const ECDSAP384SHA384: u8 = 14;
+pub const ED25519: u8 = 15;
+
+pub const SHA1: u8 = 1;
+pub const SHA256: u8 = 2;
+pub const SHA384: u8 = 4;
+
+/// Whether signatures made with algorithm can be checked.
+pub fn algorithm_supported(algorithm: u8) -> bool {
+    matches!(
+        algorithm,
+        RSASHA256 | ECDSAP256SHA256 | ECDSAP384SHA384 | ED25519
+    )
+}
+
+/// Whether DS records with digest_type can be checked.
+pub fn digest_supported(digest_type: u8) -> bool {
+    matches!(digest_type, SHA1 | SHA256 | SHA384)
+}
+
+/// A zone's public key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Dnskey {
+    pub flags: u16,
+    pub protocol: u8,
+    pub algorithm: u8,
+    pub public_key: Vec<u8>,
+}
+
+impl Dnskey {
+    pub const ZONE: u16 = 0x0100;
+    /// Set by the zone's operator to take the key out of use (RFC 5011 3).
+    pub const REVOKE: u16 = 0x0080;
+    pub const SEP: u16 = 0x0001;
+
+    pub fn parse(rdata: &[u8]) -> Option<Dnskey> {
+        if rdata.len() < 5 {
+            return None;
+        }
+        Some(Dnskey {
+            flags: u16::from_be_bytes([rdata[0], rdata[1]]),
+            protocol: rdata[2],
+            algorithm: rdata[3],
+            public_key: rdata[4..].to_vec(),
+        })
+    }
+
+    pub fn rdata(&self) -> Vec<u8> {
+        let mut rdata = self.flags.to_be_bytes().to_vec();
+        rdata.push(self.protocol);
+        rdata.push(self.algorithm);
+        rdata.extend_from_slice(&self.public_key);
+        rdata
+    }
+
+    /// The key tag of RFC 4034 appendix B, which RRSIG and DS records name the key by.
+    pub fn key_tag(&self) -> u16 {
+        let mut sum: u32 = 0;
+        for (i, b) in self.rdata().iter().enumerate() {
+            sum += if i & 1 == 0 {
+                (*b as u32) << 8
+            } else {
+                *b as u32
+            };
+        }
+        sum += (sum >> 16) & 0xFFFF;
+        (sum & 0xFFFF) as u16
+    }
+
+    /// Only zone keys that are not revoked can sign the zone's data.
+    pub fn is_zone_key(&self) -> bool {
+        self.flags & Dnskey::ZONE != 0 && self.protocol == 3 && !self.is_revoked()
+    }
+
+    pub fn is_revoked(&self) -> bool {
+        self.flags & Dnskey::REVOKE != 0
+    }
+}
+
+/// A digest of a child zone's key, published in the parent zone.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Ds {
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    pub digest: Vec<u8>,
+}
+
+impl Ds {
+    pub fn parse(rdata: &[u8]) -> Option<Ds> {
+        if rdata.len() < 5 {
+            return None;
+        }
+        Some(Ds {
+            key_tag: u16::from_be_bytes([rdata[0], rdata[1]]),
+            algorithm: rdata[2],
+            digest_type: rdata[3],
+            digest: rdata[4..].to_vec(),
+        })
+    }
+
+    pub fn rdata(&self) -> Vec<u8> {
+        let mut rdata = self.key_tag.to_be_bytes().to_vec();
+        rdata.push(self.algorithm);
+        rdata.push(self.digest_type);
+        rdata.extend_from_slice(&self.digest);
+        rdata
+    }
+
+    /// Whether this is the digest of key, the key of zone.
+    pub fn matches(&self, zone: &str, key: &Dnskey) -> bool {
+        self.key_tag == key.key_tag()
+            && self.algorithm == key.algorithm
+            && ds_digest(zone, key, self.digest_type).is_some_and(|d| d == self.digest)
+    }
+}
+
+/// The digest a DS record of type digest_type for key, the key of zone, holds.
+pub fn ds_digest(zone: &str, key: &Dnskey, digest_type: u8) -> Option<Vec<u8>> {
+    let algorithm = match digest_type {
+        SHA1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        SHA256 => &digest::SHA256,
+        SHA384 => &digest::SHA384,
+        _ => return None,
+    };
+    let mut data = canonical_name(zone).ok()?;
+    data.extend(key.rdata());
+    Some(digest::digest(algorithm, &data).as_ref().to_vec())
+}
+
+/// A signature over the records of one name and type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rrsig {
+    pub type_covered: u16,
+    pub algorithm: u8,
+    /// Labels of the owner name the signature was made for, fewer than the owner has if the
+    /// records were synthesized from a wildcard.
+    pub labels: u8,
+    pub original_ttl: u32,
+    pub expiration: u32,
+    pub inception: u32,
+    pub key_tag: u16,
+    /// The zone whose key made the signature, normalized.
+    pub signer: String,
+    pub signature: Vec<u8>,
+}
+
+impl Rrsig {
+    pub fn parse(rdata: &[u8]) -> Option<Rrsig> {
+        let u32_at = |at: usize| -> Option<u32> {
+            Some(u32::from_be_bytes(rdata.get(at..at + 4)?.try_into().ok()?))
+        };
+        let (signer, at) = read_name(rdata, 18)?;
+        Some(Rrsig {
+            type_covered: u16::from_be_bytes([*rdata.first()?, *rdata.get(1)?]),
+            algorithm: *rdata.get(2)?,
+            labels: *rdata.get(3)?,
+            original_ttl: u32_at(4)?,
+            expiration: u32_at(8)?,
+            inception: u32_at(12)?,
+            key_tag: u16::from_be_bytes([*rdata.get(16)?, *rdata.get(17)?]),
+            signer: labels_to_name(&signer),
+            signature: rdata[at..].to_vec(),
+        })
+    }
+
+    /// The RDATA without the signature, the part the signature covers itself.
+    fn rdata_prefix(&self) -> Option<Vec<u8>> {
+        let mut data = self.type_covered.to_be_bytes().to_vec();
+        data.push(self.algorithm);
+        data.push(self.labels);
+        data.extend(self.original_ttl.to_be_bytes());
+        data.extend(self.expiration.to_be_bytes());
+        data.extend(self.inception.to_be_bytes());
+        data.extend(self.key_tag.to_be_bytes());
+        data.extend(canonical_name(&self.signer).ok()?);
+        Some(data)
+    }
+
+    pub fn rdata(&self) -> Option<Vec<u8>> {
+        let mut rdata = self.rdata_prefix()?;
+        rdata.extend_from_slice(&self.signature);
+        Some(rdata)
+    }
+
+    /// Whether now, in seconds since the epoch, lies between inception and expiration, in
+    /// the serial number arithmetic of RFC 1982 that RFC 4034 3.1.5 calls for.
+    pub fn is_current(&self, now: u32) -> bool {
+        !serial_lt(now, self.inception) && !serial_lt(self.expiration, now)
+    }
+
+    pub fn is_expired(&self, now: u32) -> bool {
+        serial_lt(self.expiration, now)
+    }
+
+    /// The wildcard the signed records were synthesized from, if they were: the owner
+    /// name with its leftmost labels replaced by *.
+    pub fn wildcard(&self, owner: &str) -> Option<String> {
+        let labels = name_labels(owner).ok()?;
+        let count = labels.len() - (labels.first().is_some_and(|l| l == b"*") as usize);
+        if (self.labels as usize) >= count {
+            return None;
+        }
+        let suffix = labels_to_name(&labels[labels.len() - self.labels as usize..]);
+        Some(if suffix.is_empty() {
+            "*".to_string()
+        } else {
+            format!("*.{}", suffix)
+        })
+    }
+
+    /// What the signature was computed over, the data of RFC 4034 3.1.8.1, for the records
+    /// of owner with the given RDATA.
+    pub fn signed_data(&self, owner: &str, class: u16, rdatas: &[&[u8]]) -> Option<Vec<u8>> {
+        let mut data = self.rdata_prefix()?;
+        let owner =
+            canonical_name(&self.wildcard(owner).unwrap_or_else(|| owner.to_string())).ok()?;
+        let mut rdatas = rdatas.to_vec();
+        rdatas.sort();
+        rdatas.dedup();
+        for rdata in rdatas {
+            data.extend_from_slice(&owner);
+            data.extend(self.type_covered.to_be_bytes());
+            data.extend(class.to_be_bytes());
+            data.extend(self.original_ttl.to_be_bytes());
+            data.extend((rdata.len() as u16).to_be_bytes());
+            data.extend_from_slice(rdata);
+        }
+        Some(data)
+    }
+}
+
+fn serial_lt(a: u32, b: u32) -> bool {
+    a != b && (b.wrapping_sub(a) as i32) > 0
+}
+
+/// Checks signature over data with key, for a signature made with algorithm.
+pub fn verify(key: &Dnskey, algorithm: u8, data: &[u8], signature: &[u8]) -> bool {
+    if key.algorithm != algorithm {
+        return false;
+    }
+    match algorithm {
+        RSASHA256 => {
+            // RFC 3110 2: the exponent length in one octet, or in three if the first is 0
+            let k = &key.public_key;
+            let (length, at) = match k.first() {
+                Some(0) if k.len() > 3 => (u16::from_be_bytes([k[1], k[2]]) as usize, 3),
+                Some(l) => (*l as usize, 1),
+                None => return false,
+            };
+            let Some(e) = k.get(at..at + length) else {
+                return false;
+            };
+            let n = &k[at + length..];
+            signature::RsaPublicKeyComponents { n, e }
+                .verify(
+                    &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                    data,
+                    signature,
+                )
+                .is_ok()
+        }
+        ECDSAP256SHA256 | ECDSAP384SHA384 => {
+            // RFC 6605 4: the point without the uncompressed point prefix ring expects
+            let mut point = vec![4];
+            point.extend_from_slice(&key.public_key);
+            let parameters = if algorithm == ECDSAP256SHA256 {
+                &signature::ECDSA_P256_SHA256_FIXED
+            } else {
+                &signature::ECDSA_P384_SHA384_FIXED
+            };
+            UnparsedPublicKey::new(parameters, &point)
+                .verify(data, signature)
+                .is_ok()
+        }
+        ED25519 => UnparsedPublicKey::new(&signature::ED25519, &key.public_key)
+            .verify(data, signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/// Proof that no names exist between owner and next, and which types owner has.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nsec {
+    pub next: String,
+    pub types: Vec<u8>,
+}
+
+impl Nsec {
+    pub fn parse(rdata: &[u8]) -> Option<Nsec> {
+        let (next, at) = read_name(rdata, 0)?;
+        Some(Nsec {
+            next: labels_to_name(&next),
+            types: rdata[at..].to_vec(),
+        })
+    }
+
+    pub fn has_type(&self, r#type: u16) -> bool {
+        has_type(&self.types, r#type)
+    }
+
+    /// Whether name lies strictly between owner and next, so does not exist. The last
+    /// NSEC of a zone points back at the apex, and covers everything after its owner.
+    pub fn covers(&self, owner: &str, name: &str) -> bool {
+        let after_owner = canonical_cmp(owner, name) == Ordering::Less;
+        match canonical_cmp(owner, &self.next) {
+            Ordering::Less => after_owner && canonical_cmp(name, &self.next) == Ordering::Less,
+            _ => after_owner || canonical_cmp(name, &self.next) == Ordering::Less,
+        }
+    }
+}
+
+/// The hashed form of NSEC, where owner names are hashes of the names they stand for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nsec3 {
+    pub hash_algorithm: u8,
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+    pub next_hashed: Vec<u8>,
+    pub types: Vec<u8>,
+}
+
+impl Nsec3 {
+    pub const OPT_OUT: u8 = 0x01;
+
+    pub fn parse(rdata: &[u8]) -> Option<Nsec3> {
+        let salt_length = *rdata.get(4)? as usize;
+        let salt = rdata.get(5..5 + salt_length)?.to_vec();
+        let at = 5 + salt_length;
+        let hash_length = *rdata.get(at)? as usize;
+        let next_hashed = rdata.get(at + 1..at + 1 + hash_length)?.to_vec();
+        Some(Nsec3 {
+            hash_algorithm: rdata[0],
+            flags: rdata[1],
+            iterations: u16::from_be_bytes([rdata[2], rdata[3]]),
+            salt,
+            next_hashed,
+            types: rdata[at + 1 + hash_length..].to_vec(),
+        })
+    }
+
+    pub fn has_type(&self, r#type: u16) -> bool {
+        has_type(&self.types, r#type)
+    }
+
+    pub fn opt_out(&self) -> bool {
+        self.flags & Nsec3::OPT_OUT != 0
+    }
+
+    /// The hash of name with this record's parameters, None for hash algorithms other than
+    /// SHA-1, the only one defined.
+    pub fn hash(&self, name: &str) -> Option<Vec<u8>> {
+        (self.hash_algorithm == 1)
+            .then(|| nsec3_hash(name, &self.salt, self.iterations))
+            .flatten()
+    }
+
+    /// Whether the hash of a name equals the one owner, the name of this record, stands
+    /// for.
+    pub fn matches(&self, owner: &str, hash: &[u8]) -> bool {
+        owner_hash(owner).is_some_and(|h| h == hash)
+    }
+
+    /// Whether hash lies strictly between the hash owner stands for and the next one.
+    pub fn covers(&self, owner: &str, hash: &[u8]) -> bool {
+        let Some(own) = owner_hash(owner) else {
+            return false;
+        };
+        let next = self.next_hashed.as_slice();
+        if own.as_slice() < next {
+            own.as_slice() < hash && hash < next
+        } else {
+            own.as_slice() < hash || hash < next
+        }
+    }
+}
+
+/// The hash an NSEC3 owner name stands for, from its first label.
+pub fn owner_hash(owner: &str) -> Option<Vec<u8>> {
+    base32hex_decode(owner.split('.').next()?)
+}
+
+/// The iterated and salted SHA-1 hash of RFC 5155 5.
+pub fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Option<Vec<u8>> {
+    let mut hash = canonical_name(name).ok()?;
+    for _ in 0..=iterations {
+        hash.extend_from_slice(salt);
+        hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &hash)
+            .as_ref()
+            .to_vec();
+    }
+    Some(hash)
+}
+
+const BASE32HEX: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+
+/// bytes in the lowercase, unpadded Base 32 with extended hex alphabet NSEC3 owner names
+/// are written in.
+pub fn base32hex(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for b in bytes {
+        buffer = buffer << 8 | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32HEX[(buffer >> bits) as usize & 0x1F] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32HEX[(buffer << (5 - bits)) as usize & 0x1F] as char);
+    }
+    out
+}
+
+fn base32hex_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = BASE32HEX
+            .iter()
+            .position(|b| *b == c.to_ascii_lowercase())?;
+        buffer = buffer << 5 | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Whether the type bit map of an NSEC or NSEC3 record (RFC 4034 4.1.2) lists r#type.
+pub fn has_type(bitmap: &[u8], r#type: u16) -> bool {
+    let (window, bit) = ((r#type >> 8) as u8, (r#type & 0xFF) as usize);
+    let mut at = 0;
+    while at + 2 <= bitmap.len() {
+        let length = bitmap[at + 1] as usize;
+        if bitmap[at] == window {
+            return bitmap
+                .get(at + 2 + bit / 8)
+                .is_some_and(|b| b & (0x80 >> (bit % 8)) != 0)
+                && bit / 8 < length;
+        }
+        at += 2 + length;
+    }
+    false
+}
+
+/// Builds a type bit map listing types.
+pub fn type_bitmap(types: &[u16]) -> Vec<u8> {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+    let mut bitmap = Vec::new();
+    let mut window: Option<(u8, Vec<u8>)> = None;
+    for t in types {
+        let (w, bit) = ((t >> 8) as u8, (t & 0xFF) as usize);
+        if window.as_ref().is_some_and(|(current, _)| *current != w) {
+            let (current, bits) = window.take().unwrap();
+            bitmap.push(current);
+            bitmap.push(bits.len() as u8);
+            bitmap.extend(bits);
+        }
+        let (_, bits) = window.get_or_insert((w, Vec::new()));
+        if bits.len() <= bit / 8 {
+            bits.resize(bit / 8 + 1, 0);
+        }
+        bits[bit / 8] |= 0x80 >> (bit % 8);
+    }
+    if let Some((current, bits)) = window {
+        bitmap.push(current);
+        bitmap.push(bits.len() as u8);
+        bitmap.extend(bits);
+    }
+    bitmap
+}
+
+/// The canonical ordering of names of RFC 4034 6.1: label by label from the root, each
+/// compared as lowercase octets.
+pub fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let (Ok(a), Ok(b)) = (name_labels(a), name_labels(b)) else {
+        return a.cmp(b);
+    };
+    a.iter().rev().cmp(b.iter().rev())
+}
+
+/// Reads an uncompressed name from rdata at at, returning its labels and where it ends.
+fn read_name(rdata: &[u8], mut at: usize) -> Option<(Vec<Vec<u8>>, usize)> {
+    let mut labels = Vec::new();
+    loop {
+        let length = *rdata.get(at)? as usize;
+        at += 1;
+        if length == 0 {
+            return Some((labels, at));
+        }
+        if length > 63 {
+            return None;
+        }
+        labels.push(rdata.get(at..at + length)?.to_ascii_lowercase());
+        at += length;
+    }
+}
+
+fn labels_to_name(labels: &[Vec<u8>]) -> String {
+    let name = labels
+        .iter()
+        .map(|l| {
+            l.iter()
+                .map(|&b| match b {
+                    b'.' | b'\\' => format!("\\{}", b as char),
+                    b if b.is_ascii_graphic() => (b as char).to_string(),
+                    b => format!("\\{:03}", b),
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(".");
+    normalize_name(&name)
+}
+
+/// A key the validator trusts without proof, the start of every chain of trust. Given as
+/// a DS or DNSKEY record in presentation format, such as the root zone's
+/// `. DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TrustAnchor {
+    Ds { zone: String, ds: Ds },
+    Dnskey { zone: String, key: Dnskey },
+}
+
+impl TrustAnchor {
+    /// The zone the anchor is for, normalized.
+    pub fn zone(&self) -> &str {
+        match self {
+            TrustAnchor::Ds { zone, .. } | TrustAnchor::Dnskey { zone, .. } => zone,
+        }
+    }
+
+    /// Whether key is the anchored key.
+    pub fn trusts(&self, key: &Dnskey) -> bool {
+        match self {
+            TrustAnchor::Ds { zone, ds } => ds.matches(zone, key),
+            TrustAnchor::Dnskey { key: anchor, .. } => {
+                anchor.algorithm == key.algorithm
+                    && anchor.public_key == key.public_key
+                    && anchor.protocol == key.protocol
+            }
+        }
+    }
+
+    /// The algorithm of the anchored key.
+    pub fn algorithm(&self) -> u8 {
+        match self {
+            TrustAnchor::Ds { ds, .. } => ds.algorithm,
+            TrustAnchor::Dnskey { key, .. } => key.algorithm,
+        }
+    }
+}
+
+impl FromStr for TrustAnchor {
+    type Err = String;
+
+    /// Parses "zone [ttl] [class] DS tag algorithm digest-type digest" or "zone [ttl]
+    /// [class] DNSKEY flags protocol algorithm key", with the digest in hex and the key in
+    /// Base64, either of them possibly split by spaces.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |why: &str| format!("trust anchor \"{}\": {}", s, why);
+        let mut tokens = s.split_whitespace().peekable();
+        let zone = normalize_name(tokens.next().ok_or_else(|| invalid("it is empty"))?);
+        tokens.next_if(|t| t.parse::<u32>().is_ok());
+        tokens.next_if(|t| t.eq_ignore_ascii_case("IN"));
+        let r#type = tokens
+            .next()
+            .ok_or_else(|| invalid("the type is missing"))?
+            .to_ascii_uppercase();
+        let mut number = |what: &str| -> Result<u32, String> {
+            tokens
+                .next()
+                .and_then(|t| t.parse::<u32>().ok())
+                .ok_or_else(|| invalid(&format!("the {} is missing or not a number", what)))
+        };
+        match r#type.as_str() {
+            "DS" => {
+                let key_tag = number("key tag")?;
+                let algorithm = number("algorithm")?;
+                let digest_type = number("digest type")?;
+                let digest = hex_decode(&tokens.collect::<String>())
+                    .filter(|d| !d.is_empty())
+                    .ok_or_else(|| invalid("the digest is not hex"))?;
+                Ok(TrustAnchor::Ds {
+                    zone,
+                    ds: Ds {
+                        key_tag: u16::try_from(key_tag).map_err(|_| invalid("bad key tag"))?,
+                        algorithm: u8::try_from(algorithm).map_err(|_| invalid("bad algorithm"))?,
+                        digest_type: u8::try_from(digest_type)
+                            .map_err(|_| invalid("bad digest type"))?,
+                        digest,
+                    },
+                })
+            }
+            "DNSKEY" => {
+                let flags = number("flags")?;
+                let protocol = number("protocol")?;
+                let algorithm = number("algorithm")?;
+                let public_key = base64::decode(tokens.collect::<String>())
+                    .ok()
+                    .filter(|k| !k.is_empty())
+                    .ok_or_else(|| invalid("the key is not Base64"))?;
+                Ok(TrustAnchor::Dnskey {
+                    zone,
+                    key: Dnskey {
+                        flags: u16::try_from(flags).map_err(|_| invalid("bad flags"))?,
+                        protocol: u8::try_from(protocol).map_err(|_| invalid("bad protocol"))?,
+                        algorithm: u8::try_from(algorithm).map_err(|_| invalid("bad algorithm"))?,
+                        public_key,
+                    },
+                })
+            }
+            _ => Err(invalid("only DS and DNSKEY records can be trust anchors")),
+        }
+    }
+}
+
+impl fmt::Display for TrustAnchor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.", self.zone())?;
+        match self {
+            TrustAnchor::Ds { ds, .. } => write!(
+                f,
+                " DS {} {} {} {}",
+                ds.key_tag,
+                ds.algorithm,
+                ds.digest_type,
+                ds.digest
+                    .iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect::<String>()
+            ),
+            TrustAnchor::Dnskey { key, .. } => write!(
+                f,
+                " DNSKEY {} {} {} {}",
+                key.flags,
+                key.protocol,
+                key.algorithm,
+                base64::encode(&key.public_key)
+            ),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TrustAnchor {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
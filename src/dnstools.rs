@@ -0,0 +1,134 @@
+use crate::dnserror::{DnsError, Result};
+use rustdns::{
+    Extension, Message, Rcode, Record,
+    Resource::{A, SOA},
+    QR,
+};
+use std::time::Duration;
+
+pub fn has_answer(rsp: &Message) -> bool {
+    !rsp.answers.is_empty()
+}
+
+pub fn get_answer(rsp: &Message) -> Option<&Vec<Record>> {
+    if !rsp.answers.is_empty() {
+        Some(&rsp.answers)
+    } else {
+        None
+    }
+}
+
+pub fn get_glue(rsp: &Message) -> Option<&Vec<Record>> {
+    if !rsp.additionals.is_empty() {
+        Some(&rsp.additionals)
+    } else {
+        None
+    }
+}
+
+pub fn get_authoritys(rsp: &Message) -> Option<&Vec<Record>> {
+    if !rsp.authoritys.is_empty() {
+        Some(&rsp.authoritys)
+    } else {
+        None
+    }
+}
+
+pub fn parse_answer_a(rsp: &Message) -> Result<(&str, std::net::Ipv4Addr)> {
+    if !has_answer(rsp) {
+        Err(DnsError::new(Rcode::ServFail)
+            .with_info("Expected an answer but didn't get one".to_string()))
+    } else {
+        let answer = rsp.answers.first().unwrap();
+        match answer.resource {
+            A(a) => Ok((answer.name.as_str(), a)),
+            _ => Err(DnsError::new(Rcode::NXDomain)
+                .with_info("Error parsing answer of record type A".to_string())),
+        }
+    }
+}
+
+pub fn string_of_question(rsp: &Message) -> Result<String> {
+    let question = rsp.questions.first().expect("No questions present");
+    Ok(format!(
+        "{} {} {}",
+        question.name, question.r#type, question.class
+    ))
+}
+
+/// How long rsp may be cached: the TTL of its first answer, or for a negative answer the
+/// smaller of its SOA record's TTL and minimum (RFC 2308 5).
+pub fn parse_ttl_from_answer(rsp: &Message) -> Result<Duration> {
+    if let Some(answer) = rsp.answers.first() {
+        return Ok(answer.ttl);
+    }
+    rsp.authoritys
+        .iter()
+        .find_map(|r| match &r.resource {
+            SOA(soa) => Some(r.ttl.min(soa.minimum)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            DnsError::new(Rcode::ServFail)
+                .with_info("A negative answer without a SOA record cannot be cached".to_string())
+        })
+}
+
+/// An empty response to query, carrying over its ID, question and flags. Recursion is
+/// always offered, and EDNS is answered with EDNS, with the DO bit copied (RFC 3225).
+pub fn response_to(query: &Message) -> Message {
+    Message {
+        id: query.id,
+        qr: QR::Response,
+        opcode: query.opcode,
+        rd: query.rd,
+        cd: query.cd,
+        ra: true,
+        // rustdns defaults to claiming authenticated data
+        ad: false,
+        questions: query.questions.clone(),
+        extension: query.extension.as_ref().map(|e| Extension {
+            payload_size: EDNS_PAYLOAD_SIZE,
+            dnssec_ok: e.dnssec_ok,
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Largest UDP payload Dash sends and receives with EDNS, as suggested by RFC 6891.
+pub const EDNS_PAYLOAD_SIZE: u16 = 4096;
+
+/// Largest response query's sender said it can receive over UDP.
+pub fn udp_payload_limit(query: &Message) -> usize {
+    match &query.extension {
+        Some(e) => e.payload_size.clamp(512, EDNS_PAYLOAD_SIZE) as usize,
+        None => 512,
+    }
+}
+
+/// A query ID from the operating system's random number generator, so responses cannot be
+/// forged by guessing it.
+pub fn random_id() -> u16 {
+    let mut bytes = [0u8; 2];
+    getrandom::getrandom(&mut bytes).expect("the operating system has no random numbers");
+    u16::from_be_bytes(bytes)
+}
+
+/// name with the case of each letter chosen at random, the "0x20" bits of
+/// draft-vixie-dnsext-dns0x20. A server that copies the question as it got it proves it
+/// saw the query, adding a bit of entropy per letter.
+pub fn randomize_case(name: &str) -> String {
+    let mut bits = vec![0u8; name.len().div_ceil(8)];
+    getrandom::getrandom(&mut bits).expect("the operating system has no random numbers");
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if bits[i / 8] & (1 << (i % 8)) != 0 {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
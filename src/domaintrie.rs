@@ -0,0 +1,99 @@
+use crate::config::normalize_name;
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+struct TrieNode<T> {
+    children: HashMap<Box<str>, TrieNode<T>>,
+    value: Option<T>,
+}
+
+/// Domain names stored label by label from the root down, so finding every stored name that
+/// is a suffix of a query takes one step per label of the query, however many are stored.
+#[derive(Debug)]
+pub struct DomainTrie<T> {
+    root: TrieNode<T>,
+    len: usize,
+}
+
+impl<T> Default for DomainTrie<T> {
+    fn default() -> Self {
+        DomainTrie {
+            root: TrieNode {
+                children: HashMap::new(),
+                value: None,
+            },
+            len: 0,
+        }
+    }
+}
+
+impl<T> DomainTrie<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn labels(name: &str) -> impl Iterator<Item = String> {
+        let name = normalize_name(name);
+        let labels: Vec<String> = if name.is_empty() {
+            Vec::new()
+        } else {
+            name.rsplit('.').map(str::to_string).collect()
+        };
+        labels.into_iter()
+    }
+
+    /// Stores value for name. A value already stored for the name is kept and false is
+    /// returned.
+    pub fn insert(&mut self, name: &str, value: T) -> bool {
+        let mut node = &mut self.root;
+        for label in Self::labels(name) {
+            node = node
+                .children
+                .entry(label.into_boxed_str())
+                .or_insert_with(|| TrieNode {
+                    children: HashMap::new(),
+                    value: None,
+                });
+        }
+        if node.value.is_some() {
+            return false;
+        }
+        node.value = Some(value);
+        self.len += 1;
+        true
+    }
+
+    /// The value stored for name itself.
+    pub fn get(&self, name: &str) -> Option<&T> {
+        let mut node = &self.root;
+        for label in Self::labels(name) {
+            node = node.children.get(label.as_str())?;
+        }
+        node.value.as_ref()
+    }
+
+    /// The value of the closest stored name that name is equal to or below, with the
+    /// number of labels of that stored name.
+    pub fn longest_match(&self, name: &str) -> Option<(usize, &T)> {
+        let mut node = &self.root;
+        let mut found = node.value.as_ref().map(|v| (0, v));
+        for (depth, label) in Self::labels(name).enumerate() {
+            node = match node.children.get(label.as_str()) {
+                Some(n) => n,
+                None => break,
+            };
+            if let Some(v) = &node.value {
+                found = Some((depth + 1, v));
+            }
+        }
+        found
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
@@ -0,0 +1,194 @@
+use crate::threadpool::{panic_message, ThreadPoolJob};
+use crate::threadpoolerror::{Result, ThreadPoolError, ThreadPoolErrorReason};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Runs one queued pool job on the calling thread, returning false if there was nothing to run.
+/// Lets a thread blocked on a handle keep the pool moving when it is itself a pool worker.
+pub(crate) type Helper = Arc<dyn Fn() -> bool + Send + Sync>;
+
+/// How long a thread waiting on pool jobs sleeps between attempts to help run queued jobs.
+pub(crate) const HELP_POLL_INTERVAL_MS: u64 = 5;
+
+const QUEUED: u8 = 0;
+const RUNNING: u8 = 1;
+const FINISHED: u8 = 2;
+const CANCELLED: u8 = 3;
+
+/// Shared between a submitted job and the handle returned to the submitter.
+pub(crate) struct JobState<T> {
+    status: AtomicU8,
+    result: Mutex<Option<Result<T>>>,
+    ready: Condvar,
+}
+
+impl<T> JobState<T> {
+    fn new() -> Self {
+        JobState {
+            status: AtomicU8::new(QUEUED),
+            result: Mutex::new(None),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn complete(&self, status: u8, result: Result<T>) {
+        *self.result.lock().unwrap_or_else(PoisonError::into_inner) = Some(result);
+        self.status.store(status, Ordering::SeqCst);
+        self.ready.notify_all();
+    }
+}
+
+/// Wraps a closure so it can be sent through the pool's job queue, storing whatever the
+/// closure returns (or the panic it raised) in the shared job state.
+pub(crate) struct HandleJob<F, T> {
+    task: Mutex<Option<F>>,
+    state: Arc<JobState<T>>,
+}
+
+impl<F, T> HandleJob<F, T>
+where
+    F: FnOnce() -> T,
+{
+    pub(crate) fn new(task: F, helper: Helper) -> (Self, JobHandle<T>) {
+        let state = Arc::new(JobState::new());
+        let job = HandleJob {
+            task: Mutex::new(Some(task)),
+            state: Arc::clone(&state),
+        };
+        (job, JobHandle { state, helper })
+    }
+}
+
+impl<F, T> ThreadPoolJob for HandleJob<F, T>
+where
+    F: FnOnce() -> T,
+{
+    fn run_job(&self) {
+        if self
+            .state
+            .status
+            .compare_exchange(QUEUED, RUNNING, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            // Cancelled before a worker got to it
+            return;
+        }
+
+        let task = match self
+            .task
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+        {
+            Some(t) => t,
+            None => return,
+        };
+
+        match panic::catch_unwind(AssertUnwindSafe(task)) {
+            Ok(v) => self.state.complete(FINISHED, Ok(v)),
+            Err(payload) => {
+                self.state.complete(
+                    FINISHED,
+                    Err(ThreadPoolError::new(ThreadPoolErrorReason::JobPanicked(
+                        panic_message(payload.as_ref()),
+                    ))),
+                );
+                // Let the worker account for the panic like any other job
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+/// Handle to a job submitted with [`crate::threadpool::ThreadPool::submit`], used to wait for
+/// and collect the value the job produced.
+pub struct JobHandle<T> {
+    state: Arc<JobState<T>>,
+    helper: Helper,
+}
+
+impl<T> JobHandle<T> {
+    /// Blocks until the job has finished and returns its output. Returns an error if the job
+    /// panicked or was cancelled. While waiting the calling thread runs other queued jobs, so
+    /// joining from inside a worker cannot starve the pool.
+    pub fn join(mut self) -> Result<T> {
+        loop {
+            match self.join_timeout(Duration::from_millis(HELP_POLL_INTERVAL_MS)) {
+                Err(e) if matches!(e.reason(), ThreadPoolErrorReason::JobTimedOut) => continue,
+                r => return r,
+            }
+        }
+    }
+
+    /// Returns the job output if it has already finished, without blocking.
+    /// Once the output has been returned, later calls return None.
+    pub fn try_join(&mut self) -> Option<Result<T>> {
+        self.state
+            .result
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+
+    /// Waits at most timeout for the job to finish. On JobTimedOut the job is left running
+    /// and the handle can be joined again later.
+    pub fn join_timeout(&mut self, timeout: Duration) -> Result<T> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(r) = self.try_join() {
+                return r;
+            }
+            if Instant::now() >= deadline {
+                return Err(ThreadPoolError::new(ThreadPoolErrorReason::JobTimedOut));
+            }
+            if !self.is_finished() && (self.helper)() {
+                continue;
+            }
+
+            let result = self
+                .state
+                .result
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if result.is_some() {
+                continue;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                continue;
+            }
+            let wait = (deadline - now).min(Duration::from_millis(HELP_POLL_INTERVAL_MS));
+            drop(
+                self.state
+                    .ready
+                    .wait_timeout(result, wait)
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+        }
+    }
+
+    /// Cancels the job if no worker has started running it yet. Returns true if the job was
+    /// cancelled, false if it is already running or finished. A running job is never interrupted.
+    pub fn cancel(&self) -> bool {
+        let cancelled = self
+            .state
+            .status
+            .compare_exchange(QUEUED, CANCELLED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+        if cancelled {
+            self.state.complete(
+                CANCELLED,
+                Err(ThreadPoolError::new(ThreadPoolErrorReason::JobCancelled)),
+            );
+        }
+        cancelled
+    }
+
+    /// True once the job has produced a value, panicked or been cancelled.
+    pub fn is_finished(&self) -> bool {
+        let status = self.state.status.load(Ordering::SeqCst);
+        status == FINISHED || status == CANCELLED
+    }
+}
@@ -8,6 +8,60 @@ pub mod threadpool;
 
 pub mod threadpoolerror;
 
+pub mod threadpoolstats;
+
+pub mod jobhandle;
+
+pub mod scheduler;
+
 pub mod dashjob;
 
+pub mod dashcontext;
+
+pub mod upstream;
+
+pub mod delegationcache;
+
 pub mod lru_ttl_cache;
+
+pub mod config;
+
+pub mod configerror;
+
+pub mod wire;
+
+pub mod zonefile;
+
+pub mod zoneerror;
+
+pub mod localzone;
+
+pub mod localdata;
+
+pub mod domaintrie;
+
+pub mod blocklist;
+
+pub mod cidr;
+
+pub mod rpz;
+
+pub mod accesscontrol;
+
+pub mod rrl;
+
+pub mod clientlimits;
+
+pub mod credibility;
+
+pub mod bailiwick;
+
+pub mod dnssec;
+
+pub mod validator;
+
+pub mod trustanchors;
+
+pub mod nseccache;
+
+pub mod clientsubnet;
@@ -0,0 +1,269 @@
+use crate::config::{normalize_name, LocalDataConfig};
+use crate::configerror::{ConfigError, ConfigErrorReason, Result};
+use log::{info, warn};
+use rustdns::{Class, Message, Record, Resource, Type};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+/// The reverse lookup name of an address, in in-addr.arpa or ip6.arpa.
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(v6) => {
+            let mut name = String::with_capacity(72);
+            for byte in v6.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0x0F, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// One snapshot of every local name.
+#[derive(Debug, Default)]
+struct LocalData {
+    addresses: HashMap<String, Vec<IpAddr>>,
+    // Reverse name to the names it points to
+    pointers: HashMap<String, Vec<String>>,
+}
+
+impl LocalData {
+    fn add_address(&mut self, name: &str, ip: IpAddr) {
+        let addresses = self.addresses.entry(normalize_name(name)).or_default();
+        if !addresses.contains(&ip) {
+            addresses.push(ip);
+        }
+    }
+
+    fn add_pointer(&mut self, reverse: String, name: &str) {
+        let names = self.pointers.entry(reverse).or_default();
+        let name = normalize_name(name);
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    /// Adds the PTR records implied by the addresses. An address with an explicit PTR keeps
+    /// only that one, otherwise the first name seen for it is used, which in a hosts file
+    /// is its canonical name.
+    fn synthesize_pointers(&mut self, order: &[(String, IpAddr)]) {
+        for (name, ip) in order {
+            self.pointers
+                .entry(reverse_name(*ip))
+                .or_insert_with(|| vec![name.clone()]);
+        }
+    }
+
+    fn read_hosts(&mut self, path: &Path, order: &mut Vec<(String, IpAddr)>) -> Result<()> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            ConfigError::new(ConfigErrorReason::Io(format!(
+                "reading {}: {}",
+                path.display(),
+                e
+            )))
+        })?;
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(address) = fields.next() else {
+                continue;
+            };
+            let ip = address.parse::<IpAddr>().map_err(|_| {
+                ConfigError::new(ConfigErrorReason::Parse(format!(
+                    "{}:{}: \"{}\" is not an IP address",
+                    path.display(),
+                    line_number + 1,
+                    address
+                )))
+            })?;
+            for name in fields {
+                self.add_address(name, ip);
+                order.push((normalize_name(name), ip));
+            }
+        }
+        Ok(())
+    }
+
+    fn add_record(&mut self, record: &str, order: &mut Vec<(String, IpAddr)>) -> Result<()> {
+        let invalid = || {
+            ConfigError::new(ConfigErrorReason::InvalidValue(format!(
+                "local_data record \"{}\" is not \"name A|AAAA|PTR data\"",
+                record
+            )))
+        };
+        let fields: Vec<&str> = record.split_whitespace().collect();
+        let [name, rtype, data] = fields[..] else {
+            return Err(invalid());
+        };
+        match rtype.to_ascii_uppercase().as_str() {
+            "A" | "AAAA" => {
+                let ip = data.parse::<IpAddr>().map_err(|_| invalid())?;
+                if ip.is_ipv4() != rtype.eq_ignore_ascii_case("A") {
+                    return Err(invalid());
+                }
+                self.add_address(name, ip);
+                order.push((normalize_name(name), ip));
+            }
+            "PTR" => {
+                let reverse = match name.parse::<IpAddr>() {
+                    Ok(ip) => reverse_name(ip),
+                    Err(_) => normalize_name(name),
+                };
+                self.add_pointer(reverse, data);
+            }
+            _ => return Err(invalid()),
+        }
+        Ok(())
+    }
+
+    fn load(config: &LocalDataConfig) -> Result<Self> {
+        let mut data = LocalData::default();
+        let mut order = Vec::new();
+        // Inline records come first so their names win the synthesized PTRs
+        for record in &config.records {
+            data.add_record(record, &mut order)?;
+        }
+        for path in &config.hosts_files {
+            data.read_hosts(path, &mut order)?;
+        }
+        data.synthesize_pointers(&order);
+        Ok(data)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.addresses.is_empty() && self.pointers.is_empty()
+    }
+}
+
+/// Local names from hosts files and inline records, reloaded when a hosts file changes.
+#[derive(Debug)]
+pub struct LocalDataStore {
+    config: LocalDataConfig,
+    data: RwLock<LocalData>,
+}
+
+impl LocalDataStore {
+    pub fn new(config: &LocalDataConfig) -> Result<Self> {
+        Ok(LocalDataStore {
+            config: config.clone(),
+            data: RwLock::new(LocalData::load(config)?),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+    }
+
+    /// True if name has local data, so it will never be resolved remotely.
+    pub fn contains(&self, name: &str) -> bool {
+        let name = normalize_name(name);
+        let data = self.data.read().unwrap_or_else(PoisonError::into_inner);
+        data.addresses.contains_key(&name) || data.pointers.contains_key(&name)
+    }
+
+    /// Answers query from the local data, or returns None if its name has none. A name that
+    /// is known but has nothing of the queried type gets an empty NOERROR answer.
+    pub fn answer(&self, query: &Message) -> Option<Message> {
+        let question = query.questions.first()?;
+        let name = normalize_name(&question.name);
+        let data = self.data.read().unwrap_or_else(PoisonError::into_inner);
+        let ttl = Duration::from_secs(self.config.ttl as u64);
+        let record = |resource| Record {
+            name: question.name.clone(),
+            class: Class::Internet,
+            ttl,
+            resource,
+        };
+
+        let mut rsp = Message {
+            aa: true,
+            ..Default::default()
+        };
+        if let Some(addresses) = data.addresses.get(&name) {
+            for ip in addresses {
+                match (ip, question.r#type) {
+                    (IpAddr::V4(v4), Type::A | Type::ANY) => {
+                        rsp.answers.push(record(Resource::A(*v4)))
+                    }
+                    (IpAddr::V6(v6), Type::AAAA | Type::ANY) => {
+                        rsp.answers.push(record(Resource::AAAA(*v6)))
+                    }
+                    _ => (),
+                }
+            }
+        }
+        if let Some(names) = data.pointers.get(&name) {
+            if matches!(question.r#type, Type::PTR | Type::ANY) {
+                for target in names {
+                    rsp.answers
+                        .push(record(Resource::PTR(format!("{}.", target))));
+                }
+            }
+        } else if !data.addresses.contains_key(&name) {
+            return None;
+        }
+        Some(rsp)
+    }
+
+    fn modification_times(&self) -> Vec<Option<SystemTime>> {
+        self.config
+            .hosts_files
+            .iter()
+            .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    fn reload(&self) {
+        match LocalData::load(&self.config) {
+            Ok(data) => {
+                *self.data.write().unwrap_or_else(PoisonError::into_inner) = data;
+                info!(
+                    "Reloaded local data from {}",
+                    display_paths(&self.config.hosts_files)
+                );
+            }
+            Err(e) => warn!("Keeping the current local data, reload failed: {}", e),
+        }
+    }
+
+    /// Checks the hosts files once per interval and reloads everything when one of them
+    /// changed. Stops once the store is dropped, which happens when a reload replaces it.
+    pub fn start_watcher(store: &Arc<LocalDataStore>) {
+        let interval = match store.config.reload_interval() {
+            Some(i) if !store.config.hosts_files.is_empty() => i,
+            _ => return,
+        };
+        let mut seen = store.modification_times();
+        let store: Weak<LocalDataStore> = Arc::downgrade(store);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let store = match store.upgrade() {
+                Some(s) => s,
+                None => return,
+            };
+            let current = store.modification_times();
+            if current != seen {
+                seen = current;
+                store.reload();
+            }
+        });
+    }
+}
+
+fn display_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,247 @@
+use crate::config::{normalize_name, ZoneConfig};
+use crate::upstream::{is_subdomain, label_count};
+use crate::zoneerror::{Result, ZoneError, ZoneErrorReason};
+use crate::zonefile::parse_zone_file;
+use rustdns::{Message, Rcode, Record, Resource, Type};
+use std::collections::{HashMap, HashSet};
+
+/// How many CNAMEs are followed inside a zone before giving up on the chain.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// A zone Dash is authoritative for, loaded from a zone file.
+#[derive(Debug)]
+pub struct Zone {
+    origin: String,
+    soa: Record,
+    // Owner name to its records, names are normalized
+    nodes: HashMap<String, Vec<Record>>,
+    // Every name that exists, including empty non-terminals between the records and the
+    // origin, so they answer NODATA rather than NXDOMAIN (RFC 8020)
+    names: HashSet<String>,
+}
+
+impl Zone {
+    /// Checks the records form a zone for origin. There must be exactly one SOA, at the
+    /// origin, every record must be inside the zone and a CNAME cannot share its owner.
+    pub fn new(origin: &str, records: Vec<Record>) -> Result<Zone> {
+        let origin = normalize_name(origin);
+        let invalid = |info: String| ZoneError::new(ZoneErrorReason::InvalidZone(info));
+
+        let mut soa = None;
+        let mut nodes: HashMap<String, Vec<Record>> = HashMap::new();
+        let mut names = HashSet::new();
+        for mut record in records {
+            record.name = normalize_name(&record.name);
+            if !is_subdomain(&record.name, &origin) {
+                return Err(invalid(format!(
+                    "{} is outside of zone {}",
+                    record.name, origin
+                )));
+            }
+            if let Resource::SOA(_) = record.resource {
+                if record.name != origin || soa.is_some() {
+                    return Err(invalid(format!(
+                        "zone {} needs exactly one SOA record, at its origin",
+                        origin
+                    )));
+                }
+                soa = Some(record.clone());
+            }
+            let mut name = record.name.as_str();
+            while names.insert(name.to_string()) && name != origin {
+                name = name.split_once('.').map_or("", |(_, parent)| parent);
+            }
+            nodes.entry(record.name.clone()).or_default().push(record);
+        }
+        let soa = soa.ok_or_else(|| invalid(format!("zone {} has no SOA record", origin)))?;
+        for (name, records) in &nodes {
+            let has_cname = records.iter().any(|r| r.resource.r#type() == Type::CNAME);
+            if has_cname && records.len() > 1 {
+                return Err(invalid(format!("{} has a CNAME and other records", name)));
+            }
+        }
+        Ok(Zone {
+            origin,
+            soa,
+            nodes,
+            names,
+        })
+    }
+
+    pub fn load(config: &ZoneConfig) -> Result<Zone> {
+        let records = parse_zone_file(&config.file, &normalize_name(&config.name))?;
+        Zone::new(&config.name, records)
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// Number of owner names with records.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn records_of(&self, name: &str, qtype: Type) -> Vec<Record> {
+        self.nodes
+            .get(name)
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|r| qtype == Type::ANY || r.resource.r#type() == qtype)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The highest delegation between the origin and name, if name is below a zone cut.
+    fn zone_cut(&self, name: &str) -> Option<(&str, &Vec<Record>)> {
+        let mut ancestors = Vec::new();
+        let mut current = name;
+        while current != self.origin {
+            ancestors.push(current);
+            current = current.split_once('.').map_or("", |(_, parent)| parent);
+        }
+        ancestors.iter().rev().find_map(|cut| {
+            let (cut, records) = self.nodes.get_key_value(*cut)?;
+            records
+                .iter()
+                .any(|r| r.resource.r#type() == Type::NS)
+                .then_some((cut.as_str(), records))
+        })
+    }
+
+    fn negative(&self, rsp: &mut Message, rcode: Rcode) {
+        rsp.rcode = rcode;
+        // RFC 2308 3: the SOA is cached for the lesser of its TTL and its minimum
+        let mut soa = self.soa.clone();
+        if let Resource::SOA(data) = &soa.resource {
+            soa.ttl = soa.ttl.min(data.minimum);
+        }
+        rsp.authoritys.push(soa);
+    }
+
+    fn referral(&self, rsp: &mut Message, cut: &str, records: &[Record]) {
+        rsp.aa = false;
+        for ns in records.iter().filter(|r| r.resource.r#type() == Type::NS) {
+            rsp.authoritys.push(ns.clone());
+            if let Resource::NS(target) = &ns.resource {
+                // Glue is only needed, and only trustworthy, for servers inside the cut
+                let target = normalize_name(target);
+                if is_subdomain(&target, cut) {
+                    rsp.additionals.extend(self.records_of(&target, Type::A));
+                    rsp.additionals.extend(self.records_of(&target, Type::AAAA));
+                }
+            }
+        }
+    }
+
+    /// Adds the answer for qname to rsp, following CNAMEs that stay inside the zone.
+    fn answer(&self, qname: &str, qtype: Type, rsp: &mut Message, chain: usize) {
+        if let Some((cut, records)) = self.zone_cut(qname) {
+            if rsp.answers.is_empty() {
+                self.referral(rsp, cut, records);
+            }
+            return;
+        }
+
+        let (owner, synthesized) = if self.names.contains(qname) {
+            (qname.to_string(), false)
+        } else {
+            // RFC 4592: a wildcard at the closest encloser matches names that do not exist
+            let mut encloser = qname;
+            while !self.names.contains(encloser) {
+                encloser = encloser.split_once('.').map_or("", |(_, parent)| parent);
+            }
+            let wildcard = if encloser.is_empty() {
+                "*".to_string()
+            } else {
+                format!("*.{}", encloser)
+            };
+            if !self.nodes.contains_key(&wildcard) {
+                self.negative(rsp, Rcode::NXDomain);
+                return;
+            }
+            (wildcard, true)
+        };
+        let rename = |mut record: Record| {
+            if synthesized {
+                record.name = qname.to_string();
+            }
+            record
+        };
+
+        let cname = self.records_of(&owner, Type::CNAME).pop();
+        if let (Some(cname), false) = (cname, matches!(qtype, Type::CNAME | Type::ANY)) {
+            let target = match &cname.resource {
+                Resource::CNAME(target) => normalize_name(target),
+                _ => unreachable!(),
+            };
+            rsp.answers.push(rename(cname));
+            if chain < MAX_CNAME_CHAIN && is_subdomain(&target, &self.origin) {
+                self.answer(&target, qtype, rsp, chain + 1);
+            }
+            return;
+        }
+
+        let records = self.records_of(&owner, qtype);
+        if records.is_empty() {
+            self.negative(rsp, Rcode::NoError);
+        } else {
+            rsp.answers.extend(records.into_iter().map(rename));
+        }
+    }
+}
+
+/// All local zones, answered before the cache and recursion.
+#[derive(Debug, Default)]
+pub struct ZoneStore {
+    // Most specific zone first
+    zones: Vec<Zone>,
+}
+
+impl ZoneStore {
+    pub fn new(mut zones: Vec<Zone>) -> Self {
+        zones.sort_by_key(|z| std::cmp::Reverse(label_count(z.origin())));
+        ZoneStore { zones }
+    }
+
+    pub fn load(configs: &[ZoneConfig]) -> Result<Self> {
+        let zones = configs.iter().map(Zone::load).collect::<Result<Vec<_>>>()?;
+        Ok(ZoneStore::new(zones))
+    }
+
+    pub fn zones(&self) -> &[Zone] {
+        &self.zones
+    }
+
+    /// The closest zone containing name.
+    pub fn zone_for(&self, name: &str) -> Option<&Zone> {
+        let name = normalize_name(name);
+        self.zones.iter().find(|z| is_subdomain(&name, z.origin()))
+    }
+
+    /// Answers query from the local zones, or returns None if no zone contains the name.
+    /// Only the answer, authority and additional sections, the rcode and the AA bit of the
+    /// returned message are meaningful.
+    pub fn answer(&self, query: &Message) -> Option<Message> {
+        let question = query.questions.first()?;
+        let zone = self.zone_for(&question.name)?;
+        let mut rsp = Message {
+            aa: true,
+            ..Default::default()
+        };
+        zone.answer(
+            &normalize_name(&question.name),
+            question.r#type,
+            &mut rsp,
+            0,
+        );
+        Some(rsp)
+    }
+}
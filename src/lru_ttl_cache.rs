@@ -45,6 +45,24 @@ where
             .map(|entry_link| entry_link.lock().unwrap().value.clone())
     }
 
+    /// Replaces the value and ttl stored for k. Returns false, changing nothing, if k is not
+    /// in the cache.
+    pub fn replace(&mut self, k: &K, v: V, ttl: SystemTime) -> bool {
+        match self.cache_map.lock().unwrap().get(k) {
+            Some(entry_link) => {
+                let mut entry = entry_link.lock().unwrap();
+                entry.value = v;
+                entry.ttl = ttl;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn contains(&self, k: &K) -> bool {
+        self.cache_map.lock().unwrap().contains_key(k)
+    }
+
     fn evict(&mut self, link: CacheEntryLink<K, V>) {
         let entry = link.lock().unwrap();
         let prev = &entry.prev;
@@ -92,12 +110,9 @@ where
         }));
         self.cache_map.lock().unwrap().insert(k, new_entry.clone());
 
-        match &self.list_head {
-            Some(h) => {
-                h.lock().unwrap().prev = Some(new_entry.clone());
-                self.list_tail = Some(new_entry.clone());
-            }
-            None => (),
+        if let Some(h) = &self.list_head {
+            h.lock().unwrap().prev = Some(new_entry.clone());
+            self.list_tail = Some(new_entry.clone());
         }
 
         if self.list_tail.is_none() {
@@ -1,17 +1,295 @@
+use clap::Parser;
+use dash::clientlimits::Admission;
+use dash::config::{
+    parse_forwarder, parse_log_level, AclAction, Config, LimitAction, UpstreamMode,
+    UpstreamSelection,
+};
+use dash::configerror::{ConfigError, ConfigErrorReason, Result as ConfigResult};
+use dash::dashcontext::DashContext;
 use dash::dashjob::DashJob;
-use dash::dnserror::DnsError;
+use dash::dnstools::{response_to, udp_payload_limit};
 use dash::lru_ttl_cache::Cache;
-use dash::threadpool::ThreadPool;
-use rustdns::{Class, Extension, Message, Rcode, Type};
-use std::io::{Error, ErrorKind};
-use std::net::UdpSocket;
+use dash::rrl::apply_verdict;
+use dash::threadpool::{JobPriority, ThreadPool};
+use dash::wire::{decode, encode_error, encode_response, raw_question};
+use log::{debug, error, info, warn, LevelFilter};
+use rustdns::{Message, Rcode};
+use std::io::Error;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+/// Dash caching DNS resolver. Flags override values read from the configuration file.
+///
+/// Sending SIGHUP reloads the configuration file. Flags keep overriding it, and the listen
+/// addresses, port, cache capacity and max exec time only change on restart.
+#[derive(Parser, Debug)]
+#[command(name = "dash", version)]
+struct Args {
+    /// TOML configuration file
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, may be given more than once [default: 0.0.0.0]
+    #[arg(long, value_name = "ADDR")]
+    listen: Vec<IpAddr>,
+
+    /// UDP port to listen on [default: 50051]
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Initial number of worker threads
+    #[arg(long)]
+    pool_size: Option<usize>,
+
+    /// Lower bound for dynamic resizing of the worker pool
+    #[arg(long)]
+    pool_min_size: Option<usize>,
+
+    /// Upper bound for dynamic resizing of the worker pool
+    #[arg(long)]
+    pool_max_size: Option<usize>,
+
+    /// Time after which a running job is reported as timed out
+    #[arg(long, value_name = "MS")]
+    max_exec_time_ms: Option<u64>,
+
+    /// Number of answers kept in the cache
+    #[arg(long)]
+    cache_capacity: Option<usize>,
+
+    /// How long to wait for a single upstream server to answer
+    #[arg(long, value_name = "MS")]
+    query_timeout_ms: Option<u64>,
+
+    /// recursive or forward
+    #[arg(long, value_name = "MODE")]
+    upstream_mode: Option<UpstreamMode>,
+
+    /// Upstream server for forward mode as IP or IP:port, may be given more than once
+    #[arg(long = "forwarder", value_name = "ADDR", value_parser = parse_forwarder)]
+    forwarders: Vec<SocketAddr>,
+
+    /// round-robin or fastest
+    #[arg(long, value_name = "SELECTION")]
+    upstream_selection: Option<UpstreamSelection>,
+
+    /// off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL", value_parser = parse_log_level)]
+    log_level: Option<LevelFilter>,
+}
+
+impl Args {
+    fn apply(&self, config: &mut Config) {
+        if !self.listen.is_empty() {
+            config.server.listen = self.listen.clone();
+        }
+        if let Some(port) = self.port {
+            config.server.port = port;
+        }
+        if let Some(size) = self.pool_size {
+            config.pool.size = size;
+        }
+        if let Some(min_size) = self.pool_min_size {
+            config.pool.min_size = min_size;
+        }
+        if let Some(max_size) = self.pool_max_size {
+            config.pool.max_size = max_size;
+        }
+        if let Some(ms) = self.max_exec_time_ms {
+            config.pool.max_exec_time_ms = ms;
+        }
+        if let Some(capacity) = self.cache_capacity {
+            config.cache.capacity = capacity;
+        }
+        if let Some(ms) = self.query_timeout_ms {
+            config.resolver.query_timeout_ms = ms;
+        }
+        if let Some(mode) = self.upstream_mode {
+            config.resolver.mode = mode;
+        }
+        if !self.forwarders.is_empty() {
+            config.resolver.forwarders = self.forwarders.clone();
+        }
+        if let Some(selection) = self.upstream_selection {
+            config.resolver.selection = selection;
+        }
+        if let Some(level) = self.log_level {
+            config.log.level = level;
+        }
+    }
+}
+
+fn load_config(args: &Args) -> ConfigResult<Config> {
+    let mut config = match &args.config {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
+    };
+    args.apply(&mut config);
+    config.validate()?;
+    Ok(config)
+}
+
+/// Reads the configuration again and applies it to the pool and logger. Nothing is changed
+/// unless the whole new configuration is valid. Returns the context for new jobs.
+fn reload(args: &Args, current: &Config, tp: &mut ThreadPool) -> ConfigResult<DashContext> {
+    if args.config.is_none() {
+        warn!("Reload requested but dash was started without a configuration file");
+    }
+    let mut config = load_config(args)?;
+
+    // These are baked into sockets, the cache and the workers when the server starts
+    if config.server != current.server {
+        warn!("Changes to [server] only take effect after a restart");
+        config.server = current.server.clone();
+    }
+    if config.cache != current.cache {
+        warn!("Changes to [cache] only take effect after a restart");
+        config.cache = current.cache.clone();
+    }
+    if config.pool.max_exec_time_ms != current.pool.max_exec_time_ms {
+        warn!("Changes to pool.max_exec_time_ms only take effect after a restart");
+        config.pool.max_exec_time_ms = current.pool.max_exec_time_ms;
+    }
+
+    // Zone files are loaded before anything is applied, so a broken one changes nothing
+    let context = DashContext::new(config)?;
+    let config = context.config();
+
+    match tp.set_pool_bounds(config.pool.min_size, config.pool.max_size) {
+        Ok(0) => (),
+        Ok(c) => info!("Resized pool by {} to fit the new bounds", c),
+        Err(e) => {
+            return Err(ConfigError::new(ConfigErrorReason::InvalidValue(format!(
+                "pool bounds rejected by the thread pool: {}",
+                e
+            ))))
+        }
+    }
+    log::set_max_level(config.log.level);
+
+    Ok(context)
+}
+
+/// Answers a query the access control rules do not let through.
+fn send_refused(context: &DashContext, socket: &UdpSocket, client: SocketAddr, query: &Message) {
+    let mut rsp = response_to(query);
+    rsp.rcode = Rcode::Refused;
+    let verdict = context.rate_limiter().check(client.ip(), &rsp);
+    let Some(rsp) = apply_verdict(verdict, rsp) else {
+        return;
+    };
+    let sent = encode_response(&rsp, udp_payload_limit(query), None)
+        .map_err(|e| e.to_string())
+        .and_then(|encoded| socket.send_to(&encoded, client).map_err(|e| e.to_string()));
+    if let Err(e) = sent {
+        warn!("Could not refuse the query from {}: {}", client, e);
+    }
+}
+
+/// Answers a query that could not be decoded, or not represented, with only its header and
+/// question. The response is no larger than the query, so it is sent without rate limiting.
+fn send_error(socket: &UdpSocket, client: SocketAddr, packet: &[u8], rcode: Rcode) {
+    let Some(encoded) = encode_error(packet, rcode) else {
+        return;
+    };
+    if let Err(e) = socket.send_to(&encoded, client) {
+        warn!("Could not send the response to {}: {}", client, e);
+    }
+}
+
+/// Blocklist, policy zone, access control and rate limiting counters start over with every
+/// context, so they are logged before one is replaced or the server stops.
+fn log_filter_stats(context: &DashContext) {
+    let view = context
+        .view()
+        .map_or_else(String::new, |v| format!(" in view {}", v));
+    for list in context.blocklist().stats() {
+        info!(
+            "Blocklist {}{}: {} domains, {} queries blocked",
+            list.path.display(),
+            view,
+            list.entries,
+            list.hits
+        );
+    }
+    for zone in context.policy_zones().stats() {
+        info!(
+            "Policy zone {}{}: {} rules, {} queries rewritten",
+            zone.zone, view, zone.rules, zone.hits
+        );
+    }
+    if context.view().is_none() {
+        for rule in context.access_control().stats() {
+            let clients = match &rule.clients {
+                Some(clients) => clients
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                None => "everyone else".to_string(),
+            };
+            info!(
+                "Access control {} for {}: {} queries",
+                rule.action, clients, rule.hits
+            );
+        }
+        if context.config().response_rate_limit.responses_per_second > 0 {
+            let rrl = context.rate_limiter().stats();
+            info!(
+                "Response rate limiting: {} responses slipped, {} dropped",
+                rrl.slipped, rrl.dropped
+            );
+        }
+        let limits = context.client_limits().stats();
+        if limits.dropped > 0 || limits.refused > 0 || limits.in_flight > 0 {
+            info!(
+                "Client limits: {} clients tracked, {} recursions in flight, {} queries dropped, {} refused",
+                limits.tracked_clients, limits.in_flight, limits.dropped, limits.refused
+            );
+        }
+        for rule in limits.rules {
+            let clients: Vec<String> = rule.clients.iter().map(|c| c.to_string()).collect();
+            info!(
+                "Client limit for {}: {} recursions in flight, {} queries limited",
+                clients.join(", "),
+                rule.in_flight,
+                rule.limited
+            );
+        }
+    }
+    for view in context.views() {
+        log_filter_stats(view);
+    }
+}
+
 fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_BACKTRACE", "1");
 
+    let args = Args::parse();
+    let config = match load_config(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    // The logger lets everything through and the level is enforced by log's max level, which
+    // unlike the logger's own filter can be changed on reload
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .init();
+    log::set_max_level(config.log.level);
+    let context = match DashContext::new(config) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
     // Note from RFC 1035 2.3.4
     // UDP messages    512 octets or less
     // This is due to lower bound MTU of 576 bytes in RFC 791 Section 3.1
@@ -23,125 +301,146 @@ fn main() -> std::io::Result<()> {
     ctrlc::set_handler(move || stop.store(true, Ordering::SeqCst))
         .expect("Error with control c logic");
 
-    std::thread::spawn(move || {
-        loop {
-            for i in 0..30 {
-                std::thread::spawn(move || {
-                    // Sleep so the server has time to startup
-                    std::thread::sleep(Duration::from_millis(100));
-                    let mut msg = Message::default();
-                    #[allow(clippy::if_same_then_else)]
-                    if i > 25 {
-                        msg.add_question("datatracker.ietf.org", Type::A, Class::Internet);
-                    } else if i > 20 {
-                        msg.add_question("cis1905.org", Type::A, Class::Internet);
-                    } else if i > 15 {
-                        msg.add_question("seas.upenn.edu", Type::A, Class::Internet);
-                    } else if i > 10 {
-                        msg.add_question("nyartcc.org", Type::A, Class::Internet);
-                    } else if i > 5 {
-                        msg.add_question("nytimes.com", Type::A, Class::Internet);
-                    } else if i >= 0 {
-                        msg.add_question("savetibet.org", Type::A, Class::Internet);
-                    }
-
-                    msg.add_extension(Extension {
-                        payload_size: 4096,
-                        ..Default::default()
-                    });
-
-                    let sending_socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-                    sending_socket.set_nonblocking(true).unwrap();
-
-                    sending_socket
-                        .send_to(&msg.to_vec().unwrap(), "127.0.0.1:50051")
-                        .unwrap();
-
-                    let mut resp = [0; EDNS_RECCOMENDED_OCTETS];
-                    loop {
-                        match sending_socket.recv(&mut resp) {
-                            Ok(_) => (),
-                            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
-                                std::thread::sleep(Duration::from_millis(2020));
-                                continue;
-                            }
-                            Err(e) => {
-                                println!(
-                                    "{}",
-                                    DnsError::new(Rcode::ServFail).with_info(format!(
-                                        "Issue at sock.recv in query_name_server: {}",
-                                        e
-                                    ))
-                                );
-                                return;
-                            }
-                        }
-                        let msg_received = String::from_utf8_lossy(&resp);
-                        println!(
-                            "Received message: {}, {} ---- {}",
-                            i,
-                            msg_received,
-                            msg.questions.first().unwrap().name
-                        );
-                        return;
-                    }
-                });
-            }
-            std::thread::sleep(Duration::from_millis(5000));
-        }
-    });
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, reload_requested.clone())?;
 
     let handle = std::thread::spawn(move || -> std::io::Result<()> {
-        let mut tp = match ThreadPool::new(10, 5, 15, Duration::from_secs(5)) {
+        let config = context.config().clone();
+        let pool = &config.pool;
+        let mut tp = match ThreadPool::new(
+            pool.size,
+            pool.min_size,
+            pool.max_size,
+            pool.max_exec_time(),
+        ) {
             Ok(tp) => tp,
-            Err(e) => return Err(Error::new(ErrorKind::Other, format!("{}", e))),
+            Err(e) => return Err(Error::other(format!("{}", e))),
         };
 
-        let socket = UdpSocket::bind("0.0.0.0:50051")?;
-        socket.set_nonblocking(true)?;
-        println!("Started Dash DNS server on port 50051");
+        let mut sockets = Vec::with_capacity(config.server.listen.len());
+        for address in &config.server.listen {
+            let socket = UdpSocket::bind((*address, config.server.port))?;
+            socket.set_nonblocking(true)?;
+            info!("Started Dash DNS server on {}", socket.local_addr()?);
+            sockets.push(Arc::new(socket));
+        }
 
-        const CACHE_CAPACITY: usize = 100;
-        let cache = Arc::new(Mutex::new(Cache::<String, Message>::new(CACHE_CAPACITY)));
-        Cache::start_ttl_daemon(cache.clone(), CACHE_CAPACITY);
+        let cache_capacity = config.cache.capacity;
+        let cache = Arc::new(Mutex::new(Cache::<String, Message>::new(cache_capacity)));
+        Cache::start_ttl_daemon(cache.clone(), cache_capacity);
+        let mut context = Arc::new(context);
+        context.start_background_tasks();
 
         let mut receive_buffer = [0; EDNS_RECCOMENDED_OCTETS];
         let mut i = 0;
         while !stop_copy.load(Ordering::SeqCst) {
-            let (rec_bytes, client) = match socket.recv_from(&mut receive_buffer) {
-                Ok(s) => s,
-                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
-                    std::thread::sleep(Duration::from_millis(20));
+            if reload_requested.swap(false, Ordering::SeqCst) {
+                match reload(&args, context.config(), &mut tp) {
+                    Ok(new_context) => {
+                        log_filter_stats(&context);
+                        context = Arc::new(new_context);
+                        context.start_background_tasks();
+                        info!("Reloaded configuration");
+                    }
+                    Err(e) => error!("Keeping the current configuration, reload failed: {}", e),
+                }
+            }
+            let pool = &context.config().pool;
+
+            let mut received_any = false;
+            for socket in &sockets {
+                let (rec_bytes, client) = match socket.recv_from(&mut receive_buffer) {
+                    Ok(s) => s,
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                };
+                received_any = true;
+                let action = context.access_control().check(client.ip());
+                if action == AclAction::Deny {
+                    continue;
+                }
+                let packet = &receive_buffer[..rec_bytes];
+                let decoded = match decode(packet) {
+                    Ok(raw) => match raw_question(packet) {
+                        // A question rustdns cannot represent, such as DS, DNSKEY or HTTPS
+                        Some(question) if raw.message.questions.is_empty() => {
+                            debug!("Unsupported query type {} from {}", question.r#type, client);
+                            Err(Rcode::NotImp)
+                        }
+                        _ => Ok(raw.message),
+                    },
+                    Err(e) => {
+                        debug!("Malformed query from {}: {}", client, e);
+                        Err(Rcode::FormErr)
+                    }
+                };
+                let dns_request = match (decoded, action) {
+                    (Ok(m), _) => m,
+                    (Err(_), AclAction::Refuse) => {
+                        send_error(socket, client, packet, Rcode::Refused);
+                        continue;
+                    }
+                    (Err(rcode), _) => {
+                        send_error(socket, client, packet, rcode);
+                        continue;
+                    }
+                };
+                if action == AclAction::Refuse {
+                    send_refused(&context, socket, client, &dns_request);
                     continue;
                 }
-                Err(e) => return Err(e),
-            };
-            let dns_request = Message::from_slice(&receive_buffer[0..rec_bytes])?;
 
-            tp.submit_job(Box::new(DashJob::new(dns_request, client, cache.clone())));
-            if i % 20 == 0 {
-                match tp.dynamic_resizing(3, 6) {
-                    Ok(c) => println!("Dynamic resizing with factor {}", c),
-                    Err(e) => println!("Error in dynamic resizing {}", e),
+                let mut job = DashJob::new(
+                    dns_request,
+                    client,
+                    socket.clone(),
+                    cache.clone(),
+                    context.clone(),
+                );
+                // Without the RD bit only local answers are given, the cache is not probed
+                if action == AclAction::Allow && !job.query().rd && !job.answered_locally() {
+                    send_refused(&context, socket, client, job.query());
+                    continue;
                 }
+                let priority = job.priority();
+                let recursion = priority == JobPriority::ClientRecursion;
+                match context.client_limits().admit(client.ip(), recursion) {
+                    Admission::Admitted(in_flight) => job.set_in_flight(in_flight),
+                    Admission::Limited(LimitAction::Drop) => continue,
+                    Admission::Limited(LimitAction::Refuse) => {
+                        send_refused(&context, socket, client, job.query());
+                        continue;
+                    }
+                }
+                tp.submit_job_with_priority(Box::new(job), priority);
+                if i % pool.resize_interval == 0 {
+                    match tp.dynamic_resizing(pool.resize_lower_bound, pool.resize_upper_bound) {
+                        Ok(c) => info!("Dynamic resizing with factor {}", c),
+                        Err(e) => error!("Error in dynamic resizing {}", e),
+                    }
+                }
+                i += 1;
+            }
+            if !received_any {
+                std::thread::sleep(Duration::from_millis(20));
             }
-            i += 1;
         }
         tp.shutdown();
+        log_filter_stats(&context);
         Ok(())
     });
 
     match handle.join() {
         Ok(r) => match &r {
             Ok(_) => {
-                println!("Shutting down server");
+                info!("Shutting down server");
                 r
             }
             Err(e) => {
-                eprintln!("{}", e);
+                error!("{}", e);
                 r
             }
         },
-        Err(_) => Err(Error::new(ErrorKind::Other, "Error in joining main loop")),
+        Err(_) => Err(Error::other("Error in joining main loop")),
     }
 }
@@ -0,0 +1,243 @@
+//! Aggressive use of DNSSEC-validated cache (RFC 8198).
+//!
+//! Once NSEC or NSEC3 records of a zone have been validated, the spans they prove empty are
+//! known to hold no names at all, not just the one that was asked for. They are indexed per
+//! zone, in canonical order for NSEC and hash order for NSEC3, so queries for other names in
+//! a span are answered NXDOMAIN or NODATA without asking the zone again. This takes the load
+//! of random subdomain floods off the zones being flooded.
+
+use crate::config::normalize_name;
+use crate::dnssec::{owner_hash, Nsec, Nsec3, DS};
+use crate::dnstools::response_to;
+use crate::upstream::is_subdomain;
+use crate::validator::{parent, prepend_wildcard, prove, Denial};
+use crate::wire::name_labels;
+use rustdns::{Message, Rcode, Record, Resource, Type};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Zones whose proofs are kept at most this many at once.
+const MAX_ZONES: usize = 10_000;
+
+/// NSEC or NSEC3 records kept at most this many per zone. A zone being walked by a flood
+/// of random names fills up to here and no further.
+const MAX_RECORDS_PER_ZONE: usize = 10_000;
+
+#[derive(Debug, Clone)]
+struct Span<T> {
+    owner: String,
+    record: T,
+    expires: Instant,
+}
+
+#[derive(Debug, Default)]
+struct ZoneDenials {
+    // With its TTL already lowered to its minimum
+    soa: Option<(Record, Instant)>,
+    // Keyed by labels, most significant first, which sorts in canonical order
+    nsecs: BTreeMap<Vec<Vec<u8>>, Span<Nsec>>,
+    nsec3s: BTreeMap<Vec<u8>, Span<Nsec3>>,
+}
+
+impl ZoneDenials {
+    fn purge(&mut self, now: Instant) {
+        self.nsecs.retain(|_, s| s.expires > now);
+        self.nsec3s.retain(|_, s| s.expires > now);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.nsecs.is_empty() && self.nsec3s.is_empty()
+    }
+
+    /// The NSEC whose span name falls in: the one owned by name or the last before it,
+    /// wrapping around to the last of the zone.
+    fn nsec_at(&self, name: &str) -> Option<&Span<Nsec>> {
+        let key = canonical_key(name)?;
+        self.nsecs
+            .range((Bound::Unbounded, Bound::Included(key)))
+            .next_back()
+            .or_else(|| self.nsecs.iter().next_back())
+            .map(|(_, span)| span)
+    }
+
+    /// The NSEC3 matching or covering the hash of name, found the same way.
+    fn nsec3_at(&self, name: &str) -> Option<&Span<Nsec3>> {
+        let (_, first) = self.nsec3s.iter().next()?;
+        let hash = first.record.hash(name)?;
+        self.nsec3s
+            .range((Bound::Unbounded, Bound::Included(hash)))
+            .next_back()
+            .or_else(|| self.nsec3s.iter().next_back())
+            .map(|(_, span)| span)
+    }
+}
+
+/// Validated NSEC and NSEC3 records, with the SOA record negative answers carry, per zone.
+#[derive(Debug, Default)]
+pub struct NsecCache {
+    zones: Mutex<HashMap<String, ZoneDenials>>,
+}
+
+impl NsecCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remembers the NSEC and NSEC3 records of zone that validated in rsp, with their
+    /// owners and TTLs, and the SOA record of zone in rsp.
+    pub(crate) fn insert(
+        &self,
+        zone: &str,
+        rsp: &Message,
+        nsecs: &[(String, Nsec, u32)],
+        nsec3s: &[(String, Nsec3, u32)],
+    ) {
+        let Some(soa) = rsp.authoritys.iter().find_map(|r| match &r.resource {
+            Resource::SOA(data) if normalize_name(&r.name) == zone => {
+                let mut soa = r.clone();
+                soa.ttl = r.ttl.min(data.minimum);
+                Some(soa)
+            }
+            _ => None,
+        }) else {
+            return;
+        };
+        if soa.ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        // RFC 8198 5.4: the records are kept no longer than a negative answer would be
+        let expires = |ttl: u32| now + Duration::from_secs(ttl as u64).min(soa.ttl);
+        let mut zones = self.zones.lock().unwrap_or_else(PoisonError::into_inner);
+        if !zones.contains_key(zone) && zones.len() >= MAX_ZONES {
+            zones.retain(|_, z| {
+                z.purge(now);
+                !z.is_empty()
+            });
+            if zones.len() >= MAX_ZONES {
+                return;
+            }
+        }
+        let denials = zones.entry(zone.to_string()).or_default();
+        let records = denials.nsecs.len() + denials.nsec3s.len();
+        if records + nsecs.len() + nsec3s.len() > MAX_RECORDS_PER_ZONE {
+            denials.purge(now);
+        }
+        for (owner, nsec, ttl) in nsecs {
+            if denials.nsecs.len() >= MAX_RECORDS_PER_ZONE {
+                break;
+            }
+            if let Some(key) = canonical_key(owner) {
+                let span = Span {
+                    owner: owner.clone(),
+                    record: nsec.clone(),
+                    expires: expires(*ttl),
+                };
+                denials.nsecs.insert(key, span);
+            }
+        }
+        for (owner, nsec3, ttl) in nsec3s {
+            if denials.nsec3s.len() >= MAX_RECORDS_PER_ZONE {
+                break;
+            }
+            if let Some(hash) = owner_hash(owner) {
+                let span = Span {
+                    owner: owner.clone(),
+                    record: nsec3.clone(),
+                    expires: expires(*ttl),
+                };
+                denials.nsec3s.insert(hash, span);
+            }
+        }
+        denials.soa = Some((soa.clone(), now + soa.ttl));
+    }
+
+    /// The NXDOMAIN or NODATA answer to msg the cached records prove, with the AD bit set.
+    /// None if they prove nothing about its question, or not enough of them are left.
+    pub fn answer(&self, msg: &Message) -> Option<Message> {
+        let question = msg.questions.first()?;
+        if question.r#type == Type::ANY {
+            return None;
+        }
+        let qname = normalize_name(&question.name);
+        let qtype = question.r#type as u16;
+        // DS records are in the parent zone, not the one at qname
+        let within = match qtype {
+            DS => parent(&qname)?,
+            _ => qname.as_str(),
+        };
+        let now = Instant::now();
+        let zones = self.zones.lock().unwrap_or_else(PoisonError::into_inner);
+        let (zone, denials) = zones
+            .iter()
+            .filter(|(zone, _)| is_subdomain(within, zone))
+            .max_by_key(|(zone, _)| zone.len())?;
+        let (soa, soa_expires) = denials.soa.as_ref().filter(|(_, e)| *e > now)?;
+
+        // The records a proof may need: those at or around qname and the wildcards that
+        // could have matched it
+        let mut names = vec![qname.clone()];
+        let mut ancestor = qname.as_str();
+        while ancestor != zone.as_str() {
+            ancestor = parent(ancestor)?;
+            names.push(ancestor.to_string());
+            names.push(prepend_wildcard(ancestor));
+        }
+        let remaining = |expires: Instant| expires.saturating_duration_since(now).as_secs() as u32;
+        let mut nsecs: Vec<(String, Nsec, u32)> = Vec::new();
+        let mut nsec3s: Vec<(String, Nsec3, u32)> = Vec::new();
+        for name in &names {
+            if let Some(span) = denials.nsec_at(name).filter(|s| s.expires > now) {
+                if !nsecs.iter().any(|(o, _, _)| *o == span.owner) {
+                    nsecs.push((
+                        span.owner.clone(),
+                        span.record.clone(),
+                        remaining(span.expires),
+                    ));
+                }
+            }
+            if !denials.nsecs.is_empty() {
+                continue;
+            }
+            if let Some(span) = denials.nsec3_at(name).filter(|s| s.expires > now) {
+                if !nsec3s.iter().any(|(o, _, _)| *o == span.owner) {
+                    nsec3s.push((
+                        span.owner.clone(),
+                        span.record.clone(),
+                        remaining(span.expires),
+                    ));
+                }
+            }
+        }
+        let rcode = match prove(&nsecs, &nsec3s, &qname, qtype, zone) {
+            Ok(Denial::NxDomain) => Rcode::NXDomain,
+            Ok(Denial::NoData { .. }) => Rcode::NoError,
+            _ => return None,
+        };
+        let ttl = nsecs
+            .iter()
+            .map(|(_, _, ttl)| *ttl)
+            .chain(nsec3s.iter().map(|(_, _, ttl)| *ttl))
+            .chain(std::iter::once(remaining(*soa_expires)))
+            .min()?;
+        if ttl == 0 {
+            return None;
+        }
+        let mut rsp = response_to(msg);
+        rsp.rcode = rcode;
+        rsp.ad = true;
+        let mut soa = soa.clone();
+        soa.ttl = Duration::from_secs(ttl as u64);
+        rsp.authoritys.push(soa);
+        Some(rsp)
+    }
+}
+
+/// The labels of name, most significant first, for ordering names canonically.
+fn canonical_key(name: &str) -> Option<Vec<Vec<u8>>> {
+    let mut labels = name_labels(name).ok()?;
+    labels.reverse();
+    Some(labels)
+}
@@ -1,20 +1,110 @@
+use crate::bailiwick::{chain_end, scrub};
+use crate::clientsubnet::{ClientSubnet, ECS_OPTION};
+use crate::config::{normalize_name, QnameMinimization, ResolverConfig, UpstreamMode};
+use crate::credibility::Credibility;
+use crate::dashcontext::DashContext;
 use crate::dnserror::{DnsError, Result};
-use crate::dnstools;
+use crate::dnssec::DS;
+use crate::dnstools::{self, EDNS_PAYLOAD_SIZE};
+use crate::upstream::{child_toward, is_subdomain, label_count, UpstreamPool};
+use crate::validator::{validate, Security};
+use crate::wire::{
+    decode, encode_query, encode_with_options, raw_question, RawMessage, RawQuestion,
+};
+use log::{debug, warn};
 use rustdns::{
     Class, Extension, Message, Rcode,
-    Resource::{A, NS},
+    Resource::{A, AAAA, NS, SOA},
     Type,
 };
-use std::net::{Ipv4Addr, UdpSocket};
-use std::time::Duration;
+use std::cell::Cell;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+const DNS_PORT: u16 = 53;
 
 pub fn check_format_query(msg: &Message) -> bool {
     !(rustdns::QR::Query != msg.qr || msg.questions.is_empty())
 }
 
-pub fn dispatch_query(msg: &Message) -> Result<Message> {
+/// Longest chain of CNAMEs followed across separate lookups.
+const MAX_CNAME_HOPS: usize = 8;
+
+/// Most referrals followed by one lookup.
+const MAX_REFERRALS: usize = 16;
+
+/// Most queries one lookup sends with a minimized name, the MAX_MINIMISE_COUNT of RFC 9156
+/// 2.3. A name with more labels than this is sent in full to the zones below them.
+const MAX_MINIMIZED_QUERIES: usize = 10;
+
+/// Most lookups nested inside one another for one query. A lookup nests another to find the
+/// address of a nameserver delegated to without glue, or the DS and DNSKEY records its
+/// answer is validated with.
+const MAX_LOOKUP_DEPTH: usize = 6;
+
+/// Most queries sent to authoritative servers for one query, nested lookups included.
+const MAX_QUERIES: usize = 100;
+
+/// What resolving one query may still spend, shared by every lookup nested inside it. The
+/// limits of a single lookup do not stop delegations whose nameservers can only be found
+/// through each other, this does.
+#[derive(Debug, Default)]
+pub struct Budget {
+    depth: Cell<usize>,
+    queries: Cell<usize>,
+}
+
+impl Budget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a query about to be sent to an authoritative server.
+    fn spend_query(&self) -> Result<()> {
+        let queries = self.queries.get() + 1;
+        if queries > MAX_QUERIES {
+            return Err(DnsError::new(Rcode::ServFail).with_info(format!(
+                "Gave up after {} queries to authoritative servers",
+                MAX_QUERIES
+            )));
+        }
+        self.queries.set(queries);
+        Ok(())
+    }
+
+    /// Runs lookup one level deeper, or fails without running it past the deepest level.
+    fn nested<T>(&self, lookup: impl FnOnce() -> Result<T>) -> Result<T> {
+        let depth = self.depth.get();
+        if depth >= MAX_LOOKUP_DEPTH {
+            return Err(DnsError::new(Rcode::ServFail)
+                .with_info(format!("Gave up after {} nested lookups", MAX_LOOKUP_DEPTH)));
+        }
+        self.depth.set(depth + 1);
+        let result = lookup();
+        self.depth.set(depth);
+        result
+    }
+}
+
+pub fn dispatch_query(
+    msg: &Message,
+    context: &DashContext,
+    subnet: Option<&ClientSubnet>,
+) -> Result<Message> {
+    dispatch_within(msg, context, subnet, &Budget::new())
+}
+
+/// Like [`dispatch_query`], spending from the budget of the query msg is part of.
+fn dispatch_within(
+    msg: &Message,
+    context: &DashContext,
+    subnet: Option<&ClientSubnet>,
+    budget: &Budget,
+) -> Result<Message> {
     if msg.rd {
-        recursive_resolution(msg)
+        let mut rsp = dispatch_once(msg, context, subnet, budget)?;
+        follow_cnames(msg, &mut rsp, context, subnet, budget)?;
+        Ok(rsp)
     } else {
         //iterative_resolution(msg)
         Err(DnsError::new(Rcode::NotImp)
@@ -22,11 +112,85 @@ pub fn dispatch_query(msg: &Message) -> Result<Message> {
     }
 }
 
-pub fn resolve_message_query(msg: &Message) -> Result<Message> {
+fn dispatch_once(
+    msg: &Message,
+    context: &DashContext,
+    subnet: Option<&ClientSubnet>,
+    budget: &Budget,
+) -> Result<Message> {
+    let upstreams = context.upstreams();
+    // Forward zones take precedence over both recursion and the default forwarders
+    if let Some((zone, pool)) = msg
+        .questions
+        .first()
+        .and_then(|q| upstreams.forward_zone(&q.name))
+    {
+        return forward_query(msg, zone, pool, context);
+    }
+    match (context.resolver().mode, upstreams.forwarders()) {
+        (UpstreamMode::Forward, Some(pool)) => forward_query(msg, "", pool, context),
+        _ => recursive_resolution(msg, context, subnet, budget),
+    }
+}
+
+/// Completes an answer that ends in a CNAME without records of the type asked for, which is
+/// what is left when scrubbing drops the part of a chain outside a server's bailiwick, by
+/// looking up the target on its own.
+fn follow_cnames(
+    msg: &Message,
+    rsp: &mut Message,
+    context: &DashContext,
+    subnet: Option<&ClientSubnet>,
+    budget: &Budget,
+) -> Result<()> {
+    let Some(question) = msg.questions.first() else {
+        return Ok(());
+    };
+    if matches!(question.r#type, Type::CNAME | Type::ANY) {
+        return Ok(());
+    }
+    let qname = normalize_name(&question.name);
+    for _ in 0..MAX_CNAME_HOPS {
+        let end = chain_end(rsp, &qname);
+        let answered = rsp
+            .answers
+            .iter()
+            .any(|r| normalize_name(&r.name) == end && r.resource.r#type() == question.r#type);
+        if end == qname || answered || rsp.rcode != Rcode::NoError {
+            return Ok(());
+        }
+        let mut follow = msg.clone();
+        follow.questions[0].name = format!("{}.", end);
+        let next = dispatch_once(&follow, context, subnet, budget)?;
+        // The answer is no longer all from one server, and only as secure as its weakest part
+        rsp.aa = false;
+        rsp.ad &= next.ad;
+        if next.answers.is_empty() {
+            rsp.rcode = next.rcode;
+            rsp.authoritys = next.authoritys;
+            return Ok(());
+        }
+        rsp.answers.extend(next.answers);
+    }
+    Ok(())
+}
+
+pub fn resolve_message_query(msg: &Message, context: &DashContext) -> Result<Message> {
+    resolve_for_client(msg, context, None)
+}
+
+/// Like [`resolve_message_query`], telling the authoritative servers configured for it the
+/// subnet of the client the query is from. subnet is left with the scope the answer holds
+/// for.
+pub fn resolve_for_client(
+    msg: &Message,
+    context: &DashContext,
+    subnet: Option<&ClientSubnet>,
+) -> Result<Message> {
     if !check_format_query(msg) {
         Err(DnsError::new(Rcode::FormErr))
     } else {
-        dispatch_query(msg)
+        dispatch_query(msg, context, subnet)
     }
 }
 
@@ -57,45 +221,628 @@ pub fn iterative_resolution(mut msg : Message) -> Result<Message> {
 }
 */
 
-pub fn recursive_resolution(msg: &Message) -> Result<Message> {
-    let root_server_ip: Ipv4Addr = "198.41.0.4".parse::<Ipv4Addr>()?;
-    const ROOT_SERVER_NAME: &str = "a.root-servers.net";
+/// Where iteration for name starts: the deepest of a configured stub zone and a cached
+/// referral, falling back to the root servers. A stub zone wins over a referral for the same
+/// zone, since it was configured on purpose.
+fn starting_point(name: &str, context: &DashContext) -> (String, Vec<SocketAddr>) {
+    let stub = context.stub_zone(name);
+    let cached = context.delegations().closest(name);
+    match (stub, cached) {
+        (Some((stub_zone, _)), Some((zone, servers)))
+            if label_count(&zone) > label_count(stub_zone) =>
+        {
+            (zone, servers)
+        }
+        (Some((stub_zone, servers)), _) => (stub_zone.to_string(), servers.to_vec()),
+        (None, Some(cached)) => cached,
+        (None, None) => (
+            String::new(),
+            context
+                .resolver()
+                .root_servers
+                .iter()
+                .map(|ip| SocketAddr::new((*ip).into(), DNS_PORT))
+                .collect(),
+        ),
+    }
+}
+
+/// Sends the query to the first of the servers that answers, with the client subnet to the
+/// servers it is sent to.
+fn query_servers(
+    servers: &[SocketAddr],
+    msg: &Message,
+    context: &DashContext,
+    subnet: Option<&ClientSubnet>,
+    budget: &Budget,
+) -> Result<RawMessage> {
+    let mut last_error =
+        DnsError::new(Rcode::ServFail).with_info("No nameservers to start from".to_string());
+    for server in servers {
+        budget.spend_query()?;
+        let config = context.resolver();
+        let subnet =
+            subnet.filter(|_| ClientSubnet::sent_to(server, &context.config().client_subnet));
+        let options: Vec<_> = subnet
+            .map(|s| (ECS_OPTION, s.option_data()))
+            .into_iter()
+            .collect();
+        let rsp = match query_server_raw(
+            *server,
+            msg,
+            config.query_timeout(),
+            config.randomize_case,
+            &options,
+        ) {
+            Ok(rsp) => rsp,
+            Err(e) => {
+                last_error = e;
+                continue;
+            }
+        };
+        let Some(subnet) = subnet else {
+            return Ok(rsp);
+        };
+        // A response without the option holds for every client
+        match rsp.options.iter().find(|(code, _)| *code == ECS_OPTION) {
+            None => {
+                subnet.record_scope(0);
+                return Ok(rsp);
+            }
+            Some((_, data)) => match subnet.response_scope(data) {
+                Some(scope) => {
+                    subnet.record_scope(scope);
+                    return Ok(rsp);
+                }
+                None => {
+                    last_error = DnsError::new(Rcode::ServFail).with_info(format!(
+                        "{} answered for another client subnet than the one sent",
+                        server
+                    ))
+                }
+            },
+        }
+    }
+    Err(last_error)
+}
+
+/// Whether the answer to msg gets validated: DNSSEC validation is on and the client did not
+/// set the CD bit to do it itself.
+fn validating(msg: &Message, context: &DashContext) -> bool {
+    context.config().dnssec.validation && !msg.cd
+}
 
-    let mut curr_rsp = query_name_server(root_server_ip, ROOT_SERVER_NAME, msg)?;
-    let mut ans_found = false;
+/// The EDNS options of queries recursion sends, with the DO bit asking for signatures when
+/// the answer gets validated.
+fn query_extension(dnssec_ok: bool) -> Extension {
+    Extension {
+        payload_size: EDNS_PAYLOAD_SIZE,
+        dnssec_ok,
+        ..Default::default()
+    }
+}
+
+pub fn recursive_resolution(
+    msg: &Message,
+    context: &DashContext,
+    subnet: Option<&ClientSubnet>,
+    budget: &Budget,
+) -> Result<Message> {
+    let qname = msg
+        .questions
+        .first()
+        .map(|q| q.name.clone())
+        .unwrap_or_default();
+    let validating = validating(msg, context);
+    if validating && context.config().dnssec.aggressive_nsec {
+        if let Some(rsp) = context.nsec_cache().answer(msg) {
+            debug!("{} answered from cached NSEC records", qname);
+            return Ok(rsp);
+        }
+    }
+    let target = normalize_name(&qname);
+    let (mut zone, mut servers) = starting_point(&target, context);
+    let mut minimization = context.resolver().qname_minimization;
+    // The deepest name known to exist on the way to the target
+    let mut known = zone.clone();
+    let mut minimized_queries = 0;
+
+    // The servers are asked to answer from their own data, never to recurse for us
+    let mut query = msg.clone();
+    query.rd = false;
+    query.cd = false;
+    query.extension = Some(query_extension(validating));
+
+    for _ in 0..MAX_REFERRALS + MAX_MINIMIZED_QUERIES {
+        let minimized = (minimization != QnameMinimization::Off
+            && minimized_queries < MAX_MINIMIZED_QUERIES
+            && label_count(&target) > label_count(&known) + 1)
+            .then(|| child_toward(&known, &target));
+        let (asked, sent) = match &minimized {
+            Some(name) => {
+                minimized_queries += 1;
+                let mut sent = Message::default();
+                sent.add_question(name, Type::A, Class::Internet);
+                sent.rd = false;
+                sent.add_extension(query_extension(validating));
+                (name.as_str(), sent)
+            }
+            None => (target.as_str(), query.clone()),
+        };
+        // Minimized queries keep the client's subnet to themselves as well
+        let subnet = subnet.filter(|_| minimized.is_none());
+        let mut rsp = match query_servers(&servers, &sent, context, subnet, budget) {
+            Ok(rsp) => rsp,
+            Err(e) if minimized.is_some() && minimization == QnameMinimization::Relaxed => {
+                debug!(
+                    "Minimized query for {} failed, sending {}: {}",
+                    asked, qname, e
+                );
+                minimization = QnameMinimization::Off;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
 
-    // TODO: make some sort of way to limit max iterations or loops on DNS queries
-    while !ans_found {
-        let (new_ans, new_rsp) = process_dns_response(&curr_rsp)?;
-        ans_found = new_ans;
-        curr_rsp = new_rsp;
+        // rsp came from a server for zone
+        scrub(&mut rsp.message, asked, &zone);
+        cache_zone_nameservers(&rsp.message, context);
+        if let Some(child) = cache_referral(&rsp.message, &zone, asked, context) {
+            servers = match context.delegations().nameservers(&child) {
+                Some((_, addresses)) => addresses,
+                None => glueless_servers(&rsp.message, &child, context, budget)?,
+            };
+            known = child.clone();
+            zone = child;
+            continue;
+        }
+        let answered = dnstools::has_answer(&rsp.message) || is_negative(&rsp.message);
+        if minimized.is_none() {
+            if !answered {
+                return Err(DnsError::new(Rcode::ServFail)
+                    .with_info(format!("No answer or referral for {}", qname)));
+            }
+            return finish_resolution(msg, rsp, validating, context, budget);
+        }
+        match rsp.message.rcode {
+            // The name exists and is in the same zone, the next label is asked of the same
+            // servers
+            Rcode::NoError if answered => known = asked.to_string(),
+            // Nothing exists below a name that does not exist (RFC 8020)
+            Rcode::NXDomain if minimization == QnameMinimization::Strict => {
+                return finish_resolution(msg, rsp, validating, context, budget)
+            }
+            _ if minimization == QnameMinimization::Relaxed => {
+                debug!(
+                    "Servers for {} mishandled a minimized query for {}, sending {}",
+                    zone, asked, qname
+                );
+                minimization = QnameMinimization::Off;
+            }
+            rcode => {
+                return Err(DnsError::new(Rcode::ServFail).with_info(format!(
+                    "Minimized query for {} failed with {}",
+                    asked, rcode
+                )))
+            }
+        }
     }
+    Err(DnsError::new(Rcode::ServFail)
+        .with_info(format!("Too many referrals looking up {}", qname)))
+}
 
-    Ok(curr_rsp)
+/// The response to msg from rsp, the final response of recursion, once validated.
+fn finish_resolution(
+    msg: &Message,
+    raw: RawMessage,
+    validating: bool,
+    context: &DashContext,
+    budget: &Budget,
+) -> Result<Message> {
+    let (qname, qtype) = msg
+        .questions
+        .first()
+        .map(|q| (q.name.as_str(), q.r#type))
+        .unwrap_or(("", Type::A));
+    let mut rsp = raw.message.clone();
+    // A denial of a minimized name stands for the question itself
+    rsp.questions = msg.questions.clone();
+    rsp.ad = false;
+    if validating {
+        match validate(&raw, qname, qtype as u16, context, budget) {
+            Security::Secure => rsp.ad = true,
+            Security::Insecure => {}
+            Security::Bogus(e) => {
+                return Err(DnsError::new(Rcode::ServFail)
+                    .with_info(format!("DNSSEC validation of {} failed", qname))
+                    .with_extended(e))
+            }
+        }
+    }
+    Ok(rsp)
 }
 
-pub fn query_name_server(ip: Ipv4Addr, _name: &str, msg: &Message) -> Result<Message> {
-    const DNS_PORT: &str = "53";
+/// Looks up the records of name and r#type, which need not be a type [`Message`] can
+/// hold, by iterating from the closest known servers. DS records are asked of the servers
+/// of the parent zone, where they live. The response is returned as the authoritative
+/// server gave it, negative or not. The lookup is nested in the one budget is for.
+pub fn lookup_raw(
+    name: &str,
+    r#type: u16,
+    context: &DashContext,
+    budget: &Budget,
+) -> Result<RawMessage> {
+    budget.nested(|| lookup_within(name, r#type, context, budget))
+}
 
-    let sock = match UdpSocket::bind("0.0.0.0:0") {
-        Ok(s) => s,
-        Err(_) => return Err(DnsError::new(Rcode::ServFail)),
+fn lookup_within(
+    name: &str,
+    r#type: u16,
+    context: &DashContext,
+    budget: &Budget,
+) -> Result<RawMessage> {
+    let name = normalize_name(name);
+    let start = match (r#type, name.split_once('.')) {
+        (DS, Some((_, parent))) => parent,
+        (DS, None) => "",
+        _ => name.as_str(),
     };
-    sock.set_nonblocking(true)?;
-    sock.set_read_timeout(Some(Duration::from_secs(5)))?;
-    let server_address = format!("{}:{}", ip, DNS_PORT);
-    if sock.connect(server_address).is_err() {
-        return Err(DnsError::new(Rcode::ServFail)
-            .with_info(format!("UDP Connection Error to Nameserver: {}", ip)));
+    let (mut zone, mut servers) = starting_point(start, context);
+    for _ in 0..MAX_REFERRALS {
+        let mut rsp = query_servers_for(&servers, &name, r#type, context, budget)?;
+        scrub(&mut rsp.message, &name, &zone);
+        cache_zone_nameservers(&rsp.message, context);
+        if rsp.message.aa || !rsp.answers.is_empty() {
+            return Ok(rsp);
+        }
+        match cache_referral(&rsp.message, &zone, &name, context) {
+            // The child's servers have no DS records for their own zone
+            Some(child) if !(r#type == DS && child == name) => {
+                servers = match context.delegations().nameservers(&child) {
+                    Some((_, addresses)) => addresses,
+                    None => glueless_servers(&rsp.message, &child, context, budget)?,
+                };
+                zone = child;
+            }
+            _ => return Ok(rsp),
+        }
+    }
+    Err(DnsError::new(Rcode::ServFail).with_info(format!("Too many referrals looking up {}", name)))
+}
+
+/// Sends a query for name and r#type to the first of the servers that answers.
+fn query_servers_for(
+    servers: &[SocketAddr],
+    name: &str,
+    r#type: u16,
+    context: &DashContext,
+    budget: &Budget,
+) -> Result<RawMessage> {
+    let mut last_error =
+        DnsError::new(Rcode::ServFail).with_info("No nameservers to start from".to_string());
+    let config = context.resolver();
+    let name = match config.randomize_case {
+        true => dnstools::randomize_case(name),
+        false => name.to_string(),
+    };
+    for server in servers {
+        budget.spend_query()?;
+        let query = encode_query(dnstools::random_id(), &name, r#type, true)?;
+        match exchange(
+            *server,
+            &query,
+            config.query_timeout(),
+            config.randomize_case,
+        ) {
+            Ok(rsp) => return Ok(rsp),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// The addresses of the nameservers rsp delegates child to without glue, looked up on their
+/// own. The nameservers are tried in turn, for A and then AAAA records, until one has
+/// addresses. Those named inside child are skipped: without glue, looking them up only
+/// leads back to the same referral.
+fn glueless_servers(
+    rsp: &Message,
+    child: &str,
+    context: &DashContext,
+    budget: &Budget,
+) -> Result<Vec<SocketAddr>> {
+    let mut last_error = DnsError::new(Rcode::ServFail)
+        .with_info(format!("Referral to {} without nameservers", child));
+    let targets = rsp.authoritys.iter().filter_map(|r| match &r.resource {
+        NS(target) if normalize_name(&r.name) == child => Some(target),
+        _ => None,
+    });
+    for target in targets {
+        if is_subdomain(&normalize_name(target), child) {
+            last_error = DnsError::new(Rcode::ServFail).with_info(format!(
+                "Nameserver {} of {} is inside it and has no glue",
+                target, child
+            ));
+            continue;
+        }
+        for r#type in [Type::A, Type::AAAA] {
+            let mut query = Message::default();
+            query.add_question(target, r#type, Class::Internet);
+            let answer = match budget.nested(|| dispatch_within(&query, context, None, budget)) {
+                Ok(answer) => answer,
+                Err(e) => {
+                    last_error = e;
+                    continue;
+                }
+            };
+            let servers: Vec<SocketAddr> = answer
+                .answers
+                .iter()
+                .filter_map(|r| match r.resource {
+                    A(a) => Some(SocketAddr::new(a.into(), DNS_PORT)),
+                    AAAA(aaaa) => Some(SocketAddr::new(aaaa.into(), DNS_PORT)),
+                    _ => None,
+                })
+                .collect();
+            if !servers.is_empty() {
+                return Ok(servers);
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Addresses from the additional section of rsp for the nameservers in targets, with the
+/// smallest of their TTLs and ttl.
+fn glue_addresses(
+    rsp: &Message,
+    targets: &[String],
+    mut ttl: Duration,
+) -> (Vec<SocketAddr>, Duration) {
+    let mut servers = Vec::new();
+    for record in &rsp.additionals {
+        if !targets.contains(&normalize_name(&record.name)) {
+            continue;
+        }
+        let ip: IpAddr = match &record.resource {
+            A(a) => (*a).into(),
+            AAAA(aaaa) => (*aaaa).into(),
+            _ => continue,
+        };
+        servers.push(SocketAddr::new(ip, DNS_PORT));
+        ttl = ttl.min(record.ttl);
+    }
+    (servers, ttl)
+}
+
+/// Caches the delegation in rsp if it is a referral from zone towards qname, and returns the
+/// zone it delegates. Only referrals to a zone strictly below the one queried are accepted,
+/// so a server cannot point the cache at names it has no authority over. Delegations without
+/// glue are followed but not cached, as their server addresses come from another lookup.
+fn cache_referral(rsp: &Message, zone: &str, qname: &str, context: &DashContext) -> Option<String> {
+    if dnstools::has_answer(rsp) {
+        return None;
+    }
+    let qname = normalize_name(qname);
+    let mut child = None;
+    let mut targets = Vec::new();
+    let mut ttl = Duration::MAX;
+    for record in &rsp.authoritys {
+        let owner = normalize_name(&record.name);
+        let target = match &record.resource {
+            NS(target) => normalize_name(target),
+            _ => continue,
+        };
+        if owner == zone || !is_subdomain(&owner, zone) || !is_subdomain(&qname, &owner) {
+            continue;
+        }
+        match &child {
+            None => child = Some(owner),
+            Some(c) if *c != owner => continue,
+            Some(_) => {}
+        }
+        targets.push(target);
+        ttl = ttl.min(record.ttl);
+    }
+    let child = child?;
+
+    let (servers, ttl) = glue_addresses(rsp, &targets, ttl);
+    if !servers.is_empty() {
+        debug!("Caching delegation of {} to {:?}", child, servers);
+        context
+            .delegations()
+            .insert(&child, targets, servers, ttl, Credibility::Referral);
+    }
+    Some(child)
+}
+
+/// Caches the NS records an authoritative response gives for its own zone, which outrank
+/// the copy in the parent's referral.
+fn cache_zone_nameservers(rsp: &Message, context: &DashContext) {
+    if !rsp.aa {
+        return;
+    }
+    for (section, credibility) in [
+        (&rsp.answers, Credibility::AuthoritativeAnswer),
+        (&rsp.authoritys, Credibility::AuthoritativeAuthority),
+    ] {
+        let mut zone = None;
+        let mut targets = Vec::new();
+        let mut ttl = Duration::MAX;
+        for record in section {
+            let NS(target) = &record.resource else {
+                continue;
+            };
+            let owner = normalize_name(&record.name);
+            match &zone {
+                None => zone = Some(owner),
+                Some(z) if *z != owner => continue,
+                Some(_) => {}
+            }
+            targets.push(normalize_name(target));
+            ttl = ttl.min(record.ttl);
+        }
+        let Some(zone) = zone else {
+            continue;
+        };
+        let (servers, ttl) = glue_addresses(rsp, &targets, ttl);
+        if !servers.is_empty() {
+            debug!("Caching nameservers of {} from its own servers", zone);
+            context
+                .delegations()
+                .insert(&zone, targets, servers, ttl, credibility);
+        }
+        return;
+    }
+}
+
+/// Hands the whole query to a server of the pool, leaving the recursion to it. Servers are
+/// tried in the pool's order until one gives a usable answer, which is scrubbed for the
+/// forward zone the pool serves, the root for the default forwarders.
+pub fn forward_query(
+    msg: &Message,
+    zone: &str,
+    pool: &UpstreamPool,
+    context: &DashContext,
+) -> Result<Message> {
+    let timeout = context.resolver().query_timeout();
+    let mut last_error =
+        DnsError::new(Rcode::ServFail).with_info("No forwarders are configured".to_string());
+    for upstream in pool.candidates() {
+        let start = Instant::now();
+        match query_server(
+            upstream.address(),
+            msg,
+            timeout,
+            context.resolver().randomize_case,
+        ) {
+            // The server is fine, it just could not answer this one. Another might.
+            Ok(rsp) if matches!(rsp.rcode, Rcode::ServFail | Rcode::Refused) => {
+                upstream.record_success(start.elapsed());
+                last_error = DnsError::new(rsp.rcode).with_info(format!(
+                    "Forwarder {} answered {}",
+                    upstream.address(),
+                    rsp.rcode
+                ));
+            }
+            Ok(mut rsp) => {
+                upstream.record_success(start.elapsed());
+                // Dash has not validated the answer itself
+                rsp.ad = false;
+                if let Some(question) = msg.questions.first() {
+                    scrub(&mut rsp, &question.name, zone);
+                }
+                return Ok(rsp);
+            }
+            Err(e) => {
+                upstream.record_failure(timeout);
+                last_error = e;
+            }
+        }
     }
+    Err(last_error)
+}
+
+pub fn query_name_server(
+    ip: Ipv4Addr,
+    _name: &str,
+    msg: &Message,
+    config: &ResolverConfig,
+) -> Result<Message> {
+    query_server(
+        SocketAddr::new(ip.into(), DNS_PORT),
+        msg,
+        config.query_timeout(),
+        config.randomize_case,
+    )
+}
+
+/// Why packet, received from from, is not the response to the query sent to server, if it
+/// is not. Anyone can send a packet to the query's port, only the real response comes from
+/// the server and repeats the random ID and the question.
+fn check_response(
+    packet: &[u8],
+    from: SocketAddr,
+    server: SocketAddr,
+    sent: &RawQuestion,
+    match_case: bool,
+) -> std::result::Result<(), String> {
+    if from != server {
+        return Err(format!("it came from {} instead of {}", from, server));
+    }
+    let received = raw_question(packet).ok_or("it has no readable question")?;
+    if !received.is_response {
+        return Err("it is not a response".to_string());
+    }
+    if received.id != sent.id {
+        return Err(format!("its ID {} is not {}", received.id, sent.id));
+    }
+    if received.question_count != 1 || !received.same_question(sent, match_case) {
+        return Err("its question is not the one asked".to_string());
+    }
+    Ok(())
+}
 
-    let nameserver_query = match msg.to_vec() {
+/// Sends msg to server_address under a random ID, with the query name in random case if
+/// randomize_case is set, and waits up to timeout for the response. Packets that fail
+/// [`check_response`] are discarded and the wait goes on. The source port is the random
+/// one the operating system picks for a new socket.
+pub fn query_server(
+    server_address: SocketAddr,
+    msg: &Message,
+    timeout: Duration,
+    randomize_case: bool,
+) -> Result<Message> {
+    query_server_raw(server_address, msg, timeout, randomize_case, &[]).map(|rsp| rsp.message)
+}
+
+/// Like [`query_server`], keeping the records of the response in wire format too, and
+/// sending the EDNS options as (code, data) pairs.
+fn query_server_raw(
+    server_address: SocketAddr,
+    msg: &Message,
+    timeout: Duration,
+    randomize_case: bool,
+    options: &[(u16, Vec<u8>)],
+) -> Result<RawMessage> {
+    let mut query = msg.clone();
+    query.id = dnstools::random_id();
+    if randomize_case {
+        for question in &mut query.questions {
+            question.name = dnstools::randomize_case(&question.name);
+        }
+    }
+    let nameserver_query = match encode_with_options(&query, options) {
         Ok(q) => q,
         Err(e) => {
             return Err(DnsError::new(Rcode::ServFail)
                 .with_info(format!("Error serializing nameserver query: {}", e)))
         }
     };
+    exchange(server_address, &nameserver_query, timeout, randomize_case)
+}
+
+/// Sends the encoded query to server_address and waits up to timeout for its response.
+fn exchange(
+    server_address: SocketAddr,
+    nameserver_query: &[u8],
+    timeout: Duration,
+    randomize_case: bool,
+) -> Result<RawMessage> {
+    let bind_address = if server_address.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let sock = match UdpSocket::bind(bind_address) {
+        Ok(s) => s,
+        Err(_) => return Err(DnsError::new(Rcode::ServFail)),
+    };
+    sock.set_nonblocking(true)?;
+
+    let sent = raw_question(nameserver_query).ok_or_else(|| {
+        DnsError::new(Rcode::ServFail).with_info("Nameserver query has no question".to_string())
+    })?;
 
     // Note from RFC 1035 2.3.4
     // UDP messages    512 octets or less
@@ -111,111 +858,58 @@ pub fn query_name_server(ip: Ipv4Addr, _name: &str, msg: &Message) -> Result<Mes
         )));
     }
 
-    sock.send(&nameserver_query)?;
+    if sock.send_to(nameserver_query, server_address).is_err() {
+        return Err(DnsError::new(Rcode::ServFail).with_info(format!(
+            "UDP Connection Error to Nameserver: {}",
+            server_address
+        )));
+    }
 
     let mut resp = [0; EDNS_RECCOMENDED_OCTETS];
-    let resp_length;
     // Use non blocking socket to prevent OS error 35 EAGIN issues with recv from UDP socket.
     // Sleep for 20ms if data not received. Avgerage DNS query latency = 20ms
-    let mut retry_count = 500; // Give a max of 52 for message to be received, just like Linux
-                               // Kernel
+    const RETRY_INTERVAL_MS: u64 = 20;
+    let deadline = Instant::now() + timeout;
     loop {
-        resp_length = match sock.recv(&mut resp) {
-            Ok(s) => s,
+        match sock.recv_from(&mut resp) {
+            Ok((length, from)) => {
+                let packet = &resp[0..length];
+                match check_response(packet, from, server_address, &sent, randomize_case) {
+                    Ok(()) => return decode(packet),
+                    Err(reason) => warn!(
+                        "Discarded a packet on the query to {} for {}: {}",
+                        server_address,
+                        sent.name(),
+                        reason
+                    ),
+                }
+            }
             Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
-                if retry_count == 0 {
+                if Instant::now() >= deadline {
                     return Err(
                         DnsError::new(Rcode::ServFail).with_info("Request timed out".to_string())
                     );
                 }
-                std::thread::sleep(Duration::from_millis(20));
-                retry_count -= 1;
-                continue;
+                std::thread::sleep(Duration::from_millis(RETRY_INTERVAL_MS));
             }
             Err(e) => {
                 return Err(DnsError::new(Rcode::ServFail)
                     .with_info(format!("Issue at sock.recv in query_name_server: {}", e)))
             }
-        };
-        break;
+        }
     }
-
-    let resp_msg = Message::from_slice(&resp[0..resp_length])?;
-
-    // print_query_response(&resp_msg, ip, Some(name), true);
-
-    Ok(resp_msg)
 }
 
-/// Processes a DNS query response and then sends the corresponding request to the next nameserver.
-/// Returns the response from the query to the next namesever.
-/// Checks to ensure that rsp is truly a DNS response, and conforms to other formatting concerns.
-/// If rsp contains an answer, then the output boolean is set to true
-fn process_dns_response(rsp: &Message) -> Result<(bool, Message)> {
-    // Base case where response is an answer
-    if rustdns::QR::Response != rsp.qr {
-        return Err(DnsError::new(Rcode::FormErr));
+/// Whether rsp is a negative answer rather than a referral: NXDOMAIN, or NODATA from an
+/// authoritative server or with the SOA record of the zone (RFC 2308 2.2).
+fn is_negative(rsp: &Message) -> bool {
+    if !rsp.answers.is_empty() {
+        return false;
     }
-
-    if dnstools::has_answer(rsp) {
-        Ok((true, rsp.clone()))
-    } else if let Some(glue) = dnstools::get_glue(rsp) {
-        let mut new_msg = Message::default();
-
-        // TODO check the class and ttl for caching and thoroughness and check for only A records
-        let glue_record = glue.first().unwrap();
-        let next_nameserver_ip = match &glue_record.resource {
-            A(a) => a,
-            _ => panic!("Couldn't find valid glue record"),
-        };
-
-        // Note that from 4.1.2 of RFC 1035 there really should only be one question due to
-        // ambiguities in rcode handling.
-        new_msg.questions = rsp.questions.clone();
-        new_msg.add_extension(Extension {
-            payload_size: 4096,
-            ..Default::default()
-        });
-
-        Ok((
-            false,
-            query_name_server(*next_nameserver_ip, glue_record.name.as_str(), &new_msg)?,
-        ))
-    } else if let Some(authoritys) = dnstools::get_authoritys(rsp) {
-        let mut new_msg = Message::default();
-        let authority_record = authoritys.first().unwrap();
-        let authority_name = match &authority_record.resource {
-            NS(ns) => ns,
-            _ => panic!("Couldn't find valid authority ns to redirect to"),
-        };
-
-        new_msg.add_question(authority_name.as_str(), Type::A, Class::Internet);
-        new_msg.add_extension(Extension {
-            payload_size: 4096,
-            ..Default::default()
-        });
-
-        //println!("Start of new lookup for authority {}", authority_name);
-        let authority_server_answer = resolve_message_query(&new_msg)?;
-        let (authority_server_name, authority_server_ip) =
-            dnstools::parse_answer_a(&authority_server_answer)?;
-        //println!("End of new lookup for authority {}", authority_name);
-
-        // Now that we have the authority server IP, we can repeat the lookup for DNS at the same
-        // authority level.
-        new_msg = Message::default();
-        new_msg.questions = rsp.questions.clone();
-        new_msg.add_extension(Extension {
-            payload_size: 4096,
-            ..Default::default()
-        });
-
-        Ok((
-            false,
-            query_name_server(authority_server_ip, authority_server_name, &new_msg)?,
-        ))
-    } else {
-        Err(DnsError::new(Rcode::NXDomain)
-            .with_info("In resolve_message_query couldn't find next steps".to_string()))
+    if rsp.rcode == Rcode::NXDomain {
+        return true;
     }
+    let referral = rsp.authoritys.iter().any(|r| matches!(r.resource, NS(_)));
+    let soa = rsp.authoritys.iter().any(|r| matches!(r.resource, SOA(_)));
+    rsp.rcode == Rcode::NoError && (soa || (rsp.aa && !referral))
 }
@@ -0,0 +1,429 @@
+//! Response Policy Zones.
+//!
+//! Policy zones are zone files whose owner names encode triggers and whose records encode
+//! actions. A QNAME trigger is the name itself relative to the policy zone, response IP
+//! triggers live under rpz-ip, nameserver name and address triggers under rpz-nsdname and
+//! rpz-nsip. A CNAME to ".", "*.", rpz-passthru., rpz-drop. or rpz-tcp-only. selects the
+//! NXDOMAIN, NODATA, PASSTHRU, DROP or TCP-only action, any other records are answered as
+//! local data.
+//!
+//! Zones are consulted in the order they are configured and the first zone with a match
+//! decides. Within a zone QNAME triggers come first, then response IPs, nameserver names
+//! and nameserver addresses.
+
+use crate::cidr::Cidr;
+use crate::config::{normalize_name, ZoneConfig};
+use crate::configerror::{ConfigError, ConfigErrorReason, Result};
+use crate::dashcontext::DashContext;
+use crate::domaintrie::DomainTrie;
+use crate::upstream::is_subdomain;
+use crate::zonefile::parse_zone_file;
+use log::{info, warn};
+use rustdns::{Message, Record, Resource, Type};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyAction {
+    Nxdomain,
+    Nodata,
+    /// Answer normally, no lower priority policy applies.
+    Passthru,
+    /// Send nothing back.
+    Drop,
+    /// Answer over UDP with only the TC bit, so the client has to retry over TCP.
+    TcpOnly,
+    /// Answer with a CNAME to another name, which is resolved as usual.
+    Cname(Record),
+    /// Answer with these records, renamed to the query name.
+    LocalData(Vec<Record>),
+}
+
+impl fmt::Display for PolicyAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyAction::Nxdomain => write!(f, "NXDOMAIN"),
+            PolicyAction::Nodata => write!(f, "NODATA"),
+            PolicyAction::Passthru => write!(f, "PASSTHRU"),
+            PolicyAction::Drop => write!(f, "DROP"),
+            PolicyAction::TcpOnly => write!(f, "TCP-only"),
+            PolicyAction::Cname(record) => match &record.resource {
+                Resource::CNAME(target) => write!(f, "CNAME {}", target),
+                _ => write!(f, "CNAME"),
+            },
+            PolicyAction::LocalData(records) => write!(f, "{} local records", records.len()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Qname,
+    Ip,
+    NsDname,
+    NsIp,
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Qname => write!(f, "QNAME"),
+            Trigger::Ip => write!(f, "IP"),
+            Trigger::NsDname => write!(f, "NSDNAME"),
+            Trigger::NsIp => write!(f, "NSIP"),
+        }
+    }
+}
+
+/// A matched policy: which zone, by which trigger, and what to do.
+#[derive(Debug, Clone)]
+pub struct PolicyHit {
+    pub zone_index: usize,
+    pub zone: String,
+    pub trigger: Trigger,
+    pub action: PolicyAction,
+}
+
+/// Hits of one policy zone.
+#[derive(Debug, Clone)]
+pub struct PolicyZoneStats {
+    pub zone: String,
+    pub rules: usize,
+    pub hits: u64,
+}
+
+/// Exact names and wildcards, where *.example.com matches every name below example.com
+/// but not example.com itself.
+#[derive(Debug, Default)]
+struct NameTriggers {
+    exact: HashMap<String, PolicyAction>,
+    wildcards: DomainTrie<PolicyAction>,
+}
+
+impl NameTriggers {
+    fn insert(&mut self, name: &str, action: PolicyAction) {
+        match name.strip_prefix("*.") {
+            Some(base) => {
+                self.wildcards.insert(base, action);
+            }
+            None if name == "*" => {
+                self.wildcards.insert("", action);
+            }
+            None => {
+                self.exact.insert(name.to_string(), action);
+            }
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<&PolicyAction> {
+        let name = normalize_name(name);
+        if let Some(action) = self.exact.get(&name) {
+            return Some(action);
+        }
+        if name.is_empty() {
+            return None;
+        }
+        let parent = name.split_once('.').map_or("", |(_, p)| p);
+        self.wildcards.longest_match(parent).map(|(_, a)| a)
+    }
+
+    fn len(&self) -> usize {
+        self.exact.len() + self.wildcards.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Default)]
+struct AddressTriggers(Vec<(Cidr, PolicyAction)>);
+
+impl AddressTriggers {
+    /// The most specific prefix containing ip.
+    fn find(&self, ip: IpAddr) -> Option<&PolicyAction> {
+        self.0
+            .iter()
+            .filter(|(cidr, _)| cidr.contains(ip))
+            .max_by_key(|(cidr, _)| cidr.prefix_len())
+            .map(|(_, action)| action)
+    }
+}
+
+#[derive(Debug)]
+struct PolicyZone {
+    name: String,
+    qname: NameTriggers,
+    ip: AddressTriggers,
+    nsdname: NameTriggers,
+    nsip: AddressTriggers,
+    hits: AtomicU64,
+}
+
+/// Decodes the address prefix of an rpz-ip or rpz-nsip owner, written as the prefix
+/// length followed by the address labels in reverse, with zz standing for :: in IPv6.
+pub fn parse_trigger_prefix(labels: &str) -> Option<Cidr> {
+    let mut parts: Vec<&str> = labels.split('.').collect();
+    let prefix_len = parts.remove(0).parse::<u8>().ok()?;
+    parts.reverse();
+    let address = if parts.len() == 4 && parts.iter().all(|p| p.parse::<u8>().is_ok()) {
+        parts.join(".")
+    } else {
+        let groups: Vec<&str> = parts
+            .iter()
+            .map(|p| if *p == "zz" { "" } else { p })
+            .collect();
+        let mut address = groups.join(":");
+        if address.starts_with(':') {
+            address.insert(0, ':');
+        }
+        if address.ends_with(':') {
+            address.push(':');
+        }
+        address
+    };
+    Cidr::new(address.parse().ok()?, prefix_len)
+}
+
+fn action_of(records: Vec<Record>) -> PolicyAction {
+    let cname = records.iter().find_map(|r| match &r.resource {
+        Resource::CNAME(target) => Some((normalize_name(target), r.clone())),
+        _ => None,
+    });
+    match cname {
+        Some((target, _)) if target.is_empty() => PolicyAction::Nxdomain,
+        Some((target, _)) if target == "*" => PolicyAction::Nodata,
+        Some((target, _)) if target == "rpz-passthru" => PolicyAction::Passthru,
+        Some((target, _)) if target == "rpz-drop" => PolicyAction::Drop,
+        Some((target, _)) if target == "rpz-tcp-only" => PolicyAction::TcpOnly,
+        Some((_, record)) => PolicyAction::Cname(record),
+        None => PolicyAction::LocalData(records),
+    }
+}
+
+impl PolicyZone {
+    fn load(config: &ZoneConfig) -> Result<PolicyZone> {
+        let name = normalize_name(&config.name);
+        let records = parse_zone_file(&config.file, &name).map_err(|e| {
+            ConfigError::new(ConfigErrorReason::InvalidValue(format!(
+                "loading policy zone {}: {}",
+                name, e
+            )))
+        })?;
+
+        let mut owners: HashMap<String, Vec<Record>> = HashMap::new();
+        for record in records {
+            let owner = normalize_name(&record.name);
+            if !is_subdomain(&owner, &name) {
+                warn!("Ignoring {} outside of policy zone {}", owner, name);
+                continue;
+            }
+            owners.entry(owner).or_default().push(record);
+        }
+
+        let mut zone = PolicyZone {
+            name: name.clone(),
+            qname: NameTriggers::default(),
+            ip: AddressTriggers::default(),
+            nsdname: NameTriggers::default(),
+            nsip: AddressTriggers::default(),
+            hits: AtomicU64::new(0),
+        };
+        for (owner, records) in owners {
+            // The apex only holds the SOA and NS records every zone needs
+            if owner == name {
+                continue;
+            }
+            let relative = if name.is_empty() {
+                owner.as_str()
+            } else {
+                &owner[..owner.len() - name.len() - 1]
+            };
+            let action = action_of(records);
+            if let Some(prefix) = relative.strip_suffix(".rpz-ip") {
+                match parse_trigger_prefix(prefix) {
+                    Some(cidr) => zone.ip.0.push((cidr, action)),
+                    None => warn!("Ignoring malformed rpz-ip trigger {}", owner),
+                }
+            } else if let Some(prefix) = relative.strip_suffix(".rpz-nsip") {
+                match parse_trigger_prefix(prefix) {
+                    Some(cidr) => zone.nsip.0.push((cidr, action)),
+                    None => warn!("Ignoring malformed rpz-nsip trigger {}", owner),
+                }
+            } else if let Some(nsdname) = relative.strip_suffix(".rpz-nsdname") {
+                zone.nsdname.insert(nsdname, action);
+            } else if relative.ends_with(".rpz-client-ip") {
+                warn!("Ignoring unsupported rpz-client-ip trigger {}", owner);
+            } else {
+                zone.qname.insert(relative, action);
+            }
+        }
+        info!(
+            "Loaded policy zone {} with {} rules",
+            zone.name,
+            zone.rules()
+        );
+        Ok(zone)
+    }
+
+    fn rules(&self) -> usize {
+        self.qname.len() + self.ip.0.len() + self.nsdname.len() + self.nsip.0.len()
+    }
+
+    fn has_response_triggers(&self) -> bool {
+        !self.ip.0.is_empty() || !self.nsdname.is_empty() || !self.nsip.0.is_empty()
+    }
+}
+
+/// Nameserver names and addresses behind a response: the NS records and glue it carries,
+/// and the delegation recursion found for the name.
+fn nameservers_of(qname: &str, rsp: &Message, context: &DashContext) -> (Vec<String>, Vec<IpAddr>) {
+    let mut names: Vec<String> = Vec::new();
+    let mut addresses: Vec<IpAddr> = Vec::new();
+    for record in &rsp.authoritys {
+        if let Resource::NS(ns) = &record.resource {
+            names.push(normalize_name(ns));
+        }
+    }
+    for record in &rsp.additionals {
+        let is_nameserver = names.contains(&normalize_name(&record.name));
+        match &record.resource {
+            Resource::A(a) if is_nameserver => addresses.push((*a).into()),
+            Resource::AAAA(a) if is_nameserver => addresses.push((*a).into()),
+            _ => (),
+        }
+    }
+    if let Some((delegated, servers)) = context.delegations().nameservers(qname) {
+        names.extend(delegated);
+        addresses.extend(servers.iter().map(|s| s.ip()));
+    }
+    (names, addresses)
+}
+
+/// Every configured policy zone, highest priority first.
+#[derive(Debug, Default)]
+pub struct PolicyZones {
+    zones: Vec<PolicyZone>,
+}
+
+impl PolicyZones {
+    pub fn load(configs: &[ZoneConfig]) -> Result<Self> {
+        let zones = configs
+            .iter()
+            .map(PolicyZone::load)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(PolicyZones { zones })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    fn hit(&self, zone_index: usize, trigger: Trigger, action: &PolicyAction) -> PolicyHit {
+        PolicyHit {
+            zone_index,
+            zone: self.zones[zone_index].name.clone(),
+            trigger,
+            action: action.clone(),
+        }
+    }
+
+    /// The first zone with a QNAME trigger for qname.
+    pub fn qname_hit(&self, qname: &str) -> Option<PolicyHit> {
+        self.zones.iter().enumerate().find_map(|(i, zone)| {
+            zone.qname
+                .find(qname)
+                .map(|action| self.hit(i, Trigger::Qname, action))
+        })
+    }
+
+    /// True if a zone ahead of zone_index could still match once the response is known, so
+    /// a QNAME hit in zone_index cannot be applied before resolving.
+    pub fn has_response_triggers_before(&self, zone_index: usize) -> bool {
+        self.zones[..zone_index]
+            .iter()
+            .any(PolicyZone::has_response_triggers)
+    }
+
+    /// The first zone ahead of zone_limit whose response IP, nameserver name or nameserver
+    /// address triggers match the response to qname.
+    pub fn response_hit(
+        &self,
+        qname: &str,
+        rsp: &Message,
+        context: &DashContext,
+        zone_limit: usize,
+    ) -> Option<PolicyHit> {
+        let zones = &self.zones[..zone_limit.min(self.zones.len())];
+        if !zones.iter().any(PolicyZone::has_response_triggers) {
+            return None;
+        }
+        let answer_addresses: Vec<IpAddr> = rsp
+            .answers
+            .iter()
+            .filter_map(|r| match &r.resource {
+                Resource::A(a) => Some((*a).into()),
+                Resource::AAAA(a) => Some((*a).into()),
+                _ => None,
+            })
+            .collect();
+        let (ns_names, ns_addresses) = nameservers_of(qname, rsp, context);
+
+        zones.iter().enumerate().find_map(|(i, zone)| {
+            if let Some(action) = answer_addresses.iter().find_map(|ip| zone.ip.find(*ip)) {
+                return Some(self.hit(i, Trigger::Ip, action));
+            }
+            if let Some(action) = ns_names.iter().find_map(|n| zone.nsdname.find(n)) {
+                return Some(self.hit(i, Trigger::NsDname, action));
+            }
+            ns_addresses
+                .iter()
+                .find_map(|ip| zone.nsip.find(*ip))
+                .map(|action| self.hit(i, Trigger::NsIp, action))
+        })
+    }
+
+    /// Counts a hit that was acted upon.
+    pub fn record_hit(&self, hit: &PolicyHit) {
+        self.zones[hit.zone_index]
+            .hits
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> Vec<PolicyZoneStats> {
+        self.zones
+            .iter()
+            .map(|z| PolicyZoneStats {
+                zone: z.name.clone(),
+                rules: z.rules(),
+                hits: z.hits.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// The records of a local data action for a query of qtype, renamed to qname. A CNAME
+/// among them answers every type.
+pub fn local_data_answer(records: &[Record], qname: &str, qtype: Type) -> Vec<Record> {
+    let cname: Vec<&Record> = records
+        .iter()
+        .filter(|r| r.resource.r#type() == Type::CNAME)
+        .collect();
+    let matching: Vec<&Record> = if !cname.is_empty() {
+        cname
+    } else {
+        records
+            .iter()
+            .filter(|r| qtype == Type::ANY || r.resource.r#type() == qtype)
+            .collect()
+    };
+    matching
+        .into_iter()
+        .map(|r| Record {
+            name: qname.to_string(),
+            ..r.clone()
+        })
+        .collect()
+}
@@ -0,0 +1,186 @@
+use crate::cidr::Cidr;
+use crate::config::ResponseRateLimitConfig;
+use log::info;
+use rustdns::{Message, Rcode};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Buckets untouched for this long are full again and can be forgotten.
+const IDLE_BUCKET: Duration = Duration::from_secs(60);
+
+/// The kinds of response counted apart from each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResponseKind {
+    Answer,
+    Nodata,
+    Nxdomain,
+    Error,
+}
+
+impl ResponseKind {
+    pub fn of(rsp: &Message) -> ResponseKind {
+        match rsp.rcode {
+            Rcode::NoError if rsp.answers.is_empty() => ResponseKind::Nodata,
+            Rcode::NoError => ResponseKind::Answer,
+            Rcode::NXDomain => ResponseKind::Nxdomain,
+            _ => ResponseKind::Error,
+        }
+    }
+}
+
+/// What to do with a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RrlVerdict {
+    Send,
+    /// Send it truncated, with no records and the TC bit set.
+    Slip,
+    Drop,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // Limited responses since the bucket ran empty, for slipping every slip-th one
+    limited: u64,
+}
+
+/// Responses limited since the limiter was created.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RrlStats {
+    pub slipped: u64,
+    pub dropped: u64,
+}
+
+/// Token buckets per client network and kind of response.
+#[derive(Debug)]
+pub struct ResponseRateLimiter {
+    config: ResponseRateLimitConfig,
+    buckets: Mutex<HashMap<(Cidr, ResponseKind), Bucket>>,
+    slipped: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl ResponseRateLimiter {
+    pub fn new(config: &ResponseRateLimitConfig) -> Self {
+        ResponseRateLimiter {
+            config: config.clone(),
+            buckets: Mutex::new(HashMap::new()),
+            slipped: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn rate(&self, kind: ResponseKind) -> u32 {
+        let config = &self.config;
+        let rate = match kind {
+            ResponseKind::Answer => None,
+            ResponseKind::Nodata => config.nodata_per_second,
+            ResponseKind::Nxdomain => config.nxdomains_per_second,
+            ResponseKind::Error => config.errors_per_second,
+        };
+        rate.unwrap_or(config.responses_per_second)
+    }
+
+    /// Takes a token for sending rsp to client and decides what happens to it.
+    pub fn check(&self, client: IpAddr, rsp: &Message) -> RrlVerdict {
+        if self.config.responses_per_second == 0 {
+            return RrlVerdict::Send;
+        }
+        let kind = ResponseKind::of(rsp);
+        let rate = self.rate(kind);
+        if rate == 0 {
+            return RrlVerdict::Send;
+        }
+        let client = match client {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(client, IpAddr::V4),
+            v4 => v4,
+        };
+        let prefix_len = if client.is_ipv4() {
+            self.config.ipv4_prefix_len
+        } else {
+            self.config.ipv6_prefix_len
+        };
+        let network = Cidr::new(client, prefix_len).expect("validated prefix length");
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if buckets.len() >= self.config.max_table_size && !buckets.contains_key(&(network, kind)) {
+            buckets.retain(|_, b| now.duration_since(b.updated) < IDLE_BUCKET);
+            if buckets.len() >= self.config.max_table_size {
+                // Better to stop limiting new networks than to grow without bound
+                return RrlVerdict::Send;
+            }
+        }
+        let bucket = buckets.entry((network, kind)).or_insert(Bucket {
+            tokens: rate as f64,
+            updated: now,
+            limited: 0,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            if bucket.limited > 0 {
+                info!(
+                    "Stopped limiting {:?} responses to {} after {} responses",
+                    kind, network, bucket.limited
+                );
+                bucket.limited = 0;
+            }
+            return RrlVerdict::Send;
+        }
+
+        bucket.limited += 1;
+        if bucket.limited == 1 {
+            info!(
+                "{} {:?} responses to {}, over {} per second",
+                if self.config.log_only {
+                    "Would limit"
+                } else {
+                    "Limiting"
+                },
+                kind,
+                network,
+                rate
+            );
+        }
+        if self.config.log_only {
+            return RrlVerdict::Send;
+        }
+        let slip = self.config.slip as u64;
+        if slip > 0 && bucket.limited.is_multiple_of(slip) {
+            self.slipped.fetch_add(1, Ordering::Relaxed);
+            RrlVerdict::Slip
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            RrlVerdict::Drop
+        }
+    }
+
+    pub fn stats(&self) -> RrlStats {
+        RrlStats {
+            slipped: self.slipped.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Applies a verdict to rsp. None when it is not to be sent at all.
+pub fn apply_verdict(verdict: RrlVerdict, mut rsp: Message) -> Option<Message> {
+    match verdict {
+        RrlVerdict::Send => Some(rsp),
+        RrlVerdict::Slip => {
+            rsp.tc = true;
+            rsp.answers.clear();
+            rsp.authoritys.clear();
+            rsp.additionals.clear();
+            Some(rsp)
+        }
+        RrlVerdict::Drop => None,
+    }
+}
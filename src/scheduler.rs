@@ -0,0 +1,348 @@
+use crate::threadpool::{JobPriority, ThreadPoolJob};
+use crossbeam_deque::{Injector, Steal, Stealer, Worker as LocalDeque};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Job = dyn ThreadPoolJob + Send + 'static;
+
+pub(crate) struct QueuedJob {
+    pub(crate) job: Box<Job>,
+    pub(crate) priority: JobPriority,
+    pub(crate) enqueued: Instant,
+}
+
+/// Per worker thread scheduling state. Lives in a thread local so that jobs submitted from
+/// inside a running job go to the submitting worker's own deques without any locking.
+struct LocalQueue {
+    scheduler_id: usize,
+    // One deque per priority class, so sub-jobs are picked between like any other job
+    deques: [LocalDeque<QueuedJob>; JobPriority::COUNT],
+    // Smooth weighted round robin credit per priority class, see nginx's upstream balancer
+    credits: [isize; JobPriority::COUNT],
+}
+
+thread_local! {
+    static LOCAL: RefCell<Option<LocalQueue>> = const { RefCell::new(None) };
+}
+
+static NEXT_SCHEDULER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Work stealing scheduler shared by every worker of a pool.
+///
+/// Jobs submitted from outside the pool go to a global injector per priority class. Jobs
+/// submitted by a running job go to the current worker's local deque for their class. An
+/// idle worker picks a class with work by weighted fair dequeueing (serving any class that
+/// has not been served within the starvation threshold first), then takes a job of that class
+/// from its own deque, the class's injector, or by stealing from other workers' deques, in
+/// that order. Nothing holds a lock while waiting for work.
+pub(crate) struct Scheduler {
+    id: usize,
+    injectors: [Injector<QueuedJob>; JobPriority::COUNT],
+    stealers: RwLock<Vec<(usize, [Stealer<QueuedJob>; JobPriority::COUNT])>>,
+    weights: [AtomicUsize; JobPriority::COUNT],
+    starvation_threshold_ms: AtomicU64,
+    // Nanoseconds since epoch at which each class was last served, used for starvation checks
+    last_served: [AtomicU64; JobPriority::COUNT],
+    epoch: Instant,
+    queued: AtomicUsize,
+    // Jobs of each class in workers' local deques, so finding the classes with work does not
+    // need to look at every deque
+    locally_queued: [AtomicUsize; JobPriority::COUNT],
+    sleepers: AtomicUsize,
+    sleep_lock: Mutex<()>,
+    wakeup: Condvar,
+}
+
+impl Scheduler {
+    pub(crate) fn new() -> Self {
+        const DEFAULT_STARVATION_THRESHOLD_MS: u64 = 500;
+
+        Scheduler {
+            id: NEXT_SCHEDULER_ID.fetch_add(1, Ordering::SeqCst),
+            injectors: Default::default(),
+            stealers: RwLock::new(Vec::new()),
+            weights: JobPriority::ALL.map(|p| AtomicUsize::new(p.default_weight())),
+            starvation_threshold_ms: AtomicU64::new(DEFAULT_STARVATION_THRESHOLD_MS),
+            last_served: Default::default(),
+            epoch: Instant::now(),
+            queued: AtomicUsize::new(0),
+            locally_queued: Default::default(),
+            sleepers: AtomicUsize::new(0),
+            sleep_lock: Mutex::new(()),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    fn now_nanos(&self) -> u64 {
+        self.epoch.elapsed().as_nanos() as u64
+    }
+
+    /// Gives the calling thread its local deques. Must be called by a worker thread before it
+    /// starts taking jobs.
+    pub(crate) fn register_worker(&self, worker_id: usize) {
+        let deques: [LocalDeque<QueuedJob>; JobPriority::COUNT] =
+            std::array::from_fn(|_| LocalDeque::new_fifo());
+        self.stealers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((worker_id, deques.each_ref().map(|d| d.stealer())));
+        LOCAL.with(|local| {
+            *local.borrow_mut() = Some(LocalQueue {
+                scheduler_id: self.id,
+                deques,
+                credits: [0; JobPriority::COUNT],
+            })
+        });
+    }
+
+    /// Removes the calling worker's deques, moving any jobs left in them back to the injectors
+    /// so they are not lost when a worker stops or dies.
+    pub(crate) fn unregister_worker(&self, worker_id: usize) {
+        self.stealers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(id, _)| *id != worker_id);
+        let local = LOCAL.with(|local| local.borrow_mut().take());
+        if let Some(local) = local.filter(|l| l.scheduler_id == self.id) {
+            for (class, deque) in local.deques.iter().enumerate() {
+                while let Some(queued) = deque.pop() {
+                    self.locally_queued[class].fetch_sub(1, Ordering::SeqCst);
+                    self.injectors[class].push(queued);
+                }
+            }
+        }
+        self.wake_all();
+    }
+
+    pub(crate) fn push(&self, job: Box<Job>, priority: JobPriority) {
+        let queued = QueuedJob {
+            job,
+            priority,
+            enqueued: Instant::now(),
+        };
+        self.queued.fetch_add(1, Ordering::SeqCst);
+
+        let class = priority.index();
+        if !self.waiting_classes(None)[class] {
+            // The class was idle, start its starvation clock now rather than at the last time
+            // it happened to be served
+            self.last_served[class].store(self.now_nanos(), Ordering::SeqCst);
+        }
+        let queued = LOCAL.with(|local| match local.borrow().as_ref() {
+            Some(l) if l.scheduler_id == self.id => {
+                self.locally_queued[class].fetch_add(1, Ordering::SeqCst);
+                l.deques[class].push(queued);
+                None
+            }
+            _ => Some(queued),
+        });
+        if let Some(queued) = queued {
+            self.injectors[class].push(queued);
+        }
+
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _guard = self
+                .sleep_lock
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            self.wakeup.notify_one();
+        }
+    }
+
+    fn wake_all(&self) {
+        let _guard = self
+            .sleep_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.wakeup.notify_all();
+    }
+
+    fn has_work(&self) -> bool {
+        self.queued.load(Ordering::SeqCst) > 0
+    }
+
+    /// Waits at most timeout for a job, so callers can periodically check for shutdown.
+    pub(crate) fn pop_timeout(&self, timeout: Duration) -> Option<QueuedJob> {
+        // Jobs tend to arrive in bursts, briefly yielding before going to sleep avoids paying
+        // for a condvar wakeup on every submission
+        const SPIN_ROUNDS: usize = 16;
+        for _ in 0..SPIN_ROUNDS {
+            if let Some(queued) = self.try_pop() {
+                return Some(queued);
+            }
+            thread::yield_now();
+        }
+
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        {
+            let guard = self
+                .sleep_lock
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if !self.has_work() {
+                drop(
+                    self.wakeup
+                        .wait_timeout(guard, timeout)
+                        .unwrap_or_else(PoisonError::into_inner),
+                );
+            }
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+
+        self.try_pop()
+    }
+
+    /// Finds a job without blocking. Works from any thread, threads without a local deque
+    /// simply skip straight to the injectors.
+    pub(crate) fn try_pop(&self) -> Option<QueuedJob> {
+        let found = LOCAL.with(|local| {
+            let mut local = local.borrow_mut();
+            let local = local.as_mut().filter(|l| l.scheduler_id == self.id);
+            match local {
+                Some(l) => self.pop_weighted(Some(&l.deques), &mut l.credits),
+                None => {
+                    let mut credits = [0; JobPriority::COUNT];
+                    self.pop_weighted(None, &mut credits)
+                }
+            }
+        });
+
+        if found.is_some() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+        found
+    }
+
+    /// Which classes have jobs waiting anywhere: in the calling worker's deques, the
+    /// injectors or other workers' deques.
+    fn waiting_classes(
+        &self,
+        deques: Option<&[LocalDeque<QueuedJob>]>,
+    ) -> [bool; JobPriority::COUNT] {
+        std::array::from_fn(|i| {
+            deques.is_some_and(|d| !d[i].is_empty())
+                || !self.injectors[i].is_empty()
+                || self.locally_queued[i].load(Ordering::SeqCst) > 0
+        })
+    }
+
+    fn pop_weighted(
+        &self,
+        deques: Option<&[LocalDeque<QueuedJob>]>,
+        credits: &mut [isize; JobPriority::COUNT],
+    ) -> Option<QueuedJob> {
+        let now = self.now_nanos();
+        let threshold = Duration::from_millis(self.starvation_threshold_ms.load(Ordering::SeqCst))
+            .as_nanos() as u64;
+
+        let mut waiting = self.waiting_classes(deques);
+
+        // Starvation protection, the class that has gone longest without service goes first
+        let starved = (0..JobPriority::COUNT)
+            .filter(|&i| waiting[i])
+            .map(|i| (i, self.last_served[i].load(Ordering::SeqCst)))
+            .filter(|(_, served)| now.saturating_sub(*served) >= threshold)
+            .min_by_key(|(_, served)| *served)
+            .map(|(i, _)| i);
+        if let Some(i) = starved {
+            if let Some(queued) = self.take(i, deques) {
+                return Some(queued);
+            }
+        }
+
+        // Weighted fair dequeueing between the classes that have work
+        loop {
+            let mut total = 0;
+            let mut best: Option<usize> = None;
+            for i in (0..JobPriority::COUNT).filter(|&i| waiting[i]) {
+                let weight = self.weights[i].load(Ordering::SeqCst).max(1) as isize;
+                credits[i] += weight;
+                total += weight;
+                if best.is_none_or(|b| credits[i] > credits[b]) {
+                    best = Some(i);
+                }
+            }
+
+            let best = best?;
+            credits[best] -= total;
+            if let Some(queued) = self.take(best, deques) {
+                return Some(queued);
+            }
+            // Other workers emptied that class in the meantime, pick again
+            waiting[best] = false;
+        }
+    }
+
+    /// A job of class from the calling worker's own deque, the injector, or another worker.
+    fn take(&self, class: usize, deques: Option<&[LocalDeque<QueuedJob>]>) -> Option<QueuedJob> {
+        let found = match deques.and_then(|d| d[class].pop()) {
+            Some(queued) => {
+                self.locally_queued[class].fetch_sub(1, Ordering::SeqCst);
+                Some(queued)
+            }
+            None => self.steal_injector(class).or_else(|| self.steal(class)),
+        };
+        if found.is_some() {
+            self.last_served[class].store(self.now_nanos(), Ordering::SeqCst);
+        }
+        found
+    }
+
+    fn steal_injector(&self, class: usize) -> Option<QueuedJob> {
+        loop {
+            match self.injectors[class].steal() {
+                Steal::Success(queued) => return Some(queued),
+                Steal::Empty => return None,
+                Steal::Retry => continue,
+            }
+        }
+    }
+
+    fn steal(&self, class: usize) -> Option<QueuedJob> {
+        if self.locally_queued[class].load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+        let stealers = self.stealers.read().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let mut retry = false;
+            for (_, stealers) in stealers.iter() {
+                match stealers[class].steal() {
+                    Steal::Success(queued) => {
+                        self.locally_queued[class].fetch_sub(1, Ordering::SeqCst);
+                        return Some(queued);
+                    }
+                    Steal::Empty => (),
+                    Steal::Retry => retry = true,
+                }
+            }
+            if !retry {
+                return None;
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Jobs waiting in each priority class's injector, not counting local deques.
+    pub(crate) fn len_by_priority(&self) -> HashMap<JobPriority, usize> {
+        JobPriority::ALL
+            .iter()
+            .map(|p| (*p, self.injectors[p.index()].len()))
+            .collect()
+    }
+
+    pub(crate) fn set_weight(&self, priority: JobPriority, weight: usize) {
+        self.weights[priority.index()].store(weight, Ordering::SeqCst);
+    }
+
+    pub(crate) fn set_starvation_threshold(&self, threshold: Duration) {
+        self.starvation_threshold_ms
+            .store(threshold.as_millis() as u64, Ordering::SeqCst);
+    }
+}
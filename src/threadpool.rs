@@ -1,86 +1,302 @@
+use crate::jobhandle::{HandleJob, Helper, JobHandle, HELP_POLL_INTERVAL_MS};
+use crate::scheduler::QueuedJob;
+use crate::scheduler::Scheduler;
 use crate::threadpoolerror::{Result, ThreadPoolError, ThreadPoolErrorReason};
+use crate::threadpoolstats::{PoolCounters, PoolStats, WorkerCounters};
+use log::warn;
+use std::any::Any;
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
 type Job = dyn ThreadPoolJob + Send + 'static;
 
+const MAX_POOL_SIZE: usize = 20480; // Configuration for my Mac found via sysctl kern.num_threads
+
+/// Called with the id of the worker and the panic message whenever a job panics.
+pub type PanicHook = dyn Fn(usize, &str) + Send + Sync + 'static;
+
+/// Worker id reported for jobs run by a thread helping out while it waits on a
+/// [`JobHandle`] or in [`ThreadPool::scope`].
+pub const SCOPE_HELPER_ID: usize = usize::MAX;
+
 pub trait ThreadPoolJob {
     fn run_job(&self);
 }
 
+/// Scheduling class of a submitted job. Workers pick between classes with weighted fair
+/// dequeueing, and any job that has waited longer than the starvation threshold is served
+/// next regardless of its class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JobPriority {
+    /// Queries that can be answered straight from the cache
+    CacheHit,
+    /// Client queries that need recursive resolution
+    ClientRecursion,
+    /// Prefetches and other maintenance work no client is waiting on
+    Background,
+}
+
+impl JobPriority {
+    pub const COUNT: usize = 3;
+    pub const ALL: [JobPriority; JobPriority::COUNT] = [
+        JobPriority::CacheHit,
+        JobPriority::ClientRecursion,
+        JobPriority::Background,
+    ];
+
+    pub(crate) fn index(self) -> usize {
+        match self {
+            JobPriority::CacheHit => 0,
+            JobPriority::ClientRecursion => 1,
+            JobPriority::Background => 2,
+        }
+    }
+
+    /// Share of dequeues each class gets while every class has work waiting.
+    pub fn default_weight(self) -> usize {
+        match self {
+            JobPriority::CacheHit => 8,
+            JobPriority::ClientRecursion => 4,
+            JobPriority::Background => 1,
+        }
+    }
+}
+
+/// A unit of work that produces a value, submitted with [`ThreadPool::submit`].
+/// Implemented for every closure returning a value, so most callers never implement it by hand.
+pub trait ThreadPoolTask {
+    type Output;
+
+    fn run_task(self) -> Self::Output;
+}
+
+impl<F, T> ThreadPoolTask for F
+where
+    F: FnOnce() -> T,
+{
+    type Output = T;
+
+    fn run_task(self) -> T {
+        self()
+    }
+}
+
 struct Statistics {
     number_of_jobs_serviced: Option<usize>,
+    counters: Arc<WorkerCounters>,
+}
+
+/// Locks a mutex, recovering the guard if another thread panicked while holding it.
+/// None of the state guarded inside the pool is left inconsistent by a panic, so it is
+/// always safe to keep using it.
+fn lock_recover<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Job panicked with a non string payload".to_string()
+    }
+}
+
+/// State shared between every worker and the pool itself, used to track and report panics.
+struct PanicState {
+    panic_count: AtomicUsize,
+    respawn_count: AtomicUsize,
+    hook: RwLock<Option<Box<PanicHook>>>,
+}
+
+impl PanicState {
+    fn record(&self, id: usize, payload: &(dyn Any + Send)) {
+        let message = panic_message(payload);
+        self.panic_count.fetch_add(1, Ordering::SeqCst);
+        warn!("Worker {} recovered from a panicking job: {}", id, message);
+
+        let hook = self.hook.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(h) = hook.as_ref() {
+            // A panicking hook must not take the worker down with it
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| h(id, &message)));
+        }
+    }
+}
+
+/// Counters and limits every thread that runs pool jobs reports to.
+#[derive(Clone)]
+struct JobAccounting {
+    panic_state: Arc<PanicState>,
+    counters: Arc<PoolCounters>,
+    max_exec_time: Duration,
+}
+
+impl JobAccounting {
+    /// Runs a dequeued job, isolating any panic and recording how long it queued and ran.
+    fn execute(&self, id: usize, queued: QueuedJob) {
+        let started = Instant::now();
+        self.counters
+            .record_queue_latency(queued.priority, started.duration_since(queued.enqueued));
+
+        let job = queued.job;
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| job.run_job())) {
+            self.panic_state.record(id, payload.as_ref());
+        }
+        self.counters
+            .record_job_finished(started.elapsed(), self.max_exec_time);
+    }
+}
+
+/// Runs a single queued job on the calling thread if one is immediately available.
+fn run_queued_job(scheduler: &Scheduler, accounting: &JobAccounting) -> bool {
+    match scheduler.try_pop() {
+        Some(queued) => {
+            accounting.execute(SCOPE_HELPER_ID, queued);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Everything a worker thread needs, kept together so that a replacement thread can be
+/// spawned with the same state if the original one dies.
+#[derive(Clone)]
+struct WorkerContext {
+    id: usize,
+    scheduler: Arc<Scheduler>,
+    statistics: Arc<Mutex<HashMap<usize, Statistics>>>,
+    counters: Arc<WorkerCounters>,
+    stop_execution: Arc<AtomicBool>,
+    accounting: JobAccounting,
+    thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+}
+
+/// Lives on the stack of a worker thread. Hands the worker's local jobs back to the scheduler
+/// when the thread exits. If the thread unwinds past the job loop, the sentinel is dropped
+/// while panicking and spawns a replacement worker with the same id.
+struct Sentinel {
+    context: Option<WorkerContext>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if let Some(context) = self.context.take() {
+            context.scheduler.unregister_worker(context.id);
+            if thread::panicking() && !context.stop_execution.load(Ordering::SeqCst) {
+                warn!("Worker {} died, respawning", context.id);
+                context
+                    .accounting
+                    .panic_state
+                    .respawn_count
+                    .fetch_add(1, Ordering::SeqCst);
+                Worker::spawn(context);
+            }
+        }
+    }
 }
 
 #[allow(dead_code)]
 struct Worker {
     id: usize,
-    thread: thread::JoinHandle<()>,
+    thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
     stop_execution: Arc<AtomicBool>,
 }
 
 impl Worker {
     fn new(
         id: usize,
-        rx: Arc<Mutex<mpsc::Receiver<Box<Job>>>>,
+        scheduler: Arc<Scheduler>,
         statistics: Arc<Mutex<HashMap<usize, Statistics>>>,
-        _max_exec_time: Duration,
+        accounting: JobAccounting,
     ) -> Worker {
+        let counters = Arc::new(WorkerCounters::new());
+        lock_recover(&statistics).insert(
+            id,
+            Statistics {
+                number_of_jobs_serviced: None,
+                counters: Arc::clone(&counters),
+            },
+        );
+
+        let context = WorkerContext {
+            id,
+            scheduler,
+            statistics,
+            counters,
+            stop_execution: Arc::new(AtomicBool::new(false)),
+            accounting,
+            thread: Arc::new(Mutex::new(None)),
+        };
+        let worker = Worker {
+            id,
+            thread: Arc::clone(&context.thread),
+            stop_execution: Arc::clone(&context.stop_execution),
+        };
+
+        Worker::spawn(context);
+        worker
+    }
+
+    fn spawn(context: WorkerContext) {
+        let handle_slot = Arc::clone(&context.thread);
+        let handle = thread::spawn(move || Worker::run(context));
+        *lock_recover(&handle_slot) = Some(handle);
+    }
+
+    fn run(context: WorkerContext) {
         const COUNT_RESET_TIME_SECS: u64 = 10;
-        let atomic_bool = Arc::new(AtomicBool::new(false));
-        let cloned_bool = Arc::clone(&atomic_bool);
-
-        let thread = thread::spawn(move || {
-            let mut job_count = 0;
-            let mut start = SystemTime::now();
-
-            while !cloned_bool.load(Ordering::SeqCst) {
-                let job = match rx.lock().unwrap().recv() {
-                    Ok(j) => j,
-                    Err(_) => {
-                        if !cloned_bool.load(Ordering::SeqCst) {
-                            return;
-                        } else {
-                            continue;
-                        }
-                    }
-                };
-                println!("Worker {} received a job", id);
-
-                if let Ok(t) = start.elapsed() {
-                    if t.as_secs() > COUNT_RESET_TIME_SECS {
-                        let mut statistics_info = statistics.lock().unwrap();
-                        statistics_info
-                            .get_mut(&id)
-                            .unwrap()
-                            .number_of_jobs_serviced = Some(job_count);
-                        job_count = 0;
-                        start = SystemTime::now();
-                    }
-                }
+        const STOP_POLL_INTERVAL_MS: u64 = 100;
+        let id = context.id;
+        let scheduler = Arc::clone(&context.scheduler);
+        scheduler.register_worker(id);
+        let statistics = Arc::clone(&context.statistics);
+        let stop_execution = Arc::clone(&context.stop_execution);
+        let counters = Arc::clone(&context.counters);
+        let accounting = context.accounting.clone();
+        let _sentinel = Sentinel {
+            context: Some(context),
+        };
+
+        let mut job_count = 0;
+        let mut start = SystemTime::now();
 
-                job_count += 1;
-                /*
-                if let Err(_) = rt.block_on(tokio::time::timeout(
-                    max_exec_time,
-                    tokio::task::spawn_blocking(move || job.run_job()),
-                )){
-                    // Todo do something else here
-                    println!("Job did not finish executing within time limit");
+        while !stop_execution.load(Ordering::SeqCst) {
+            // Wake up periodically so a stopped worker exits even if no more jobs arrive
+            let queued = match scheduler.pop_timeout(Duration::from_millis(STOP_POLL_INTERVAL_MS)) {
+                Some(queued) => queued,
+                None => continue,
+            };
+
+            if let Ok(t) = start.elapsed() {
+                if t.as_secs() > COUNT_RESET_TIME_SECS {
+                    let mut statistics_info = lock_recover(&statistics);
+                    if let Some(s) = statistics_info.get_mut(&id) {
+                        s.number_of_jobs_serviced = Some(job_count);
+                    }
+                    job_count = 0;
+                    start = SystemTime::now();
                 }
-                */
-                job.run_job();
             }
-        });
 
-        Worker {
-            id,
-            thread,
-            stop_execution: atomic_bool,
+            job_count += 1;
+            /*
+            if let Err(_) = rt.block_on(tokio::time::timeout(
+                max_exec_time,
+                tokio::task::spawn_blocking(move || job.run_job()),
+            )){
+                // Todo do something else here
+                println!("Job did not finish executing within time limit");
+            }
+            */
+            counters.job_started();
+            accounting.execute(id, queued);
+            counters.job_finished();
         }
     }
 }
@@ -89,11 +305,10 @@ pub struct ThreadPool {
     // Periodically reset statistics to 0, used to check for dynamic resizing of resources.
     worker_statistics: Arc<Mutex<HashMap<usize, Statistics>>>,
     workers: Vec<Worker>,
-    send_queue: mpsc::Sender<Box<Job>>,
+    scheduler: Arc<Scheduler>,
     min_pool_size: usize,
     max_pool_size: usize,
-    max_exec_time: Duration,
-    rx_queue: Arc<Mutex<mpsc::Receiver<Box<Job>>>>,
+    accounting: JobAccounting,
     next_id: usize,
 }
 
@@ -104,8 +319,6 @@ impl ThreadPool {
         max_pool_size: usize,
         max_exec_time: Duration,
     ) -> Result<ThreadPool> {
-        const MAX_POOL_SIZE: usize = 20480; // Configuration for my Mac found via sysctl kern.num_threads
-
         if pool_size > MAX_POOL_SIZE {
             return Err(ThreadPoolError::new(ThreadPoolErrorReason::InvalidPoolSize));
         } else if max_pool_size > MAX_POOL_SIZE {
@@ -114,45 +327,176 @@ impl ThreadPool {
             ));
         }
 
-        let (tx, rx) = mpsc::channel(); // Tokio MPSC
-        let rx_arc = Arc::new(Mutex::new(rx));
+        let scheduler = Arc::new(Scheduler::new());
 
         let mut workers = Vec::with_capacity(pool_size);
-        let mut worker_statistics = HashMap::new();
-
-        for id in 0..pool_size {
-            worker_statistics.insert(
-                id,
-                Statistics {
-                    number_of_jobs_serviced: None,
-                },
-            );
-        }
-
-        let worker_statistics_arc = Arc::new(Mutex::new(worker_statistics));
+        let worker_statistics_arc = Arc::new(Mutex::new(HashMap::new()));
+        let accounting = JobAccounting {
+            panic_state: Arc::new(PanicState {
+                panic_count: AtomicUsize::new(0),
+                respawn_count: AtomicUsize::new(0),
+                hook: RwLock::new(None),
+            }),
+            counters: Arc::new(PoolCounters::new()),
+            max_exec_time,
+        };
         for id in 0..pool_size {
             workers.push(Worker::new(
                 id,
-                Arc::clone(&rx_arc),
+                Arc::clone(&scheduler),
                 Arc::clone(&worker_statistics_arc),
-                max_exec_time,
+                accounting.clone(),
             ));
         }
 
         Ok(ThreadPool {
             worker_statistics: worker_statistics_arc,
             workers,
-            send_queue: tx,
+            scheduler,
             min_pool_size,
             max_pool_size,
-            max_exec_time,
-            rx_queue: rx_arc,
+            accounting,
             next_id: pool_size,
         })
     }
 
+    /// Submits a job with [`JobPriority::ClientRecursion`] priority.
     pub fn submit_job(&self, job: Box<Job>) {
-        self.send_queue.send(job).unwrap();
+        self.submit_job_with_priority(job, JobPriority::ClientRecursion);
+    }
+
+    pub fn submit_job_with_priority(&self, job: Box<Job>, priority: JobPriority) {
+        self.scheduler.push(job, priority);
+    }
+
+    /// Number of jobs waiting for a worker across every priority class.
+    pub fn queue_depth(&self) -> usize {
+        self.scheduler.len()
+    }
+
+    /// Sets the relative share of dequeues the given class gets when several classes have
+    /// work waiting. A weight of 0 is treated as 1 so no class is ever shut out entirely.
+    pub fn set_priority_weight(&self, priority: JobPriority, weight: usize) {
+        self.scheduler.set_weight(priority, weight);
+    }
+
+    /// Jobs that have been queued for longer than threshold are served before any other job,
+    /// oldest first, whatever their priority class.
+    pub fn set_starvation_threshold(&self, threshold: Duration) {
+        self.scheduler.set_starvation_threshold(threshold);
+    }
+
+    /// Submits a task that produces a value and returns a handle to wait for it.
+    pub fn submit<J>(&self, task: J) -> JobHandle<J::Output>
+    where
+        J: ThreadPoolTask + Send + 'static,
+        J::Output: Send + 'static,
+    {
+        self.submit_with_priority(task, JobPriority::ClientRecursion)
+    }
+
+    pub fn submit_with_priority<J>(&self, task: J, priority: JobPriority) -> JobHandle<J::Output>
+    where
+        J: ThreadPoolTask + Send + 'static,
+        J::Output: Send + 'static,
+    {
+        let (job, handle) = HandleJob::new(move || task.run_task(), self.helper());
+        self.submit_job_with_priority(Box::new(job), priority);
+        handle
+    }
+
+    fn helper(&self) -> Helper {
+        let scheduler = Arc::clone(&self.scheduler);
+        let accounting = self.accounting.clone();
+        Arc::new(move || run_queued_job(&scheduler, &accounting))
+    }
+
+    /// Runs f with a [`Scope`] that can submit jobs borrowing from the calling stack frame.
+    /// Does not return until every job submitted through the scope has finished. While waiting
+    /// the calling thread runs queued jobs itself, so calling scope from inside a worker
+    /// cannot deadlock the pool.
+    ///
+    /// Nothing in Dash calls this or [`ThreadPool::submit`] yet: the resolver has no handle
+    /// to the pool, and looks up the nameservers of a referral without glue one at a time.
+    pub fn scope<'env, F, R>(&self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        let scope = Scope {
+            scheduler: Arc::clone(&self.scheduler),
+            helper: self.helper(),
+            pending: Arc::new(ScopePending {
+                count: Mutex::new(0),
+                done: Condvar::new(),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+
+        // Wait for the scope's jobs even if f panics, they may still borrow from its frame
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.wait_all();
+        match result {
+            Ok(r) => r,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    /// Total number of jobs that have panicked since the pool was created. Panicking jobs are
+    /// isolated from the worker running them, so this does not imply any worker was lost.
+    pub fn panic_count(&self) -> usize {
+        self.accounting
+            .panic_state
+            .panic_count
+            .load(Ordering::SeqCst)
+    }
+
+    /// Number of worker threads that died and were replaced with a fresh thread.
+    pub fn respawn_count(&self) -> usize {
+        self.accounting
+            .panic_state
+            .respawn_count
+            .load(Ordering::SeqCst)
+    }
+
+    /// Registers a hook that is called on the worker thread with the worker id and panic
+    /// message every time a job panics. Replaces any previously registered hook.
+    pub fn set_panic_hook<F>(&self, hook: F)
+    where
+        F: Fn(usize, &str) + Send + Sync + 'static,
+    {
+        *self
+            .accounting
+            .panic_state
+            .hook
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Box::new(hook));
+    }
+
+    /// Snapshot of the pool's current state and counters since it was created.
+    pub fn stats(&self) -> PoolStats {
+        let mut workers: Vec<_> = lock_recover(&self.worker_statistics)
+            .iter()
+            .map(|(id, s)| s.counters.snapshot(*id, s.number_of_jobs_serviced))
+            .collect();
+        workers.sort_by_key(|w| w.id);
+
+        let counters = &self.accounting.counters;
+        let panic_state = &self.accounting.panic_state;
+        PoolStats {
+            pool_size: self.workers.len(),
+            min_pool_size: self.min_pool_size,
+            max_pool_size: self.max_pool_size,
+            workers,
+            queue_depth: self.scheduler.len(),
+            queue_depth_by_priority: self.scheduler.len_by_priority(),
+            queue_latency: counters.queue_latency(),
+            jobs_completed: counters.jobs_completed(),
+            jobs_panicked: panic_state.panic_count.load(Ordering::SeqCst) as u64,
+            jobs_timed_out: counters.jobs_timed_out(),
+            workers_respawned: panic_state.respawn_count.load(Ordering::SeqCst) as u64,
+            resize_history: counters.resize_history(),
+        }
     }
 
     fn get_next_id(&mut self) -> usize {
@@ -173,7 +517,7 @@ impl ThreadPool {
         jobs_per_duration_upper_bound: usize,
     ) -> Result<i32> {
         let mut reallocation: i32 = 0;
-        let mut statistics = self.worker_statistics.lock().unwrap();
+        let mut statistics = lock_recover(&self.worker_statistics);
         for (_, s) in statistics.iter_mut() {
             if let Some(count) = s.number_of_jobs_serviced {
                 if count < jobs_per_duration_lower_bound {
@@ -184,6 +528,8 @@ impl ThreadPool {
             }
         }
 
+        drop(statistics);
+
         let new_pool_size = reallocation + (self.workers.len() as i32);
         if new_pool_size < (self.min_pool_size as i32) {
             reallocation = (self.min_pool_size as i32) - (self.workers.len() as i32);
@@ -191,43 +537,158 @@ impl ThreadPool {
             reallocation = (self.max_pool_size as i32) - (self.workers.len() as i32);
         }
 
+        self.resize_by(reallocation)?;
+        Ok(reallocation)
+    }
+
+    /// Changes the bounds used by dynamic resizing, immediately growing or shrinking the pool
+    /// if its current size falls outside them. Returns the change in pool size.
+    pub fn set_pool_bounds(&mut self, min_pool_size: usize, max_pool_size: usize) -> Result<i32> {
+        if min_pool_size > max_pool_size || max_pool_size > MAX_POOL_SIZE {
+            return Err(ThreadPoolError::new(
+                ThreadPoolErrorReason::InvalidDynamicPoolBounds,
+            ));
+        }
+        self.min_pool_size = min_pool_size;
+        self.max_pool_size = max_pool_size;
+
+        let pool_size = self.workers.len();
+        let reallocation = pool_size.clamp(min_pool_size, max_pool_size) as i32 - pool_size as i32;
+        self.resize_by(reallocation)?;
+        Ok(reallocation)
+    }
+
+    fn resize_by(&mut self, reallocation: i32) -> Result<()> {
+        let old_pool_size = self.workers.len();
         match reallocation.cmp(&0) {
             std::cmp::Ordering::Less => {
                 let stop_execution_count = -reallocation as usize;
-                for i in 0..stop_execution_count {
-                    let w = self.workers.get(i);
-                    match w {
-                        Some(worker) => worker.stop_execution.store(true, Ordering::SeqCst),
-                        None => {
-                            return Err(ThreadPoolError::new(
-                                ThreadPoolErrorReason::DynamicResizingError,
-                            ))
-                        }
-                    }
+                if stop_execution_count > self.workers.len() {
+                    return Err(ThreadPoolError::new(
+                        ThreadPoolErrorReason::DynamicResizingError,
+                    ));
+                }
+                let mut statistics = lock_recover(&self.worker_statistics);
+                // Stopped workers finish their current job and hand any local jobs back
+                for worker in self.workers.drain(0..stop_execution_count) {
+                    worker.stop_execution.store(true, Ordering::SeqCst);
+                    statistics.remove(&worker.id);
                 }
             }
             std::cmp::Ordering::Greater => {
-                drop(statistics);
                 for _ in 0..(reallocation as usize) {
                     let new_id = self.get_next_id();
-                    self.worker_statistics.lock().unwrap().insert(
-                        new_id,
-                        Statistics {
-                            number_of_jobs_serviced: None,
-                        },
-                    );
-
                     self.workers.push(Worker::new(
                         new_id,
-                        Arc::clone(&self.rx_queue),
+                        Arc::clone(&self.scheduler),
                         Arc::clone(&self.worker_statistics),
-                        self.max_exec_time,
+                        self.accounting.clone(),
                     ));
                 }
             }
             _ => (),
         }
 
-        Ok(reallocation)
+        if reallocation != 0 {
+            self.accounting
+                .counters
+                .record_resize(old_pool_size, self.workers.len());
+        }
+        Ok(())
+    }
+}
+
+struct ScopePending {
+    count: Mutex<usize>,
+    done: Condvar,
+}
+
+/// Job submitted through a [`Scope`]. Dropping it, whether after running or because it was
+/// never run, marks it as no longer pending in its scope.
+struct ScopedJob<'scope> {
+    job: Option<Box<dyn ThreadPoolJob + Send + 'scope>>,
+    pending: Arc<ScopePending>,
+}
+
+impl ThreadPoolJob for ScopedJob<'_> {
+    fn run_job(&self) {
+        if let Some(job) = &self.job {
+            job.run_job();
+        }
+    }
+}
+
+impl Drop for ScopedJob<'_> {
+    fn drop(&mut self) {
+        // Drop the borrowed job before signalling the scope that waits on it
+        self.job.take();
+        let mut count = lock_recover(&self.pending.count);
+        *count -= 1;
+        if *count == 0 {
+            self.pending.done.notify_all();
+        }
+    }
+}
+
+/// Submits jobs that may borrow data living for 'env. Created by [`ThreadPool::scope`].
+pub struct Scope<'scope, 'env: 'scope> {
+    scheduler: Arc<Scheduler>,
+    helper: Helper,
+    pending: Arc<ScopePending>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Submits a task to the pool and returns a handle to its output. The task is guaranteed
+    /// to have finished by the time the enclosing [`ThreadPool::scope`] call returns.
+    pub fn submit<J>(&'scope self, task: J) -> JobHandle<J::Output>
+    where
+        J: ThreadPoolTask + Send + 'scope,
+        J::Output: Send + 'scope,
+    {
+        self.submit_with_priority(task, JobPriority::ClientRecursion)
+    }
+
+    pub fn submit_with_priority<J>(
+        &'scope self,
+        task: J,
+        priority: JobPriority,
+    ) -> JobHandle<J::Output>
+    where
+        J: ThreadPoolTask + Send + 'scope,
+        J::Output: Send + 'scope,
+    {
+        let (job, handle) = HandleJob::new(move || task.run_task(), Arc::clone(&self.helper));
+        *lock_recover(&self.pending.count) += 1;
+        let scoped: Box<dyn ThreadPoolJob + Send + 'scope> = Box::new(ScopedJob {
+            job: Some(Box::new(job)),
+            pending: Arc::clone(&self.pending),
+        });
+
+        // SAFETY: ThreadPool::scope does not return until every ScopedJob has been dropped,
+        // so nothing borrowed for 'scope is used after 'scope ends.
+        let scoped: Box<Job> = unsafe {
+            std::mem::transmute::<Box<dyn ThreadPoolJob + Send + 'scope>, Box<Job>>(scoped)
+        };
+        self.scheduler.push(scoped, priority);
+        handle
+    }
+
+    fn wait_all(&self) {
+        let mut count = lock_recover(&self.pending.count);
+        while *count > 0 {
+            drop(count);
+            let helped = (self.helper)();
+            count = lock_recover(&self.pending.count);
+            if !helped && *count > 0 {
+                count = self
+                    .pending
+                    .done
+                    .wait_timeout(count, Duration::from_millis(HELP_POLL_INTERVAL_MS))
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+            }
+        }
     }
 }
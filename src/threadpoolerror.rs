@@ -6,6 +6,9 @@ pub enum ThreadPoolErrorReason {
     InvalidPoolSize,
     InvalidDynamicPoolBounds,
     DynamicResizingError,
+    JobPanicked(String),
+    JobCancelled,
+    JobTimedOut,
 }
 
 impl std::fmt::Display for ThreadPoolErrorReason {
@@ -17,6 +20,9 @@ impl std::fmt::Display for ThreadPoolErrorReason {
                 write!(f, "InvalidDynamicPoolBounds")
             }
             ThreadPoolErrorReason::DynamicResizingError => write!(f, "DynamicResizingError"),
+            ThreadPoolErrorReason::JobPanicked(s) => write!(f, "JobPanicked: {}", s),
+            ThreadPoolErrorReason::JobCancelled => write!(f, "JobCancelled"),
+            ThreadPoolErrorReason::JobTimedOut => write!(f, "JobTimedOut"),
         }
     }
 }
@@ -30,6 +36,10 @@ impl ThreadPoolError {
     pub fn new(reason: ThreadPoolErrorReason) -> ThreadPoolError {
         ThreadPoolError { reason }
     }
+
+    pub fn reason(&self) -> &ThreadPoolErrorReason {
+        &self.reason
+    }
 }
 
 impl std::fmt::Display for ThreadPoolError {
@@ -0,0 +1,244 @@
+use crate::threadpool::JobPriority;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Upper bounds of the enqueue-to-start latency buckets. Anything slower than the last bound
+/// lands in a final overflow bucket.
+const LATENCY_BUCKET_BOUNDS_MICROS: [u64; 14] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000,
+];
+const LATENCY_BUCKET_COUNT: usize = LATENCY_BUCKET_BOUNDS_MICROS.len() + 1;
+
+/// Number of resize events kept in the history, older ones are dropped.
+const RESIZE_HISTORY_LENGTH: usize = 128;
+
+/// Point in time snapshot of a [`crate::threadpool::ThreadPool`], see
+/// [`crate::threadpool::ThreadPool::stats`].
+#[derive(Debug, Clone)]
+pub struct PoolStats {
+    pub pool_size: usize,
+    pub min_pool_size: usize,
+    pub max_pool_size: usize,
+    /// One entry per live worker, ordered by worker id.
+    pub workers: Vec<WorkerStats>,
+    /// Jobs waiting to start, including sub-jobs sitting in workers' local deques.
+    pub queue_depth: usize,
+    /// Jobs waiting in the global queue of each priority class.
+    pub queue_depth_by_priority: HashMap<JobPriority, usize>,
+    /// Time from submission until a thread started running the job, per priority class.
+    pub queue_latency: HashMap<JobPriority, LatencyHistogram>,
+    /// Jobs run to completion (or panic) since the pool was created, by workers or by threads
+    /// helping while they wait on a job handle.
+    pub jobs_completed: u64,
+    pub jobs_panicked: u64,
+    /// Jobs that ran for longer than the pool's max_exec_time. Jobs are never preempted, so
+    /// these did eventually finish.
+    pub jobs_timed_out: u64,
+    pub workers_respawned: u64,
+    /// Most recent pool size changes, oldest first.
+    pub resize_history: Vec<ResizeEvent>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WorkerStats {
+    pub id: usize,
+    pub jobs_completed: u64,
+    pub busy_time: Duration,
+    pub idle_time: Duration,
+    /// How long the job the worker is running right now has been running, None if idle.
+    pub current_job_age: Option<Duration>,
+    /// Jobs serviced during the last completed window used by dynamic resizing.
+    pub jobs_serviced_last_window: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResizeEvent {
+    pub at: SystemTime,
+    pub from: usize,
+    pub to: usize,
+}
+
+/// Histogram with fixed, roughly logarithmic buckets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Upper bound of each bucket, None for the final overflow bucket.
+    pub buckets: Vec<(Option<Duration>, u64)>,
+}
+
+impl LatencyHistogram {
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|(_, c)| c).sum()
+    }
+
+    /// Upper bound of the bucket containing the given percentile (0.0 to 1.0). Returns None
+    /// if the histogram is empty or the percentile falls in the overflow bucket.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let total = self.count();
+        if total == 0 {
+            return None;
+        }
+        let target = ((total as f64) * p.clamp(0.0, 1.0)).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bound, count) in &self.buckets {
+            seen += count;
+            if seen >= target {
+                return *bound;
+            }
+        }
+        None
+    }
+}
+
+/// Live counters for one worker, updated by the worker thread without taking any lock.
+pub(crate) struct WorkerCounters {
+    spawned: Instant,
+    jobs_completed: AtomicU64,
+    busy_nanos: AtomicU64,
+    // Nanoseconds after spawned at which the current job started, 0 while idle
+    current_job_started: AtomicU64,
+}
+
+impl WorkerCounters {
+    pub(crate) fn new() -> Self {
+        WorkerCounters {
+            spawned: Instant::now(),
+            jobs_completed: AtomicU64::new(0),
+            busy_nanos: AtomicU64::new(0),
+            current_job_started: AtomicU64::new(0),
+        }
+    }
+
+    fn nanos_since_spawn(&self) -> u64 {
+        // Never 0 so that 0 can mean idle
+        (self.spawned.elapsed().as_nanos() as u64).max(1)
+    }
+
+    pub(crate) fn job_started(&self) {
+        self.current_job_started
+            .store(self.nanos_since_spawn(), Ordering::SeqCst);
+    }
+
+    pub(crate) fn job_finished(&self) {
+        let started = self.current_job_started.swap(0, Ordering::SeqCst);
+        if started != 0 {
+            self.busy_nanos.fetch_add(
+                self.nanos_since_spawn().saturating_sub(started),
+                Ordering::SeqCst,
+            );
+        }
+        self.jobs_completed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn snapshot(
+        &self,
+        id: usize,
+        jobs_serviced_last_window: Option<usize>,
+    ) -> WorkerStats {
+        let now = self.nanos_since_spawn();
+        let started = self.current_job_started.load(Ordering::SeqCst);
+        let current_job_age =
+            (started != 0).then(|| Duration::from_nanos(now.saturating_sub(started)));
+        let busy_time = Duration::from_nanos(self.busy_nanos.load(Ordering::SeqCst));
+        let idle_time = Duration::from_nanos(now)
+            .saturating_sub(busy_time)
+            .saturating_sub(current_job_age.unwrap_or_default());
+
+        WorkerStats {
+            id,
+            jobs_completed: self.jobs_completed.load(Ordering::SeqCst),
+            busy_time,
+            idle_time,
+            current_job_age,
+            jobs_serviced_last_window,
+        }
+    }
+}
+
+/// Pool wide counters shared by every worker and helping thread.
+pub(crate) struct PoolCounters {
+    jobs_completed: AtomicU64,
+    jobs_timed_out: AtomicU64,
+    queue_latency: [[AtomicU64; LATENCY_BUCKET_COUNT]; JobPriority::COUNT],
+    resize_history: Mutex<VecDeque<ResizeEvent>>,
+}
+
+impl PoolCounters {
+    pub(crate) fn new() -> Self {
+        PoolCounters {
+            jobs_completed: AtomicU64::new(0),
+            jobs_timed_out: AtomicU64::new(0),
+            queue_latency: Default::default(),
+            resize_history: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub(crate) fn record_queue_latency(&self, priority: JobPriority, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        let bucket = LATENCY_BUCKET_BOUNDS_MICROS
+            .iter()
+            .position(|bound| micros <= *bound)
+            .unwrap_or(LATENCY_BUCKET_COUNT - 1);
+        self.queue_latency[priority.index()][bucket].fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn record_job_finished(&self, run_time: Duration, max_exec_time: Duration) {
+        self.jobs_completed.fetch_add(1, Ordering::SeqCst);
+        if run_time > max_exec_time {
+            self.jobs_timed_out.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn record_resize(&self, from: usize, to: usize) {
+        let mut history = self
+            .resize_history
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if history.len() == RESIZE_HISTORY_LENGTH {
+            history.pop_front();
+        }
+        history.push_back(ResizeEvent {
+            at: SystemTime::now(),
+            from,
+            to,
+        });
+    }
+
+    pub(crate) fn jobs_completed(&self) -> u64 {
+        self.jobs_completed.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn jobs_timed_out(&self) -> u64 {
+        self.jobs_timed_out.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn queue_latency(&self) -> HashMap<JobPriority, LatencyHistogram> {
+        JobPriority::ALL
+            .iter()
+            .map(|p| {
+                let buckets = self.queue_latency[p.index()]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, count)| {
+                        let bound = LATENCY_BUCKET_BOUNDS_MICROS
+                            .get(i)
+                            .map(|micros| Duration::from_micros(*micros));
+                        (bound, count.load(Ordering::SeqCst))
+                    })
+                    .collect();
+                (*p, LatencyHistogram { buckets })
+            })
+            .collect()
+    }
+
+    pub(crate) fn resize_history(&self) -> Vec<ResizeEvent> {
+        self.resize_history
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .copied()
+            .collect()
+    }
+}
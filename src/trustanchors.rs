@@ -0,0 +1,430 @@
+//! The trust anchor store, kept current as the anchored zones roll their keys (RFC 5011).
+//!
+//! Every anchored zone's DNSKEY records are fetched periodically and checked with the keys
+//! already trusted. A key seen for the first time is trusted only once it has been there
+//! for the add hold-down, so a stolen key cannot be slipped in with a single forged
+//! answer. A key published with the REVOKE bit and signing the set itself is distrusted at
+//! once and forgotten after the remove hold-down. Keys that disappear stay trusted, as
+//! missing, until the operator removes them.
+
+use crate::config::{normalize_name, DnssecConfig};
+use crate::configerror::{ConfigError, ConfigErrorReason, Result};
+use crate::dashcontext::DashContext;
+use crate::dnssec::{Dnskey, TrustAnchor, DNSKEY};
+use crate::resolver::{lookup_raw, Budget};
+use crate::validator::verify_rrset;
+use log::{debug, info, warn};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Shortest and longest time between two checks of a zone's keys (RFC 5011 2.3).
+const MIN_REFRESH: Duration = Duration::from_secs(3600);
+const MAX_REFRESH: Duration = Duration::from_secs(15 * 86400);
+
+/// Where an anchored key is in the life cycle of RFC 5011 4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    /// Seen, waiting out the add hold-down before it is trusted.
+    AddPend,
+    Valid,
+    /// Trusted but no longer published.
+    Missing,
+    /// Revoked by its zone, never trusted again.
+    Revoked,
+}
+
+impl KeyState {
+    fn is_trusted(self) -> bool {
+        matches!(self, KeyState::Valid | KeyState::Missing)
+    }
+}
+
+impl fmt::Display for KeyState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            KeyState::AddPend => "addpend",
+            KeyState::Valid => "valid",
+            KeyState::Missing => "missing",
+            KeyState::Revoked => "revoked",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for KeyState {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "addpend" => Ok(KeyState::AddPend),
+            "valid" => Ok(KeyState::Valid),
+            "missing" => Ok(KeyState::Missing),
+            "revoked" => Ok(KeyState::Revoked),
+            _ => Err(format!(
+                "unknown key state \"{}\", expected addpend, valid, missing or revoked",
+                s
+            )),
+        }
+    }
+}
+
+/// A trust anchor, its state and since when, in seconds since the epoch, it is in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnchorEntry {
+    pub anchor: TrustAnchor,
+    pub state: KeyState,
+    pub since: u64,
+}
+
+impl AnchorEntry {
+    /// Whether the entry anchors key, whatever its flags say.
+    fn is_key(&self, key: &Dnskey) -> bool {
+        match &self.anchor {
+            TrustAnchor::Dnskey { key: anchor, .. } => {
+                anchor.algorithm == key.algorithm && anchor.public_key == key.public_key
+            }
+            TrustAnchor::Ds { .. } => false,
+        }
+    }
+}
+
+/// The trust anchors validation starts from, with the state file they are saved in.
+#[derive(Debug)]
+pub struct TrustAnchorStore {
+    path: Option<PathBuf>,
+    add_hold_down: Duration,
+    remove_hold_down: Duration,
+    entries: RwLock<Vec<AnchorEntry>>,
+}
+
+impl TrustAnchorStore {
+    /// Loads the state file if there is one, and adds the configured anchors of zones it
+    /// has none for. Fails if the file cannot be read or parsed.
+    pub fn load(config: &DnssecConfig) -> Result<Self> {
+        let mut entries = match &config.trust_anchor_file {
+            Some(path) if path.exists() => read_state(path)?,
+            _ => Vec::new(),
+        };
+        let now = now();
+        let known: Vec<String> = entries
+            .iter()
+            .map(|e| e.anchor.zone().to_string())
+            .collect();
+        for anchor in &config.trust_anchors {
+            if !known.iter().any(|zone| zone == anchor.zone()) {
+                entries.push(AnchorEntry {
+                    anchor: anchor.clone(),
+                    state: KeyState::Valid,
+                    since: now,
+                });
+            }
+        }
+        let store = TrustAnchorStore {
+            path: config.trust_anchor_file.clone(),
+            add_hold_down: config.add_hold_down(),
+            remove_hold_down: config.remove_hold_down(),
+            entries: RwLock::new(entries),
+        };
+        store.save(&store.entries.read().unwrap_or_else(PoisonError::into_inner));
+        Ok(store)
+    }
+
+    /// The anchors currently trusted.
+    pub fn anchors(&self) -> Vec<TrustAnchor> {
+        self.entries
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|e| e.state.is_trusted())
+            .map(|e| e.anchor.clone())
+            .collect()
+    }
+
+    pub fn entries(&self) -> Vec<AnchorEntry> {
+        self.entries
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The zones with anchors.
+    pub fn zones(&self) -> Vec<String> {
+        let mut zones: Vec<String> = self
+            .entries
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|e| e.anchor.zone().to_string())
+            .collect();
+        zones.sort();
+        zones.dedup();
+        zones
+    }
+
+    /// Applies what the validated DNSKEY records of zone show at now, in seconds since the
+    /// epoch: keys holds the whole set, revoked the revoked keys that signed it themselves.
+    /// Returns whether anything changed, the state file is saved if so.
+    pub fn update(&self, zone: &str, keys: &[Dnskey], revoked: &[Dnskey], now: u64) -> bool {
+        let zone = normalize_name(zone);
+        let mut entries = self.entries.write().unwrap_or_else(PoisonError::into_inner);
+        let before = entries.clone();
+        let published: Vec<&Dnskey> = keys
+            .iter()
+            .filter(|k| k.flags & Dnskey::SEP != 0 && k.is_zone_key())
+            .collect();
+
+        // DS anchors are replaced by the keys they stand for, which can then be tracked
+        let mut added = Vec::new();
+        entries.retain(|entry| {
+            let TrustAnchor::Ds { zone: z, ds } = &entry.anchor else {
+                return true;
+            };
+            if *z != zone || !entry.state.is_trusted() {
+                return true;
+            }
+            let matching: Vec<AnchorEntry> = published
+                .iter()
+                .filter(|k| ds.matches(z, k))
+                .map(|k| AnchorEntry {
+                    anchor: TrustAnchor::Dnskey {
+                        zone: zone.clone(),
+                        key: (*k).clone(),
+                    },
+                    state: KeyState::Valid,
+                    since: now,
+                })
+                .collect();
+            let keep = matching.is_empty();
+            added.extend(matching);
+            keep
+        });
+        for entry in added {
+            if !entries.iter().any(|e| e.anchor == entry.anchor) {
+                entries.push(entry);
+            }
+        }
+
+        for entry in entries.iter_mut().filter(|e| e.anchor.zone() == zone) {
+            if entry.state == KeyState::Revoked {
+                continue;
+            }
+            if let Some(key) = revoked.iter().find(|k| entry.is_key(k)) {
+                warn!("Trust anchor {} was revoked", entry.anchor);
+                entry.anchor = TrustAnchor::Dnskey {
+                    zone: zone.clone(),
+                    key: key.clone(),
+                };
+                entry.state = KeyState::Revoked;
+                entry.since = now;
+                continue;
+            }
+            let seen = published.iter().any(|k| entry.is_key(k));
+            match (entry.state, seen) {
+                (KeyState::AddPend, true)
+                    if now.saturating_sub(entry.since) >= self.add_hold_down.as_secs() =>
+                {
+                    info!("Trusting the new key {} after its hold-down", entry.anchor);
+                    entry.state = KeyState::Valid;
+                    entry.since = now;
+                }
+                (KeyState::Missing, true) => {
+                    entry.state = KeyState::Valid;
+                    entry.since = now;
+                }
+                (KeyState::Valid, false) => {
+                    info!("Trust anchor {} is no longer published", entry.anchor);
+                    entry.state = KeyState::Missing;
+                    entry.since = now;
+                }
+                _ => {}
+            }
+        }
+        // Keys waiting for their hold-down start over if they disappear meanwhile
+        entries.retain(|e| {
+            let pending_gone = e.state == KeyState::AddPend
+                && e.anchor.zone() == zone
+                && !published.iter().any(|k| e.is_key(k));
+            let forgotten = e.state == KeyState::Revoked
+                && now.saturating_sub(e.since) >= self.remove_hold_down.as_secs();
+            !pending_gone && !forgotten
+        });
+        for key in published {
+            if !entries
+                .iter()
+                .any(|e| e.anchor.zone() == zone && e.is_key(key))
+            {
+                let entry = AnchorEntry {
+                    anchor: TrustAnchor::Dnskey {
+                        zone: zone.clone(),
+                        key: key.clone(),
+                    },
+                    state: KeyState::AddPend,
+                    since: now,
+                };
+                info!("New key {} is pending its add hold-down", entry.anchor);
+                entries.push(entry);
+            }
+        }
+
+        let changed = *entries != before;
+        if changed {
+            self.save(&entries);
+        }
+        changed
+    }
+
+    /// Writes entries to the state file, if there is one, through a temporary file so a
+    /// crash cannot leave it half written.
+    fn save(&self, entries: &[AnchorEntry]) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut contents = String::from(
+            "# Trust anchors kept by Dash as RFC 5011 describes: state, since (seconds since\n\
+             # the epoch), then the DS or DNSKEY record.\n",
+        );
+        for entry in entries {
+            contents.push_str(&format!(
+                "{} {} {}\n",
+                entry.state, entry.since, entry.anchor
+            ));
+        }
+        let temporary = path.with_extension("tmp");
+        let written =
+            std::fs::write(&temporary, contents).and_then(|_| std::fs::rename(&temporary, path));
+        if let Err(e) = written {
+            warn!(
+                "Could not save the trust anchors to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    /// Checks the keys of every anchored zone now and then as often as RFC 5011 2.3 asks.
+    /// Stops once the context is dropped, which happens when a reload replaces it.
+    pub fn start_refresher(context: &Arc<DashContext>) {
+        if !context.config().dnssec.validation {
+            return;
+        }
+        let context: Weak<DashContext> = Arc::downgrade(context);
+        std::thread::spawn(move || loop {
+            let interval = {
+                let context = match context.upgrade() {
+                    Some(c) => c,
+                    None => return,
+                };
+                context
+                    .trust_anchor_store()
+                    .zones()
+                    .iter()
+                    .map(|zone| refresh(zone, &context).unwrap_or(MIN_REFRESH))
+                    .min()
+                    .unwrap_or(MAX_REFRESH)
+            };
+            std::thread::sleep(interval);
+        });
+    }
+}
+
+/// Fetches the DNSKEY records of zone and applies them to the store if a trusted key
+/// signed them. Returns when to check again.
+fn refresh(zone: &str, context: &DashContext) -> Option<Duration> {
+    let store = context.trust_anchor_store();
+    let rsp = match lookup_raw(zone, DNSKEY, context, &Budget::new()) {
+        Ok(rsp) => rsp,
+        Err(e) => {
+            warn!("Could not refresh the trust anchors of {:?}: {}", zone, e);
+            return None;
+        }
+    };
+    let keys: Vec<Dnskey> = rsp
+        .answers
+        .iter()
+        .filter(|r| r.name == zone && r.r#type == DNSKEY)
+        .filter_map(|r| Dnskey::parse(&r.rdata))
+        .collect();
+    let anchors: Vec<TrustAnchor> = store
+        .anchors()
+        .into_iter()
+        .filter(|a| a.zone() == zone)
+        .collect();
+    let trusted: Vec<Dnskey> = keys
+        .iter()
+        .filter(|k| k.is_zone_key() && anchors.iter().any(|a| a.trusts(k)))
+        .cloned()
+        .collect();
+    let ttl = match verify_rrset(&rsp.answers, zone, DNSKEY, &trusted, zone) {
+        Ok(verified) => verified.ttl,
+        Err(e) => {
+            warn!(
+                "The keys of {:?} are not signed by a trust anchor: {}",
+                zone, e
+            );
+            return None;
+        }
+    };
+    // A revocation only counts if the revoked key signed it (RFC 5011 2.1)
+    let revoked: Vec<Dnskey> = keys
+        .iter()
+        .filter(|k| k.is_revoked() && k.flags & Dnskey::SEP != 0)
+        .filter(|k| verify_rrset(&rsp.answers, zone, DNSKEY, &[(*k).clone()], zone).is_ok())
+        .cloned()
+        .collect();
+    if store.update(zone, &keys, &revoked, now()) {
+        context.keys().remove(zone);
+    }
+    debug!("Refreshed the trust anchors of {:?}", zone);
+    Some(Duration::from_secs(ttl as u64 / 2).clamp(MIN_REFRESH, MAX_REFRESH))
+}
+
+fn read_state(path: &Path) -> Result<Vec<AnchorEntry>> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        ConfigError::new(ConfigErrorReason::Io(format!(
+            "reading {}: {}",
+            path.display(),
+            e
+        )))
+    })?;
+    let invalid = |line: usize, why: String| {
+        ConfigError::new(ConfigErrorReason::InvalidValue(format!(
+            "{} line {}: {}",
+            path.display(),
+            line + 1,
+            why
+        )))
+    };
+    let mut entries = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(3, char::is_whitespace);
+        let (Some(state), Some(since), Some(anchor)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid(
+                i,
+                "expected a state, a time and a trust anchor".to_string(),
+            ));
+        };
+        entries.push(AnchorEntry {
+            state: state.parse().map_err(|e| invalid(i, e))?,
+            since: since
+                .parse()
+                .map_err(|_| invalid(i, format!("\"{}\" is not a time", since)))?,
+            anchor: anchor.parse().map_err(|e| invalid(i, e))?,
+        });
+    }
+    Ok(entries)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,227 @@
+use crate::config::{normalize_name, ResolverConfig, UpstreamSelection};
+use crate::resolver::query_server;
+use log::{info, warn};
+use rustdns::{Class, Message, Rcode, Type};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+/// Consecutive failed queries after which a server is considered down.
+const FAILURES_BEFORE_DOWN: u32 = 3;
+
+/// One upstream resolver and what has been observed about it so far.
+#[derive(Debug)]
+pub struct Upstream {
+    address: SocketAddr,
+    healthy: AtomicBool,
+    consecutive_failures: AtomicU32,
+    // Smoothed response time in microseconds, 0 until the first answer
+    srtt_micros: AtomicU64,
+}
+
+impl Upstream {
+    fn new(address: SocketAddr) -> Self {
+        Upstream {
+            address,
+            healthy: AtomicBool::new(true),
+            consecutive_failures: AtomicU32::new(0),
+            srtt_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        match self.srtt_micros.load(Ordering::SeqCst) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    fn update_srtt(&self, sample: Duration) {
+        let sample = (sample.as_micros() as u64).max(1);
+        // Same smoothing factor as TCP's SRTT (RFC 6298)
+        let _ = self
+            .srtt_micros
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |old| {
+                Some(if old == 0 {
+                    sample
+                } else {
+                    (old * 7 + sample) / 8
+                })
+            });
+    }
+
+    pub fn record_success(&self, rtt: Duration) {
+        self.update_srtt(rtt);
+        self.consecutive_failures.store(0, Ordering::SeqCst);
+        if !self.healthy.swap(true, Ordering::SeqCst) {
+            info!("Upstream {} is back up", self.address);
+        }
+    }
+
+    /// Counts a query that got no usable answer. The timeout is folded into the response
+    /// time so a slow, flaky server also loses out under fastest selection.
+    pub fn record_failure(&self, timeout: Duration) {
+        self.update_srtt(timeout);
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= FAILURES_BEFORE_DOWN && self.healthy.swap(false, Ordering::SeqCst) {
+            warn!(
+                "Upstream {} marked down after {} failed queries",
+                self.address, failures
+            );
+        }
+    }
+}
+
+/// A set of interchangeable upstream resolvers.
+#[derive(Debug)]
+pub struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    selection: UpstreamSelection,
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub fn new(addresses: &[SocketAddr], selection: UpstreamSelection) -> Self {
+        UpstreamPool {
+            upstreams: addresses.iter().map(|a| Upstream::new(*a)).collect(),
+            selection,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn upstreams(&self) -> &[Upstream] {
+        &self.upstreams
+    }
+
+    /// Order in which to try the servers for one query. Healthy servers come first, ordered
+    /// by the selection policy. Servers marked down follow as a last resort, so a query still
+    /// has a chance when every server has been failing.
+    pub fn candidates(&self) -> Vec<&Upstream> {
+        if self.upstreams.is_empty() {
+            return Vec::new();
+        }
+        let start = self.next.fetch_add(1, Ordering::SeqCst) % self.upstreams.len();
+        let mut rotated: Vec<&Upstream> = self.upstreams[start..]
+            .iter()
+            .chain(self.upstreams[..start].iter())
+            .collect();
+        if self.selection == UpstreamSelection::Fastest {
+            // Servers that never answered sort first so each one gets measured
+            rotated.sort_by_key(|u| u.srtt().unwrap_or_default());
+        }
+        // Stable, so the selection order is kept within each group
+        rotated.sort_by_key(|u| !u.is_healthy());
+        rotated
+    }
+}
+
+/// Every upstream pool built from the resolver configuration.
+#[derive(Debug)]
+pub struct Upstreams {
+    forwarders: Option<UpstreamPool>,
+    // Most specific zone first
+    zones: Vec<(String, UpstreamPool)>,
+}
+
+impl Upstreams {
+    pub fn new(config: &ResolverConfig) -> Self {
+        let forwarders = (!config.forwarders.is_empty())
+            .then(|| UpstreamPool::new(&config.forwarders, config.selection));
+        let mut zones: Vec<_> = config
+            .forward_zones
+            .iter()
+            .map(|z| {
+                (
+                    normalize_name(&z.name),
+                    UpstreamPool::new(&z.forwarders, config.selection),
+                )
+            })
+            .collect();
+        zones.sort_by_key(|(name, _)| std::cmp::Reverse(label_count(name)));
+        Upstreams { forwarders, zones }
+    }
+
+    /// The pool used in forward mode for names outside every forward zone.
+    pub fn forwarders(&self) -> Option<&UpstreamPool> {
+        self.forwarders.as_ref()
+    }
+
+    /// The pool of the closest forward zone containing name, if any.
+    pub fn forward_zone(&self, name: &str) -> Option<(&str, &UpstreamPool)> {
+        let name = normalize_name(name);
+        self.zones
+            .iter()
+            .find(|(zone, _)| is_subdomain(&name, zone))
+            .map(|(zone, pool)| (zone.as_str(), pool))
+    }
+
+    fn pools(&self) -> impl Iterator<Item = &UpstreamPool> {
+        self.forwarders
+            .iter()
+            .chain(self.zones.iter().map(|(_, pool)| pool))
+    }
+
+    /// Probes every upstream once per interval until the pools are dropped, which happens
+    /// when a reload replaces them.
+    pub fn start_health_checker(upstreams: &Arc<Upstreams>, interval: Duration, timeout: Duration) {
+        if upstreams.pools().next().is_none() {
+            return;
+        }
+        let upstreams: Weak<Upstreams> = Arc::downgrade(upstreams);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let upstreams = match upstreams.upgrade() {
+                Some(u) => u,
+                None => return,
+            };
+            for upstream in upstreams.pools().flat_map(|p| p.upstreams.iter()) {
+                probe(upstream, timeout);
+            }
+        });
+    }
+}
+
+fn probe(upstream: &Upstream, timeout: Duration) {
+    let mut msg = Message::default();
+    msg.add_question(".", Type::NS, Class::Internet);
+
+    let start = Instant::now();
+    match query_server(upstream.address, &msg, timeout, false) {
+        Ok(rsp) if !matches!(rsp.rcode, Rcode::ServFail | Rcode::Refused) => {
+            upstream.record_success(start.elapsed())
+        }
+        _ => upstream.record_failure(timeout),
+    }
+}
+
+pub(crate) fn label_count(name: &str) -> usize {
+    if name.is_empty() {
+        0
+    } else {
+        name.split('.').count()
+    }
+}
+
+/// The name one label below ancestor on the way to name.
+pub(crate) fn child_toward(ancestor: &str, name: &str) -> String {
+    let labels: Vec<&str> = name.split('.').collect();
+    labels[labels.len() - label_count(ancestor) - 1..].join(".")
+}
+
+/// True if name (normalized) is zone or below it.
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    zone.is_empty()
+        || name == zone
+        || (name.len() > zone.len()
+            && name.ends_with(zone)
+            && name.as_bytes()[name.len() - zone.len() - 1] == b'.')
+}
@@ -0,0 +1,859 @@
+//! DNSSEC validation of the answers recursion finds (RFC 4035 5).
+//!
+//! A chain of trust is built from the closest trust anchor down to the zone that signed an
+//! answer: the anchor vouches for its zone's keys, each zone's keys sign the DS records
+//! vouching for its children's keys, and the last zone's keys sign the answer. A zone whose
+//! parent proves it has no DS records is insecure, and so is everything below it. Keys, and
+//! proofs of their absence, are cached per zone.
+
+use crate::config::normalize_name;
+use crate::dashcontext::DashContext;
+use crate::dnserror::ExtendedError;
+use crate::dnssec::{
+    algorithm_supported, digest_supported, verify, Dnskey, Ds, Nsec, Nsec3, Rrsig, DNSKEY, DS,
+    NSEC, NSEC3, RRSIG,
+};
+use crate::resolver::{lookup_raw, Budget};
+use crate::upstream::{child_toward, is_subdomain, label_count};
+use crate::wire::{RawMessage, RawRecord};
+use log::{debug, warn};
+use rustdns::Rcode;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const CNAME: u16 = 5;
+const NS: u16 = 2;
+const SOA: u16 = 6;
+
+/// Zones whose keys are kept at most this many at once.
+const MAX_ZONES: usize = 10_000;
+
+/// Upper bound on how long a zone's keys are trusted, whatever their TTL.
+const MAX_KEY_TTL: u32 = 86_400;
+
+/// How long a zone found bogus is remembered, so a broken zone does not cost a walk down
+/// the chain of trust on every query for it (RFC 4035 4.7).
+const BOGUS_TTL: u32 = 60;
+
+/// NSEC3 records with more iterations are too costly to check, answers relying on them
+/// are treated as insecure (RFC 9276 3.2).
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+/// The outcome of validating an answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Security {
+    /// Every record was proven authentic, the answer gets the AD bit.
+    Secure,
+    /// No chain of trust covers the answer, it is passed on as it is.
+    Insecure,
+    /// The answer should have been signed and was not, or the signatures are wrong. The
+    /// client gets SERVFAIL with the reason.
+    Bogus(ExtendedError),
+}
+
+#[derive(Debug, Clone)]
+enum ZoneKeys {
+    Secure(Vec<Dnskey>),
+    Insecure,
+    Bogus(ExtendedError),
+}
+
+/// The validated keys of zones, and the zones proven to be insecure or found bogus.
+#[derive(Debug, Default)]
+pub struct KeyCache {
+    entries: Mutex<HashMap<String, (ZoneKeys, Instant)>>,
+}
+
+impl KeyCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get(&self, zone: &str) -> Option<ZoneKeys> {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries
+            .get(zone)
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(keys, _)| keys.clone())
+    }
+
+    fn insert(&self, zone: &str, keys: ZoneKeys, ttl: u32) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() >= MAX_ZONES {
+            entries.retain(|_, (_, expires)| *expires > now);
+            if entries.len() >= MAX_ZONES {
+                return;
+            }
+        }
+        let ttl = Duration::from_secs(ttl.min(MAX_KEY_TTL) as u64);
+        entries.insert(zone.to_string(), (keys, now + ttl));
+    }
+
+    /// Forgets the keys of zone, after its trust anchors changed.
+    pub fn remove(&self, zone: &str) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(zone);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Validates rsp, the final response recursion got for qname and qtype, after scrubbing.
+/// Each record set of the answer is checked on its own, a negative answer by its proof of
+/// nonexistence.
+pub fn validate(
+    rsp: &RawMessage,
+    qname: &str,
+    qtype: u16,
+    context: &DashContext,
+    budget: &Budget,
+) -> Security {
+    let qname = normalize_name(qname);
+    if closest_anchor(&qname, context).is_none() {
+        return Security::Insecure;
+    }
+    let mut rrsets: Vec<(String, u16)> = Vec::new();
+    for record in &rsp.message.answers {
+        let rrset = (
+            normalize_name(&record.name),
+            record.resource.r#type() as u16,
+        );
+        if !rrsets.contains(&rrset) {
+            rrsets.push(rrset);
+        }
+    }
+    let security = if rrsets.is_empty() {
+        validate_negative(rsp, &qname, qtype, context, budget)
+    } else {
+        let mut security = Security::Secure;
+        for (owner, r#type) in rrsets {
+            match validate_rrset(rsp, &owner, r#type, context, budget) {
+                Security::Secure => {}
+                Security::Insecure => security = Security::Insecure,
+                bogus => {
+                    security = bogus;
+                    break;
+                }
+            }
+        }
+        security
+    };
+    if let Security::Bogus(e) = &security {
+        warn!(
+            "DNSSEC validation of {} {} failed: {}",
+            qname,
+            type_name(qtype),
+            e
+        );
+    }
+    security
+}
+
+/// Validates the records of owner and type r#type in the answer section.
+fn validate_rrset(
+    rsp: &RawMessage,
+    owner: &str,
+    r#type: u16,
+    context: &DashContext,
+    budget: &Budget,
+) -> Security {
+    let signer = signatures(&rsp.answers, owner, r#type)
+        .map(|sig| sig.signer)
+        .find(|signer| is_subdomain(owner, signer));
+    let Some(signer) = signer else {
+        // Unsigned data is fine in an insecure zone only
+        return match zone_keys(owner, context, budget).0 {
+            ZoneKeys::Secure(_) => Security::Bogus(ExtendedError::new(
+                ExtendedError::RRSIGS_MISSING,
+                format!("{} {} is not signed", owner, type_name(r#type)),
+            )),
+            ZoneKeys::Insecure => Security::Insecure,
+            ZoneKeys::Bogus(e) => Security::Bogus(e),
+        };
+    };
+    let keys = match signing_keys(&signer, context, budget) {
+        Ok(Some(keys)) => keys,
+        Ok(None) => return Security::Insecure,
+        Err(e) => return Security::Bogus(e),
+    };
+    let verified = match verify_rrset(&rsp.answers, owner, r#type, &keys, &signer) {
+        Ok(v) => v,
+        Err(e) => return Security::Bogus(e),
+    };
+    // Records synthesized from a wildcard are only valid if owner itself does not exist
+    if let Some(wildcard) = verified.wildcard {
+        let encloser = wildcard.strip_prefix("*.").unwrap_or("");
+        if !no_closer_match(&rsp.authoritys, owner, encloser, &keys, &signer) {
+            return Security::Bogus(ExtendedError::new(
+                ExtendedError::NSEC_MISSING,
+                format!("nothing proves {} is not a name of its own", owner),
+            ));
+        }
+    }
+    Security::Secure
+}
+
+/// Validates a NODATA or NXDOMAIN response by the SOA record of its zone and the NSEC or
+/// NSEC3 records proving the denial.
+fn validate_negative(
+    rsp: &RawMessage,
+    qname: &str,
+    qtype: u16,
+    context: &DashContext,
+    budget: &Budget,
+) -> Security {
+    let soa = rsp
+        .authoritys
+        .iter()
+        .find(|r| r.r#type == SOA && is_subdomain(qname, &r.name));
+    let Some(soa) = soa else {
+        return match zone_keys(qname, context, budget).0 {
+            ZoneKeys::Secure(_) => Security::Bogus(ExtendedError::new(
+                ExtendedError::NSEC_MISSING,
+                format!("the negative answer for {} has no SOA record", qname),
+            )),
+            ZoneKeys::Insecure => Security::Insecure,
+            ZoneKeys::Bogus(e) => Security::Bogus(e),
+        };
+    };
+    let zone = soa.name.clone();
+    let keys = match signing_keys(&zone, context, budget) {
+        Ok(Some(keys)) => keys,
+        Ok(None) => return Security::Insecure,
+        Err(e) => return Security::Bogus(e),
+    };
+    if let Err(e) = verify_rrset(&rsp.authoritys, &zone, SOA, &keys, &zone) {
+        return Security::Bogus(e);
+    }
+    let nsecs = verified_nsecs(&rsp.authoritys, &keys, &zone);
+    let nsec3s = match nsecs.is_empty() {
+        true => verified_nsec3s(&rsp.authoritys, &keys, &zone),
+        false => Vec::new(),
+    };
+    let nxdomain = rsp.message.rcode == Rcode::NXDomain;
+    let security = match prove(&nsecs, &nsec3s, qname, qtype, &zone) {
+        Ok(Denial::NxDomain) if nxdomain => Security::Secure,
+        Ok(Denial::NoData { .. }) if !nxdomain => Security::Secure,
+        Ok(Denial::Insecure) => Security::Insecure,
+        Ok(_) => Security::Bogus(ExtendedError::new(
+            ExtendedError::DNSSEC_BOGUS,
+            format!(
+                "the proof of nonexistence for {} contradicts the rcode",
+                qname
+            ),
+        )),
+        Err(e) => Security::Bogus(e),
+    };
+    // The proven ranges can answer for other names too (RFC 8198)
+    if security == Security::Secure && context.config().dnssec.aggressive_nsec {
+        context
+            .nsec_cache()
+            .insert(&zone, &rsp.message, &nsecs, &nsec3s);
+    }
+    security
+}
+
+/// The keys of signer for checking its signatures, None if signer is insecure.
+fn signing_keys(
+    signer: &str,
+    context: &DashContext,
+    budget: &Budget,
+) -> Result<Option<Vec<Dnskey>>, ExtendedError> {
+    match zone_keys(signer, context, budget) {
+        (ZoneKeys::Secure(keys), zone) if zone == signer => Ok(Some(keys)),
+        (ZoneKeys::Secure(_), _) => Err(ExtendedError::new(
+            ExtendedError::DNSKEY_MISSING,
+            format!("{} signs records but is not a zone", signer),
+        )),
+        (ZoneKeys::Insecure, _) => Ok(None),
+        (ZoneKeys::Bogus(e), _) => Err(e),
+    }
+}
+
+/// The deepest trust anchor containing name, None if there is none or name is not to be
+/// validated. Names in stub and forward zones below the anchor are left alone, the anchor's
+/// chain of trust cannot reach private zones.
+fn closest_anchor(name: &str, context: &DashContext) -> Option<String> {
+    let dnssec = &context.config().dnssec;
+    if dnssec
+        .insecure_zones
+        .iter()
+        .any(|zone| is_subdomain(name, &normalize_name(zone)))
+    {
+        return None;
+    }
+    let anchor = context
+        .trust_anchors()
+        .into_iter()
+        .map(|a| a.zone().to_string())
+        .filter(|zone| is_subdomain(name, zone))
+        .max_by_key(|zone| label_count(zone))?;
+    let resolver = &context.config().resolver;
+    let private = resolver
+        .stub_zones
+        .iter()
+        .map(|z| &z.name)
+        .chain(resolver.forward_zones.iter().map(|z| &z.name))
+        .map(|zone| normalize_name(zone))
+        .any(|zone| is_subdomain(name, &zone) && label_count(&zone) > label_count(&anchor));
+    (!private).then_some(anchor)
+}
+
+/// The keys of the zone name is in, found by walking from the closest trust anchor down
+/// to name, and the zone they belong to.
+fn zone_keys(name: &str, context: &DashContext, budget: &Budget) -> (ZoneKeys, String) {
+    let Some(anchor) = closest_anchor(name, context) else {
+        return (ZoneKeys::Insecure, String::new());
+    };
+    let cache = context.keys();
+    if let Some(keys) = cache.get(name) {
+        return (keys, name.to_string());
+    }
+    let mut keys = cache.get(&anchor).unwrap_or_else(|| {
+        let (keys, ttl) = anchor_keys(&anchor, context, budget);
+        cache.insert(&anchor, keys.clone(), ttl);
+        keys
+    });
+    let mut zone = anchor.clone();
+    let mut current = anchor;
+    while current != name {
+        let ZoneKeys::Secure(parent_keys) = &keys else {
+            break;
+        };
+        let child = child_toward(&current, name);
+        if let Some(child_keys) = cache.get(&child) {
+            keys = child_keys;
+            zone = child.clone();
+        } else if let Some((child_keys, ttl)) =
+            delegation(&child, parent_keys, &zone, context, budget)
+        {
+            cache.insert(&child, child_keys.clone(), ttl);
+            keys = child_keys;
+            zone = child.clone();
+        }
+        current = child;
+    }
+    (keys, zone)
+}
+
+/// The keys of anchor, the zone of a trust anchor, checked against the anchors.
+fn anchor_keys(anchor: &str, context: &DashContext, budget: &Budget) -> (ZoneKeys, u32) {
+    let anchors: Vec<_> = context
+        .trust_anchors()
+        .into_iter()
+        .filter(|a| a.zone() == anchor)
+        .collect();
+    if !anchors.iter().any(|a| algorithm_supported(a.algorithm())) {
+        debug!("No trust anchor of {:?} has a supported algorithm", anchor);
+        return (ZoneKeys::Insecure, MAX_KEY_TTL);
+    }
+    dnskeys(
+        anchor,
+        |key| anchors.iter().any(|a| a.trusts(key)),
+        context,
+        budget,
+    )
+}
+
+/// Looks up the DS records of child, a name one label below zone, whose keys are given.
+/// Returns the keys of child if it is a zone, None if it is not.
+fn delegation(
+    child: &str,
+    keys: &[Dnskey],
+    zone: &str,
+    context: &DashContext,
+    budget: &Budget,
+) -> Option<(ZoneKeys, u32)> {
+    let rsp = match lookup_raw(child, DS, context, budget) {
+        Ok(rsp) => rsp,
+        Err(e) => {
+            let error = ExtendedError::new(
+                ExtendedError::DNSSEC_BOGUS,
+                format!("no DS records for {}: {}", child, e),
+            );
+            return Some((ZoneKeys::Bogus(error), BOGUS_TTL));
+        }
+    };
+    if rsp
+        .answers
+        .iter()
+        .any(|r| r.name == child && r.r#type == DS)
+    {
+        let ttl = match verify_rrset(&rsp.answers, child, DS, keys, zone) {
+            Ok(v) => v.ttl,
+            Err(e) => return Some((ZoneKeys::Bogus(e), BOGUS_TTL)),
+        };
+        let ds_set: Vec<Ds> = rsp
+            .answers
+            .iter()
+            .filter(|r| r.name == child && r.r#type == DS)
+            .filter_map(|r| Ds::parse(&r.rdata))
+            .filter(|ds| algorithm_supported(ds.algorithm) && digest_supported(ds.digest_type))
+            .collect();
+        // A zone signed only with algorithms Dash cannot check is treated as unsigned
+        if ds_set.is_empty() {
+            debug!("No DS record of {} has a supported algorithm", child);
+            return Some((ZoneKeys::Insecure, ttl));
+        }
+        let (keys, key_ttl) = dnskeys(
+            child,
+            |key| ds_set.iter().any(|ds| ds.matches(child, key)),
+            context,
+            budget,
+        );
+        return Some((keys, ttl.min(key_ttl)));
+    }
+    match denial(&rsp.authoritys, child, DS, keys, zone) {
+        Ok(Denial::NoData {
+            delegation: false, ..
+        }) => {
+            debug!("{} is a name in {}, not a zone of its own", child, zone);
+            None
+        }
+        Ok(Denial::NoData { ttl, .. }) => {
+            debug!("{} is an unsigned delegation from {}", child, zone);
+            Some((ZoneKeys::Insecure, ttl))
+        }
+        Ok(Denial::Insecure) => Some((ZoneKeys::Insecure, BOGUS_TTL)),
+        Ok(Denial::NxDomain) => {
+            let error = ExtendedError::new(
+                ExtendedError::DNSSEC_BOGUS,
+                format!("{} does not exist in {}", child, zone),
+            );
+            Some((ZoneKeys::Bogus(error), BOGUS_TTL))
+        }
+        Err(e) => Some((ZoneKeys::Bogus(e), BOGUS_TTL)),
+    }
+}
+
+/// Looks up the DNSKEY records of zone. They are valid if one of the keys trusted, by a DS
+/// record or a trust anchor, signs them all.
+fn dnskeys(
+    zone: &str,
+    trusted: impl Fn(&Dnskey) -> bool,
+    context: &DashContext,
+    budget: &Budget,
+) -> (ZoneKeys, u32) {
+    let bogus = |code, text| (ZoneKeys::Bogus(ExtendedError::new(code, text)), BOGUS_TTL);
+    let rsp = match lookup_raw(zone, DNSKEY, context, budget) {
+        Ok(rsp) => rsp,
+        Err(e) => {
+            return bogus(
+                ExtendedError::DNSKEY_MISSING,
+                format!("no DNSKEY records for {}: {}", zone, e),
+            )
+        }
+    };
+    let keys: Vec<Dnskey> = rsp
+        .answers
+        .iter()
+        .filter(|r| r.name == zone && r.r#type == DNSKEY)
+        .filter_map(|r| Dnskey::parse(&r.rdata))
+        .filter(Dnskey::is_zone_key)
+        .collect();
+    let entry: Vec<Dnskey> = keys.iter().filter(|k| trusted(k)).cloned().collect();
+    if entry.is_empty() {
+        return bogus(
+            ExtendedError::DNSKEY_MISSING,
+            format!("no DNSKEY of {} matches its DS records", zone),
+        );
+    }
+    match verify_rrset(&rsp.answers, zone, DNSKEY, &entry, zone) {
+        Ok(v) => {
+            debug!("Validated {} keys of {:?}", keys.len(), zone);
+            (ZoneKeys::Secure(keys), v.ttl)
+        }
+        Err(e) => (ZoneKeys::Bogus(e), BOGUS_TTL),
+    }
+}
+
+/// The outcome of checking one record set's signatures.
+pub(crate) struct Verified {
+    /// How long the result holds: the smallest of the records' TTLs, the signature's
+    /// original TTL and the time until it expires.
+    pub(crate) ttl: u32,
+    /// The wildcard the records were synthesized from.
+    pub(crate) wildcard: Option<String>,
+}
+
+/// The RRSIG records for owner and type r#type in records.
+fn signatures<'a>(
+    records: &'a [RawRecord],
+    owner: &'a str,
+    r#type: u16,
+) -> impl Iterator<Item = Rrsig> + 'a {
+    records
+        .iter()
+        .filter(move |r| r.name == owner && r.r#type == RRSIG)
+        .filter_map(|r| Rrsig::parse(&r.rdata))
+        .filter(move |sig| sig.type_covered == r#type)
+}
+
+/// Checks the records of owner and type r#type in records against their signatures by
+/// signer, with one of keys.
+pub(crate) fn verify_rrset(
+    records: &[RawRecord],
+    owner: &str,
+    r#type: u16,
+    keys: &[Dnskey],
+    signer: &str,
+) -> Result<Verified, ExtendedError> {
+    let rrset: Vec<&RawRecord> = records
+        .iter()
+        .filter(|r| r.name == owner && r.r#type == r#type)
+        .collect();
+    let rdatas: Vec<&[u8]> = rrset.iter().map(|r| r.rdata.as_slice()).collect();
+    let class = rrset.first().map_or(1, |r| r.class);
+    let now = now();
+    let mut error = ExtendedError::new(
+        ExtendedError::RRSIGS_MISSING,
+        format!(
+            "{} {} has no signature by {:?}",
+            owner,
+            type_name(r#type),
+            signer
+        ),
+    );
+    for sig in signatures(records, owner, r#type).filter(|sig| sig.signer == signer) {
+        if !algorithm_supported(sig.algorithm) {
+            error = ExtendedError::new(
+                ExtendedError::UNSUPPORTED_DNSKEY_ALGORITHM,
+                format!("{} is signed with algorithm {}", owner, sig.algorithm),
+            );
+            continue;
+        }
+        if !sig.is_current(now) {
+            let (code, when) = if sig.is_expired(now) {
+                (ExtendedError::SIGNATURE_EXPIRED, "expired")
+            } else {
+                (ExtendedError::SIGNATURE_NOT_YET_VALID, "not yet valid")
+            };
+            error = ExtendedError::new(
+                code,
+                format!(
+                    "the signature of {} {} is {}",
+                    owner,
+                    type_name(r#type),
+                    when
+                ),
+            );
+            continue;
+        }
+        let Some(data) = sig.signed_data(owner, class, &rdatas) else {
+            continue;
+        };
+        let verified = keys
+            .iter()
+            .filter(|k| k.key_tag() == sig.key_tag && k.algorithm == sig.algorithm)
+            .any(|k| verify(k, sig.algorithm, &data, &sig.signature));
+        if verified {
+            let ttl = rrset
+                .iter()
+                .map(|r| r.ttl)
+                .min()
+                .unwrap_or(0)
+                .min(sig.original_ttl)
+                .min(sig.expiration.wrapping_sub(now));
+            return Ok(Verified {
+                ttl,
+                wildcard: sig.wildcard(owner),
+            });
+        }
+        error = ExtendedError::new(
+            ExtendedError::DNSSEC_BOGUS,
+            format!(
+                "the signature of {} {} does not verify",
+                owner,
+                type_name(r#type)
+            ),
+        );
+    }
+    Err(error)
+}
+
+/// What the NSEC or NSEC3 records of a response prove.
+#[derive(Debug)]
+pub(crate) enum Denial {
+    NxDomain,
+    /// The name exists without the type. delegation tells if the name is a zone cut
+    /// without DS records, an unsigned child zone.
+    NoData {
+        delegation: bool,
+        ttl: u32,
+    },
+    /// The name falls in an opt-out span or the proof costs too much to check, nothing
+    /// about it is secure.
+    Insecure,
+}
+
+/// The NSEC records in records signed by signer, with their owners.
+fn verified_nsecs(
+    records: &[RawRecord],
+    keys: &[Dnskey],
+    signer: &str,
+) -> Vec<(String, Nsec, u32)> {
+    let mut owners: Vec<&str> = records
+        .iter()
+        .filter(|r| r.r#type == NSEC && is_subdomain(&r.name, signer))
+        .map(|r| r.name.as_str())
+        .collect();
+    owners.dedup();
+    owners
+        .into_iter()
+        .filter_map(|owner| {
+            let ttl = verify_rrset(records, owner, NSEC, keys, signer).ok()?.ttl;
+            let record = records
+                .iter()
+                .find(|r| r.name == owner && r.r#type == NSEC)?;
+            Some((owner.to_string(), Nsec::parse(&record.rdata)?, ttl))
+        })
+        .collect()
+}
+
+/// The NSEC3 records in records signed by signer, with their owners.
+fn verified_nsec3s(
+    records: &[RawRecord],
+    keys: &[Dnskey],
+    signer: &str,
+) -> Vec<(String, Nsec3, u32)> {
+    let mut owners: Vec<&str> = records
+        .iter()
+        .filter(|r| r.r#type == NSEC3 && is_subdomain(&r.name, signer))
+        .map(|r| r.name.as_str())
+        .collect();
+    owners.dedup();
+    owners
+        .into_iter()
+        .filter_map(|owner| {
+            let ttl = verify_rrset(records, owner, NSEC3, keys, signer).ok()?.ttl;
+            let record = records
+                .iter()
+                .find(|r| r.name == owner && r.r#type == NSEC3)?;
+            Some((owner.to_string(), Nsec3::parse(&record.rdata)?, ttl))
+        })
+        .collect()
+}
+
+/// What the NSEC or NSEC3 records among records, signed by zone, prove about qname and
+/// qtype.
+fn denial(
+    records: &[RawRecord],
+    qname: &str,
+    qtype: u16,
+    keys: &[Dnskey],
+    zone: &str,
+) -> Result<Denial, ExtendedError> {
+    let nsecs = verified_nsecs(records, keys, zone);
+    let nsec3s = match nsecs.is_empty() {
+        true => verified_nsec3s(records, keys, zone),
+        false => Vec::new(),
+    };
+    prove(&nsecs, &nsec3s, qname, qtype, zone)
+}
+
+/// What validated NSEC or NSEC3 records of zone prove about qname and qtype.
+pub(crate) fn prove(
+    nsecs: &[(String, Nsec, u32)],
+    nsec3s: &[(String, Nsec3, u32)],
+    qname: &str,
+    qtype: u16,
+    zone: &str,
+) -> Result<Denial, ExtendedError> {
+    let missing = || {
+        ExtendedError::new(
+            ExtendedError::NSEC_MISSING,
+            format!(
+                "nothing proves {} {} does not exist",
+                qname,
+                type_name(qtype)
+            ),
+        )
+    };
+    if !nsecs.is_empty() {
+        return nsec_denial(nsecs, qname, qtype).ok_or_else(missing);
+    }
+    if nsec3s
+        .iter()
+        .any(|(_, n, _)| n.iterations > MAX_NSEC3_ITERATIONS)
+    {
+        debug!("NSEC3 records of {:?} take too many iterations", zone);
+        return Ok(Denial::Insecure);
+    }
+    if !nsec3s.is_empty() {
+        return nsec3_denial(nsec3s, qname, qtype, zone).ok_or_else(missing);
+    }
+    Err(missing())
+}
+
+/// RFC 4035 5.4: an NSEC record of qname without the type, or NSEC records covering qname
+/// and the wildcard that could have matched it.
+fn nsec_denial(nsecs: &[(String, Nsec, u32)], qname: &str, qtype: u16) -> Option<Denial> {
+    let ttl = nsecs.iter().map(|(_, _, ttl)| *ttl).min().unwrap_or(0);
+    if let Some((_, nsec, _)) = nsecs.iter().find(|(owner, _, _)| owner == qname) {
+        let delegation = nsec.has_type(NS) && !nsec.has_type(SOA);
+        // At a zone cut the parent only speaks for the DS records
+        if nsec.has_type(qtype) || nsec.has_type(CNAME) || (delegation && qtype != DS) {
+            return None;
+        }
+        return Some(Denial::NoData { delegation, ttl });
+    }
+    let (owner, covering, _) = nsecs.iter().find(|(o, n, _)| n.covers(o, qname))?;
+    // Names below a zone cut are in the child zone, whatever the parent's span says
+    if is_subdomain(qname, owner) && covering.has_type(NS) && !covering.has_type(SOA) {
+        return None;
+    }
+    // An empty non-terminal has no NSEC of its own, but names below it come next
+    if is_subdomain(&covering.next, qname) {
+        return Some(Denial::NoData {
+            delegation: false,
+            ttl,
+        });
+    }
+    // The closest encloser is the deepest ancestor of qname either end of the span is in
+    let mut encloser = qname;
+    while !(is_subdomain(owner, encloser) || is_subdomain(&covering.next, encloser)) {
+        encloser = parent(encloser)?;
+    }
+    let wildcard = prepend_wildcard(encloser);
+    if let Some((_, nsec, _)) = nsecs.iter().find(|(o, _, _)| *o == wildcard) {
+        // The wildcard exists without the type
+        return (!nsec.has_type(qtype) && !nsec.has_type(CNAME)).then_some(Denial::NoData {
+            delegation: false,
+            ttl,
+        });
+    }
+    nsecs
+        .iter()
+        .any(|(o, n, _)| n.covers(o, &wildcard))
+        .then_some(Denial::NxDomain)
+}
+
+/// RFC 5155 8: an NSEC3 record matching qname without the type, or the closest encloser
+/// proof with the wildcard below the closest encloser covered.
+fn nsec3_denial(
+    nsec3s: &[(String, Nsec3, u32)],
+    qname: &str,
+    qtype: u16,
+    zone: &str,
+) -> Option<Denial> {
+    let ttl = nsec3s.iter().map(|(_, _, ttl)| *ttl).min().unwrap_or(0);
+    let matching = |name: &str| {
+        nsec3s
+            .iter()
+            .find(|(owner, n, _)| n.hash(name).is_some_and(|h| n.matches(owner, &h)))
+    };
+    let covering = |name: &str| {
+        nsec3s
+            .iter()
+            .find(|(owner, n, _)| n.hash(name).is_some_and(|h| n.covers(owner, &h)))
+    };
+    if let Some((_, nsec3, _)) = matching(qname) {
+        let delegation = nsec3.has_type(NS) && !nsec3.has_type(SOA);
+        if nsec3.has_type(qtype) || nsec3.has_type(CNAME) || (delegation && qtype != DS) {
+            return None;
+        }
+        return Some(Denial::NoData { delegation, ttl });
+    }
+    let mut next_closer = qname;
+    let mut encloser = parent(qname)?;
+    let closest = loop {
+        if let Some((_, nsec3, _)) = matching(encloser) {
+            break nsec3;
+        }
+        if encloser == zone {
+            return None;
+        }
+        next_closer = encloser;
+        encloser = parent(encloser)?;
+    };
+    if closest.has_type(NS) && !closest.has_type(SOA) {
+        return None;
+    }
+    let (_, span, _) = covering(next_closer)?;
+    // An opt-out span may hide unsigned delegations, nothing below it can be proven
+    if span.opt_out() {
+        return Some(Denial::Insecure);
+    }
+    let wildcard = prepend_wildcard(encloser);
+    if let Some((_, nsec3, _)) = matching(&wildcard) {
+        return (!nsec3.has_type(qtype) && !nsec3.has_type(CNAME)).then_some(Denial::NoData {
+            delegation: false,
+            ttl,
+        });
+    }
+    covering(&wildcard).map(|_| Denial::NxDomain)
+}
+
+/// Whether NSEC or NSEC3 records among records prove that owner, answered from the
+/// wildcard below encloser, does not exist itself (RFC 4035 5.3.4, RFC 5155 8.8).
+fn no_closer_match(
+    records: &[RawRecord],
+    owner: &str,
+    encloser: &str,
+    keys: &[Dnskey],
+    signer: &str,
+) -> bool {
+    let nsecs = verified_nsecs(records, keys, signer);
+    if nsecs.iter().any(|(o, n, _)| n.covers(o, owner)) {
+        return true;
+    }
+    let next_closer = child_toward(encloser, owner);
+    verified_nsec3s(records, keys, signer)
+        .iter()
+        .any(|(o, n, _)| n.hash(&next_closer).is_some_and(|h| n.covers(o, &h)))
+}
+
+pub(crate) fn parent(name: &str) -> Option<&str> {
+    match name.split_once('.') {
+        Some((_, parent)) => Some(parent),
+        None if !name.is_empty() => Some(""),
+        None => None,
+    }
+}
+
+pub(crate) fn prepend_wildcard(name: &str) -> String {
+    if name.is_empty() {
+        "*".to_string()
+    } else {
+        format!("*.{}", name)
+    }
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32)
+}
+
+fn type_name(r#type: u16) -> String {
+    let name = match r#type {
+        1 => "A",
+        NS => "NS",
+        CNAME => "CNAME",
+        SOA => "SOA",
+        12 => "PTR",
+        15 => "MX",
+        16 => "TXT",
+        28 => "AAAA",
+        33 => "SRV",
+        DS => "DS",
+        DNSKEY => "DNSKEY",
+        NSEC => "NSEC",
+        NSEC3 => "NSEC3",
+        t => return format!("TYPE{}", t),
+    };
+    name.to_string()
+}
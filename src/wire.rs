@@ -0,0 +1,699 @@
+//! DNS wire format encoding for complete messages.
+//!
+//! rustdns only knows how to write queries, so responses sent to clients are encoded here.
+//! Names are compressed as described in RFC 1035 4.1.4. rustdns also lowercases names, so
+//! queries to upstream servers are encoded here too, and the question of their responses
+//! is read here with its case intact. Neither does rustdns know the DNSSEC record types, so
+//! upstream responses are decoded here, keeping every record as it was signed.
+
+use crate::dnserror::{DnsError, ExtendedError, Result};
+use crate::dnstools::EDNS_PAYLOAD_SIZE;
+use rustdns::{Extension, Message, Rcode, Record, Resource, QR, SOA};
+use std::collections::HashMap;
+
+/// Largest response sent over UDP to a client that did not advertise a payload size
+/// (RFC 1035 2.3.4).
+pub const MAX_UDP_PAYLOAD: usize = 512;
+
+/// Pointers can only address the first 16 KiB of a message.
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+const OPT: u16 = 41;
+const DO_BIT: u16 = 0x8000;
+const EDE_OPTION: u16 = 15;
+
+struct Encoder {
+    buf: Vec<u8>,
+    // Lowercased name suffix to the offset it was first written at
+    names: HashMap<String, usize>,
+}
+
+/// Splits a presentation format name into its labels, honouring \. and \DDD escapes.
+fn labels(name: &str) -> Result<Vec<Vec<u8>>> {
+    let mut labels = Vec::new();
+    let mut label = Vec::new();
+    let mut chars = name.bytes().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            b'.' => {
+                if label.is_empty() {
+                    if chars.peek().is_none() && labels.is_empty() {
+                        // The root, written as "."
+                        break;
+                    }
+                    return Err(invalid_name(name));
+                }
+                labels.push(std::mem::take(&mut label));
+            }
+            b'\\' => {
+                let digits: Vec<u8> = (0..3)
+                    .map_while(|_| chars.next_if(|d| d.is_ascii_digit()))
+                    .collect();
+                if digits.is_empty() {
+                    label.push(chars.next().ok_or_else(|| invalid_name(name))?);
+                } else if digits.len() == 3 {
+                    let value = digits.iter().fold(0u32, |v, d| v * 10 + (d - b'0') as u32);
+                    label.push(u8::try_from(value).map_err(|_| invalid_name(name))?);
+                } else {
+                    return Err(invalid_name(name));
+                }
+            }
+            c => label.push(c),
+        }
+    }
+    if !label.is_empty() {
+        labels.push(label);
+    }
+    if labels.iter().any(|l| l.len() > 63) {
+        return Err(invalid_name(name));
+    }
+    Ok(labels)
+}
+
+fn invalid_name(name: &str) -> DnsError {
+    DnsError::new(Rcode::ServFail).with_info(format!("Cannot encode name \"{}\"", name))
+}
+
+impl Encoder {
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn name(&mut self, name: &str, compress: bool) -> Result<()> {
+        let labels = labels(name)?;
+        for i in 0..labels.len() {
+            let suffix = labels[i..]
+                .iter()
+                .map(|l| String::from_utf8_lossy(l).to_ascii_lowercase())
+                .collect::<Vec<_>>()
+                .join(".");
+            if compress {
+                if let Some(offset) = self.names.get(&suffix) {
+                    self.u16(0xC000 | *offset as u16);
+                    return Ok(());
+                }
+            }
+            if self.buf.len() <= MAX_POINTER_OFFSET {
+                self.names.entry(suffix).or_insert(self.buf.len());
+            }
+            self.buf.push(labels[i].len() as u8);
+            self.buf.extend_from_slice(&labels[i]);
+        }
+        self.buf.push(0);
+        if labels.iter().map(|l| l.len() + 1).sum::<usize>() + 1 > 255 {
+            return Err(invalid_name(name));
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, record: &Record) -> Result<()> {
+        self.name(&record.name, true)?;
+        self.u16(record.resource.r#type() as u16);
+        self.u16(record.class as u16);
+        self.u32(record.ttl.as_secs().min(u32::MAX as u64) as u32);
+
+        let length_at = self.buf.len();
+        self.u16(0);
+        match &record.resource {
+            Resource::A(a) => self.buf.extend_from_slice(&a.octets()),
+            Resource::AAAA(a) => self.buf.extend_from_slice(&a.octets()),
+            Resource::CNAME(n) | Resource::NS(n) | Resource::PTR(n) => self.name(n, true)?,
+            Resource::MX(mx) => {
+                self.u16(mx.preference);
+                self.name(&mx.exchange, true)?;
+            }
+            Resource::TXT(txt) | Resource::SPF(txt) => {
+                for string in &txt.0 {
+                    // Character strings are at most 255 octets, longer text is split
+                    for chunk in string.chunks(255) {
+                        self.buf.push(chunk.len() as u8);
+                        self.buf.extend_from_slice(chunk);
+                    }
+                    if string.is_empty() {
+                        self.buf.push(0);
+                    }
+                }
+            }
+            Resource::SOA(soa) => {
+                self.name(&soa.mname, true)?;
+                let rname = if soa.rname.contains('@') {
+                    SOA::email_to_rname(&soa.rname).map_err(|_| invalid_name(&soa.rname))?
+                } else {
+                    soa.rname.clone()
+                };
+                self.name(&rname, true)?;
+                self.u32(soa.serial);
+                for d in [soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                    self.u32(d.as_secs().min(u32::MAX as u64) as u32);
+                }
+            }
+            Resource::SRV(srv) => {
+                self.u16(srv.priority);
+                self.u16(srv.weight);
+                self.u16(srv.port);
+                // RFC 2782 forbids compressing the target
+                self.name(&srv.name, false)?;
+            }
+            Resource::OPT | Resource::ANY => {
+                return Err(DnsError::new(Rcode::ServFail).with_info(format!(
+                    "Cannot encode a {} record",
+                    record.resource.r#type()
+                )))
+            }
+        }
+        let length = self.buf.len() - length_at - 2;
+        self.buf[length_at..length_at + 2].copy_from_slice(&(length as u16).to_be_bytes());
+        Ok(())
+    }
+
+    /// The OPT pseudo-record of RFC 6891 6.1.2, with options as (code, data) pairs.
+    fn opt(&mut self, extension: &Extension, options: &[(u16, Vec<u8>)]) {
+        self.buf.push(0);
+        self.u16(OPT);
+        self.u16(extension.payload_size);
+        self.buf.push(extension.extend_rcode);
+        self.buf.push(extension.version);
+        self.u16(if extension.dnssec_ok { DO_BIT } else { 0 });
+        let length: usize = options.iter().map(|(_, data)| 4 + data.len()).sum();
+        self.u16(length as u16);
+        for (code, data) in options {
+            self.u16(*code);
+            self.u16(data.len() as u16);
+            self.buf.extend_from_slice(data);
+        }
+    }
+}
+
+/// Encodes a whole message, including the answer, authority and additional sections.
+pub fn encode(msg: &Message) -> Result<Vec<u8>> {
+    encode_message(msg, &[])
+}
+
+/// Like [`encode`], with EDNS options as (code, data) pairs. They are left out if msg has
+/// no EDNS extension.
+pub fn encode_with_options(msg: &Message, options: &[(u16, Vec<u8>)]) -> Result<Vec<u8>> {
+    encode_message(msg, options)
+}
+
+fn encode_message(msg: &Message, options: &[(u16, Vec<u8>)]) -> Result<Vec<u8>> {
+    let mut e = Encoder {
+        buf: Vec::with_capacity(MAX_UDP_PAYLOAD),
+        names: HashMap::new(),
+    };
+    e.u16(msg.id);
+
+    let mut b = 0_u8;
+    b |= if msg.qr == QR::Response {
+        0b1000_0000
+    } else {
+        0
+    };
+    b |= ((msg.opcode as u8) << 3) & 0b0111_1000;
+    b |= if msg.aa { 0b0000_0100 } else { 0 };
+    b |= if msg.tc { 0b0000_0010 } else { 0 };
+    b |= if msg.rd { 0b0000_0001 } else { 0 };
+    e.buf.push(b);
+    let mut b = 0_u8;
+    b |= if msg.ra { 0b1000_0000 } else { 0 };
+    b |= if msg.ad { 0b0010_0000 } else { 0 };
+    b |= if msg.cd { 0b0001_0000 } else { 0 };
+    b |= (msg.rcode as u8) & 0b0000_1111;
+    e.buf.push(b);
+
+    e.u16(msg.questions.len() as u16);
+    e.u16(msg.answers.len() as u16);
+    e.u16(msg.authoritys.len() as u16);
+    e.u16(msg.additionals.len() as u16 + msg.extension.is_some() as u16);
+
+    for question in &msg.questions {
+        e.name(&question.name, true)?;
+        e.u16(question.r#type as u16);
+        e.u16(question.class as u16);
+    }
+    for record in msg
+        .answers
+        .iter()
+        .chain(&msg.authoritys)
+        .chain(&msg.additionals)
+    {
+        e.record(record)?;
+    }
+    if let Some(extension) = &msg.extension {
+        e.opt(extension, options);
+    }
+    Ok(e.buf)
+}
+
+/// Encodes a response so it fits in limit octets. A response that does not fit is sent
+/// with only its question and the TC bit set, telling the client to retry over TCP
+/// (RFC 2181 9). Additional records are dropped first since they are optional. An
+/// extended error is only sent to clients that sent EDNS.
+pub fn encode_response(
+    msg: &Message,
+    limit: usize,
+    extended_error: Option<&ExtendedError>,
+) -> Result<Vec<u8>> {
+    let options: Vec<(u16, Vec<u8>)> = extended_error
+        .map(|error| {
+            let mut data = error.code.to_be_bytes().to_vec();
+            data.extend_from_slice(error.text.as_bytes());
+            (EDE_OPTION, data)
+        })
+        .into_iter()
+        .collect();
+    let encoded = encode_message(msg, &options)?;
+    if encoded.len() <= limit {
+        return Ok(encoded);
+    }
+    let mut trimmed = msg.clone();
+    trimmed.additionals.clear();
+    let encoded = encode_message(&trimmed, &options)?;
+    if encoded.len() <= limit {
+        return Ok(encoded);
+    }
+    trimmed.answers.clear();
+    trimmed.authoritys.clear();
+    trimmed.tc = true;
+    encode_message(&trimmed, &options)
+}
+
+/// A response to a query that cannot be answered as a [`Message`], because it is malformed
+/// or asks for a type rustdns does not know. Only the header and the first question, if
+/// one can be read, are echoed back with rcode. None if packet has no complete header or
+/// is itself a response, which is never answered.
+pub fn encode_error(packet: &[u8], rcode: Rcode) -> Option<Vec<u8>> {
+    let header = packet.get(..12)?;
+    if header[2] & 0b1000_0000 != 0 {
+        return None;
+    }
+    let mut buf = Vec::with_capacity(MAX_UDP_PAYLOAD);
+    buf.extend_from_slice(&header[..2]);
+    // QR set, the opcode and RD copied, RA set
+    buf.push(0b1000_0000 | (header[2] & 0b0111_1001));
+    buf.push(0b1000_0000 | (rcode as u16 & 0x0F) as u8);
+    let question = raw_question(packet);
+    buf.extend((question.is_some() as u16).to_be_bytes());
+    buf.extend([0; 6]);
+    if let Some(question) = question {
+        buf.extend(name_wire(&question.labels, false));
+        buf.extend(question.r#type.to_be_bytes());
+        buf.extend(question.class.to_be_bytes());
+    }
+    Some(buf)
+}
+
+/// The header fields and first question of a message as they are on the wire, with the
+/// case of the name kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawQuestion {
+    pub id: u16,
+    pub is_response: bool,
+    pub question_count: u16,
+    pub labels: Vec<Vec<u8>>,
+    pub r#type: u16,
+    pub class: u16,
+}
+
+impl RawQuestion {
+    /// The name asked for, in the case it was sent in.
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(&self.labels.join(&b'.')).into_owned()
+    }
+
+    /// Whether other asks the same question. Names are compared byte for byte when
+    /// match_case is set, otherwise ignoring ASCII case.
+    pub fn same_question(&self, other: &RawQuestion, match_case: bool) -> bool {
+        self.r#type == other.r#type
+            && self.class == other.class
+            && self.labels.len() == other.labels.len()
+            && self.labels.iter().zip(&other.labels).all(|(a, b)| {
+                if match_case {
+                    a == b
+                } else {
+                    a.eq_ignore_ascii_case(b)
+                }
+            })
+    }
+}
+
+/// Reads the header and the first question of packet. None if the packet is too short or
+/// has no question, or the name is not made of plain labels, as a first question's name
+/// always is.
+pub fn raw_question(packet: &[u8]) -> Option<RawQuestion> {
+    let u16_at = |at: usize| -> Option<u16> {
+        Some(u16::from_be_bytes(packet.get(at..at + 2)?.try_into().ok()?))
+    };
+    let id = u16_at(0)?;
+    let is_response = packet.get(2)? & 0b1000_0000 != 0;
+    let question_count = u16_at(4)?;
+    if question_count == 0 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut at = 12;
+    loop {
+        let length = *packet.get(at)? as usize;
+        at += 1;
+        if length == 0 {
+            break;
+        }
+        if length > 63 {
+            return None;
+        }
+        labels.push(packet.get(at..at + length)?.to_vec());
+        at += length;
+    }
+    Some(RawQuestion {
+        id,
+        is_response,
+        question_count,
+        labels,
+        r#type: u16_at(at)?,
+        class: u16_at(at + 2)?,
+    })
+}
+
+/// A resource record as it was on the wire. Names in the RDATA are decompressed, and
+/// lowercased for the types RFC 4034 6.2 lists, so rdata is in the canonical form
+/// signatures are computed over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawRecord {
+    /// Lowercased and without the trailing dot, "" for the root.
+    pub name: String,
+    pub r#type: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata: Vec<u8>,
+}
+
+/// A decoded response. message holds the records rustdns knows, which is what the rest of
+/// Dash works with, the record lists hold every record of each section, OPT aside.
+#[derive(Debug, Clone)]
+pub struct RawMessage {
+    pub message: Message,
+    pub answers: Vec<RawRecord>,
+    pub authoritys: Vec<RawRecord>,
+    pub additionals: Vec<RawRecord>,
+    /// The options of the OPT record, as (code, data) pairs.
+    pub options: Vec<(u16, Vec<u8>)>,
+}
+
+impl RawMessage {
+    /// Every record of the answer and authority sections.
+    pub fn records(&self) -> impl Iterator<Item = &RawRecord> {
+        self.answers.iter().chain(&self.authoritys)
+    }
+}
+
+/// Record types rustdns can read, other records are left out of [`RawMessage::message`].
+const RUSTDNS_TYPES: [u16; 10] = [1, 2, 5, 6, 12, 15, 16, 28, 33, 99];
+const ANY: u16 = 255;
+
+/// Types whose names in the RDATA are lowercased in the canonical form. Other types on the
+/// list of RFC 4034 6.2 are obsolete or never signed by anyone.
+const NS: u16 = 2;
+const CNAME: u16 = 5;
+const SOA_TYPE: u16 = 6;
+const PTR: u16 = 12;
+const MX: u16 = 15;
+const SRV: u16 = 33;
+const DNAME: u16 = 39;
+const RRSIG: u16 = 46;
+
+struct Decoder<'a> {
+    packet: &'a [u8],
+    at: usize,
+}
+
+impl Decoder<'_> {
+    fn bytes(&mut self, n: usize) -> Option<&[u8]> {
+        let bytes = self.packet.get(self.at..self.at.checked_add(n)?)?;
+        self.at += n;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    /// Reads a possibly compressed name as its labels. Pointers must point back, which
+    /// rules out loops.
+    fn name(&mut self) -> Option<Vec<Vec<u8>>> {
+        let mut labels = Vec::new();
+        let mut at = self.at;
+        let mut end = None;
+        let mut length = 1;
+        loop {
+            let b = *self.packet.get(at)? as usize;
+            match b & 0xC0 {
+                0x00 if b == 0 => {
+                    at += 1;
+                    break;
+                }
+                0x00 => {
+                    labels.push(self.packet.get(at + 1..at + 1 + b)?.to_vec());
+                    length += b + 1;
+                    at += b + 1;
+                }
+                0xC0 => {
+                    let pointer = (b & 0x3F) << 8 | *self.packet.get(at + 1)? as usize;
+                    end.get_or_insert(at + 2);
+                    if pointer >= at {
+                        return None;
+                    }
+                    at = pointer;
+                }
+                _ => return None,
+            }
+            if length > 255 {
+                return None;
+            }
+        }
+        self.at = end.unwrap_or(at);
+        Some(labels)
+    }
+
+    fn record(&mut self) -> Option<RawRecord> {
+        let name = self.name()?;
+        let r#type = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let length = self.u16()? as usize;
+        let end = self.at.checked_add(length)?;
+        if end > self.packet.len() {
+            return None;
+        }
+        let mut rdata = Vec::with_capacity(length);
+        // Bytes before and after the names of the types with names in their RDATA
+        let (before, names) = match r#type {
+            NS | CNAME | PTR | DNAME => (0, 1),
+            MX => (2, 1),
+            SOA_TYPE => (0, 2),
+            SRV => (6, 1),
+            RRSIG => (18, 1),
+            _ => (length, 0),
+        };
+        rdata.extend_from_slice(self.bytes(before.min(length))?);
+        for _ in 0..names {
+            let labels = self.name()?;
+            if self.at > end {
+                return None;
+            }
+            rdata.extend(name_wire(&labels, true));
+        }
+        rdata.extend_from_slice(self.packet.get(self.at..end)?);
+        self.at = end;
+        Some(RawRecord {
+            name: name_string(&name),
+            r#type,
+            class,
+            ttl,
+            rdata,
+        })
+    }
+}
+
+/// name in presentation format, lowercased and without the trailing dot. Dots, backslashes
+/// and unprintable bytes in labels are escaped the way [`labels`] reads them.
+fn name_string(labels: &[Vec<u8>]) -> String {
+    let mut name = String::new();
+    for (i, label) in labels.iter().enumerate() {
+        if i > 0 {
+            name.push('.');
+        }
+        for &b in label {
+            match b.to_ascii_lowercase() {
+                b @ (b'.' | b'\\') => {
+                    name.push('\\');
+                    name.push(b as char);
+                }
+                b if b.is_ascii_graphic() => name.push(b as char),
+                b => name.push_str(&format!("\\{:03}", b)),
+            }
+        }
+    }
+    name
+}
+
+fn name_wire(labels: &[Vec<u8>], lowercase: bool) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in labels {
+        wire.push(label.len() as u8);
+        if lowercase {
+            wire.extend(label.iter().map(u8::to_ascii_lowercase));
+        } else {
+            wire.extend_from_slice(label);
+        }
+    }
+    wire.push(0);
+    wire
+}
+
+/// name in the canonical wire format of RFC 4034 6.2: uncompressed and lowercased.
+pub fn canonical_name(name: &str) -> Result<Vec<u8>> {
+    Ok(name_wire(&labels(name)?, true))
+}
+
+/// The labels of name, lowercased, most significant last.
+pub fn name_labels(name: &str) -> Result<Vec<Vec<u8>>> {
+    Ok(labels(name)?
+        .into_iter()
+        .map(|l| l.to_ascii_lowercase())
+        .collect())
+}
+
+fn malformed() -> DnsError {
+    DnsError::new(Rcode::FormErr).with_info("Malformed message".to_string())
+}
+
+/// Decodes a query from a client or a response from an upstream server, keeping every
+/// record. Questions of types rustdns does not know are left out of message.
+pub fn decode(packet: &[u8]) -> Result<RawMessage> {
+    let mut d = Decoder { packet, at: 4 };
+    let counts: Vec<u16> = (0..4)
+        .map(|_| d.u16())
+        .collect::<Option<_>>()
+        .ok_or_else(malformed)?;
+    let mut questions = Vec::new();
+    for _ in 0..counts[0] {
+        let name = d.name().ok_or_else(malformed)?;
+        let r#type = d.u16().ok_or_else(malformed)?;
+        let class = d.u16().ok_or_else(malformed)?;
+        questions.push((name, r#type, class));
+    }
+    let mut sections = [Vec::new(), Vec::new(), Vec::new()];
+    let mut opt = None;
+    for (section, count) in sections.iter_mut().zip(&counts[1..]) {
+        for _ in 0..*count {
+            let record = d.record().ok_or_else(malformed)?;
+            if record.r#type == OPT {
+                opt = Some(record);
+            } else {
+                section.push(record);
+            }
+        }
+    }
+
+    // The records rustdns knows are handed to it in a message of their own, uncompressed
+    let mut known = packet[..4].to_vec();
+    let questions: Vec<_> = questions
+        .into_iter()
+        .filter(|(_, t, _)| RUSTDNS_TYPES.contains(t) || *t == ANY)
+        .collect();
+    known.extend((questions.len() as u16).to_be_bytes());
+    for (i, section) in sections.iter().enumerate() {
+        let count = section
+            .iter()
+            .filter(|r| RUSTDNS_TYPES.contains(&r.r#type))
+            .count()
+            + (i == 2 && opt.is_some()) as usize;
+        known.extend((count as u16).to_be_bytes());
+    }
+    for (name, r#type, class) in &questions {
+        known.extend(name_wire(name, false));
+        known.extend(r#type.to_be_bytes());
+        known.extend(class.to_be_bytes());
+    }
+    for record in sections
+        .iter()
+        .flatten()
+        .filter(|r| RUSTDNS_TYPES.contains(&r.r#type))
+        .chain(&opt)
+    {
+        known.extend(canonical_name(&record.name)?);
+        known.extend(record.r#type.to_be_bytes());
+        known.extend(record.class.to_be_bytes());
+        known.extend(record.ttl.to_be_bytes());
+        let rdata = if record.r#type == OPT {
+            &[][..]
+        } else {
+            &record.rdata[..]
+        };
+        known.extend((rdata.len() as u16).to_be_bytes());
+        known.extend_from_slice(rdata);
+    }
+    let message = Message::from_slice(&known)?;
+
+    let options = match &opt {
+        Some(opt) => edns_options(&opt.rdata).ok_or_else(malformed)?,
+        None => Vec::new(),
+    };
+    let [answers, authoritys, additionals] = sections;
+    Ok(RawMessage {
+        message,
+        answers,
+        authoritys,
+        additionals,
+        options,
+    })
+}
+
+/// The options in the RDATA of an OPT record (RFC 6891 6.1.2).
+fn edns_options(rdata: &[u8]) -> Option<Vec<(u16, Vec<u8>)>> {
+    let mut d = Decoder {
+        packet: rdata,
+        at: 0,
+    };
+    let mut options = Vec::new();
+    while d.at < rdata.len() {
+        let code = d.u16()?;
+        let length = d.u16()? as usize;
+        options.push((code, d.bytes(length)?.to_vec()));
+    }
+    Some(options)
+}
+
+/// A query for name of type r#type, which rustdns need not know, asking for DNSSEC records
+/// if dnssec_ok is set. Recursion is not desired.
+pub fn encode_query(id: u16, name: &str, r#type: u16, dnssec_ok: bool) -> Result<Vec<u8>> {
+    let mut e = Encoder {
+        buf: Vec::with_capacity(MAX_UDP_PAYLOAD),
+        names: HashMap::new(),
+    };
+    e.u16(id);
+    e.u16(0);
+    e.u16(1);
+    e.u16(0);
+    e.u16(0);
+    e.u16(1);
+    e.name(name, false)?;
+    e.u16(r#type);
+    e.u16(1);
+    e.opt(
+        &Extension {
+            payload_size: EDNS_PAYLOAD_SIZE,
+            dnssec_ok,
+            ..Default::default()
+        },
+        &[],
+    );
+    Ok(e.buf)
+}
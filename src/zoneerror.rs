@@ -0,0 +1,41 @@
+pub type Result<T> = std::result::Result<T, ZoneError>;
+
+#[derive(Debug, Clone)]
+pub enum ZoneErrorReason {
+    Io(String),
+    Syntax(String),
+    InvalidZone(String),
+}
+
+impl std::fmt::Display for ZoneErrorReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ZoneErrorReason::Io(s) => write!(f, "Io: {}", s),
+            ZoneErrorReason::Syntax(s) => write!(f, "Syntax: {}", s),
+            ZoneErrorReason::InvalidZone(s) => write!(f, "InvalidZone: {}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ZoneError {
+    reason: ZoneErrorReason,
+}
+
+impl ZoneError {
+    pub fn new(reason: ZoneErrorReason) -> ZoneError {
+        ZoneError { reason }
+    }
+
+    pub fn reason(&self) -> &ZoneErrorReason {
+        &self.reason
+    }
+}
+
+impl std::fmt::Display for ZoneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ZoneError: {}", self.reason)
+    }
+}
+
+impl std::error::Error for ZoneError {}
@@ -0,0 +1,483 @@
+//! Parser for RFC 1035 master files (section 5).
+//!
+//! Supports the $ORIGIN, $TTL and $INCLUDE directives, relative owner names and "@",
+//! blank owners repeating the previous one, parentheses spanning lines, quoted strings,
+//! comments and BIND style TTL units such as 1h30m. The record types are the ones rustdns
+//! can represent: A, AAAA, NS, CNAME, PTR, MX, TXT, SPF, SOA and SRV.
+
+use crate::zoneerror::{Result, ZoneError, ZoneErrorReason};
+use rustdns::{Class, Record, Resource, Type, MX, SOA, SRV, TXT};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Nesting depth at which $INCLUDE is assumed to be looping.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// One logical line, which may span several physical lines inside parentheses.
+struct Entry {
+    line: usize,
+    // The owner is omitted when the line starts with a blank
+    starts_with_blank: bool,
+    tokens: Vec<Token>,
+}
+
+fn tokenize(text: &str, source: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    let mut depth = 0;
+    let mut entry: Option<Entry> = None;
+    let mut at_line_start = true;
+
+    while let Some(c) = chars.next() {
+        let current = entry.get_or_insert_with(|| Entry {
+            line,
+            starts_with_blank: at_line_start && (c == ' ' || c == '\t'),
+            tokens: Vec::new(),
+        });
+        at_line_start = false;
+        match c {
+            '\n' => {
+                line += 1;
+                at_line_start = true;
+                if depth == 0 {
+                    if let Some(e) = entry.take().filter(|e| !e.tokens.is_empty()) {
+                        entries.push(e);
+                    }
+                }
+            }
+            ' ' | '\t' | '\r' => (),
+            ';' => while chars.next_if(|c| *c != '\n').is_some() {},
+            '(' => depth += 1,
+            ')' => {
+                if depth == 0 {
+                    return Err(syntax(source, line, "unbalanced \")\""));
+                }
+                depth -= 1;
+            }
+            '"' => {
+                let mut token = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            token.push('\\');
+                            if let Some(escaped) = chars.next() {
+                                token.push(escaped);
+                            }
+                        }
+                        Some('\n') | None => {
+                            return Err(syntax(source, line, "unterminated quoted string"))
+                        }
+                        Some(c) => token.push(c),
+                    }
+                }
+                current.tokens.push(Token {
+                    text: token,
+                    quoted: true,
+                });
+            }
+            c => {
+                let mut token = c.to_string();
+                if c == '\\' {
+                    if let Some(escaped) = chars.next() {
+                        token.push(escaped);
+                    }
+                }
+                while let Some(c) = chars
+                    .next_if(|c| !matches!(c, ' ' | '\t' | '\r' | '\n' | ';' | '(' | ')' | '"'))
+                {
+                    token.push(c);
+                    if c == '\\' {
+                        if let Some(escaped) = chars.next() {
+                            token.push(escaped);
+                        }
+                    }
+                }
+                current.tokens.push(Token {
+                    text: token,
+                    quoted: false,
+                });
+            }
+        }
+    }
+    if depth != 0 {
+        return Err(syntax(source, line, "unbalanced \"(\""));
+    }
+    if let Some(e) = entry.filter(|e| !e.tokens.is_empty()) {
+        entries.push(e);
+    }
+    Ok(entries)
+}
+
+fn syntax(source: &str, line: usize, info: &str) -> ZoneError {
+    ZoneError::new(ZoneErrorReason::Syntax(format!(
+        "{}:{}: {}",
+        source, line, info
+    )))
+}
+
+/// Parses a TTL given in seconds or with w, d, h, m and s units.
+pub fn parse_ttl(s: &str) -> Option<Duration> {
+    if s.is_empty() {
+        return None;
+    }
+    if let Ok(seconds) = s.parse::<u32>() {
+        return Some(Duration::from_secs(seconds as u64));
+    }
+    let mut total: u64 = 0;
+    let mut value: Option<u64> = None;
+    for c in s.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            value = Some(value.unwrap_or(0).checked_mul(10)? + digit as u64);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            'w' => 604_800,
+            'd' => 86_400,
+            'h' => 3_600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        total = total.checked_add(value.take()?.checked_mul(unit)?)?;
+    }
+    if value.is_some() {
+        return None;
+    }
+    (total <= u32::MAX as u64).then(|| Duration::from_secs(total))
+}
+
+/// True if the last character of name is a dot that is not escaped.
+fn is_absolute(name: &str) -> bool {
+    let trailing_backslashes = name
+        .strip_suffix('.')
+        .map(|n| n.chars().rev().take_while(|c| *c == '\\').count());
+    matches!(trailing_backslashes, Some(n) if n % 2 == 0)
+}
+
+/// Turns a name as written in the file into an absolute name without the trailing dot.
+fn absolute_name(name: &str, origin: &str) -> String {
+    if name == "@" {
+        origin.to_string()
+    } else if name == "." {
+        String::new()
+    } else if is_absolute(name) {
+        name[..name.len() - 1].to_string()
+    } else if origin.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, origin)
+    }
+}
+
+/// Resolves \X and \DDD escapes inside a character string.
+fn unescape(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut iter = text.bytes().peekable();
+    while let Some(b) = iter.next() {
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+        let digits: Vec<u8> = (0..3)
+            .map_while(|_| iter.next_if(|d| d.is_ascii_digit()))
+            .collect();
+        if digits.len() == 3 {
+            let value = digits.iter().fold(0u32, |v, d| v * 10 + (d - b'0') as u32);
+            bytes.push(value.min(255) as u8);
+        } else {
+            bytes.extend_from_slice(&digits);
+            if digits.is_empty() {
+                if let Some(escaped) = iter.next() {
+                    bytes.push(escaped);
+                }
+            }
+        }
+    }
+    bytes
+}
+
+struct Parser {
+    records: Vec<Record>,
+    default_ttl: Option<Duration>,
+    last_ttl: Option<Duration>,
+}
+
+impl Parser {
+    fn parse_text(
+        &mut self,
+        text: &str,
+        source: &str,
+        directory: Option<&Path>,
+        mut origin: String,
+        depth: usize,
+    ) -> Result<()> {
+        let mut last_owner: Option<String> = None;
+        for entry in tokenize(text, source)? {
+            let line = entry.line;
+            let err = |info: String| syntax(source, line, &info);
+            let tokens = &entry.tokens;
+            let first = tokens[0].text.to_ascii_uppercase();
+
+            if !entry.starts_with_blank && first.starts_with('$') {
+                match (first.as_str(), tokens.len()) {
+                    ("$ORIGIN", 2) => {
+                        origin = absolute_name(&tokens[1].text, &origin);
+                    }
+                    ("$TTL", 2) => {
+                        self.default_ttl =
+                            Some(parse_ttl(&tokens[1].text).ok_or_else(|| {
+                                err(format!("invalid TTL \"{}\"", tokens[1].text))
+                            })?);
+                    }
+                    ("$INCLUDE", 2 | 3) => {
+                        if depth >= MAX_INCLUDE_DEPTH {
+                            return Err(err("$INCLUDE nested too deeply".to_string()));
+                        }
+                        let mut path = PathBuf::from(&tokens[1].text);
+                        if let (true, Some(directory)) = (path.is_relative(), directory) {
+                            path = directory.join(path);
+                        }
+                        // The origin given to an included file does not leak back out
+                        let include_origin = match tokens.get(2) {
+                            Some(t) => absolute_name(&t.text, &origin),
+                            None => origin.clone(),
+                        };
+                        self.parse_path(&path, include_origin, depth + 1)?;
+                    }
+                    _ => {
+                        return Err(err(format!(
+                            "unknown or malformed directive \"{}\"",
+                            tokens[0].text
+                        )))
+                    }
+                }
+                continue;
+            }
+
+            let mut rest = tokens.iter();
+            let owner = if entry.starts_with_blank {
+                last_owner
+                    .clone()
+                    .ok_or_else(|| err("record without an owner name".to_string()))?
+            } else {
+                absolute_name(&rest.next().unwrap().text, &origin)
+            };
+            last_owner = Some(owner.clone());
+
+            let mut ttl = None;
+            let mut rtype = None;
+            for token in rest.by_ref() {
+                if token.quoted {
+                    return Err(err(format!(
+                        "expected a record type, found \"{}\"",
+                        token.text
+                    )));
+                }
+                let upper = token.text.to_ascii_uppercase();
+                if ttl.is_none() && token.text.starts_with(|c: char| c.is_ascii_digit()) {
+                    ttl = Some(
+                        parse_ttl(&token.text)
+                            .ok_or_else(|| err(format!("invalid TTL \"{}\"", token.text)))?,
+                    );
+                } else if matches!(upper.as_str(), "IN" | "CH" | "HS" | "CS") {
+                    if upper != "IN" {
+                        return Err(err(format!("class {} is not supported", upper)));
+                    }
+                } else {
+                    rtype = Some(
+                        upper
+                            .parse::<Type>()
+                            .map_err(|_| err(format!("unknown record type \"{}\"", token.text)))?,
+                    );
+                    break;
+                }
+            }
+            let rtype = rtype.ok_or_else(|| err("record without a type".to_string()))?;
+            let rdata: Vec<&Token> = rest.collect();
+            let resource = parse_rdata(rtype, &rdata, &origin).map_err(err)?;
+
+            let ttl = match (ttl, &resource) {
+                (Some(ttl), _) => {
+                    self.last_ttl = Some(ttl);
+                    ttl
+                }
+                (None, _) if self.default_ttl.or(self.last_ttl).is_some() => {
+                    self.default_ttl.or(self.last_ttl).unwrap()
+                }
+                // RFC 2308 4: without $TTL the SOA minimum has been the default
+                (None, Resource::SOA(soa)) => {
+                    self.last_ttl = Some(soa.minimum);
+                    soa.minimum
+                }
+                (None, _) => return Err(err("no TTL given and no $TTL set".to_string())),
+            };
+
+            self.records.push(Record {
+                name: owner,
+                class: Class::Internet,
+                ttl,
+                resource,
+            });
+        }
+        Ok(())
+    }
+
+    fn parse_path(&mut self, path: &Path, origin: String, depth: usize) -> Result<()> {
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            ZoneError::new(ZoneErrorReason::Io(format!(
+                "reading {}: {}",
+                path.display(),
+                e
+            )))
+        })?;
+        self.parse_text(
+            &text,
+            &path.display().to_string(),
+            path.parent(),
+            origin,
+            depth,
+        )
+    }
+}
+
+fn parse_rdata(
+    rtype: Type,
+    rdata: &[&Token],
+    origin: &str,
+) -> std::result::Result<Resource, String> {
+    let expect = |count: usize| {
+        if rdata.len() == count {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} needs {} fields of data, found {}",
+                rtype,
+                count,
+                rdata.len()
+            ))
+        }
+    };
+    let number = |token: &Token| {
+        token
+            .text
+            .parse::<u16>()
+            .map_err(|_| format!("invalid number \"{}\" in {} record", token.text, rtype))
+    };
+    let duration = |token: &Token| {
+        parse_ttl(&token.text)
+            .ok_or_else(|| format!("invalid time \"{}\" in {} record", token.text, rtype))
+    };
+    let name = |token: &Token| absolute_name(&token.text, origin);
+
+    Ok(match rtype {
+        Type::A => {
+            expect(1)?;
+            Resource::A(
+                rdata[0]
+                    .text
+                    .parse::<Ipv4Addr>()
+                    .map_err(|_| format!("invalid IPv4 address \"{}\"", rdata[0].text))?,
+            )
+        }
+        Type::AAAA => {
+            expect(1)?;
+            Resource::AAAA(
+                rdata[0]
+                    .text
+                    .parse::<Ipv6Addr>()
+                    .map_err(|_| format!("invalid IPv6 address \"{}\"", rdata[0].text))?,
+            )
+        }
+        Type::NS => {
+            expect(1)?;
+            Resource::NS(name(rdata[0]))
+        }
+        Type::CNAME => {
+            expect(1)?;
+            Resource::CNAME(name(rdata[0]))
+        }
+        Type::PTR => {
+            expect(1)?;
+            Resource::PTR(name(rdata[0]))
+        }
+        Type::MX => {
+            expect(2)?;
+            Resource::MX(MX {
+                preference: number(rdata[0])?,
+                exchange: name(rdata[1]),
+            })
+        }
+        Type::SRV => {
+            expect(4)?;
+            Resource::SRV(SRV {
+                priority: number(rdata[0])?,
+                weight: number(rdata[1])?,
+                port: number(rdata[2])?,
+                name: name(rdata[3]),
+            })
+        }
+        Type::TXT | Type::SPF => {
+            if rdata.is_empty() {
+                return Err(format!("{} needs at least one string", rtype));
+            }
+            let txt = TXT(rdata.iter().map(|t| unescape(&t.text)).collect());
+            if rtype == Type::TXT {
+                Resource::TXT(txt)
+            } else {
+                Resource::SPF(txt)
+            }
+        }
+        Type::SOA => {
+            expect(7)?;
+            let rname = name(rdata[1]);
+            Resource::SOA(SOA {
+                mname: name(rdata[0]),
+                // rustdns keeps the mailbox as an email address
+                rname: SOA::rname_to_email(&rname)
+                    .map_err(|_| format!("invalid SOA mailbox \"{}\"", rdata[1].text))?,
+                serial: rdata[2]
+                    .text
+                    .parse::<u32>()
+                    .map_err(|_| format!("invalid SOA serial \"{}\"", rdata[2].text))?,
+                refresh: duration(rdata[3])?,
+                retry: duration(rdata[4])?,
+                expire: duration(rdata[5])?,
+                minimum: duration(rdata[6])?,
+            })
+        }
+        _ => return Err(format!("record type {} is not supported", rtype)),
+    })
+}
+
+/// Reads the zone file at path. Relative names are taken relative to origin until an
+/// $ORIGIN directive says otherwise. Owner names are returned absolute, without the
+/// trailing dot.
+pub fn parse_zone_file(path: &Path, origin: &str) -> Result<Vec<Record>> {
+    let mut parser = Parser {
+        records: Vec::new(),
+        default_ttl: None,
+        last_ttl: None,
+    };
+    parser.parse_path(path, origin.to_string(), 0)?;
+    Ok(parser.records)
+}
+
+/// Same as [`parse_zone_file`] for text already in memory. $INCLUDE paths are taken
+/// relative to the working directory.
+pub fn parse_zone(text: &str, origin: &str) -> Result<Vec<Record>> {
+    let mut parser = Parser {
+        records: Vec::new(),
+        default_ttl: None,
+        last_ttl: None,
+    };
+    parser.parse_text(text, "<zone>", None, origin.to_string(), 0)?;
+    Ok(parser.records)
+}
@@ -0,0 +1,354 @@
+//! Known cache poisoning patterns against response scrubbing and credibility ranking.
+
+use dash::bailiwick::{chain_end, scrub};
+use dash::config::{Config, QnameMinimization, StubZoneConfig};
+use dash::credibility::Credibility;
+use dash::dashcontext::DashContext;
+use dash::delegationcache::DelegationCache;
+use dash::resolver::resolve_message_query;
+use dash::wire::canonical_name;
+use rustdns::{Class, Message, Rcode, Record, Resource, Type, QR};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn record(name: &str, resource: Resource) -> Record {
+    Record {
+        name: name.to_string(),
+        class: Class::Internet,
+        ttl: Duration::from_secs(300),
+        resource,
+    }
+}
+
+fn a(name: &str, ip: [u8; 4]) -> Record {
+    record(name, Resource::A(Ipv4Addr::from(ip)))
+}
+
+fn ns(name: &str, target: &str) -> Record {
+    record(name, Resource::NS(target.to_string()))
+}
+
+fn cname(name: &str, target: &str) -> Record {
+    record(name, Resource::CNAME(target.to_string()))
+}
+
+fn response(qname: &str, qtype: Type) -> Message {
+    let mut rsp = Message {
+        qr: QR::Response,
+        rcode: Rcode::NoError,
+        ..Default::default()
+    };
+    rsp.add_question(qname, qtype, Class::Internet);
+    rsp
+}
+
+fn names(records: &[Record]) -> Vec<&str> {
+    records.iter().map(|r| r.name.as_str()).collect()
+}
+
+#[test]
+fn keeps_a_clean_authoritative_answer() {
+    let mut rsp = response("www.example.com", Type::A);
+    rsp.aa = true;
+    rsp.answers.push(a("www.example.com.", [192, 0, 2, 1]));
+    rsp.authoritys.push(ns("example.com.", "ns1.example.com."));
+    rsp.additionals.push(a("ns1.example.com.", [192, 0, 2, 53]));
+
+    assert_eq!(scrub(&mut rsp, "www.example.com.", "example.com"), 0);
+    assert_eq!(rsp.answers.len(), 1);
+    assert_eq!(rsp.authoritys.len(), 1);
+    assert_eq!(rsp.additionals.len(), 1);
+}
+
+#[test]
+fn drops_unrelated_answer_records() {
+    // A server for evil.example answers for bank.example alongside its own name
+    let mut rsp = response("www.evil.example", Type::A);
+    rsp.aa = true;
+    rsp.answers.push(a("www.evil.example.", [198, 51, 100, 1]));
+    rsp.answers.push(a("www.bank.example.", [198, 51, 100, 66]));
+
+    scrub(&mut rsp, "www.evil.example.", "evil.example");
+    assert_eq!(names(&rsp.answers), ["www.evil.example."]);
+}
+
+#[test]
+fn stops_a_cname_chain_at_the_bailiwick() {
+    // The CNAME is the server's to give, the address of its target is not
+    let mut rsp = response("login.evil.example", Type::A);
+    rsp.aa = true;
+    rsp.answers
+        .push(cname("login.evil.example.", "www.bank.example."));
+    rsp.answers.push(a("www.bank.example.", [198, 51, 100, 66]));
+
+    scrub(&mut rsp, "login.evil.example.", "evil.example");
+    assert_eq!(names(&rsp.answers), ["login.evil.example."]);
+    assert_eq!(chain_end(&rsp, "login.evil.example."), "www.bank.example");
+}
+
+#[test]
+fn follows_a_cname_chain_inside_the_bailiwick() {
+    let mut rsp = response("www.example.com", Type::A);
+    rsp.aa = true;
+    rsp.answers
+        .push(cname("www.example.com.", "web.example.com."));
+    rsp.answers
+        .push(cname("web.example.com.", "cdn.example.com."));
+    rsp.answers.push(a("cdn.example.com.", [192, 0, 2, 7]));
+
+    assert_eq!(scrub(&mut rsp, "www.example.com.", "example.com"), 0);
+    assert_eq!(chain_end(&rsp, "www.example.com."), "cdn.example.com");
+}
+
+#[test]
+fn drops_out_of_bailiwick_glue_in_a_referral() {
+    // A .example server refers to evil.example and slips in an address for a bank nameserver
+    let mut rsp = response("www.evil.example", Type::A);
+    rsp.authoritys
+        .push(ns("evil.example.", "ns1.evil.example."));
+    rsp.authoritys.push(ns("evil.example.", "ns.bank.test."));
+    rsp.additionals
+        .push(a("ns1.evil.example.", [198, 51, 100, 53]));
+    rsp.additionals.push(a("ns.bank.test.", [198, 51, 100, 66]));
+
+    scrub(&mut rsp, "www.evil.example.", "example");
+    assert_eq!(rsp.authoritys.len(), 2);
+    assert_eq!(names(&rsp.additionals), ["ns1.evil.example."]);
+}
+
+#[test]
+fn drops_glue_for_names_no_record_points_at() {
+    let mut rsp = response("www.evil.example", Type::A);
+    rsp.authoritys
+        .push(ns("evil.example.", "ns1.evil.example."));
+    rsp.additionals
+        .push(a("ns1.evil.example.", [198, 51, 100, 53]));
+    rsp.additionals
+        .push(a("mail.evil.example.", [198, 51, 100, 25]));
+
+    scrub(&mut rsp, "www.evil.example.", "example");
+    assert_eq!(names(&rsp.additionals), ["ns1.evil.example."]);
+}
+
+#[test]
+fn drops_authority_records_for_other_zones() {
+    // The classic: an answer for the attacker's name with NS records for a victim zone
+    let mut rsp = response("www.evil.example", Type::A);
+    rsp.aa = true;
+    rsp.answers.push(a("www.evil.example.", [198, 51, 100, 1]));
+    rsp.authoritys.push(ns("bank.example.", "ns.evil.example."));
+    rsp.authoritys.push(ns("evil.example.", "ns.evil.example."));
+    rsp.additionals
+        .push(a("ns.evil.example.", [198, 51, 100, 53]));
+
+    scrub(&mut rsp, "www.evil.example.", "evil.example");
+    assert_eq!(names(&rsp.authoritys), ["evil.example."]);
+}
+
+#[test]
+fn drops_referrals_that_do_not_go_down() {
+    // A server for sub.example.com cannot hand out a delegation for example.com or for
+    // itself
+    let mut rsp = response("www.sub.example.com", Type::A);
+    rsp.authoritys.push(ns("example.com.", "ns.evil.example."));
+    rsp.authoritys
+        .push(ns("sub.example.com.", "ns.evil.example."));
+
+    scrub(&mut rsp, "www.sub.example.com.", "sub.example.com");
+    assert!(rsp.authoritys.is_empty());
+}
+
+#[test]
+fn drops_referrals_to_zones_not_containing_the_query() {
+    let mut rsp = response("www.example.com", Type::A);
+    rsp.authoritys.push(ns("other.com.", "ns.other.com."));
+    rsp.additionals.push(a("ns.other.com.", [198, 51, 100, 53]));
+
+    scrub(&mut rsp, "www.example.com.", "com");
+    assert!(rsp.authoritys.is_empty());
+    assert!(rsp.additionals.is_empty());
+}
+
+#[test]
+fn forwarders_are_trusted_for_the_chain_only() {
+    // A forwarder may answer for any name, but only records on the way to the answer count
+    let mut rsp = response("www.example.com", Type::A);
+    rsp.answers
+        .push(cname("www.example.com.", "edge.cdn.test."));
+    rsp.answers.push(a("edge.cdn.test.", [192, 0, 2, 80]));
+    rsp.answers.push(a("www.bank.example.", [198, 51, 100, 66]));
+
+    scrub(&mut rsp, "www.example.com.", "");
+    assert_eq!(names(&rsp.answers), ["www.example.com.", "edge.cdn.test."]);
+}
+
+#[test]
+fn credibility_follows_rfc_2181() {
+    assert!(Credibility::AuthoritativeAnswer > Credibility::AuthoritativeAuthority);
+    assert!(Credibility::AuthoritativeAuthority > Credibility::NonAuthoritativeAnswer);
+    assert!(Credibility::NonAuthoritativeAnswer > Credibility::Referral);
+    assert!(Credibility::Referral > Credibility::Additional);
+
+    let mut rsp = response("www.example.com", Type::A);
+    rsp.answers.push(a("www.example.com.", [192, 0, 2, 1]));
+    assert_eq!(
+        Credibility::of_answer(&rsp),
+        Credibility::NonAuthoritativeAnswer
+    );
+    rsp.aa = true;
+    assert_eq!(
+        Credibility::of_answer(&rsp),
+        Credibility::AuthoritativeAnswer
+    );
+}
+
+#[test]
+fn referral_glue_does_not_replace_authoritative_nameservers() {
+    // Kaminsky style: a forged referral for the zone races the data its own servers gave
+    let cache = DelegationCache::new();
+    let real: SocketAddr = "192.0.2.53:53".parse().unwrap();
+    let forged: SocketAddr = "198.51.100.53:53".parse().unwrap();
+    let ttl = Duration::from_secs(3600);
+
+    cache.insert(
+        "bank.example",
+        vec!["ns1.bank.example".to_string()],
+        vec![real],
+        ttl,
+        Credibility::AuthoritativeAuthority,
+    );
+    cache.insert(
+        "bank.example",
+        vec!["ns.evil.example".to_string()],
+        vec![forged],
+        ttl,
+        Credibility::Referral,
+    );
+    assert_eq!(
+        cache.closest("www.bank.example"),
+        Some(("bank.example".to_string(), vec![real]))
+    );
+}
+
+#[test]
+fn authoritative_nameservers_replace_referral_glue() {
+    let cache = DelegationCache::new();
+    let from_parent: SocketAddr = "192.0.2.1:53".parse().unwrap();
+    let from_child: SocketAddr = "192.0.2.2:53".parse().unwrap();
+    let ttl = Duration::from_secs(3600);
+
+    cache.insert(
+        "example.com",
+        vec!["a.example.com".to_string()],
+        vec![from_parent],
+        ttl,
+        Credibility::Referral,
+    );
+    cache.insert(
+        "example.com",
+        vec!["b.example.com".to_string()],
+        vec![from_child],
+        ttl,
+        Credibility::AuthoritativeAnswer,
+    );
+    assert_eq!(
+        cache.nameservers("example.com"),
+        Some((vec!["b.example.com".to_string()], vec![from_child]))
+    );
+}
+
+/// A stand-in for the nameservers of test. that delegates every zone below it without glue:
+/// loop.test to a nameserver inside it, a.test and b.test to nameservers inside each other.
+/// Returns its address and how many queries it was sent.
+fn serve_glueless() -> (SocketAddr, Arc<AtomicUsize>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    let asked = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&asked);
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let Ok((length, from)) = socket.recv_from(&mut buffer) else {
+                continue;
+            };
+            if let Some(response) = refer(&buffer[..length]) {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let _ = socket.send_to(&response, from);
+            }
+        }
+    });
+    (address, asked)
+}
+
+/// The referral for the child of test. the query is below.
+fn refer(query: &[u8]) -> Option<Vec<u8>> {
+    let mut at = 12;
+    let mut labels = Vec::new();
+    loop {
+        let length = *query.get(at)? as usize;
+        at += 1;
+        if length == 0 {
+            break;
+        }
+        labels.push(String::from_utf8_lossy(query.get(at..at + length)?).to_lowercase());
+        at += length;
+    }
+    let question = query.get(12..at + 4)?;
+    let child = labels[labels.len().checked_sub(2)?..].join(".");
+    let nameserver = match child.as_str() {
+        "a.test" => "ns.b.test",
+        "b.test" => "ns.a.test",
+        _ => "ns.loop.test",
+    };
+
+    let mut response = query[..2].to_vec();
+    response.extend([0x80, 0]);
+    for count in [1u16, 0, 1, 0] {
+        response.extend(count.to_be_bytes());
+    }
+    response.extend_from_slice(question);
+    let rdata = canonical_name(nameserver).unwrap();
+    response.extend(canonical_name(&child).unwrap());
+    response.extend(2u16.to_be_bytes());
+    response.extend(1u16.to_be_bytes());
+    response.extend(300u32.to_be_bytes());
+    response.extend((rdata.len() as u16).to_be_bytes());
+    response.extend(rdata);
+    Some(response)
+}
+
+fn resolve_glueless(qname: &str) -> (Rcode, usize) {
+    let (server, asked) = serve_glueless();
+    let mut config = Config::default();
+    config.resolver.root_servers = vec![Ipv4Addr::LOCALHOST];
+    config.resolver.stub_zones = vec![StubZoneConfig {
+        name: "test".to_string(),
+        nameservers: vec![server],
+    }];
+    config.resolver.query_timeout_ms = 500;
+    config.resolver.qname_minimization = QnameMinimization::Off;
+    config.dnssec.validation = false;
+    let context = DashContext::new(config).unwrap();
+
+    let mut msg = Message::default();
+    msg.add_question(qname, Type::A, Class::Internet);
+    let error = resolve_message_query(&msg, &context).unwrap_err();
+    (error.code(), asked.load(Ordering::Relaxed))
+}
+
+#[test]
+fn glueless_referral_to_a_nameserver_inside_the_zone_fails() {
+    let (rcode, asked) = resolve_glueless("www.loop.test");
+    assert_eq!(rcode, Rcode::ServFail);
+    // Looking the nameserver up would only get the same referral again
+    assert_eq!(asked, 1);
+}
+
+#[test]
+fn glueless_referrals_to_each_other_fail_instead_of_recursing_forever() {
+    let (rcode, asked) = resolve_glueless("www.a.test");
+    assert_eq!(rcode, Rcode::ServFail);
+    assert!(asked <= 100, "{} queries", asked);
+}
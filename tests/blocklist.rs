@@ -0,0 +1,228 @@
+//! Domain tries, the list formats blocklists are read from, and the answers blocked names
+//! get.
+
+use dash::blocklist::Blocklist;
+use dash::config::{BlockAction, BlocklistConfig, Config};
+use dash::dashcontext::DashContext;
+use dash::dashjob::DashJob;
+use dash::domaintrie::DomainTrie;
+use dash::lru_ttl_cache::Cache;
+use dash::threadpool::ThreadPoolJob;
+use dash::wire::decode;
+use rustdns::{Class, Message, Rcode, Resource, Type};
+use std::net::{Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[test]
+fn trie_get_only_matches_the_name_itself() {
+    let mut trie = DomainTrie::new();
+    assert!(trie.is_empty());
+    assert!(trie.insert("ads.example.com", 1));
+    assert!(trie.insert("example.org.", 2));
+    assert_eq!(trie.len(), 2);
+
+    assert_eq!(trie.get("ads.example.com"), Some(&1));
+    assert_eq!(trie.get("ADS.Example.COM."), Some(&1));
+    assert_eq!(trie.get("example.org"), Some(&2));
+    assert_eq!(trie.get("www.ads.example.com"), None);
+    assert_eq!(trie.get("example.com"), None);
+    assert_eq!(trie.get("com"), None);
+}
+
+#[test]
+fn trie_longest_match_finds_the_closest_enclosing_name() {
+    let mut trie = DomainTrie::new();
+    trie.insert("example.com", "example");
+    trie.insert("ads.example.com", "ads");
+
+    assert_eq!(trie.longest_match("example.com"), Some((2, &"example")));
+    assert_eq!(trie.longest_match("www.example.com"), Some((2, &"example")));
+    assert_eq!(trie.longest_match("ads.example.com."), Some((3, &"ads")));
+    assert_eq!(trie.longest_match("x.y.ads.example.com"), Some((3, &"ads")));
+    // Labels are compared whole, not as text suffixes
+    assert_eq!(trie.longest_match("badexample.com"), None);
+    assert_eq!(trie.longest_match("com"), None);
+
+    // The root encloses everything
+    trie.insert(".", "root");
+    assert_eq!(trie.longest_match("org"), Some((0, &"root")));
+}
+
+#[test]
+fn trie_keeps_the_first_value_for_a_name() {
+    let mut trie = DomainTrie::new();
+    assert!(trie.insert("example.com", 1));
+    assert!(!trie.insert("Example.com.", 2));
+    assert_eq!(trie.get("example.com"), Some(&1));
+    assert_eq!(trie.len(), 1);
+}
+
+/// Writes a list under the system temporary directory, every one with a name of its own as
+/// tests run in parallel.
+fn list_file(contents: &str) -> PathBuf {
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "dash-blocklist-{}-{}.txt",
+        std::process::id(),
+        FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn blocklist(lists: &[&str], allowlist: &[&str]) -> Blocklist {
+    let paths: Vec<PathBuf> = lists.iter().map(|l| list_file(l)).collect();
+    let config = BlocklistConfig {
+        lists: paths.clone(),
+        allowlist: allowlist.iter().map(|d| d.to_string()).collect(),
+        ..Default::default()
+    };
+    let blocklist = Blocklist::new(&config).unwrap();
+    for path in paths {
+        std::fs::remove_file(path).unwrap();
+    }
+    blocklist
+}
+
+#[test]
+fn every_list_format_blocks_the_domain_and_names_below_it() {
+    let blocklist = blocklist(
+        &[
+            "# hosts\n0.0.0.0 tracker.example localhost\n",
+            "! adblock\n||ads.example^\n||example.net/banner.js\n",
+            "plain.example\n*.wild.example\n",
+        ],
+        &[],
+    );
+    assert_eq!(blocklist.blocking_list("tracker.example"), Some(0));
+    assert_eq!(blocklist.blocking_list("cdn.tracker.example"), Some(0));
+    assert_eq!(blocklist.blocking_list("ads.example"), Some(1));
+    assert_eq!(blocklist.blocking_list("plain.example"), Some(2));
+    assert_eq!(blocklist.blocking_list("www.wild.example"), Some(2));
+
+    // Hosts file names for the machine itself and rules about paths are not domains
+    assert_eq!(blocklist.blocking_list("localhost"), None);
+    assert_eq!(blocklist.blocking_list("example.net"), None);
+    assert_eq!(blocklist.blocking_list("example"), None);
+    assert_eq!(blocklist.stats()[0].entries, 1);
+}
+
+#[test]
+fn allowlisted_domains_and_exceptions_are_never_blocked() {
+    let blocklist = blocklist(
+        &["ads.example\n@@||cdn.ads.example^\n"],
+        &["safe.ads.example"],
+    );
+    assert_eq!(blocklist.blocking_list("www.ads.example"), Some(0));
+    assert_eq!(blocklist.blocking_list("cdn.ads.example"), None);
+    assert_eq!(blocklist.blocking_list("img.cdn.ads.example"), None);
+    assert_eq!(blocklist.blocking_list("safe.ads.example"), None);
+}
+
+fn query(qname: &str, qtype: Type) -> Message {
+    let mut msg = Message {
+        id: 0x4d2,
+        rd: true,
+        ..Default::default()
+    };
+    msg.add_question(qname, qtype, Class::Internet);
+    msg
+}
+
+/// Runs a job for msg the way the server does and returns the response the client gets.
+fn respond(context: Arc<DashContext>, msg: Message) -> Message {
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let server = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+    let cache = Arc::new(Mutex::new(Cache::new(64)));
+    let job = DashJob::new(msg, client.local_addr().unwrap(), server, cache, context);
+    job.run_job();
+
+    let mut buffer = [0; 4096];
+    let len = client.recv(&mut buffer).unwrap();
+    decode(&buffer[..len]).unwrap().message
+}
+
+/// Answers qname and qtype from a context blocking ads.example with action.
+fn blocked_answer(action: BlockAction, qname: &str, qtype: Type) -> Message {
+    let list = list_file("ads.example\n");
+    let config = Config {
+        blocklist: BlocklistConfig {
+            lists: vec![list.clone()],
+            action,
+            sinkhole_ipv4: Some(Ipv4Addr::new(10, 0, 0, 250)),
+            sinkhole_ipv6: Some("fd00::250".parse().unwrap()),
+            ttl: 120,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let context = Arc::new(DashContext::new(config).unwrap());
+    std::fs::remove_file(list).unwrap();
+
+    let msg = query(qname, qtype);
+    let rsp = respond(context, msg.clone());
+    assert_eq!(rsp.id, msg.id);
+    assert_eq!(rsp.questions, msg.questions);
+    rsp
+}
+
+fn answers(rsp: &Message) -> Vec<(Resource, Duration)> {
+    rsp.answers
+        .iter()
+        .map(|r| (r.resource.clone(), r.ttl))
+        .collect()
+}
+
+#[test]
+fn nxdomain_action_denies_the_name() {
+    let rsp = blocked_answer(BlockAction::Nxdomain, "www.ads.example", Type::A);
+    assert_eq!(rsp.rcode, Rcode::NXDomain);
+    assert!(rsp.answers.is_empty());
+}
+
+#[test]
+fn nodata_action_answers_without_records() {
+    let rsp = blocked_answer(BlockAction::Nodata, "www.ads.example", Type::A);
+    assert_eq!(rsp.rcode, Rcode::NoError);
+    assert!(rsp.answers.is_empty());
+}
+
+#[test]
+fn null_action_answers_unspecified_addresses() {
+    let ttl = Duration::from_secs(120);
+    let rsp = blocked_answer(BlockAction::Null, "ads.example", Type::A);
+    assert_eq!(rsp.rcode, Rcode::NoError);
+    assert_eq!(
+        answers(&rsp),
+        vec![(Resource::A(Ipv4Addr::UNSPECIFIED), ttl)]
+    );
+    let rsp = blocked_answer(BlockAction::Null, "ads.example", Type::AAAA);
+    assert_eq!(
+        answers(&rsp),
+        vec![(Resource::AAAA(Ipv6Addr::UNSPECIFIED), ttl)]
+    );
+    let rsp = blocked_answer(BlockAction::Null, "ads.example", Type::TXT);
+    assert_eq!(rsp.rcode, Rcode::NoError);
+    assert!(rsp.answers.is_empty());
+}
+
+#[test]
+fn sinkhole_action_answers_the_sinkhole_addresses() {
+    let ttl = Duration::from_secs(120);
+    let rsp = blocked_answer(BlockAction::Sinkhole, "www.ads.example", Type::A);
+    assert_eq!(
+        answers(&rsp),
+        vec![(Resource::A(Ipv4Addr::new(10, 0, 0, 250)), ttl)]
+    );
+    let rsp = blocked_answer(BlockAction::Sinkhole, "www.ads.example", Type::AAAA);
+    assert_eq!(
+        answers(&rsp),
+        vec![(Resource::AAAA("fd00::250".parse().unwrap()), ttl)]
+    );
+}
@@ -0,0 +1,152 @@
+//! Per client and per rule limits on query rate and recursions in flight.
+
+use dash::clientlimits::{Admission, ClientLimits, InFlight};
+use dash::config::{ClientLimitRuleConfig, ClientLimitsConfig, LimitAction};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+fn limits(config: ClientLimitsConfig) -> Arc<ClientLimits> {
+    Arc::new(ClientLimits::new(&config))
+}
+
+fn client(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+/// The recursion slots of an admitted query, failing the test if it was limited.
+fn admitted(admission: Admission) -> Option<InFlight> {
+    match admission {
+        Admission::Admitted(in_flight) => in_flight,
+        Admission::Limited(action) => panic!("limited with {:?}", action),
+    }
+}
+
+fn limited(admission: Admission) -> Option<LimitAction> {
+    match admission {
+        Admission::Admitted(_) => None,
+        Admission::Limited(action) => Some(action),
+    }
+}
+
+#[test]
+fn nothing_is_tracked_without_limits() {
+    let limits = limits(ClientLimitsConfig::default());
+    for _ in 0..100 {
+        assert!(admitted(limits.admit(client("198.51.100.1"), true)).is_none());
+    }
+    assert_eq!(limits.stats().tracked_clients, 0);
+}
+
+#[test]
+fn each_client_gets_its_own_query_rate() {
+    let limits = limits(ClientLimitsConfig {
+        queries_per_second: 2,
+        action: LimitAction::Refuse,
+        ..Default::default()
+    });
+    let first = client("198.51.100.1");
+    admitted(limits.admit(first, false));
+    admitted(limits.admit(first, true));
+    assert_eq!(
+        limited(limits.admit(first, false)),
+        Some(LimitAction::Refuse)
+    );
+    // The next address over is another client
+    admitted(limits.admit(client("198.51.100.2"), false));
+
+    let stats = limits.stats();
+    assert_eq!(stats.tracked_clients, 2);
+    assert_eq!((stats.refused, stats.dropped), (1, 0));
+}
+
+#[test]
+fn in_flight_slots_are_released_when_dropped() {
+    let limits = limits(ClientLimitsConfig {
+        max_in_flight: 2,
+        ..Default::default()
+    });
+    let client = client("198.51.100.1");
+    let first = admitted(limits.admit(client, true)).unwrap();
+    let second = admitted(limits.admit(client, true)).unwrap();
+    assert_eq!(limited(limits.admit(client, true)), Some(LimitAction::Drop));
+    // Queries answered without recursion take no slot
+    assert!(admitted(limits.admit(client, false)).is_none());
+
+    let stats = limits.stats();
+    assert_eq!(stats.in_flight, 2);
+    assert_eq!(stats.busiest_clients, vec![(client, 2)]);
+    assert_eq!(stats.dropped, 1);
+
+    drop(first);
+    assert_eq!(limits.stats().in_flight, 1);
+    let third = admitted(limits.admit(client, true)).unwrap();
+    drop(second);
+    drop(third);
+    let stats = limits.stats();
+    assert_eq!(stats.in_flight, 0);
+    assert!(stats.busiest_clients.is_empty());
+}
+
+#[test]
+fn a_rule_limits_its_clients_together() {
+    let limits = limits(ClientLimitsConfig {
+        rules: vec![ClientLimitRuleConfig {
+            clients: vec!["10.42.0.0/16".parse().unwrap()],
+            queries_per_second: 0,
+            max_in_flight: 2,
+        }],
+        ..Default::default()
+    });
+    let a = admitted(limits.admit(client("10.42.0.1"), true)).unwrap();
+    let _b = admitted(limits.admit(client("10.42.1.1"), true)).unwrap();
+    assert_eq!(
+        limited(limits.admit(client("10.42.2.2"), true)),
+        Some(LimitAction::Drop)
+    );
+    // Clients outside the rule are not held back by it
+    let _outside = admitted(limits.admit(client("10.43.0.1"), true)).unwrap();
+
+    let rule = &limits.stats().rules[0];
+    assert_eq!((rule.in_flight, rule.limited), (2, 1));
+
+    drop(a);
+    admitted(limits.admit(client("10.42.2.2"), true)).unwrap();
+}
+
+#[test]
+fn a_limited_query_uses_up_nothing() {
+    let limits = limits(ClientLimitsConfig {
+        queries_per_second: 1,
+        rules: vec![ClientLimitRuleConfig {
+            clients: vec!["10.42.0.0/16".parse().unwrap()],
+            queries_per_second: 0,
+            max_in_flight: 1,
+        }],
+        ..Default::default()
+    });
+    let _held = admitted(limits.admit(client("10.42.0.1"), true)).unwrap();
+    // Limited by the rule, so the client's one query a second is still there
+    let other = client("10.42.0.2");
+    assert!(limited(limits.admit(other, true)).is_some());
+    assert!(admitted(limits.admit(other, false)).is_none());
+    assert!(limited(limits.admit(other, false)).is_some());
+    assert_eq!(limits.stats().rules[0].in_flight, 1);
+}
+
+#[test]
+fn clients_past_the_table_size_are_not_limited() {
+    let limits = limits(ClientLimitsConfig {
+        queries_per_second: 1,
+        max_clients: 1,
+        ..Default::default()
+    });
+    let tracked = client("198.51.100.1");
+    admitted(limits.admit(tracked, false));
+    assert!(limited(limits.admit(tracked, false)).is_some());
+    // The first client is not idle yet, so there is no room to track the second
+    let untracked = client("198.51.100.2");
+    for _ in 0..5 {
+        admitted(limits.admit(untracked, false));
+    }
+    assert_eq!(limits.stats().tracked_clients, 1);
+}
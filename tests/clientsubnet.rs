@@ -0,0 +1,221 @@
+//! EDNS Client Subnet: the option sent, the scope answers come back with, and the clients
+//! the cached answers are handed to.
+
+use dash::clientsubnet::ClientSubnet;
+use dash::config::{ClientSubnetConfig, Config, QnameMinimization, StubZoneConfig};
+use dash::dashcontext::DashContext;
+use dash::dashjob::DashJob;
+use dash::lru_ttl_cache::Cache;
+use dash::threadpool::{JobPriority, ThreadPoolJob};
+use rustdns::{Class, Message, Type};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+
+const OPT: u16 = 41;
+const ECS_OPTION: u16 = 8;
+
+fn config() -> ClientSubnetConfig {
+    ClientSubnetConfig {
+        enabled: true,
+        servers: vec!["127.0.0.1/32".parse().unwrap()],
+        ..Default::default()
+    }
+}
+
+fn subnet(client: &str) -> ClientSubnet {
+    ClientSubnet::for_client(client.parse().unwrap(), &config()).unwrap()
+}
+
+#[test]
+fn option_data_only_holds_the_octets_the_prefix_covers() {
+    assert_eq!(
+        subnet("198.51.100.7").option_data(),
+        vec![0, 1, 24, 0, 198, 51, 100]
+    );
+    assert_eq!(
+        subnet("2001:db8:1234:5678::1").option_data(),
+        vec![0, 2, 56, 0, 0x20, 0x01, 0x0d, 0xb8, 0x12, 0x34, 0x56]
+    );
+    // Clients in private networks are not revealed at all
+    assert_eq!(subnet("10.1.2.3").option_data(), vec![0, 1, 0, 0]);
+}
+
+#[test]
+fn response_scope_needs_the_option_that_was_sent() {
+    let subnet = subnet("198.51.100.7");
+    assert_eq!(
+        subnet.response_scope(&[0, 1, 24, 16, 198, 51, 100]),
+        Some(16)
+    );
+    // Another address, source prefix, family or length
+    assert_eq!(subnet.response_scope(&[0, 1, 24, 16, 198, 51, 101]), None);
+    assert_eq!(subnet.response_scope(&[0, 1, 16, 16, 198, 51]), None);
+    assert_eq!(subnet.response_scope(&[0, 2, 24, 16, 198, 51, 100]), None);
+    assert_eq!(
+        subnet.response_scope(&[0, 1, 24, 16, 198, 51, 100, 0]),
+        None
+    );
+}
+
+#[test]
+fn recorded_scope_is_the_longest_up_to_the_source_prefix() {
+    let subnet = subnet("198.51.100.7");
+    assert_eq!(subnet.scope(), None);
+    subnet.record_scope(16);
+    subnet.record_scope(0);
+    assert_eq!(subnet.scope(), Some(16));
+    subnet.record_scope(32);
+    assert_eq!(subnet.scope(), Some(24));
+}
+
+/// A stand-in authoritative server for test. answering every A query with the client subnet
+/// it was sent, given for scope. Returns its address and the options it was sent.
+fn serve(scope: u8) -> (SocketAddr, Arc<Mutex<Vec<Vec<u8>>>>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&sent);
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let Ok((length, from)) = socket.recv_from(&mut buffer) else {
+                continue;
+            };
+            if let Some((data, response)) = respond(scope, &buffer[..length]) {
+                seen.lock().unwrap().push(data);
+                let _ = socket.send_to(&response, from);
+            }
+        }
+    });
+    (address, sent)
+}
+
+fn respond(scope: u8, query: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut at = 12;
+    while *query.get(at)? != 0 {
+        at += 1 + query[at] as usize;
+    }
+    let question = query.get(12..at + 5)?;
+    at += 5;
+
+    // The options of the OPT record, the only additional one
+    let opt = query.get(at..at + 11)?;
+    if opt[0] != 0 || u16::from_be_bytes([opt[1], opt[2]]) != OPT {
+        return None;
+    }
+    let mut options = query.get(at + 11..)?;
+    let mut data = Vec::new();
+    while options.len() >= 4 {
+        let code = u16::from_be_bytes([options[0], options[1]]);
+        let length = u16::from_be_bytes([options[2], options[3]]) as usize;
+        if code == ECS_OPTION {
+            data = options.get(4..4 + length)?.to_vec();
+        }
+        options = options.get(4 + length..)?;
+    }
+
+    let mut response = query[..2].to_vec();
+    response.extend([0x84, 0]);
+    for count in [1u16, 1, 0, 1] {
+        response.extend(count.to_be_bytes());
+    }
+    response.extend_from_slice(question);
+    response.extend([0xc0, 12, 0, 1, 0, 1]);
+    response.extend(300u32.to_be_bytes());
+    response.extend([0, 4, 192, 0, 2, 1]);
+
+    let mut echoed = data.clone();
+    if let Some(s) = echoed.get_mut(3) {
+        *s = scope;
+    }
+    response.extend([0, 0, 41, 0x10, 0, 0, 0, 0, 0]);
+    response.extend((4 + echoed.len() as u16).to_be_bytes());
+    response.extend(ECS_OPTION.to_be_bytes());
+    response.extend((echoed.len() as u16).to_be_bytes());
+    response.extend(echoed);
+    Some((data, response))
+}
+
+fn context(server: SocketAddr) -> Arc<DashContext> {
+    let mut config = Config {
+        client_subnet: config(),
+        ..Default::default()
+    };
+    config.resolver.stub_zones = vec![StubZoneConfig {
+        name: "test".to_string(),
+        nameservers: vec![server],
+    }];
+    config.resolver.query_timeout_ms = 2000;
+    config.resolver.qname_minimization = QnameMinimization::Off;
+    config.dnssec.validation = false;
+    Arc::new(DashContext::new(config).unwrap())
+}
+
+/// Jobs asking www.test A for clients, sharing one cache. The responses go nowhere, the
+/// clients are outside the host.
+struct Clients {
+    context: Arc<DashContext>,
+    cache: Arc<Mutex<Cache<String, Message>>>,
+    socket: Arc<UdpSocket>,
+}
+
+impl Clients {
+    fn new(context: Arc<DashContext>) -> Clients {
+        Clients {
+            context,
+            cache: Arc::new(Mutex::new(Cache::new(64))),
+            socket: Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap()),
+        }
+    }
+
+    fn job(&self, client: &str) -> DashJob {
+        let mut msg = Message {
+            rd: true,
+            ..Default::default()
+        };
+        msg.add_question("www.test", Type::A, Class::Internet);
+        DashJob::new(
+            msg,
+            SocketAddr::new(client.parse().unwrap(), 5353),
+            Arc::clone(&self.socket),
+            Arc::clone(&self.cache),
+            Arc::clone(&self.context),
+        )
+    }
+}
+
+#[test]
+fn answer_for_a_scope_only_goes_to_clients_within_it() {
+    let (server, sent) = serve(24);
+    let clients = Clients::new(context(server));
+
+    let first = clients.job("198.51.100.7");
+    assert_eq!(first.priority(), JobPriority::ClientRecursion);
+    first.run_job();
+    assert_eq!(*sent.lock().unwrap(), vec![vec![0, 1, 24, 0, 198, 51, 100]]);
+
+    // The same /24 is answered from the cache
+    let neighbour = clients.job("198.51.100.200");
+    assert_eq!(neighbour.priority(), JobPriority::CacheHit);
+    neighbour.run_job();
+    assert_eq!(sent.lock().unwrap().len(), 1);
+
+    // Another /24 gets an answer of its own
+    let other = clients.job("203.0.113.9");
+    assert_eq!(other.priority(), JobPriority::ClientRecursion);
+    other.run_job();
+    assert_eq!(sent.lock().unwrap().len(), 2);
+    assert_eq!(sent.lock().unwrap()[1], vec![0, 1, 24, 0, 203, 0, 113]);
+}
+
+#[test]
+fn answer_for_scope_zero_goes_to_every_client() {
+    let (server, sent) = serve(0);
+    let clients = Clients::new(context(server));
+
+    clients.job("198.51.100.7").run_job();
+    let other = clients.job("203.0.113.9");
+    assert_eq!(other.priority(), JobPriority::CacheHit);
+    other.run_job();
+    assert_eq!(sent.lock().unwrap().len(), 1);
+}
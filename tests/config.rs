@@ -0,0 +1,289 @@
+//! Parsing the configuration file and the checks validate runs on the result.
+
+use dash::config::{
+    parse_forwarder, AclAction, Config, StubZoneConfig, UpstreamMode, UpstreamSelection,
+    ViewConfig, ZoneConfig,
+};
+use dash::configerror::ConfigErrorReason;
+use log::LevelFilter;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+
+fn invalid_value(config: &Config) -> String {
+    match config.validate().unwrap_err().reason() {
+        ConfigErrorReason::InvalidValue(s) => s.clone(),
+        other => panic!("expected InvalidValue, got {}", other),
+    }
+}
+
+fn invalid_combination(config: &Config) -> String {
+    match config.validate().unwrap_err().reason() {
+        ConfigErrorReason::InvalidCombination(s) => s.clone(),
+        other => panic!("expected InvalidCombination, got {}", other),
+    }
+}
+
+#[test]
+fn defaults_are_valid() {
+    Config::default().validate().unwrap();
+    assert_eq!(Config::from_toml("").unwrap(), Config::default());
+}
+
+#[test]
+fn missing_keys_keep_their_defaults() {
+    let config = Config::from_toml(
+        r#"
+        [server]
+        port = 5353
+
+        [resolver]
+        mode = "forward"
+        forwarders = ["192.0.2.1", "192.0.2.2:5300"]
+        selection = "fastest"
+
+        [log]
+        level = "debug"
+        "#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+
+    assert_eq!(config.server.port, 5353);
+    assert_eq!(config.server.listen, Config::default().server.listen);
+    assert_eq!(config.resolver.mode, UpstreamMode::Forward);
+    assert_eq!(
+        config.resolver.forwarders,
+        vec![
+            "192.0.2.1:53".parse::<SocketAddr>().unwrap(),
+            "192.0.2.2:5300".parse().unwrap()
+        ]
+    );
+    assert_eq!(config.resolver.selection, UpstreamSelection::Fastest);
+    assert_eq!(
+        config.resolver.query_timeout_ms,
+        Config::default().resolver.query_timeout_ms
+    );
+    assert_eq!(config.log.level, LevelFilter::Debug);
+    assert_eq!(config.pool, Config::default().pool);
+}
+
+#[test]
+fn unknown_keys_and_bad_values_do_not_parse() {
+    for toml in [
+        "[server]\nprot = 53",
+        "[pools]\nsize = 4",
+        "[resolver]\nmode = \"iterative\"",
+        "[resolver]\nforwarders = [\"dns.example\"]",
+        "[log]\nlevel = \"loud\"",
+        "[server]\nport = 70000",
+    ] {
+        let error = Config::from_toml(toml).unwrap_err();
+        assert!(
+            matches!(error.reason(), ConfigErrorReason::Parse(_)),
+            "{}: {}",
+            toml,
+            error
+        );
+    }
+}
+
+#[test]
+fn file_errors_name_the_file() {
+    let missing = Path::new("/nonexistent/dash.toml");
+    let error = Config::from_file(missing).unwrap_err();
+    assert!(
+        matches!(error.reason(), ConfigErrorReason::Io(s) if s.contains("/nonexistent/dash.toml"))
+    );
+
+    let path = std::env::temp_dir().join(format!("dash-config-test-{}.toml", std::process::id()));
+    std::fs::write(&path, "[server]\nport = \"fifty\"\n").unwrap();
+    let error = Config::from_file(&path).unwrap_err();
+    std::fs::remove_file(&path).unwrap();
+    assert!(
+        matches!(error.reason(), ConfigErrorReason::Parse(s) if s.starts_with(&path.display().to_string()))
+    );
+}
+
+#[test]
+fn flag_values_parse_like_the_file() {
+    assert_eq!(
+        parse_forwarder("192.0.2.1").unwrap(),
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 53)
+    );
+    assert_eq!(
+        parse_forwarder("[2001:db8::1]:5300").unwrap(),
+        "[2001:db8::1]:5300".parse().unwrap()
+    );
+    assert!(parse_forwarder("192.0.2.1:").is_err());
+    assert_eq!(
+        "Forward".parse::<UpstreamMode>().unwrap(),
+        UpstreamMode::Forward
+    );
+    assert!("forwarding".parse::<UpstreamMode>().is_err());
+    assert_eq!(
+        "round-robin".parse::<UpstreamSelection>().unwrap(),
+        UpstreamSelection::RoundRobin
+    );
+    assert!("random".parse::<UpstreamSelection>().is_err());
+}
+
+#[test]
+fn single_values_out_of_range_are_invalid() {
+    let mut config = Config::default();
+    config.server.port = 0;
+    assert_eq!(invalid_value(&config), "server.port must not be 0");
+
+    let mut config = Config::default();
+    config.server.listen.clear();
+    assert!(invalid_value(&config).starts_with("server.listen"));
+
+    let mut config = Config::default();
+    config.pool.min_size = 0;
+    assert!(invalid_value(&config).starts_with("pool.min_size"));
+
+    let mut config = Config::default();
+    config.cache.capacity = 0;
+    assert!(invalid_value(&config).starts_with("cache.capacity"));
+
+    let mut config = Config::default();
+    config.resolver.query_timeout_ms = 0;
+    assert!(invalid_value(&config).starts_with("resolver.query_timeout_ms"));
+
+    let mut config = Config::default();
+    config.response_rate_limit.ipv4_prefix_len = 33;
+    assert!(invalid_value(&config).starts_with("response_rate_limit prefix lengths"));
+
+    // Client subnets are held to the prefixes RFC 7871 recommends sending
+    let mut config = Config::default();
+    config.client_subnet.ipv4_prefix_len = 25;
+    assert!(invalid_value(&config).starts_with("client_subnet prefix lengths"));
+    let mut config = Config::default();
+    config.client_subnet.ipv6_prefix_len = 64;
+    assert!(invalid_value(&config).starts_with("client_subnet prefix lengths"));
+}
+
+#[test]
+fn values_that_contradict_each_other_are_invalid_combinations() {
+    let mut config = Config::default();
+    config.pool.min_size = 8;
+    config.pool.max_size = 4;
+    assert_eq!(
+        invalid_combination(&config),
+        "pool.min_size (8) is larger than pool.max_size (4)"
+    );
+
+    let mut config = Config::default();
+    config.pool.size = 20;
+    assert!(invalid_combination(&config).starts_with("pool.size (20)"));
+
+    let mut config = Config::default();
+    config.resolver.mode = UpstreamMode::Forward;
+    assert!(invalid_combination(&config).contains("resolver.forwarders"));
+
+    let mut config = Config::default();
+    config.resolver.forwarders = vec!["192.0.2.1:53".parse().unwrap()];
+    assert!(invalid_combination(&config).contains("set mode = \"forward\""));
+
+    let mut config = Config::default();
+    config.client_subnet.enabled = true;
+    assert!(invalid_combination(&config).contains("client_subnet.servers"));
+}
+
+#[test]
+fn zones_are_checked_by_normalized_name() {
+    let stub = |name: &str| StubZoneConfig {
+        name: name.to_string(),
+        nameservers: vec!["192.0.2.1:53".parse().unwrap()],
+    };
+    let mut config = Config::default();
+    config.resolver.stub_zones = vec![stub("corp.example"), stub("Corp.Example.")];
+    assert_eq!(
+        invalid_value(&config),
+        "stub zone \"Corp.Example.\" is configured more than once"
+    );
+
+    let mut config = Config::default();
+    config.resolver.stub_zones = vec![stub("corp..example")];
+    assert!(invalid_value(&config).contains("has an empty label"));
+
+    let mut config = Config::default();
+    config.resolver.stub_zones = vec![StubZoneConfig {
+        nameservers: Vec::new(),
+        ..stub("corp.example")
+    }];
+    assert!(invalid_value(&config).contains("needs at least one nameserver"));
+}
+
+#[test]
+fn view_errors_name_the_view() {
+    let view = |name: &str| ViewConfig {
+        name: name.to_string(),
+        clients: vec!["10.0.0.0/8".parse().unwrap()],
+        zones: None,
+        forward_zones: None,
+        blocklist: None,
+        rpz: None,
+    };
+    let zone = ZoneConfig {
+        name: "corp.example".to_string(),
+        file: "/etc/dash/corp.example.zone".into(),
+    };
+
+    let config = Config {
+        views: vec![ViewConfig {
+            zones: Some(vec![zone.clone(), zone]),
+            ..view("internal")
+        }],
+        ..Default::default()
+    };
+    assert_eq!(
+        invalid_value(&config),
+        "view \"internal\": zone \"corp.example\" is configured more than once"
+    );
+
+    let config = Config {
+        views: vec![view("internal"), view("internal")],
+        ..Default::default()
+    };
+    assert_eq!(
+        invalid_value(&config),
+        "view \"internal\" is configured more than once"
+    );
+
+    let config = Config {
+        views: vec![ViewConfig {
+            clients: Vec::new(),
+            ..view("internal")
+        }],
+        ..Default::default()
+    };
+    assert!(invalid_value(&config).contains("needs at least one clients prefix"));
+}
+
+#[test]
+fn access_control_rules_parse() {
+    let config = Config::from_toml(
+        r#"
+        [access_control]
+        default_action = "deny"
+
+        [[access_control.rules]]
+        clients = ["192.0.2.0/24"]
+        action = "allow-snoop"
+        "#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.access_control.default_action, AclAction::Deny);
+    assert_eq!(config.access_control.rules[0].action, AclAction::AllowSnoop);
+
+    let config = Config::from_toml(
+        r#"
+        [[access_control.rules]]
+        clients = []
+        action = "allow"
+        "#,
+    )
+    .unwrap();
+    assert!(invalid_value(&config).contains("needs at least one clients prefix"));
+}
@@ -0,0 +1,673 @@
+//! DNSSEC validation against zones signed on the spot and served by a stand-in
+//! authoritative server.
+//!
+//! test. is the trust anchor, signed with Ed25519 and denying with NSEC. It delegates
+//! sub.test. (ECDSA P-256, NSEC3), p384.test. (ECDSA P-384) and rsa.test. (RSA/SHA-256)
+//! with DS records, and unsigned.test. without.
+
+use dash::config::{Config, StubZoneConfig};
+use dash::dashcontext::DashContext;
+use dash::dnserror::ExtendedError;
+use dash::dnssec::{
+    base32hex, canonical_cmp, ds_digest, nsec3_hash, type_bitmap, Dnskey, Ds, Rrsig, TrustAnchor,
+    DNSKEY, DS, ECDSAP256SHA256, ECDSAP384SHA384, ED25519, NSEC, NSEC3, RRSIG, RSASHA256, SHA256,
+};
+use dash::resolver::resolve_message_query;
+use dash::wire::canonical_name;
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING,
+    ECDSA_P384_SHA384_FIXED_SIGNING, RSA_PKCS1_SHA256,
+};
+use rustdns::{Class, Message, Rcode, Type};
+use std::collections::BTreeSet;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const A: u16 = 1;
+const NS: u16 = 2;
+const SOA: u16 = 6;
+
+const NSEC3_SALT: [u8; 2] = [0xab, 0xcd];
+const NSEC3_ITERATIONS: u16 = 5;
+
+#[derive(Debug, Clone)]
+struct Rr {
+    name: String,
+    r#type: u16,
+    ttl: u32,
+    rdata: Vec<u8>,
+}
+
+fn rr(name: &str, r#type: u16, rdata: Vec<u8>) -> Rr {
+    Rr {
+        name: name.to_string(),
+        r#type,
+        ttl: 300,
+        rdata,
+    }
+}
+
+fn name(name: &str) -> Vec<u8> {
+    canonical_name(name).unwrap()
+}
+
+fn soa(zone: &str) -> Rr {
+    let mut rdata = name(&format!("ns.{}", zone));
+    rdata.extend(name(&format!("hostmaster.{}", zone)));
+    for value in [1u32, 3600, 600, 86400, 300] {
+        rdata.extend(value.to_be_bytes());
+    }
+    rr(zone, SOA, rdata)
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
+
+enum Signer {
+    Ed25519(Ed25519KeyPair),
+    Ecdsa(u8, EcdsaKeyPair),
+    Rsa(RsaKeyPair),
+}
+
+impl Signer {
+    fn generate(algorithm: u8) -> Signer {
+        let rng = SystemRandom::new();
+        match algorithm {
+            ED25519 => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+                Signer::Ed25519(Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap())
+            }
+            ECDSAP256SHA256 | ECDSAP384SHA384 => {
+                let curve = if algorithm == ECDSAP256SHA256 {
+                    &ECDSA_P256_SHA256_FIXED_SIGNING
+                } else {
+                    &ECDSA_P384_SHA384_FIXED_SIGNING
+                };
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(curve, &rng).unwrap();
+                Signer::Ecdsa(
+                    algorithm,
+                    EcdsaKeyPair::from_pkcs8(curve, pkcs8.as_ref()).unwrap(),
+                )
+            }
+            RSASHA256 => {
+                Signer::Rsa(RsaKeyPair::from_pkcs8(include_bytes!("data/dnssec-rsa.pk8")).unwrap())
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn dnskey(&self) -> Dnskey {
+        let (algorithm, public_key) = match self {
+            Signer::Ed25519(key) => (ED25519, key.public_key().as_ref().to_vec()),
+            // Without the uncompressed point marker
+            Signer::Ecdsa(algorithm, key) => (*algorithm, key.public_key().as_ref()[1..].to_vec()),
+            Signer::Rsa(key) => {
+                let exponent = key.public_key().exponent();
+                let exponent = exponent.big_endian_without_leading_zero();
+                let mut public_key = vec![exponent.len() as u8];
+                public_key.extend_from_slice(exponent);
+                public_key.extend_from_slice(
+                    key.public_key().modulus().big_endian_without_leading_zero(),
+                );
+                (RSASHA256, public_key)
+            }
+        };
+        Dnskey {
+            flags: Dnskey::ZONE | Dnskey::SEP,
+            protocol: 3,
+            algorithm,
+            public_key,
+        }
+    }
+
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let rng = SystemRandom::new();
+        match self {
+            Signer::Ed25519(key) => key.sign(data).as_ref().to_vec(),
+            Signer::Ecdsa(_, key) => key.sign(&rng, data).unwrap().as_ref().to_vec(),
+            Signer::Rsa(key) => {
+                let mut signature = vec![0; key.public_modulus_len()];
+                key.sign(&RSA_PKCS1_SHA256, &rng, data, &mut signature)
+                    .unwrap();
+                signature
+            }
+        }
+    }
+}
+
+/// What to do to one record set's signature.
+#[derive(Clone, Copy, PartialEq)]
+enum Tamper {
+    None,
+    Unsigned,
+    Corrupt,
+    Expired,
+}
+
+struct Zone {
+    apex: String,
+    records: Vec<Rr>,
+}
+
+impl Zone {
+    /// Signs records, with the SOA, NS and DNSKEY records of apex added, and chains them
+    /// with NSEC or NSEC3 records. Names below delegations in cuts are left unsigned.
+    fn sign(
+        apex: &str,
+        signer: &Signer,
+        mut records: Vec<Rr>,
+        nsec3: bool,
+        tamper: &[(&str, Tamper)],
+    ) -> Zone {
+        records.push(soa(apex));
+        records.push(rr(apex, NS, name(&format!("ns.{}", apex))));
+        records.push(rr(apex, DNSKEY, signer.dnskey().rdata()));
+        let cuts: Vec<String> = records
+            .iter()
+            .filter(|r| r.r#type == NS && r.name != apex)
+            .map(|r| r.name.clone())
+            .collect();
+
+        let names: BTreeSet<String> = records.iter().map(|r| r.name.clone()).collect();
+        let types_at = |owner: &str| -> Vec<u16> {
+            records
+                .iter()
+                .filter(|r| r.name == owner)
+                .map(|r| r.r#type)
+                .collect()
+        };
+        let mut chain = Vec::new();
+        if nsec3 {
+            let mut hashed: Vec<(Vec<u8>, Vec<u16>)> = names
+                .iter()
+                .map(|owner| {
+                    let mut types = types_at(owner);
+                    if !cuts.contains(owner) || types.contains(&DS) {
+                        types.push(RRSIG);
+                    }
+                    let hash = nsec3_hash(owner, &NSEC3_SALT, NSEC3_ITERATIONS).unwrap();
+                    (hash, types)
+                })
+                .collect();
+            hashed.sort();
+            for (i, (hash, types)) in hashed.iter().enumerate() {
+                let next = &hashed[(i + 1) % hashed.len()].0;
+                let mut rdata = vec![1, 0];
+                rdata.extend(NSEC3_ITERATIONS.to_be_bytes());
+                rdata.push(NSEC3_SALT.len() as u8);
+                rdata.extend(NSEC3_SALT);
+                rdata.push(next.len() as u8);
+                rdata.extend(next);
+                rdata.extend(type_bitmap(types));
+                chain.push(rr(&format!("{}.{}", base32hex(hash), apex), NSEC3, rdata));
+            }
+        } else {
+            let mut sorted: Vec<&String> = names.iter().collect();
+            sorted.sort_by(|a, b| canonical_cmp(a, b));
+            for (i, owner) in sorted.iter().enumerate() {
+                let next = sorted[(i + 1) % sorted.len()];
+                let mut types = types_at(owner);
+                types.extend([NSEC, RRSIG]);
+                let mut rdata = name(next);
+                rdata.extend(type_bitmap(&types));
+                chain.push(rr(owner, NSEC, rdata));
+            }
+        }
+        records.extend(chain);
+
+        let mut rrsets: Vec<(String, u16)> = Vec::new();
+        for r in &records {
+            let rrset = (r.name.clone(), r.r#type);
+            let delegated = cuts.contains(&r.name) && r.r#type == NS;
+            if !delegated && !rrsets.contains(&rrset) {
+                rrsets.push(rrset);
+            }
+        }
+        let now = now();
+        let key_tag = signer.dnskey().key_tag();
+        for (owner, r#type) in rrsets {
+            let tamper = tamper
+                .iter()
+                .find(|(n, _)| *n == owner)
+                .map_or(Tamper::None, |(_, t)| *t);
+            if tamper == Tamper::Unsigned && r#type != NSEC && r#type != NSEC3 {
+                continue;
+            }
+            let (inception, expiration) = match tamper {
+                Tamper::Expired => (now - 7200, now - 3600),
+                _ => (now - 3600, now + 3600),
+            };
+            let mut rrsig = Rrsig {
+                type_covered: r#type,
+                algorithm: signer.dnskey().algorithm,
+                labels: owner.split('.').count() as u8,
+                original_ttl: 300,
+                expiration,
+                inception,
+                key_tag,
+                signer: apex.to_string(),
+                signature: Vec::new(),
+            };
+            let rdatas: Vec<&[u8]> = records
+                .iter()
+                .filter(|r| r.name == owner && r.r#type == r#type)
+                .map(|r| r.rdata.as_slice())
+                .collect();
+            let data = rrsig.signed_data(&owner, 1, &rdatas).unwrap();
+            rrsig.signature = signer.sign(&data);
+            if tamper == Tamper::Corrupt && r#type != NSEC && r#type != NSEC3 {
+                rrsig.signature[0] ^= 0xff;
+            }
+            records.push(rr(&owner, RRSIG, rrsig.rdata().unwrap()));
+        }
+        Zone {
+            apex: apex.to_string(),
+            records,
+        }
+    }
+
+    fn rrset(&self, owner: &str, r#type: u16) -> Vec<Rr> {
+        let mut rrset: Vec<Rr> = self
+            .records
+            .iter()
+            .filter(|r| r.name == owner && r.r#type == r#type)
+            .cloned()
+            .collect();
+        rrset.extend(
+            self.records
+                .iter()
+                .filter(|r| {
+                    r.name == owner
+                        && r.r#type == RRSIG
+                        && u16::from_be_bytes([r.rdata[0], r.rdata[1]]) == r#type
+                })
+                .cloned(),
+        );
+        rrset
+    }
+
+    fn has_name(&self, owner: &str) -> bool {
+        self.records
+            .iter()
+            .any(|r| r.name == owner && r.r#type != NSEC && r.r#type != RRSIG)
+    }
+
+    /// The SOA record and the whole NSEC or NSEC3 chain, with their signatures. More than
+    /// a real server sends, the validator has to pick the records that prove the denial.
+    fn denial(&self) -> Vec<Rr> {
+        let mut authority = self.rrset(&self.apex, SOA);
+        let chain: BTreeSet<(String, u16)> = self
+            .records
+            .iter()
+            .filter(|r| r.r#type == NSEC || r.r#type == NSEC3)
+            .map(|r| (r.name.clone(), r.r#type))
+            .collect();
+        for (owner, r#type) in chain {
+            authority.extend(self.rrset(&owner, r#type));
+        }
+        authority
+    }
+}
+
+fn in_zone(name: &str, zone: &str) -> bool {
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+/// Answers queries for zones, authoritatively, always with DNSSEC records.
+fn serve(zones: Arc<Vec<Zone>>) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let Ok((length, from)) = socket.recv_from(&mut buffer) else {
+                continue;
+            };
+            if let Some(response) = respond(&zones, &buffer[..length]) {
+                let _ = socket.send_to(&response, from);
+            }
+        }
+    });
+    address
+}
+
+fn respond(zones: &[Zone], query: &[u8]) -> Option<Vec<u8>> {
+    let mut at = 12;
+    let mut labels = Vec::new();
+    loop {
+        let length = *query.get(at)? as usize;
+        at += 1;
+        if length == 0 {
+            break;
+        }
+        labels.push(String::from_utf8_lossy(query.get(at..at + length)?).to_lowercase());
+        at += length;
+    }
+    let question = query.get(12..at + 4)?;
+    let qname = labels.join(".");
+    let qtype = u16::from_be_bytes([query[at], query[at + 1]]);
+
+    // The deepest zone holding the name, its parent for DS records at an apex
+    let zone = zones
+        .iter()
+        .filter(|z| in_zone(&qname, &z.apex))
+        .filter(|z| !(qtype == DS && z.apex == qname && qname != "test"))
+        .max_by_key(|z| z.apex.split('.').count())?;
+
+    let answers = zone.rrset(&qname, qtype);
+    let (rcode, authority) = if !answers.is_empty() {
+        (0, Vec::new())
+    } else if zone.has_name(&qname) {
+        (0, zone.denial())
+    } else {
+        (3, zone.denial())
+    };
+
+    let mut response = query[..2].to_vec();
+    response.extend([0x84, rcode]);
+    for count in [1, answers.len(), authority.len(), 1] {
+        response.extend((count as u16).to_be_bytes());
+    }
+    response.extend_from_slice(question);
+    for r in answers.iter().chain(&authority) {
+        response.extend(name(&r.name));
+        response.extend(r.r#type.to_be_bytes());
+        response.extend(1u16.to_be_bytes());
+        response.extend(r.ttl.to_be_bytes());
+        response.extend((r.rdata.len() as u16).to_be_bytes());
+        response.extend(&r.rdata);
+    }
+    // OPT with the DO bit
+    response.extend([0, 0, 41, 0x10, 0, 0, 0, 0x80, 0, 0, 0]);
+    Some(response)
+}
+
+struct TestZones {
+    address: SocketAddr,
+    anchor: Dnskey,
+}
+
+fn a(owner: &str, ip: [u8; 4]) -> Rr {
+    rr(owner, A, ip.to_vec())
+}
+
+fn ds(child: &str, key: &Dnskey) -> Rr {
+    let ds = Ds {
+        key_tag: key.key_tag(),
+        algorithm: key.algorithm,
+        digest_type: SHA256,
+        digest: ds_digest(child, key, SHA256).unwrap(),
+    };
+    rr(child, DS, ds.rdata())
+}
+
+fn test_zones() -> &'static TestZones {
+    static ZONES: OnceLock<TestZones> = OnceLock::new();
+    ZONES.get_or_init(|| {
+        let root = Signer::generate(ED25519);
+        let sub = Signer::generate(ECDSAP256SHA256);
+        let p384 = Signer::generate(ECDSAP384SHA384);
+        let rsa = Signer::generate(RSASHA256);
+
+        let mut records = vec![
+            a("www.test", [192, 0, 2, 1]),
+            a("bad.test", [192, 0, 2, 66]),
+            a("old.test", [192, 0, 2, 67]),
+            a("nosig.test", [192, 0, 2, 68]),
+        ];
+        for (child, signer) in [("sub.test", &sub), ("p384.test", &p384), ("rsa.test", &rsa)] {
+            records.push(rr(child, NS, name(&format!("ns.{}", child))));
+            records.push(ds(child, &signer.dnskey()));
+        }
+        records.push(rr("unsigned.test", NS, name("ns.unsigned.test")));
+        let tamper = [
+            ("bad.test", Tamper::Corrupt),
+            ("old.test", Tamper::Expired),
+            ("nosig.test", Tamper::Unsigned),
+        ];
+        let zones = vec![
+            Zone::sign("test", &root, records, false, &tamper),
+            Zone::sign(
+                "sub.test",
+                &sub,
+                vec![a("www.sub.test", [192, 0, 2, 2])],
+                true,
+                &[],
+            ),
+            Zone::sign(
+                "p384.test",
+                &p384,
+                vec![a("www.p384.test", [192, 0, 2, 3])],
+                false,
+                &[],
+            ),
+            Zone::sign(
+                "rsa.test",
+                &rsa,
+                vec![a("www.rsa.test", [192, 0, 2, 4])],
+                false,
+                &[],
+            ),
+            Zone {
+                apex: "unsigned.test".to_string(),
+                records: vec![soa("unsigned.test"), a("www.unsigned.test", [192, 0, 2, 5])],
+            },
+        ];
+        TestZones {
+            address: serve(Arc::new(zones)),
+            anchor: root.dnskey(),
+        }
+    })
+}
+
+fn context_with_anchor(anchor: TrustAnchor) -> DashContext {
+    let zones = test_zones();
+    let mut config = Config::default();
+    config.resolver.stub_zones = vec![StubZoneConfig {
+        name: "test".to_string(),
+        nameservers: vec![zones.address],
+    }];
+    config.resolver.query_timeout_ms = 2000;
+    config.dnssec.trust_anchors = vec![anchor];
+    DashContext::new(config).unwrap()
+}
+
+fn context() -> DashContext {
+    context_with_anchor(TrustAnchor::Dnskey {
+        zone: "test".to_string(),
+        key: test_zones().anchor.clone(),
+    })
+}
+
+fn query(qname: &str, qtype: Type) -> Message {
+    let mut msg = Message {
+        rd: true,
+        ..Default::default()
+    };
+    msg.add_question(qname, qtype, Class::Internet);
+    msg
+}
+
+fn resolve(qname: &str, qtype: Type) -> Result<Message, dash::dnserror::DnsError> {
+    resolve_message_query(&query(qname, qtype), &context())
+}
+
+fn extended_code(qname: &str, qtype: Type) -> u16 {
+    let error = resolve(qname, qtype).unwrap_err();
+    assert_eq!(error.code(), Rcode::ServFail);
+    error.extended().expect("an Extended DNS Error").code
+}
+
+#[test]
+fn secure_answers_get_the_ad_bit() {
+    let rsp = resolve("www.test.", Type::A).unwrap();
+    assert_eq!(rsp.answers.len(), 1);
+    assert!(rsp.ad);
+}
+
+#[test]
+fn every_supported_algorithm_validates() {
+    for qname in ["www.sub.test.", "www.p384.test.", "www.rsa.test."] {
+        let rsp = resolve(qname, Type::A).unwrap();
+        assert_eq!(rsp.answers.len(), 1, "{}", qname);
+        assert!(rsp.ad, "{} is not secure", qname);
+    }
+}
+
+#[test]
+fn a_bad_signature_is_servfail() {
+    assert_eq!(
+        extended_code("bad.test.", Type::A),
+        ExtendedError::DNSSEC_BOGUS
+    );
+}
+
+#[test]
+fn an_expired_signature_is_servfail() {
+    assert_eq!(
+        extended_code("old.test.", Type::A),
+        ExtendedError::SIGNATURE_EXPIRED
+    );
+}
+
+#[test]
+fn unsigned_records_in_a_signed_zone_are_servfail() {
+    assert_eq!(
+        extended_code("nosig.test.", Type::A),
+        ExtendedError::RRSIGS_MISSING
+    );
+}
+
+#[test]
+fn nsec_proves_a_name_does_not_exist() {
+    let rsp = resolve("missing.test.", Type::A).unwrap();
+    assert_eq!(rsp.rcode, Rcode::NXDomain);
+    assert!(rsp.ad);
+}
+
+#[test]
+fn nsec_proves_a_type_does_not_exist() {
+    let rsp = resolve("www.test.", Type::AAAA).unwrap();
+    assert_eq!(rsp.rcode, Rcode::NoError);
+    assert!(rsp.answers.is_empty());
+    assert!(rsp.ad);
+}
+
+#[test]
+fn nsec3_proves_a_type_does_not_exist() {
+    let rsp = resolve("www.sub.test.", Type::AAAA).unwrap();
+    assert_eq!(rsp.rcode, Rcode::NoError);
+    assert!(rsp.answers.is_empty());
+    assert!(rsp.ad);
+}
+
+#[test]
+fn nsec3_proves_a_name_does_not_exist() {
+    let rsp = resolve("missing.sub.test.", Type::A).unwrap();
+    assert_eq!(rsp.rcode, Rcode::NXDomain);
+    assert!(rsp.ad);
+}
+
+#[test]
+fn cached_nsec_records_answer_for_other_names() {
+    let context = context();
+    resolve_message_query(&query("missing.test.", Type::A), &context).unwrap();
+    let rsp = context
+        .nsec_cache()
+        .answer(&query("random.test.", Type::A))
+        .expect("an answer synthesized from the NSEC records");
+    assert_eq!(rsp.rcode, Rcode::NXDomain);
+    assert!(rsp.ad);
+    let rsp = context
+        .nsec_cache()
+        .answer(&query("www.test.", Type::TXT))
+        .expect("an answer synthesized from the NSEC records");
+    assert_eq!(rsp.rcode, Rcode::NoError);
+    assert!(rsp.answers.is_empty());
+    assert!(context
+        .nsec_cache()
+        .answer(&query("www.test.", Type::A))
+        .is_none());
+    // The names below a delegation are for the child zone to deny
+    assert!(context
+        .nsec_cache()
+        .answer(&query("www.unsigned.test.", Type::A))
+        .is_none());
+}
+
+#[test]
+fn cached_nsec3_records_answer_for_other_names() {
+    let context = context();
+    resolve_message_query(&query("missing.sub.test.", Type::A), &context).unwrap();
+    let rsp = context
+        .nsec_cache()
+        .answer(&query("random.sub.test.", Type::A))
+        .expect("an answer synthesized from the NSEC3 records");
+    assert_eq!(rsp.rcode, Rcode::NXDomain);
+    assert!(rsp.ad);
+}
+
+#[test]
+fn an_unsigned_delegation_is_insecure() {
+    let rsp = resolve("www.unsigned.test.", Type::A).unwrap();
+    assert_eq!(rsp.answers.len(), 1);
+    assert!(!rsp.ad);
+}
+
+#[test]
+fn checking_disabled_skips_validation() {
+    let mut msg = query("bad.test.", Type::A);
+    msg.cd = true;
+    let rsp = resolve_message_query(&msg, &context()).unwrap();
+    assert_eq!(rsp.answers.len(), 1);
+    assert!(!rsp.ad);
+}
+
+#[test]
+fn a_ds_trust_anchor_validates() {
+    let key = &test_zones().anchor;
+    let anchor = TrustAnchor::Ds {
+        zone: "test".to_string(),
+        ds: Ds {
+            key_tag: key.key_tag(),
+            algorithm: key.algorithm,
+            digest_type: SHA256,
+            digest: ds_digest("test", key, SHA256).unwrap(),
+        },
+    };
+    let rsp = resolve_message_query(&query("www.test.", Type::A), &context_with_anchor(anchor));
+    assert!(rsp.unwrap().ad);
+}
+
+#[test]
+fn keys_not_matching_the_trust_anchor_are_servfail() {
+    let mut key = test_zones().anchor.clone();
+    key.public_key[0] ^= 0xff;
+    let anchor = TrustAnchor::Dnskey {
+        zone: "test".to_string(),
+        key,
+    };
+    let error = resolve_message_query(&query("www.test.", Type::A), &context_with_anchor(anchor))
+        .unwrap_err();
+    assert_eq!(
+        error.extended().map(|e| e.code),
+        Some(ExtendedError::DNSKEY_MISSING)
+    );
+}
+
+#[test]
+fn trust_anchors_parse_from_presentation_format() {
+    let anchor: TrustAnchor =
+        ". DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D"
+            .parse()
+            .unwrap();
+    assert_eq!(anchor.zone(), "");
+    assert_eq!(anchor.algorithm(), RSASHA256);
+    assert_eq!(anchor.to_string().parse::<TrustAnchor>(), Ok(anchor));
+}
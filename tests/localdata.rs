@@ -0,0 +1,177 @@
+//! Hosts files, inline records and the PTR records synthesized from them.
+
+use dash::config::LocalDataConfig;
+use dash::localdata::{reverse_name, LocalDataStore};
+use rustdns::{Class, Message, Rcode, Resource, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+
+const HOSTS: &str = "\
+# Comments and blank lines are skipped
+
+127.0.0.1   localhost
+10.0.0.5    nas.lan nas      # canonical name first
+10.0.0.6    printer.lan
+10.0.0.5    backup.lan
+fd00::5     nas.lan
+::1         localhost ip6-localhost
+";
+
+fn query(qname: &str, qtype: Type) -> Message {
+    let mut msg = Message {
+        rd: true,
+        ..Default::default()
+    };
+    msg.add_question(qname, qtype, Class::Internet);
+    msg
+}
+
+fn hosts_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("dash-localdata-{}-{}", name, std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn store(name: &str, records: &[&str]) -> LocalDataStore {
+    let path = hosts_file(name, HOSTS);
+    let config = LocalDataConfig {
+        hosts_files: vec![path.clone()],
+        records: records.iter().map(|r| r.to_string()).collect(),
+        ..Default::default()
+    };
+    let store = LocalDataStore::new(&config).unwrap();
+    std::fs::remove_file(path).unwrap();
+    store
+}
+
+fn pointers(rsp: &Message) -> Vec<String> {
+    rsp.answers
+        .iter()
+        .filter_map(|r| match &r.resource {
+            Resource::PTR(name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn reverse_names() {
+    assert_eq!(
+        reverse_name(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10))),
+        "10.2.0.192.in-addr.arpa"
+    );
+    assert_eq!(
+        reverse_name("2001:db8::567:89ab".parse().unwrap()),
+        "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+    );
+}
+
+#[test]
+fn hosts_file_addresses() {
+    let store = store("addresses", &[]);
+
+    let rsp = store.answer(&query("nas.lan", Type::A)).unwrap();
+    assert!(rsp.aa);
+    assert_eq!(rsp.answers.len(), 1);
+    assert_eq!(
+        rsp.answers[0].resource,
+        Resource::A(Ipv4Addr::new(10, 0, 0, 5))
+    );
+    // Aliases on a line and names repeated on later lines are all kept
+    assert!(store.contains("NAS"));
+    assert!(store.contains("backup.lan."));
+
+    let rsp = store.answer(&query("nas.lan", Type::AAAA)).unwrap();
+    assert_eq!(
+        rsp.answers[0].resource,
+        Resource::AAAA("fd00::5".parse::<Ipv6Addr>().unwrap())
+    );
+
+    // A known name without records of the type is NODATA, an unknown one is left alone
+    let rsp = store.answer(&query("printer.lan", Type::AAAA)).unwrap();
+    assert_eq!(rsp.rcode, Rcode::NoError);
+    assert!(rsp.answers.is_empty());
+    assert!(store.answer(&query("other.lan", Type::A)).is_none());
+}
+
+#[test]
+fn bad_hosts_line_is_an_error() {
+    let path = hosts_file("bad", "10.0.0.1 good.lan\nnot-an-address bad.lan\n");
+    let config = LocalDataConfig {
+        hosts_files: vec![path.clone()],
+        ..Default::default()
+    };
+    let result = LocalDataStore::new(&config);
+    std::fs::remove_file(path).unwrap();
+    assert!(result.is_err());
+}
+
+#[test]
+fn synthesized_pointers_use_the_first_name() {
+    let store = store("pointers", &[]);
+
+    // 10.0.0.5 is named nas.lan first, its alias and backup.lan get no PTR
+    let rsp = store
+        .answer(&query("5.0.0.10.in-addr.arpa", Type::PTR))
+        .unwrap();
+    assert_eq!(pointers(&rsp), vec!["nas.lan.".to_string()]);
+
+    let rsp = store
+        .answer(&query(&reverse_name("fd00::5".parse().unwrap()), Type::PTR))
+        .unwrap();
+    assert_eq!(pointers(&rsp), vec!["nas.lan.".to_string()]);
+
+    let rsp = store
+        .answer(&query(
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa",
+            Type::PTR,
+        ))
+        .unwrap();
+    assert_eq!(pointers(&rsp), vec!["localhost.".to_string()]);
+}
+
+#[test]
+fn inline_records_win_over_synthesized_pointers() {
+    let store = store(
+        "inline",
+        &[
+            "10.0.0.5 PTR storage.lan",
+            "fileserver.lan A 10.0.0.6",
+            "6.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa PTR v6.lan",
+        ],
+    );
+
+    // An explicit PTR replaces the synthesized one
+    let rsp = store
+        .answer(&query("5.0.0.10.in-addr.arpa", Type::PTR))
+        .unwrap();
+    assert_eq!(pointers(&rsp), vec!["storage.lan.".to_string()]);
+
+    // An inline address is seen before the hosts file, so its name is the one pointed to
+    let rsp = store
+        .answer(&query("6.0.0.10.in-addr.arpa", Type::PTR))
+        .unwrap();
+    assert_eq!(pointers(&rsp), vec!["fileserver.lan.".to_string()]);
+    assert!(store.contains("printer.lan"));
+
+    let rsp = store
+        .answer(&query(&reverse_name("fd00::6".parse().unwrap()), Type::PTR))
+        .unwrap();
+    assert_eq!(pointers(&rsp), vec!["v6.lan.".to_string()]);
+}
+
+#[test]
+fn invalid_inline_records_are_errors() {
+    for record in [
+        "host.lan A ::1",
+        "host.lan AAAA 10.0.0.1",
+        "host.lan MX mail.lan",
+        "host.lan A",
+    ] {
+        let config = LocalDataConfig {
+            records: vec![record.to_string()],
+            ..Default::default()
+        };
+        assert!(LocalDataStore::new(&config).is_err(), "{}", record);
+    }
+}
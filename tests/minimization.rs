@@ -0,0 +1,201 @@
+//! Query name minimization (RFC 9156) against a stand-in server authoritative for the root,
+//! test. and example.test., which records every question it is asked.
+
+use dash::config::{Config, QnameMinimization, StubZoneConfig};
+use dash::dashcontext::DashContext;
+use dash::resolver::resolve_message_query;
+use dash::wire::canonical_name;
+use rustdns::{Class, Message, Rcode, Resource, Type};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+
+const A: u16 = 1;
+const SOA: u16 = 6;
+
+/// The only name with records, everything above it up to the root exists without any.
+const TARGET: &str = "www.example.test";
+const EXISTING: [&str; 3] = ["test", "example.test", TARGET];
+
+/// How the stand-in treats names that exist without records, which only minimized queries
+/// ask about.
+#[derive(Clone, Copy)]
+enum EmptyNames {
+    Nodata,
+    Rcode(Rcode),
+}
+
+struct StandIn {
+    address: SocketAddr,
+    asked: Arc<Mutex<Vec<String>>>,
+}
+
+impl StandIn {
+    fn asked(&self) -> Vec<String> {
+        self.asked.lock().unwrap().clone()
+    }
+}
+
+fn serve(empty_names: EmptyNames) -> StandIn {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&asked);
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let Ok((length, from)) = socket.recv_from(&mut buffer) else {
+                continue;
+            };
+            if let Some((qname, response)) = respond(empty_names, &buffer[..length]) {
+                seen.lock().unwrap().push(qname);
+                let _ = socket.send_to(&response, from);
+            }
+        }
+    });
+    StandIn { address, asked }
+}
+
+fn respond(empty_names: EmptyNames, query: &[u8]) -> Option<(String, Vec<u8>)> {
+    let mut at = 12;
+    let mut labels = Vec::new();
+    loop {
+        let length = *query.get(at)? as usize;
+        at += 1;
+        if length == 0 {
+            break;
+        }
+        labels.push(String::from_utf8_lossy(query.get(at..at + length)?).to_lowercase());
+        at += length;
+    }
+    let question = query.get(12..at + 4)?;
+    let qname = labels.join(".");
+    let qtype = u16::from_be_bytes([query[at], query[at + 1]]);
+
+    let (rcode, answer) = match (EXISTING.contains(&qname.as_str()), empty_names) {
+        (true, _) if qname == TARGET && qtype == A => (Rcode::NoError, true),
+        (true, EmptyNames::Rcode(rcode)) if qname != TARGET => (rcode, false),
+        (true, _) => (Rcode::NoError, false),
+        (false, _) => (Rcode::NXDomain, false),
+    };
+    let denial = !answer && matches!(rcode, Rcode::NoError | Rcode::NXDomain);
+
+    let mut response = query[..2].to_vec();
+    response.extend([0x84, rcode as u8]);
+    for count in [1, answer as u16, denial as u16, 0] {
+        response.extend(count.to_be_bytes());
+    }
+    response.extend_from_slice(question);
+    if answer {
+        response.extend([0xc0, 12]);
+        response.extend(A.to_be_bytes());
+        response.extend(1u16.to_be_bytes());
+        response.extend(300u32.to_be_bytes());
+        response.extend(4u16.to_be_bytes());
+        response.extend([192, 0, 2, 1]);
+    }
+    if denial {
+        let mut rdata = canonical_name("ns.test").unwrap();
+        rdata.extend(canonical_name("hostmaster.test").unwrap());
+        for value in [1u32, 3600, 600, 86400, 300] {
+            rdata.extend(value.to_be_bytes());
+        }
+        response.extend(canonical_name("test").unwrap());
+        response.extend(SOA.to_be_bytes());
+        response.extend(1u16.to_be_bytes());
+        response.extend(300u32.to_be_bytes());
+        response.extend((rdata.len() as u16).to_be_bytes());
+        response.extend(rdata);
+    }
+    Some((qname, response))
+}
+
+fn context(server: &StandIn, minimization: QnameMinimization) -> DashContext {
+    let mut config = Config::default();
+    config.resolver.stub_zones = vec![StubZoneConfig {
+        name: ".".to_string(),
+        nameservers: vec![server.address],
+    }];
+    config.resolver.query_timeout_ms = 2000;
+    config.resolver.qname_minimization = minimization;
+    config.dnssec.validation = false;
+    DashContext::new(config).unwrap()
+}
+
+fn query(qname: &str) -> Message {
+    let mut msg = Message {
+        rd: true,
+        ..Default::default()
+    };
+    msg.add_question(qname, Type::A, Class::Internet);
+    msg
+}
+
+fn address(rsp: &Message) -> Option<Ipv4Addr> {
+    rsp.answers.iter().find_map(|r| match r.resource {
+        Resource::A(ip) => Some(ip),
+        _ => None,
+    })
+}
+
+#[test]
+fn each_level_only_sees_the_next_label() {
+    let server = serve(EmptyNames::Nodata);
+    let rsp = resolve_message_query(
+        &query(TARGET),
+        &context(&server, QnameMinimization::Relaxed),
+    )
+    .unwrap();
+    assert_eq!(address(&rsp), Some(Ipv4Addr::new(192, 0, 2, 1)));
+    assert_eq!(server.asked(), vec!["test", "example.test", TARGET]);
+}
+
+#[test]
+fn without_minimization_the_full_name_is_sent() {
+    let server = serve(EmptyNames::Nodata);
+    let rsp =
+        resolve_message_query(&query(TARGET), &context(&server, QnameMinimization::Off)).unwrap();
+    assert_eq!(address(&rsp), Some(Ipv4Addr::new(192, 0, 2, 1)));
+    assert_eq!(server.asked(), vec![TARGET]);
+}
+
+#[test]
+fn relaxed_falls_back_to_the_full_name_when_minimized_queries_fail() {
+    for rcode in [Rcode::Refused, Rcode::ServFail] {
+        let server = serve(EmptyNames::Rcode(rcode));
+        let rsp = resolve_message_query(
+            &query(TARGET),
+            &context(&server, QnameMinimization::Relaxed),
+        )
+        .unwrap();
+        assert_eq!(
+            address(&rsp),
+            Some(Ipv4Addr::new(192, 0, 2, 1)),
+            "{:?}",
+            rcode
+        );
+        assert_eq!(server.asked(), vec!["test", TARGET], "{:?}", rcode);
+    }
+}
+
+#[test]
+fn strict_stops_at_a_name_that_does_not_exist() {
+    let server = serve(EmptyNames::Nodata);
+    let msg = query("www.missing.example.test");
+    let rsp = resolve_message_query(&msg, &context(&server, QnameMinimization::Strict)).unwrap();
+    assert_eq!(rsp.rcode, Rcode::NXDomain);
+    assert_eq!(rsp.questions, msg.questions);
+    // Nothing exists below missing.example.test, so the full name is never sent
+    assert_eq!(
+        server.asked(),
+        vec!["test", "example.test", "missing.example.test"]
+    );
+}
+
+#[test]
+fn strict_does_not_fall_back() {
+    let server = serve(EmptyNames::Rcode(Rcode::Refused));
+    let error = resolve_message_query(&query(TARGET), &context(&server, QnameMinimization::Strict))
+        .unwrap_err();
+    assert_eq!(error.code(), Rcode::ServFail);
+    assert!(!server.asked().contains(&TARGET.to_string()));
+}
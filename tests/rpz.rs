@@ -0,0 +1,279 @@
+//! Response policy zones: trigger encoding, which zone decides, and the rewritten answers.
+
+use dash::cidr::Cidr;
+use dash::config::{Config, StubZoneConfig, ZoneConfig};
+use dash::dashcontext::DashContext;
+use dash::dashjob::DashJob;
+use dash::lru_ttl_cache::Cache;
+use dash::rpz::{parse_trigger_prefix, PolicyAction, PolicyZones, Trigger};
+use dash::threadpool::ThreadPoolJob;
+use dash::wire::decode;
+use rustdns::{Class, Message, Rcode, Record, Resource, Type};
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn query(qname: &str, qtype: Type) -> Message {
+    let mut msg = Message {
+        id: 0x4d2,
+        rd: true,
+        ..Default::default()
+    };
+    msg.add_question(qname, qtype, Class::Internet);
+    msg
+}
+
+/// Writes a zone file under the system temporary directory and returns its config. Every
+/// file gets a name of its own, as tests run in parallel.
+fn zone_file(name: &str, contents: &str) -> ZoneConfig {
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+    let file = std::env::temp_dir().join(format!(
+        "dash-rpz-{}-{}-{}.zone",
+        name,
+        std::process::id(),
+        FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&file, format!("$TTL 300\n{}", contents)).unwrap();
+    ZoneConfig {
+        name: name.to_string(),
+        file,
+    }
+}
+
+fn remove(configs: &[ZoneConfig]) {
+    for config in configs {
+        std::fs::remove_file(&config.file).unwrap();
+    }
+}
+
+fn cidr(s: &str) -> Cidr {
+    s.parse().unwrap()
+}
+
+fn a(name: &str, ip: [u8; 4]) -> Record {
+    Record {
+        name: name.to_string(),
+        class: Class::Internet,
+        ttl: Duration::from_secs(300),
+        resource: Resource::A(Ipv4Addr::from(ip)),
+    }
+}
+
+/// Runs a job for msg the way the server does and returns the response the client gets.
+fn respond(context: Arc<DashContext>, msg: Message) -> Message {
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let server = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+    let cache = Arc::new(Mutex::new(Cache::new(64)));
+    let job = DashJob::new(msg, client.local_addr().unwrap(), server, cache, context);
+    job.run_job();
+
+    let mut buffer = [0; 4096];
+    let len = client.recv(&mut buffer).unwrap();
+    decode(&buffer[..len]).unwrap().message
+}
+
+#[test]
+fn ipv4_trigger_prefixes() {
+    assert_eq!(
+        parse_trigger_prefix("32.1.2.0.192"),
+        Some(cidr("192.0.2.1/32"))
+    );
+    assert_eq!(
+        parse_trigger_prefix("24.0.2.0.192"),
+        Some(cidr("192.0.2.0/24"))
+    );
+    assert_eq!(parse_trigger_prefix("8.0.0.0.10"), Some(cidr("10.0.0.0/8")));
+    assert_eq!(parse_trigger_prefix("33.1.2.0.192"), None);
+    assert_eq!(parse_trigger_prefix("24.2.0.192"), None);
+    assert_eq!(parse_trigger_prefix("x.1.2.0.192"), None);
+}
+
+#[test]
+fn ipv6_trigger_prefixes_with_zz() {
+    // zz stands for the run of zero groups written :: in the address
+    assert_eq!(
+        parse_trigger_prefix("128.1.zz.db8.2001"),
+        Some(cidr("2001:db8::1/128"))
+    );
+    assert_eq!(
+        parse_trigger_prefix("48.zz.db8.2001"),
+        Some(cidr("2001:db8::/48"))
+    );
+    assert_eq!(parse_trigger_prefix("128.1.zz"), Some(cidr("::1/128")));
+    assert_eq!(
+        parse_trigger_prefix("64.0.0.0.0.0.0.db8.2001"),
+        Some(cidr("2001:db8::/64"))
+    );
+    assert_eq!(parse_trigger_prefix("128.1.zz.zz.2001"), None);
+    assert_eq!(parse_trigger_prefix("129.1.zz.db8.2001"), None);
+}
+
+#[test]
+fn first_configured_zone_with_a_qname_trigger_decides() {
+    let configs = vec![
+        zone_file("first.rpz", "ads.example.com CNAME .\n"),
+        zone_file(
+            "second.rpz",
+            "ads.example.com CNAME rpz-passthru.\n*.tracker.example CNAME *.\n",
+        ),
+    ];
+    let zones = PolicyZones::load(&configs).unwrap();
+    remove(&configs);
+
+    let hit = zones.qname_hit("ads.example.com").unwrap();
+    assert_eq!((hit.zone_index, hit.zone.as_str()), (0, "first.rpz"));
+    assert_eq!(hit.action, PolicyAction::Nxdomain);
+
+    let hit = zones.qname_hit("pixel.tracker.example").unwrap();
+    assert_eq!(hit.zone_index, 1);
+    assert_eq!(hit.action, PolicyAction::Nodata);
+    // A wildcard does not match the name it is under
+    assert!(zones.qname_hit("tracker.example").is_none());
+}
+
+#[test]
+fn response_trigger_in_an_earlier_zone_wins_over_a_later_qname_trigger() {
+    let configs = vec![
+        zone_file("ip.rpz", "24.0.2.0.192.rpz-ip CNAME rpz-drop.\n"),
+        zone_file(
+            "names.rpz",
+            "www.example.com CNAME rpz-passthru.\n32.1.2.0.192.rpz-ip CNAME .\n",
+        ),
+    ];
+    let zones = PolicyZones::load(&configs).unwrap();
+    remove(&configs);
+    let context = DashContext::new(Config::default()).unwrap();
+
+    let qname_hit = zones.qname_hit("www.example.com").unwrap();
+    assert_eq!(qname_hit.zone_index, 1);
+    // The QNAME hit has to wait for the response, a zone before it has IP triggers
+    assert!(zones.has_response_triggers_before(qname_hit.zone_index));
+    assert!(!zones.has_response_triggers_before(0));
+
+    let mut answer = query("www.example.com", Type::A);
+    answer.answers.push(a("www.example.com", [192, 0, 2, 1]));
+    let hit = zones
+        .response_hit("www.example.com", &answer, &context, qname_hit.zone_index)
+        .unwrap();
+    assert_eq!(hit.zone_index, 0);
+    assert_eq!(hit.trigger, Trigger::Ip);
+    assert_eq!(hit.action, PolicyAction::Drop);
+
+    // Zones from the QNAME hit on are not asked, the QNAME hit decides there
+    answer.answers[0] = a("www.example.com", [198, 51, 100, 1]);
+    assert!(zones
+        .response_hit("www.example.com", &answer, &context, qname_hit.zone_index)
+        .is_none());
+}
+
+#[test]
+fn most_specific_ip_trigger_in_a_zone_wins() {
+    let configs = vec![zone_file(
+        "ip.rpz",
+        "16.0.0.168.192.rpz-ip CNAME .\n32.7.1.168.192.rpz-ip CNAME rpz-passthru.\n",
+    )];
+    let zones = PolicyZones::load(&configs).unwrap();
+    remove(&configs);
+    let context = DashContext::new(Config::default()).unwrap();
+
+    let mut answer = query("host.example", Type::A);
+    answer.answers.push(a("host.example", [192, 168, 1, 7]));
+    let hit = zones
+        .response_hit("host.example", &answer, &context, usize::MAX)
+        .unwrap();
+    assert_eq!(hit.action, PolicyAction::Passthru);
+
+    answer.answers[0] = a("host.example", [192, 168, 1, 8]);
+    let hit = zones
+        .response_hit("host.example", &answer, &context, usize::MAX)
+        .unwrap();
+    assert_eq!(hit.action, PolicyAction::Nxdomain);
+}
+
+/// A context whose policy zone rewrites names under rewrite.example to names answered by a
+/// local zone, local data, or a stub zone whose server never answers.
+fn rewriting_context(name: &str) -> (Arc<DashContext>, Vec<ZoneConfig>, UdpSocket) {
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let rpz = zone_file(
+        &format!("{}.rpz", name),
+        "\
+zone.rewrite.example CNAME www.local.example.
+missing.rewrite.example CNAME nothing.local.example.
+data.rewrite.example CNAME printer.lan.
+*.wild.rewrite.example CNAME *.local.example.
+stuck.rewrite.example CNAME host.unreachable.example.
+",
+    );
+    let zone = zone_file(
+        "local.example",
+        "@ IN SOA ns hostmaster 1 7200 1800 1209600 60\nwww IN A 192.0.2.80\n",
+    );
+
+    let mut config = Config {
+        rpz: vec![rpz.clone()],
+        zones: vec![zone.clone()],
+        ..Default::default()
+    };
+    config.local_data.records = vec!["printer.lan A 10.0.0.6".to_string()];
+    config.resolver.stub_zones = vec![StubZoneConfig {
+        name: "unreachable.example".to_string(),
+        nameservers: vec![silent.local_addr().unwrap()],
+    }];
+    config.resolver.query_timeout_ms = 200;
+    let context = Arc::new(DashContext::new(config).unwrap());
+    (context, vec![rpz, zone], silent)
+}
+
+#[test]
+fn cname_action_target_is_answered_from_local_sources() {
+    let (context, configs, _silent) = rewriting_context("local");
+    remove(&configs);
+
+    let rsp = respond(Arc::clone(&context), query("zone.rewrite.example", Type::A));
+    assert_eq!(rsp.rcode, Rcode::NoError);
+    assert_eq!(rsp.answers.len(), 2);
+    assert_eq!(
+        rsp.answers[0].resource,
+        Resource::CNAME("www.local.example.".to_string())
+    );
+    assert_eq!(rsp.answers[1], a("www.local.example.", [192, 0, 2, 80]));
+
+    let rsp = respond(Arc::clone(&context), query("data.rewrite.example", Type::A));
+    assert_eq!(
+        rsp.answers[1].resource,
+        Resource::A(Ipv4Addr::new(10, 0, 0, 6))
+    );
+
+    let rsp = respond(
+        Arc::clone(&context),
+        query("www.wild.rewrite.example", Type::A),
+    );
+    assert_eq!(
+        rsp.answers[0].resource,
+        Resource::CNAME("www.wild.rewrite.example.local.example.".to_string())
+    );
+    assert_eq!(rsp.rcode, Rcode::NXDomain);
+
+    // The target's rcode is the answer's
+    let rsp = respond(context, query("missing.rewrite.example", Type::A));
+    assert_eq!(rsp.rcode, Rcode::NXDomain);
+    assert_eq!(rsp.answers.len(), 1);
+}
+
+#[test]
+fn cname_action_carries_a_failed_target_through() {
+    let (context, configs, _silent) = rewriting_context("failing");
+    remove(&configs);
+
+    let rsp = respond(context, query("stuck.rewrite.example", Type::A));
+    assert_eq!(rsp.rcode, Rcode::ServFail);
+    assert_eq!(
+        rsp.answers[0].resource,
+        Resource::CNAME("host.unreachable.example.".to_string())
+    );
+    assert_eq!(rsp.answers.len(), 1);
+}
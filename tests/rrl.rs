@@ -0,0 +1,186 @@
+//! Response rate limiting: which responses are sent, slipped or dropped, and what counts
+//! against the same bucket.
+
+use dash::config::ResponseRateLimitConfig;
+use dash::rrl::{apply_verdict, ResponseKind, ResponseRateLimiter, RrlVerdict};
+use rustdns::{Class, Message, Rcode, Record, Resource};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use RrlVerdict::{Drop, Send, Slip};
+
+/// Limits every kind of response to rate a second. Rates are low enough that no token is
+/// added back while a test runs.
+fn limiter(rate: u32, slip: u32) -> ResponseRateLimiter {
+    ResponseRateLimiter::new(&ResponseRateLimitConfig {
+        responses_per_second: rate,
+        slip,
+        ..Default::default()
+    })
+}
+
+fn answer() -> Message {
+    let mut rsp = Message::default();
+    rsp.answers.push(Record {
+        name: "www.example.com.".to_string(),
+        class: Class::Internet,
+        ttl: Duration::from_secs(300),
+        resource: Resource::A(Ipv4Addr::new(192, 0, 2, 1)),
+    });
+    rsp
+}
+
+fn nxdomain() -> Message {
+    Message {
+        rcode: Rcode::NXDomain,
+        ..Default::default()
+    }
+}
+
+fn client(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn verdicts(limiter: &ResponseRateLimiter, client: IpAddr, count: usize) -> Vec<RrlVerdict> {
+    (0..count)
+        .map(|_| limiter.check(client, &answer()))
+        .collect()
+}
+
+#[test]
+fn every_slip_th_limited_response_is_truncated() {
+    let limiter = limiter(3, 2);
+    assert_eq!(
+        verdicts(&limiter, client("198.51.100.1"), 9),
+        [Send, Send, Send, Drop, Slip, Drop, Slip, Drop, Slip]
+    );
+    let stats = limiter.stats();
+    assert_eq!((stats.slipped, stats.dropped), (3, 3));
+}
+
+#[test]
+fn slip_0_drops_and_slip_1_truncates_every_limited_response() {
+    let dropping = limiter(1, 0);
+    assert_eq!(
+        verdicts(&dropping, client("198.51.100.1"), 4),
+        [Send, Drop, Drop, Drop]
+    );
+    assert_eq!(dropping.stats().dropped, 3);
+
+    let slipping = limiter(1, 1);
+    assert_eq!(
+        verdicts(&slipping, client("198.51.100.1"), 4),
+        [Send, Slip, Slip, Slip]
+    );
+    assert_eq!(slipping.stats().slipped, 3);
+}
+
+#[test]
+fn log_only_sends_everything() {
+    let limiter = ResponseRateLimiter::new(&ResponseRateLimitConfig {
+        responses_per_second: 1,
+        log_only: true,
+        ..Default::default()
+    });
+    assert_eq!(verdicts(&limiter, client("198.51.100.1"), 5), [Send; 5]);
+    let stats = limiter.stats();
+    assert_eq!((stats.slipped, stats.dropped), (0, 0));
+}
+
+#[test]
+fn zero_rate_turns_limiting_off() {
+    let limiter = limiter(0, 2);
+    assert_eq!(verdicts(&limiter, client("198.51.100.1"), 100), [Send; 100]);
+}
+
+#[test]
+fn clients_in_one_network_share_a_bucket() {
+    let limiter = limiter(2, 0);
+    assert_eq!(verdicts(&limiter, client("198.51.100.1"), 1), [Send]);
+    assert_eq!(
+        verdicts(&limiter, client("198.51.100.200"), 2),
+        [Send, Drop]
+    );
+    // The IPv4 address mapped into IPv6 is the same client
+    assert_eq!(verdicts(&limiter, client("::ffff:198.51.100.9"), 1), [Drop]);
+    // Another /24 has a bucket of its own
+    assert_eq!(
+        verdicts(&limiter, client("198.51.101.1"), 3),
+        [Send, Send, Drop]
+    );
+
+    // IPv6 clients are grouped by /56
+    assert_eq!(
+        verdicts(&limiter, client("2001:db8:0:1::1"), 2),
+        [Send, Send]
+    );
+    assert_eq!(verdicts(&limiter, client("2001:db8:0:ff::2"), 1), [Drop]);
+    assert_eq!(verdicts(&limiter, client("2001:db8:0:100::1"), 1), [Send]);
+}
+
+#[test]
+fn kinds_of_response_are_limited_apart() {
+    let limiter = ResponseRateLimiter::new(&ResponseRateLimitConfig {
+        responses_per_second: 1,
+        nxdomains_per_second: Some(2),
+        errors_per_second: Some(0),
+        slip: 0,
+        ..Default::default()
+    });
+    let client = client("198.51.100.1");
+    assert_eq!(verdicts(&limiter, client, 2), [Send, Drop]);
+
+    let nxdomains: Vec<_> = (0..3).map(|_| limiter.check(client, &nxdomain())).collect();
+    assert_eq!(nxdomains, [Send, Send, Drop]);
+
+    // Nodata falls back to responses_per_second, errors are not limited at all
+    assert_eq!(limiter.check(client, &Message::default()), Send);
+    assert_eq!(limiter.check(client, &Message::default()), Drop);
+    let servfail = Message {
+        rcode: Rcode::ServFail,
+        ..Default::default()
+    };
+    for _ in 0..5 {
+        assert_eq!(limiter.check(client, &servfail), Send);
+    }
+}
+
+#[test]
+fn networks_past_the_table_size_are_not_limited() {
+    let limiter = ResponseRateLimiter::new(&ResponseRateLimitConfig {
+        responses_per_second: 1,
+        slip: 0,
+        max_table_size: 2,
+        ..Default::default()
+    });
+    assert_eq!(verdicts(&limiter, client("198.51.100.1"), 2), [Send, Drop]);
+    assert_eq!(verdicts(&limiter, client("198.51.101.1"), 2), [Send, Drop]);
+    // No bucket is idle long enough to be evicted, so a third network is let through
+    assert_eq!(verdicts(&limiter, client("198.51.102.1"), 3), [Send; 3]);
+    // While the networks already tracked stay limited
+    assert_eq!(verdicts(&limiter, client("198.51.100.1"), 1), [Drop]);
+    assert_eq!(limiter.stats().dropped, 3);
+}
+
+#[test]
+fn response_kinds() {
+    assert_eq!(ResponseKind::of(&answer()), ResponseKind::Answer);
+    assert_eq!(ResponseKind::of(&Message::default()), ResponseKind::Nodata);
+    assert_eq!(ResponseKind::of(&nxdomain()), ResponseKind::Nxdomain);
+    let refused = Message {
+        rcode: Rcode::Refused,
+        ..Default::default()
+    };
+    assert_eq!(ResponseKind::of(&refused), ResponseKind::Error);
+}
+
+#[test]
+fn slipped_responses_are_truncated_and_empty() {
+    let rsp = apply_verdict(Slip, answer()).unwrap();
+    assert!(rsp.tc);
+    assert!(rsp.answers.is_empty());
+    let rsp = apply_verdict(Send, answer()).unwrap();
+    assert!(!rsp.tc);
+    assert_eq!(rsp.answers, answer().answers);
+    assert!(apply_verdict(Drop, answer()).is_none());
+}
@@ -0,0 +1,205 @@
+//! The dash binary: configuration from a file and flags, reloading it on SIGHUP, and the
+//! queries access control lets through.
+
+use rustdns::{Class, Message, Rcode, Resource, Type};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// A dash process, killed when dropped.
+struct Server {
+    child: Child,
+    config: PathBuf,
+}
+
+impl Server {
+    fn start(name: &str, config: &str, args: &[&str]) -> Server {
+        let path = config_file(name);
+        std::fs::write(&path, config).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_dash"))
+            .arg("--config")
+            .arg(&path)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        Server {
+            child,
+            config: path,
+        }
+    }
+
+    fn rewrite_config(&self, config: &str) {
+        std::fs::write(&self.config, config).unwrap();
+    }
+
+    fn reload(&self) {
+        let status = Command::new("kill")
+            .args(["-HUP", &self.child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.config);
+    }
+}
+
+fn config_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("dash-server-{}-{}.toml", name, std::process::id()))
+}
+
+/// A port nothing is listening on, for the server to take.
+fn free_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Configuration for a server on 127.0.0.1:port that only answers from local data.
+fn config(port: u16, records: &[&str]) -> String {
+    let records: Vec<String> = records.iter().map(|r| format!("\"{}\"", r)).collect();
+    format!(
+        r#"
+        [server]
+        listen = ["127.0.0.1"]
+        port = {}
+
+        [local_data]
+        records = [{}]
+
+        [dnssec]
+        validation = false
+        "#,
+        port,
+        records.join(", ")
+    )
+}
+
+/// Asks port for qname A once, waiting up to timeout for the answer.
+fn query(port: u16, qname: &str, timeout: Duration) -> Option<Message> {
+    send(port, qname, true, timeout)
+}
+
+fn send(port: u16, qname: &str, rd: bool, timeout: Duration) -> Option<Message> {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(timeout)).unwrap();
+    let mut msg = Message {
+        id: 0x4242,
+        rd,
+        ..Default::default()
+    };
+    msg.add_question(qname, Type::A, Class::Internet);
+    let server = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    socket.send_to(&msg.to_vec().unwrap(), server).unwrap();
+    let mut buffer = [0; 4096];
+    let (length, _) = socket.recv_from(&mut buffer).ok()?;
+    Message::from_slice(&buffer[..length]).ok()
+}
+
+fn address(rsp: &Message) -> Option<Ipv4Addr> {
+    rsp.answers.iter().find_map(|r| match r.resource {
+        Resource::A(ip) => Some(ip),
+        _ => None,
+    })
+}
+
+/// Keeps asking port for qname until the answer is expected, for up to 10 seconds.
+fn wait_for_address(port: u16, qname: &str, expected: Ipv4Addr) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut last = None;
+    while Instant::now() < deadline {
+        last = query(port, qname, Duration::from_millis(200))
+            .as_ref()
+            .and_then(address);
+        if last == Some(expected) {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    panic!("{} answered {:?}, expected {}", qname, last, expected);
+}
+
+#[test]
+fn flags_override_the_configuration_file() {
+    let (file_port, flag_port) = (free_port(), free_port());
+    let _server = Server::start(
+        "flags",
+        &config(file_port, &["host.test A 10.0.0.1"]),
+        &["--port", &flag_port.to_string()],
+    );
+    wait_for_address(flag_port, "host.test", Ipv4Addr::new(10, 0, 0, 1));
+    assert!(query(file_port, "host.test", Duration::from_millis(300)).is_none());
+}
+
+#[test]
+fn invalid_configuration_exits_with_code_2() {
+    let port = free_port();
+    for (name, config, args) in [
+        ("unknown-key", "[server]\nprot = 53\n", &[][..]),
+        (
+            "bad-flag",
+            &config(port, &[]),
+            &["--pool-min-size", "0"][..],
+        ),
+        (
+            "bad-combination",
+            &config(port, &[]),
+            &["--upstream-mode", "forward"][..],
+        ),
+    ] {
+        let path = config_file(name);
+        std::fs::write(&path, config).unwrap();
+        let status = Command::new(env!("CARGO_BIN_EXE_dash"))
+            .arg("--config")
+            .arg(&path)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(status.code(), Some(2), "{}", name);
+    }
+}
+
+#[test]
+fn sighup_reloads_the_configuration_file() {
+    let port = free_port();
+    let server = Server::start("reload", &config(port, &["host.test A 10.0.0.1"]), &[]);
+    wait_for_address(port, "host.test", Ipv4Addr::new(10, 0, 0, 1));
+
+    server.rewrite_config(&config(port, &["host.test A 10.0.0.2"]));
+    server.reload();
+    wait_for_address(port, "host.test", Ipv4Addr::new(10, 0, 0, 2));
+
+    // A file that does not validate changes nothing
+    server.rewrite_config(&(config(port, &["host.test A 10.0.0.3"]) + "[pool]\nmin_size = 0\n"));
+    server.reload();
+    std::thread::sleep(Duration::from_millis(500));
+    wait_for_address(port, "host.test", Ipv4Addr::new(10, 0, 0, 2));
+}
+
+#[test]
+fn queries_without_rd_are_only_answered_locally() {
+    let port = free_port();
+    let _server = Server::start("no-rd", &config(port, &["host.test A 10.0.0.1"]), &[]);
+    wait_for_address(port, "host.test", Ipv4Addr::new(10, 0, 0, 1));
+
+    let timeout = Duration::from_secs(5);
+    let rsp = send(port, "host.test", false, timeout).unwrap();
+    assert_eq!(address(&rsp), Some(Ipv4Addr::new(10, 0, 0, 1)));
+    // Anything else could come from the cache
+    let rsp = send(port, "www.example.com", false, timeout).unwrap();
+    assert_eq!(rsp.rcode, Rcode::Refused);
+    assert!(rsp.answers.is_empty());
+}
@@ -0,0 +1,129 @@
+//! Forged responses to upstream queries: packets that do not come from the server asked,
+//! or do not repeat the ID and question of the query, are discarded while waiting for the
+//! real response.
+
+use dash::resolver::query_server;
+use rustdns::{Class, Message, Resource, Type};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+const QNAME: &str = "a-rather-long-name-for-mixing-case.example.test";
+const REAL: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const FORGED: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 66);
+
+#[derive(Clone, Copy, Debug)]
+enum Forgery {
+    /// The real response, sent from another address.
+    OtherSource,
+    OtherId,
+    OtherQuestion,
+    NotAResponse,
+    /// The question in lower case instead of the case it was sent in.
+    LowerCase,
+}
+
+/// A stand-in answering each query with a forged response, then the real one if real is
+/// set.
+fn serve(forgery: Forgery, real: bool) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let Ok((length, from)) = socket.recv_from(&mut buffer) else {
+                continue;
+            };
+            let Some(response) = respond(&buffer[..length], REAL) else {
+                continue;
+            };
+            let mut forged = respond(&buffer[..length], FORGED).unwrap();
+            let name_end = 12 + qname_length(&forged);
+            match forgery {
+                Forgery::OtherSource => (),
+                Forgery::OtherId => forged[1] ^= 0xff,
+                // AAAA instead of A
+                Forgery::OtherQuestion => forged[name_end + 1] = 28,
+                Forgery::NotAResponse => forged[2] &= 0x7f,
+                Forgery::LowerCase => forged[12..name_end].make_ascii_lowercase(),
+            }
+            let sender = match forgery {
+                Forgery::OtherSource => &other,
+                _ => &socket,
+            };
+            let _ = sender.send_to(&forged, from);
+            if real {
+                let _ = socket.send_to(&response, from);
+            }
+        }
+    });
+    address
+}
+
+fn qname_length(packet: &[u8]) -> usize {
+    let mut at = 12;
+    while packet[at] != 0 {
+        at += 1 + packet[at] as usize;
+    }
+    at + 1 - 12
+}
+
+/// The response to query answering its question with address.
+fn respond(query: &[u8], address: Ipv4Addr) -> Option<Vec<u8>> {
+    let name_end = 12 + qname_length(query);
+    let question = query.get(12..name_end + 4)?;
+    let mut response = query[..2].to_vec();
+    response.extend([0x84, 0]);
+    for count in [1u16, 1, 0, 0] {
+        response.extend(count.to_be_bytes());
+    }
+    response.extend_from_slice(question);
+    response.extend([0xc0, 12, 0, 1, 0, 1]);
+    response.extend(300u32.to_be_bytes());
+    response.extend(4u16.to_be_bytes());
+    response.extend(address.octets());
+    Some(response)
+}
+
+fn ask(server: SocketAddr, randomize_case: bool) -> Option<Ipv4Addr> {
+    let mut msg = Message::default();
+    msg.add_question(QNAME, Type::A, Class::Internet);
+    let rsp = query_server(server, &msg, Duration::from_millis(300), randomize_case).ok()?;
+    rsp.answers.iter().find_map(|r| match r.resource {
+        Resource::A(ip) => Some(ip),
+        _ => None,
+    })
+}
+
+#[test]
+fn forged_responses_are_discarded() {
+    for forgery in [
+        Forgery::OtherSource,
+        Forgery::OtherId,
+        Forgery::OtherQuestion,
+        Forgery::NotAResponse,
+    ] {
+        for randomize_case in [false, true] {
+            assert_eq!(
+                ask(serve(forgery, true), randomize_case),
+                Some(REAL),
+                "{:?}",
+                forgery
+            );
+            assert_eq!(
+                ask(serve(forgery, false), randomize_case),
+                None,
+                "{:?}",
+                forgery
+            );
+        }
+    }
+}
+
+#[test]
+fn case_only_counts_when_it_was_randomized() {
+    assert_eq!(ask(serve(Forgery::LowerCase, true), true), Some(REAL));
+    assert_eq!(ask(serve(Forgery::LowerCase, false), true), None);
+    // Without 0x20 the name is compared ignoring case, so the first response is taken
+    assert_eq!(ask(serve(Forgery::LowerCase, true), false), Some(FORGED));
+}
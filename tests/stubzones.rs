@@ -0,0 +1,167 @@
+//! Stub zones: recursion for names below one starts at its configured nameservers, a
+//! stand-in here, instead of the root servers, and the referrals they give are cached.
+
+use dash::config::{Config, QnameMinimization, StubZoneConfig};
+use dash::dashcontext::DashContext;
+use dash::resolver::resolve_message_query;
+use dash::wire::canonical_name;
+use rustdns::{Class, Message, Rcode, Resource, Type};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+
+const A: u16 = 1;
+const NS: u16 = 2;
+
+/// The stand-in has www.corp.test and delegates sub.corp.test to ns.sub.corp.test, at an
+/// address where nothing answers.
+const DELEGATED: &str = "sub.corp.test";
+
+struct StandIn {
+    address: SocketAddr,
+    asked: Arc<Mutex<Vec<String>>>,
+}
+
+fn serve() -> StandIn {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&asked);
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let Ok((length, from)) = socket.recv_from(&mut buffer) else {
+                continue;
+            };
+            if let Some((qname, response)) = respond(&buffer[..length]) {
+                seen.lock().unwrap().push(qname);
+                let _ = socket.send_to(&response, from);
+            }
+        }
+    });
+    StandIn { address, asked }
+}
+
+fn record(response: &mut Vec<u8>, name: &str, rtype: u16, rdata: &[u8]) {
+    response.extend(canonical_name(name).unwrap());
+    response.extend(rtype.to_be_bytes());
+    response.extend(1u16.to_be_bytes());
+    response.extend(300u32.to_be_bytes());
+    response.extend((rdata.len() as u16).to_be_bytes());
+    response.extend_from_slice(rdata);
+}
+
+fn respond(query: &[u8]) -> Option<(String, Vec<u8>)> {
+    let mut at = 12;
+    let mut labels = Vec::new();
+    loop {
+        let length = *query.get(at)? as usize;
+        at += 1;
+        if length == 0 {
+            break;
+        }
+        labels.push(String::from_utf8_lossy(query.get(at..at + length)?).to_lowercase());
+        at += length;
+    }
+    let question = query.get(12..at + 4)?;
+    let qname = labels.join(".");
+
+    let mut response = query[..2].to_vec();
+    if qname == DELEGATED || qname.ends_with(&format!(".{}", DELEGATED)) {
+        response.extend([0x80, 0]);
+        for count in [1u16, 0, 1, 1] {
+            response.extend(count.to_be_bytes());
+        }
+        response.extend_from_slice(question);
+        let nameserver = format!("ns.{}", DELEGATED);
+        record(
+            &mut response,
+            DELEGATED,
+            NS,
+            &canonical_name(&nameserver).unwrap(),
+        );
+        record(&mut response, &nameserver, A, &[127, 0, 0, 1]);
+    } else if qname == "www.corp.test" {
+        response.extend([0x84, 0]);
+        for count in [1u16, 1, 0, 0] {
+            response.extend(count.to_be_bytes());
+        }
+        response.extend_from_slice(question);
+        record(&mut response, &qname, A, &[192, 0, 2, 1]);
+    } else {
+        response.extend([0x84, Rcode::NXDomain as u8]);
+        for count in [1u16, 0, 0, 0] {
+            response.extend(count.to_be_bytes());
+        }
+        response.extend_from_slice(question);
+    }
+    Some((qname, response))
+}
+
+fn context(server: &StandIn) -> DashContext {
+    let mut config = Config::default();
+    // Nothing answers on port 53 of the loopback address, so recursion from the root fails
+    config.resolver.root_servers = vec![Ipv4Addr::LOCALHOST];
+    config.resolver.stub_zones = vec![StubZoneConfig {
+        name: "corp.test".to_string(),
+        nameservers: vec![server.address],
+    }];
+    config.resolver.query_timeout_ms = 500;
+    config.resolver.qname_minimization = QnameMinimization::Off;
+    config.dnssec.validation = false;
+    DashContext::new(config).unwrap()
+}
+
+fn query(qname: &str) -> Message {
+    let mut msg = Message {
+        rd: true,
+        ..Default::default()
+    };
+    msg.add_question(qname, Type::A, Class::Internet);
+    msg
+}
+
+#[test]
+fn names_below_a_stub_zone_start_at_its_nameservers() {
+    let server = serve();
+    let context = context(&server);
+
+    let rsp = resolve_message_query(&query("www.corp.test"), &context).unwrap();
+    let address = rsp.answers.iter().find_map(|r| match r.resource {
+        Resource::A(ip) => Some(ip),
+        _ => None,
+    });
+    assert_eq!(address, Some(Ipv4Addr::new(192, 0, 2, 1)));
+
+    let rsp = resolve_message_query(&query("missing.corp.test"), &context).unwrap();
+    assert_eq!(rsp.rcode, Rcode::NXDomain);
+    assert_eq!(
+        *server.asked.lock().unwrap(),
+        vec!["www.corp.test", "missing.corp.test"]
+    );
+}
+
+#[test]
+fn names_outside_every_stub_zone_start_at_the_root() {
+    let server = serve();
+    let context = context(&server);
+    let error = resolve_message_query(&query("www.other.test"), &context).unwrap_err();
+    assert_eq!(error.code(), Rcode::ServFail);
+    assert!(server.asked.lock().unwrap().is_empty());
+}
+
+#[test]
+fn referrals_from_stub_zone_nameservers_are_cached() {
+    let server = serve();
+    let context = context(&server);
+    let target = format!("www.{}", DELEGATED);
+
+    // The delegated nameserver does not answer, but the referral to it is kept
+    assert!(resolve_message_query(&query(&target), &context).is_err());
+    let (nameservers, addresses) = context.delegations().nameservers(DELEGATED).unwrap();
+    assert_eq!(nameservers, vec![format!("ns.{}", DELEGATED)]);
+    assert_eq!(addresses, vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 53))]);
+
+    // So the stub zone's nameservers are not asked about names below it again
+    assert!(resolve_message_query(&query(&format!("mail.{}", DELEGATED)), &context).is_err());
+    assert_eq!(*server.asked.lock().unwrap(), vec![target]);
+}
@@ -0,0 +1,374 @@
+//! The worker pool: panic isolation, scoped jobs, which borrow from the stack of the thread
+//! that submits them, priority classes, work stealing and the stats snapshot.
+
+use dash::threadpool::{JobPriority, ThreadPool, ThreadPoolJob};
+use dash::threadpoolerror::ThreadPoolErrorReason;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+use std::time::{Duration, Instant};
+
+fn pool(size: usize) -> ThreadPool {
+    ThreadPool::new(size, size, size, Duration::from_secs(5)).unwrap()
+}
+
+/// Counts how many times it is dropped, wherever the job holding it ends up.
+struct DropCounter<'a>(&'a AtomicUsize);
+
+impl Drop for DropCounter<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Panics when dropped, which happens on the worker after the job has run, outside the
+/// isolation around run_job, and so takes the worker thread down.
+struct PanicOnDrop;
+
+impl ThreadPoolJob for PanicOnDrop {
+    fn run_job(&self) {}
+}
+
+impl Drop for PanicOnDrop {
+    fn drop(&mut self) {
+        panic!("job dropped with a panic");
+    }
+}
+
+/// Waits up to five seconds for condition to hold.
+fn wait_for(condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    condition()
+}
+
+/// Whether every one of size workers is alive, by running size jobs that can only finish
+/// once all of them run at the same time.
+fn all_workers_run(pool: &ThreadPool, size: usize) -> bool {
+    let barrier = Arc::new(Barrier::new(size + 1));
+    let handles: Vec<_> = (0..size)
+        .map(|_| {
+            let barrier = Arc::clone(&barrier);
+            pool.submit(move || {
+                barrier.wait();
+            })
+        })
+        .collect();
+    let (done, waiting) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        barrier.wait();
+        let _ = done.send(());
+    });
+    let all_ran = waiting.recv_timeout(Duration::from_secs(5)).is_ok();
+    for mut handle in handles {
+        let _ = handle.join_timeout(Duration::from_secs(5));
+    }
+    all_ran
+}
+
+#[test]
+fn panicking_jobs_do_not_cost_workers() {
+    let pool = pool(3);
+    let panics = Arc::new(AtomicUsize::new(0));
+    let hook_panics = Arc::clone(&panics);
+    pool.set_panic_hook(move |_, message| {
+        assert_eq!(message, "job failed");
+        hook_panics.fetch_add(1, Ordering::SeqCst);
+    });
+
+    let handles: Vec<_> = (0..6)
+        .map(|_| pool.submit(|| -> u32 { panic!("job failed") }))
+        .collect();
+    for handle in handles {
+        assert!(handle.join().is_err());
+    }
+
+    assert_eq!(pool.panic_count(), 6);
+    assert_eq!(panics.load(Ordering::SeqCst), 6);
+    assert_eq!(pool.respawn_count(), 0);
+    assert!(all_workers_run(&pool, 3));
+    assert_eq!(pool.submit(|| 7).join().unwrap(), 7);
+}
+
+#[test]
+fn dead_worker_is_respawned() {
+    let pool = pool(2);
+    pool.submit_job(Box::new(PanicOnDrop));
+
+    assert!(wait_for(|| pool.respawn_count() == 1));
+    assert_eq!(pool.stats().workers.len(), 2);
+    assert!(all_workers_run(&pool, 2));
+}
+
+#[test]
+fn scoped_jobs_borrow_stack_locals() {
+    let pool = pool(4);
+    let numbers: Vec<u64> = (1..=100).collect();
+    let total = AtomicUsize::new(0);
+
+    let sums = pool.scope(|s| {
+        let handles: Vec<_> = numbers
+            .chunks(10)
+            .map(|chunk| {
+                let total = &total;
+                s.submit(move || {
+                    let sum: u64 = chunk.iter().sum();
+                    total.fetch_add(sum as usize, Ordering::SeqCst);
+                    sum
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    assert_eq!(sums.iter().sum::<u64>(), 5050);
+    assert_eq!(total.load(Ordering::SeqCst), 5050);
+}
+
+#[test]
+fn scope_waits_for_jobs_that_are_never_joined() {
+    let pool = pool(2);
+    let finished = AtomicUsize::new(0);
+
+    pool.scope(|s| {
+        for _ in 0..8 {
+            let finished = &finished;
+            s.submit(move || {
+                std::thread::sleep(Duration::from_millis(10));
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+    });
+
+    assert_eq!(finished.load(Ordering::SeqCst), 8);
+}
+
+#[test]
+fn panicking_scoped_job_does_not_poison_the_scope() {
+    let pool = pool(2);
+    let finished = AtomicUsize::new(0);
+
+    let (panicked, others) = pool.scope(|s| {
+        let panicking = s.submit(|| -> u32 { panic!("scoped job failed") });
+        let others: Vec<_> = (0..4)
+            .map(|i| {
+                let finished = &finished;
+                s.submit(move || {
+                    finished.fetch_add(1, Ordering::SeqCst);
+                    i
+                })
+            })
+            .collect();
+        (
+            panicking.join(),
+            others
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>(),
+        )
+    });
+
+    let error = panicked.unwrap_err();
+    assert!(
+        matches!(error.reason(), ThreadPoolErrorReason::JobPanicked(m) if m.contains("scoped job failed"))
+    );
+    assert_eq!(others, vec![0, 1, 2, 3]);
+    assert_eq!(finished.load(Ordering::SeqCst), 4);
+    assert_eq!(pool.panic_count(), 1);
+}
+
+#[test]
+fn nested_scope_on_a_worker_does_not_deadlock() {
+    // A single worker runs the outer job, so the inner jobs can only run if the worker
+    // helps out while its scope waits
+    let pool = Arc::new(pool(1));
+    let inner_pool = Arc::clone(&pool);
+    let mut outer = pool.submit(move || {
+        let values = [1, 2, 3, 4];
+        inner_pool.scope(|s| {
+            let handles: Vec<_> = values.iter().map(|v| s.submit(move || v * 10)).collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum::<i32>()
+        })
+    });
+
+    assert_eq!(outer.join_timeout(Duration::from_secs(5)).unwrap(), 100);
+}
+
+#[test]
+fn jobs_dropped_without_running_release_the_scope() {
+    // With every worker stopped nothing is run unless the scope helps, and cancelled jobs
+    // are only dropped
+    let pool = pool(1);
+    pool.shutdown();
+    std::thread::sleep(Duration::from_millis(250));
+
+    let ran = AtomicUsize::new(0);
+    let dropped = AtomicUsize::new(0);
+    let cancelled = pool.scope(|s| {
+        (0..4)
+            .map(|_| {
+                let guard = DropCounter(&dropped);
+                let ran = &ran;
+                let handle = s.submit(move || {
+                    let _guard = guard;
+                    ran.fetch_add(1, Ordering::SeqCst);
+                });
+                handle.cancel()
+            })
+            .filter(|c| *c)
+            .count()
+    });
+
+    assert_eq!(cancelled, 4);
+    assert_eq!(ran.load(Ordering::SeqCst), 0);
+    assert_eq!(dropped.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn flooded_class_does_not_starve_the_others() {
+    const FLOOD: usize = 500;
+    let pool = pool(1);
+    // Weighted fair dequeueing alone would not get to a background job for a long time
+    pool.set_priority_weight(JobPriority::CacheHit, 1_000_000);
+    pool.set_starvation_threshold(Duration::from_millis(50));
+
+    // Holds the only worker until everything is queued
+    let (release, blocked) = std::sync::mpsc::channel::<()>();
+    pool.submit(move || blocked.recv().ok());
+    let served = Arc::new(AtomicUsize::new(0));
+    for _ in 0..FLOOD {
+        let served = Arc::clone(&served);
+        pool.submit_with_priority(
+            move || {
+                std::thread::sleep(Duration::from_millis(2));
+                served.fetch_add(1, Ordering::SeqCst);
+            },
+            JobPriority::CacheHit,
+        );
+    }
+    // Waited for on a channel, joining the handle would help run the flood
+    let (report, reported) = std::sync::mpsc::channel();
+    let served_before = Arc::clone(&served);
+    pool.submit_with_priority(
+        move || report.send(served_before.load(Ordering::SeqCst)),
+        JobPriority::Background,
+    );
+    release.send(()).unwrap();
+
+    // About 25 cache hits fit in the threshold, the flood takes a second
+    let served_before = reported.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(served_before < FLOOD / 2, "{} served first", served_before);
+}
+
+#[test]
+fn jobs_submitted_by_a_busy_worker_are_stolen() {
+    let pool = Arc::new(pool(2));
+    let inner_pool = Arc::clone(&pool);
+    let (report, reported) = std::sync::mpsc::channel();
+    let (finish, finished) = std::sync::mpsc::channel();
+    pool.submit(move || {
+        // These go to this worker's own deque, and it stays busy until they have run, so
+        // only the other worker can run them
+        for _ in 0..4 {
+            let report = report.clone();
+            inner_pool.submit(move || report.send(std::thread::current().id()));
+        }
+        let ran: Vec<_> = (0..4)
+            .map(|_| reported.recv_timeout(Duration::from_secs(5)))
+            .collect();
+        finish.send((std::thread::current().id(), ran)).unwrap();
+    });
+
+    // Waited for on a channel, joining a handle would help run the jobs
+    let (outer_thread, ran) = finished.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert!(ran
+        .into_iter()
+        .all(|thread| thread.is_ok_and(|t| t != outer_thread)));
+}
+
+#[test]
+fn jobs_submitted_by_a_worker_keep_their_priority() {
+    let pool = Arc::new(pool(1));
+    pool.set_starvation_threshold(Duration::from_secs(60));
+    let inner_pool = Arc::clone(&pool);
+    let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let (report, done) = std::sync::mpsc::channel();
+    let outer_order = Arc::clone(&order);
+    pool.submit(move || {
+        // Queued on the only worker's deques, background first, and run once this job is done
+        for (priority, count) in [(JobPriority::Background, 2), (JobPriority::CacheHit, 8)] {
+            for _ in 0..count {
+                let order = Arc::clone(&outer_order);
+                let report = report.clone();
+                inner_pool.submit_with_priority(
+                    move || {
+                        order.lock().unwrap().push(priority);
+                        report.send(()).unwrap();
+                    },
+                    priority,
+                );
+            }
+        }
+    });
+    for _ in 0..10 {
+        done.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    // Weighted 8 to 1, the cache hits go ahead of background jobs queued before them
+    let order = order.lock().unwrap();
+    assert_eq!(order[..4], [JobPriority::CacheHit; 4]);
+    assert_eq!(
+        order
+            .iter()
+            .filter(|p| **p == JobPriority::Background)
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn stats_count_queued_running_completed_and_panicked_jobs() {
+    let pool = pool(1);
+    let (release, blocked) = std::sync::mpsc::channel::<()>();
+    let (started, running) = std::sync::mpsc::channel();
+    pool.submit(move || {
+        started.send(()).unwrap();
+        blocked.recv().ok()
+    });
+    running.recv_timeout(Duration::from_secs(5)).unwrap();
+    pool.submit_with_priority(|| (), JobPriority::CacheHit);
+    pool.submit_with_priority(|| (), JobPriority::CacheHit);
+    pool.submit_with_priority(|| -> u32 { panic!("job failed") }, JobPriority::Background);
+
+    let stats = pool.stats();
+    assert_eq!((stats.pool_size, stats.workers.len()), (1, 1));
+    assert_eq!(stats.queue_depth, 3);
+    assert_eq!(stats.queue_depth_by_priority[&JobPriority::CacheHit], 2);
+    assert_eq!(stats.queue_depth_by_priority[&JobPriority::Background], 1);
+    assert!(stats.workers[0].current_job_age.is_some());
+    assert_eq!((stats.jobs_completed, stats.jobs_panicked), (0, 0));
+
+    release.send(()).unwrap();
+    // The worker's own counters are updated just after the pool's
+    assert!(wait_for(|| {
+        let stats = pool.stats();
+        stats.jobs_completed == 4 && stats.workers[0].jobs_completed == 4
+    }));
+    let stats = pool.stats();
+    // Panicked jobs count as completed too
+    assert_eq!(stats.jobs_panicked, 1);
+    assert_eq!(stats.queue_depth, 0);
+    assert!(stats.workers[0].current_job_age.is_none());
+    assert!(stats.workers[0].busy_time > Duration::ZERO);
+    let latencies: u64 = stats.queue_latency.values().map(|h| h.count()).sum();
+    assert_eq!(latencies, 4);
+    assert_eq!(stats.queue_latency[&JobPriority::CacheHit].count(), 2);
+    assert_eq!(stats.workers_respawned, 0);
+}
@@ -0,0 +1,211 @@
+//! Trust anchors kept current through key rollovers (RFC 5011), and their state file.
+
+use dash::config::DnssecConfig;
+use dash::dnssec::{ds_digest, Dnskey, Ds, TrustAnchor};
+use dash::trustanchors::{AnchorEntry, KeyState, TrustAnchorStore};
+use std::path::PathBuf;
+
+const DAY: u64 = 86400;
+const ADD_HOLD_DOWN: u64 = 30 * DAY;
+const REMOVE_HOLD_DOWN: u64 = 30 * DAY;
+const ALGORITHM: u8 = 8;
+const SHA256: u8 = 2;
+
+/// A key signing key with public key bytes made from seed.
+fn ksk(seed: u8) -> Dnskey {
+    Dnskey {
+        flags: Dnskey::ZONE | Dnskey::SEP,
+        protocol: 3,
+        algorithm: ALGORITHM,
+        public_key: vec![3, 1, 0, 1, seed, seed, seed, seed],
+    }
+}
+
+fn revoked(key: &Dnskey) -> Dnskey {
+    Dnskey {
+        flags: key.flags | Dnskey::REVOKE,
+        ..key.clone()
+    }
+}
+
+fn key_anchor(key: &Dnskey) -> TrustAnchor {
+    TrustAnchor::Dnskey {
+        zone: "example".to_string(),
+        key: key.clone(),
+    }
+}
+
+fn config(anchors: Vec<TrustAnchor>, file: Option<PathBuf>) -> DnssecConfig {
+    DnssecConfig {
+        trust_anchors: anchors,
+        trust_anchor_file: file,
+        add_hold_down_secs: ADD_HOLD_DOWN,
+        remove_hold_down_secs: REMOVE_HOLD_DOWN,
+        ..Default::default()
+    }
+}
+
+fn store(anchors: Vec<TrustAnchor>) -> TrustAnchorStore {
+    TrustAnchorStore::load(&config(anchors, None)).unwrap()
+}
+
+fn state_of(store: &TrustAnchorStore, key: &Dnskey) -> Option<KeyState> {
+    store
+        .entries()
+        .iter()
+        .find(|e| match &e.anchor {
+            TrustAnchor::Dnskey { key: k, .. } => k.public_key == key.public_key,
+            TrustAnchor::Ds { .. } => false,
+        })
+        .map(|e| e.state)
+}
+
+#[test]
+fn new_key_is_trusted_only_after_the_add_hold_down() {
+    let (old, new) = (ksk(1), ksk(2));
+    let store = store(vec![key_anchor(&old)]);
+    let start = 1_000_000;
+
+    assert!(store.update("example", &[old.clone(), new.clone()], &[], start));
+    assert_eq!(state_of(&store, &new), Some(KeyState::AddPend));
+    assert!(!store.anchors().contains(&key_anchor(&new)));
+
+    // Still seen, but not long enough
+    let early = start + ADD_HOLD_DOWN - 1;
+    assert!(!store.update("example", &[old.clone(), new.clone()], &[], early));
+    assert_eq!(state_of(&store, &new), Some(KeyState::AddPend));
+
+    assert!(store.update(
+        "example",
+        &[old.clone(), new.clone()],
+        &[],
+        start + ADD_HOLD_DOWN
+    ));
+    assert_eq!(state_of(&store, &new), Some(KeyState::Valid));
+    assert!(store.anchors().contains(&key_anchor(&new)));
+    assert!(store.anchors().contains(&key_anchor(&old)));
+}
+
+#[test]
+fn pending_key_that_disappears_is_dropped() {
+    let (old, new) = (ksk(1), ksk(2));
+    let store = store(vec![key_anchor(&old)]);
+    let start = 1_000_000;
+
+    store.update("example", &[old.clone(), new.clone()], &[], start);
+    assert!(store.update("example", std::slice::from_ref(&old), &[], start + DAY));
+    assert_eq!(state_of(&store, &new), None);
+
+    // Coming back starts the hold-down over
+    store.update("example", &[old.clone(), new.clone()], &[], start + 2 * DAY);
+    store.update(
+        "example",
+        &[old.clone(), new.clone()],
+        &[],
+        start + ADD_HOLD_DOWN + DAY,
+    );
+    assert_eq!(state_of(&store, &new), Some(KeyState::AddPend));
+}
+
+#[test]
+fn trusted_key_that_disappears_stays_trusted_as_missing() {
+    let (old, new) = (ksk(1), ksk(2));
+    let store = store(vec![key_anchor(&old), key_anchor(&new)]);
+
+    assert!(store.update("example", std::slice::from_ref(&new), &[], 1_000_000));
+    assert_eq!(state_of(&store, &old), Some(KeyState::Missing));
+    assert!(store.anchors().contains(&key_anchor(&old)));
+
+    assert!(store.update("example", &[old.clone(), new.clone()], &[], 1_000_100));
+    assert_eq!(state_of(&store, &old), Some(KeyState::Valid));
+}
+
+#[test]
+fn revoked_key_is_distrusted_at_once_and_forgotten_after_the_remove_hold_down() {
+    let (old, new) = (ksk(1), ksk(2));
+    let store = store(vec![key_anchor(&old), key_anchor(&new)]);
+    let start = 1_000_000;
+
+    // The revoked key signed the set itself, so the revocation counts
+    let keys = [revoked(&old), new.clone()];
+    assert!(store.update("example", &keys, &[revoked(&old)], start));
+    assert_eq!(state_of(&store, &old), Some(KeyState::Revoked));
+    assert!(!store.anchors().contains(&key_anchor(&old)));
+    assert!(!store.anchors().contains(&key_anchor(&revoked(&old))));
+    assert_eq!(store.anchors(), vec![key_anchor(&new)]);
+
+    // Revoked keys never come back, even when published again without the bit
+    store.update("example", &[old.clone(), new.clone()], &[], start + DAY);
+    assert_eq!(state_of(&store, &old), Some(KeyState::Revoked));
+
+    store.update("example", &keys, &[], start + REMOVE_HOLD_DOWN - 1);
+    assert_eq!(state_of(&store, &old), Some(KeyState::Revoked));
+    assert!(store.update("example", &keys, &[], start + REMOVE_HOLD_DOWN));
+    assert_eq!(state_of(&store, &old), None);
+}
+
+#[test]
+fn ds_anchor_is_replaced_by_its_key() {
+    let (key, other) = (ksk(1), ksk(2));
+    let ds = Ds {
+        key_tag: key.key_tag(),
+        algorithm: ALGORITHM,
+        digest_type: SHA256,
+        digest: ds_digest("example", &key, SHA256).unwrap(),
+    };
+    let ds_anchor = TrustAnchor::Ds {
+        zone: "example".to_string(),
+        ds,
+    };
+    let store = store(vec![ds_anchor.clone()]);
+
+    assert!(store.update("example", &[key.clone(), other.clone()], &[], 1_000_000));
+    let anchors = store.anchors();
+    assert!(!anchors.contains(&ds_anchor));
+    // The key the DS stands for is trusted at once, the other one waits its hold-down
+    assert_eq!(anchors, vec![key_anchor(&key)]);
+    assert_eq!(state_of(&store, &key), Some(KeyState::Valid));
+    assert_eq!(state_of(&store, &other), Some(KeyState::AddPend));
+}
+
+#[test]
+fn updates_only_touch_their_zone() {
+    let key = ksk(1);
+    let other_zone = TrustAnchor::Dnskey {
+        zone: "example.org".to_string(),
+        key: ksk(9),
+    };
+    let store = store(vec![key_anchor(&key), other_zone.clone()]);
+
+    store.update("example", std::slice::from_ref(&key), &[], 1_000_000);
+    assert!(store.anchors().contains(&other_zone));
+    assert_eq!(store.zones(), vec!["example", "example.org"]);
+}
+
+#[test]
+fn state_file_round_trips() {
+    let path = std::env::temp_dir().join(format!("dash-trust-anchors-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let (old, new, gone) = (ksk(1), ksk(2), ksk(3));
+    let configured = vec![key_anchor(&old), key_anchor(&gone)];
+
+    let store = TrustAnchorStore::load(&config(configured.clone(), Some(path.clone()))).unwrap();
+    store.update(
+        "example",
+        &[revoked(&gone), old.clone(), new.clone()],
+        &[revoked(&gone)],
+        1_000_000,
+    );
+    let saved: Vec<AnchorEntry> = store.entries();
+    assert_eq!(saved.len(), 3);
+
+    // The file takes precedence over the configured anchors of the zones it covers
+    let reloaded = TrustAnchorStore::load(&config(Vec::new(), Some(path.clone()))).unwrap();
+    assert_eq!(reloaded.entries(), saved);
+    let reloaded = TrustAnchorStore::load(&config(configured, Some(path.clone()))).unwrap();
+    assert_eq!(reloaded.entries(), saved);
+
+    std::fs::write(&path, "valid not-a-time example. DNSKEY 257 3 8 AwEAAQ==\n").unwrap();
+    assert!(TrustAnchorStore::load(&config(Vec::new(), Some(path.clone()))).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,268 @@
+//! Upstream pools: the order servers are tried in, failing over to the next one, and the
+//! health checks that bring a server marked down back.
+
+use dash::config::{Config, ResolverConfig, UpstreamMode, UpstreamSelection};
+use dash::dashcontext::DashContext;
+use dash::resolver::resolve_message_query;
+use dash::upstream::{UpstreamPool, Upstreams};
+use rustdns::{Class, Message, Resource, Type};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const A: u16 = 1;
+
+fn addresses(count: u16) -> Vec<SocketAddr> {
+    (1..=count)
+        .map(|i| SocketAddr::from((Ipv4Addr::new(192, 0, 2, i as u8), 53)))
+        .collect()
+}
+
+fn order(pool: &UpstreamPool) -> Vec<SocketAddr> {
+    pool.candidates().iter().map(|u| u.address()).collect()
+}
+
+fn mark_down(pool: &UpstreamPool, address: SocketAddr) {
+    let upstream = pool
+        .upstreams()
+        .iter()
+        .find(|u| u.address() == address)
+        .unwrap();
+    while upstream.is_healthy() {
+        upstream.record_failure(Duration::from_secs(1));
+    }
+}
+
+#[test]
+fn round_robin_starts_at_the_next_server_for_every_query() {
+    let servers = addresses(3);
+    let pool = UpstreamPool::new(&servers, UpstreamSelection::RoundRobin);
+    assert_eq!(order(&pool), servers);
+    assert_eq!(order(&pool), vec![servers[1], servers[2], servers[0]]);
+    assert_eq!(order(&pool), vec![servers[2], servers[0], servers[1]]);
+    assert_eq!(order(&pool), servers);
+}
+
+#[test]
+fn servers_marked_down_are_tried_last() {
+    let servers = addresses(3);
+    let pool = UpstreamPool::new(&servers, UpstreamSelection::RoundRobin);
+    mark_down(&pool, servers[0]);
+    assert!(!pool.upstreams()[0].is_healthy());
+    assert_eq!(order(&pool), vec![servers[1], servers[2], servers[0]]);
+    assert_eq!(order(&pool), vec![servers[1], servers[2], servers[0]]);
+    assert_eq!(order(&pool), vec![servers[2], servers[1], servers[0]]);
+
+    // An answer brings it back
+    pool.upstreams()[0].record_success(Duration::from_millis(5));
+    assert_eq!(order(&pool), servers);
+}
+
+#[test]
+fn fastest_prefers_the_lowest_response_time_after_measuring_every_server() {
+    let servers = addresses(3);
+    let pool = UpstreamPool::new(&servers, UpstreamSelection::Fastest);
+    pool.upstreams()[0].record_success(Duration::from_millis(40));
+    pool.upstreams()[2].record_success(Duration::from_millis(10));
+    // The server never measured goes first
+    assert_eq!(order(&pool), vec![servers[1], servers[2], servers[0]]);
+
+    pool.upstreams()[1].record_success(Duration::from_millis(20));
+    for _ in 0..3 {
+        assert_eq!(order(&pool), vec![servers[2], servers[1], servers[0]]);
+    }
+
+    // Down servers go last however fast they were
+    mark_down(&pool, servers[2]);
+    assert_eq!(order(&pool), vec![servers[1], servers[0], servers[2]]);
+}
+
+/// A stand-in forwarder answering every A query with answer, and every other one with an
+/// empty NOERROR, counting the queries it gets.
+struct StandIn {
+    address: SocketAddr,
+    queries: Arc<AtomicUsize>,
+}
+
+fn serve(answer: Ipv4Addr) -> StandIn {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+    let queries = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&queries);
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let Ok((length, from)) = socket.recv_from(&mut buffer) else {
+                continue;
+            };
+            if let Some(response) = respond(answer, &buffer[..length]) {
+                counted.fetch_add(1, Ordering::SeqCst);
+                let _ = socket.send_to(&response, from);
+            }
+        }
+    });
+    StandIn { address, queries }
+}
+
+fn respond(answer: Ipv4Addr, query: &[u8]) -> Option<Vec<u8>> {
+    let mut at = 12;
+    while *query.get(at)? != 0 {
+        at += 1 + query[at] as usize;
+    }
+    let question = query.get(12..at + 5)?;
+    let qtype = u16::from_be_bytes([query[at + 1], query[at + 2]]);
+    let answered = qtype == A;
+
+    let mut response = query[..2].to_vec();
+    response.extend([0x81, 0x80]);
+    for count in [1, answered as u16, 0, 0] {
+        response.extend(count.to_be_bytes());
+    }
+    response.extend_from_slice(question);
+    if answered {
+        response.extend([0xc0, 12]);
+        response.extend(A.to_be_bytes());
+        response.extend(1u16.to_be_bytes());
+        response.extend(300u32.to_be_bytes());
+        response.extend(4u16.to_be_bytes());
+        response.extend(answer.octets());
+    }
+    Some(response)
+}
+
+/// A server that takes queries and never answers them.
+fn silent() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").unwrap()
+}
+
+fn forward_config(forwarders: Vec<SocketAddr>, selection: UpstreamSelection) -> ResolverConfig {
+    ResolverConfig {
+        mode: UpstreamMode::Forward,
+        forwarders,
+        selection,
+        query_timeout_ms: 200,
+        ..Default::default()
+    }
+}
+
+fn context(resolver: ResolverConfig) -> DashContext {
+    let mut config = Config {
+        resolver,
+        ..Default::default()
+    };
+    config.dnssec.validation = false;
+    DashContext::new(config).unwrap()
+}
+
+fn resolve(context: &DashContext) -> Option<Ipv4Addr> {
+    let mut msg = Message {
+        rd: true,
+        ..Default::default()
+    };
+    msg.add_question("www.example.test", Type::A, Class::Internet);
+    let rsp = resolve_message_query(&msg, context).unwrap();
+    rsp.answers.iter().find_map(|r| match r.resource {
+        Resource::A(ip) => Some(ip),
+        _ => None,
+    })
+}
+
+#[test]
+fn queries_fail_over_to_the_next_server_until_the_first_is_down() {
+    let dead = silent();
+    let live = serve(Ipv4Addr::new(192, 0, 2, 2));
+    let context = context(forward_config(
+        vec![dead.local_addr().unwrap(), live.address],
+        UpstreamSelection::RoundRobin,
+    ));
+    let pool = context.upstreams().forwarders().unwrap();
+
+    // Every other query starts at the silent server, the third time it fails it is down
+    let mut queries = 0;
+    while pool.upstreams()[0].is_healthy() {
+        assert_eq!(resolve(&context), Some(Ipv4Addr::new(192, 0, 2, 2)));
+        queries += 1;
+        assert!(queries < 10);
+    }
+    assert_eq!(queries, 5);
+
+    // From then on the live server is asked first
+    let start = Instant::now();
+    for _ in 0..4 {
+        assert_eq!(resolve(&context), Some(Ipv4Addr::new(192, 0, 2, 2)));
+    }
+    assert!(start.elapsed() < Duration::from_millis(200));
+    assert_eq!(live.queries.load(Ordering::SeqCst), 9);
+}
+
+#[test]
+fn fastest_stops_asking_a_slow_server_first_after_one_failure() {
+    let dead = silent();
+    let live = serve(Ipv4Addr::new(192, 0, 2, 2));
+    let context = context(forward_config(
+        vec![dead.local_addr().unwrap(), live.address],
+        UpstreamSelection::Fastest,
+    ));
+    let pool = context.upstreams().forwarders().unwrap();
+
+    assert_eq!(resolve(&context), Some(Ipv4Addr::new(192, 0, 2, 2)));
+    assert!(pool.upstreams()[0].srtt() > pool.upstreams()[1].srtt());
+    let start = Instant::now();
+    for _ in 0..4 {
+        assert_eq!(resolve(&context), Some(Ipv4Addr::new(192, 0, 2, 2)));
+    }
+    assert!(start.elapsed() < Duration::from_millis(200));
+    // Only ever asked once, so not marked down
+    assert!(pool.upstreams()[0].is_healthy());
+}
+
+#[test]
+fn round_robin_spreads_queries_over_the_servers() {
+    let servers = [
+        serve(Ipv4Addr::new(192, 0, 2, 1)),
+        serve(Ipv4Addr::new(192, 0, 2, 2)),
+    ];
+    let context = context(forward_config(
+        servers.iter().map(|s| s.address).collect(),
+        UpstreamSelection::RoundRobin,
+    ));
+    let answers: Vec<_> = (0..4).map(|_| resolve(&context).unwrap()).collect();
+    assert_eq!(
+        answers,
+        [1, 2, 1, 2].map(|i| Ipv4Addr::new(192, 0, 2, i)).to_vec()
+    );
+    for server in &servers {
+        assert_eq!(server.queries.load(Ordering::SeqCst), 2);
+    }
+}
+
+#[test]
+fn health_checks_bring_a_server_that_answers_again_back() {
+    let server = serve(Ipv4Addr::new(192, 0, 2, 1));
+    let dead = silent();
+    let upstreams = Arc::new(Upstreams::new(&forward_config(
+        vec![server.address, dead.local_addr().unwrap()],
+        UpstreamSelection::RoundRobin,
+    )));
+    let pool = upstreams.forwarders().unwrap();
+    mark_down(pool, server.address);
+
+    Upstreams::start_health_checker(
+        &upstreams,
+        Duration::from_millis(20),
+        Duration::from_millis(100),
+    );
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !pool.upstreams()[0].is_healthy() {
+        assert!(Instant::now() < deadline, "never marked up again");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(server.queries.load(Ordering::SeqCst) > 0);
+
+    // The server that never answers goes down instead
+    while pool.upstreams()[1].is_healthy() {
+        assert!(Instant::now() < deadline, "never marked down");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,61 @@
+//! Views share what they take from the top level configuration.
+
+use dash::config::{BlocklistConfig, Config, ViewConfig};
+use dash::dashcontext::DashContext;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+fn view(name: &str, clients: &str) -> ViewConfig {
+    ViewConfig {
+        name: name.to_string(),
+        clients: vec![clients.parse().unwrap()],
+        zones: None,
+        forward_zones: None,
+        blocklist: None,
+        rpz: None,
+    }
+}
+
+#[test]
+fn views_share_the_sections_they_do_not_override() {
+    let blocklist = BlocklistConfig {
+        allowlist: vec!["example.com".to_string()],
+        ..Default::default()
+    };
+    let config = Config {
+        views: vec![
+            view("inherits", "10.0.0.0/8"),
+            ViewConfig {
+                blocklist: Some(blocklist),
+                forward_zones: Some(Vec::new()),
+                ..view("overrides", "192.168.0.0/16")
+            },
+        ],
+        ..Default::default()
+    };
+    let context = Arc::new(DashContext::new(config).unwrap());
+    let client = |ip: &str| context.for_client(&SocketAddr::new(ip.parse().unwrap(), 5353));
+
+    let inherits = client("10.1.2.3");
+    assert_eq!(inherits.view(), Some("inherits"));
+    assert!(std::ptr::eq(inherits.zones(), context.zones()));
+    assert!(std::ptr::eq(inherits.blocklist(), context.blocklist()));
+    assert!(std::ptr::eq(
+        inherits.policy_zones(),
+        context.policy_zones()
+    ));
+    assert!(std::ptr::eq(inherits.upstreams(), context.upstreams()));
+    assert!(std::ptr::eq(inherits.local_data(), context.local_data()));
+
+    let overrides = client("192.168.1.1");
+    assert_eq!(overrides.view(), Some("overrides"));
+    assert!(!std::ptr::eq(overrides.blocklist(), context.blocklist()));
+    assert!(!std::ptr::eq(overrides.upstreams(), context.upstreams()));
+    assert!(std::ptr::eq(overrides.zones(), context.zones()));
+    assert!(std::ptr::eq(
+        overrides.policy_zones(),
+        context.policy_zones()
+    ));
+
+    assert!(client("172.16.0.1").view().is_none());
+}
@@ -0,0 +1,79 @@
+//! Decoding client queries, and the responses to those that cannot be answered.
+
+use dash::wire::{decode, encode_error, raw_question};
+use rustdns::{Class, Message, Rcode, Type, QR};
+
+fn query_packet(qname: &str, qtype: Type) -> Vec<u8> {
+    let mut msg = Message {
+        id: 0xbeef,
+        rd: true,
+        ..Default::default()
+    };
+    msg.add_question(qname, qtype, Class::Internet);
+    msg.to_vec().unwrap()
+}
+
+#[test]
+fn error_echoes_header_and_question() {
+    let mut packet = query_packet("www.example.com", Type::A);
+    // Trailing garbage makes the query malformed but leaves its question readable
+    packet.extend([0xff; 5]);
+    packet[11] = 1;
+
+    let encoded = encode_error(&packet, Rcode::FormErr).unwrap();
+    let rsp = Message::from_slice(&encoded).unwrap();
+    assert_eq!(rsp.id, 0xbeef);
+    assert_eq!(rsp.qr, QR::Response);
+    assert!(rsp.rd);
+    assert!(rsp.ra);
+    assert_eq!(rsp.rcode, Rcode::FormErr);
+    assert_eq!(rsp.questions.len(), 1);
+    assert_eq!(rsp.questions[0].name, "www.example.com.");
+    assert_eq!(rsp.questions[0].r#type, Type::A);
+    assert!(rsp.answers.is_empty() && rsp.additionals.is_empty());
+    assert!(encoded.len() <= packet.len());
+}
+
+#[test]
+fn error_without_a_readable_question_is_only_a_header() {
+    let packet = query_packet("www.example.com", Type::A);
+    let cut = &packet[..16];
+
+    let encoded = encode_error(cut, Rcode::FormErr).unwrap();
+    assert_eq!(encoded.len(), 12);
+    let rsp = Message::from_slice(&encoded).unwrap();
+    assert_eq!(rsp.id, 0xbeef);
+    assert_eq!(rsp.rcode, Rcode::FormErr);
+    assert!(rsp.questions.is_empty());
+}
+
+#[test]
+fn short_packets_and_responses_get_no_answer() {
+    assert!(encode_error(&[0x12, 0x34, 0x01], Rcode::FormErr).is_none());
+
+    let mut response = query_packet("www.example.com", Type::A);
+    response[2] |= 0b1000_0000;
+    assert!(encode_error(&response, Rcode::FormErr).is_none());
+}
+
+#[test]
+fn query_of_an_unknown_type_is_answered_not_implemented() {
+    // www.example.com HTTPS, a type rustdns does not know
+    let mut packet = vec![0xbe, 0xef, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    packet.extend(b"\x03www\x07example\x03com\x00");
+    packet.extend([0, 65, 0, 1]);
+
+    let decoded = decode(&packet).unwrap();
+    assert!(decoded.message.questions.is_empty());
+    let question = raw_question(&packet).unwrap();
+    assert_eq!(
+        (question.name(), question.r#type),
+        ("www.example.com".to_string(), 65)
+    );
+
+    let encoded = encode_error(&packet, Rcode::NotImp).unwrap();
+    let rsp = raw_question(&encoded).unwrap();
+    assert!(rsp.is_response);
+    assert!(rsp.same_question(&question, true));
+    assert_eq!(encoded[3] & 0x0F, Rcode::NotImp as u8);
+}
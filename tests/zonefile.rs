@@ -0,0 +1,289 @@
+//! Zone files and the answers local zones give from them.
+
+use dash::localzone::{Zone, ZoneStore};
+use dash::wire::{decode, encode_response, MAX_UDP_PAYLOAD};
+use dash::zonefile::{parse_zone, parse_zone_file};
+use rustdns::{Class, Message, Rcode, Record, Resource, Type};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const EXAMPLE: &str = "\
+$TTL 1h
+@       IN SOA ns1 hostmaster (
+                2024010101 ; serial
+                2h         ; refresh
+                30m        ; retry
+                14d        ; expire
+                5m )       ; minimum
+        IN NS  ns1
+ns1     IN A   192.0.2.53
+www  300 IN A  192.0.2.1
+        IN A   192.0.2.2
+alias   IN CNAME www
+*.wild  IN A   192.0.2.3
+sub     IN NS  ns.sub
+ns.sub  IN A   192.0.2.54
+";
+
+fn query(qname: &str, qtype: Type) -> Message {
+    let mut msg = Message {
+        rd: true,
+        ..Default::default()
+    };
+    msg.add_question(qname, qtype, Class::Internet);
+    msg
+}
+
+fn store(text: &str) -> ZoneStore {
+    let records = parse_zone(text, "example.com").unwrap();
+    ZoneStore::new(vec![Zone::new("example.com", records).unwrap()])
+}
+
+fn owned_by<'a>(records: &'a [Record], name: &str) -> Vec<&'a Record> {
+    records.iter().filter(|r| r.name == name).collect()
+}
+
+fn addresses(records: &[Record]) -> Vec<Ipv4Addr> {
+    records
+        .iter()
+        .filter_map(|r| match r.resource {
+            Resource::A(ip) => Some(ip),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A directory of its own under the system temporary directory.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("dash-zonefile-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn ttl_directive_parentheses_and_blank_owners() {
+    let records = parse_zone(EXAMPLE, "example.com").unwrap();
+
+    let soa = &owned_by(&records, "example.com")[0];
+    match &soa.resource {
+        Resource::SOA(soa) => {
+            assert_eq!(soa.mname, "ns1.example.com");
+            assert_eq!(soa.serial, 2024010101);
+            assert_eq!(soa.retry, Duration::from_secs(1800));
+            assert_eq!(soa.minimum, Duration::from_secs(300));
+        }
+        other => panic!("expected the SOA first, got {:?}", other),
+    }
+    assert_eq!(soa.ttl, Duration::from_secs(3600));
+
+    // The blank owner after the SOA repeats the origin
+    let apex = owned_by(&records, "example.com");
+    assert_eq!(apex.len(), 2);
+    assert_eq!(
+        apex[1].resource,
+        Resource::NS("ns1.example.com".to_string())
+    );
+
+    // A blank owner repeats www, and takes the $TTL rather than the TTL of the line before
+    let www = owned_by(&records, "www.example.com");
+    assert_eq!(www.len(), 2);
+    assert_eq!(www[0].ttl, Duration::from_secs(300));
+    assert_eq!(www[1].resource, Resource::A(Ipv4Addr::new(192, 0, 2, 2)));
+    assert_eq!(www[1].ttl, Duration::from_secs(3600));
+}
+
+#[test]
+fn soa_minimum_is_the_default_ttl_without_ttl_directive() {
+    let text = "\
+@ IN SOA ns1 hostmaster 1 7200 1800 1209600 600
+  IN NS ns1
+ns1 IN A 192.0.2.53
+www 120 IN A 192.0.2.1
+mail IN A 192.0.2.25
+";
+    let records = parse_zone(text, "example.com").unwrap();
+    let ttls: Vec<u64> = records.iter().map(|r| r.ttl.as_secs()).collect();
+    // Until a TTL is given the SOA minimum applies, after that the last TTL given does
+    assert_eq!(ttls, vec![600, 600, 600, 120, 120]);
+
+    let missing = parse_zone("www IN A 192.0.2.1\n", "example.com");
+    assert!(missing.is_err());
+}
+
+#[test]
+fn relative_origin_is_taken_relative_to_the_current_one() {
+    let text = "\
+$TTL 300
+$ORIGIN lab
+host IN A 192.0.2.10
+$ORIGIN rack1
+host IN A 192.0.2.11
+$ORIGIN example.org.
+host IN A 192.0.2.12
+";
+    let records = parse_zone(text, "example.com").unwrap();
+    let names: Vec<&str> = records.iter().map(|r| r.name.as_str()).collect();
+    assert_eq!(
+        names,
+        vec![
+            "host.lab.example.com",
+            "host.rack1.lab.example.com",
+            "host.example.org"
+        ]
+    );
+}
+
+#[test]
+fn include_is_read_relative_to_the_including_file() {
+    let dir = scratch_dir("include");
+    std::fs::write(
+        dir.join("example.com.zone"),
+        "\
+$TTL 300
+@ IN SOA ns1 hostmaster 1 7200 1800 1209600 300
+  IN NS ns1
+ns1 IN A 192.0.2.53
+$INCLUDE hosts.inc lab
+after IN A 192.0.2.99
+",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("hosts.inc"),
+        "printer IN A 192.0.2.20\n        IN AAAA 2001:db8::20\n",
+    )
+    .unwrap();
+
+    let records = parse_zone_file(&dir.join("example.com.zone"), "example.com").unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // The included file has its own origin, which does not leak back into the includer
+    assert_eq!(owned_by(&records, "printer.lab.example.com").len(), 2);
+    assert_eq!(owned_by(&records, "after.example.com").len(), 1);
+}
+
+#[test]
+fn include_loop_is_an_error() {
+    let dir = scratch_dir("loop");
+    std::fs::write(dir.join("loop.zone"), "$INCLUDE loop.zone\n").unwrap();
+    let result = parse_zone_file(&dir.join("loop.zone"), "example.com");
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(result.is_err());
+}
+
+#[test]
+fn zone_store_answers_from_the_closest_zone() {
+    let zones = store(EXAMPLE);
+
+    let rsp = zones.answer(&query("www.example.com", Type::A)).unwrap();
+    assert!(rsp.aa);
+    assert_eq!(rsp.rcode, Rcode::NoError);
+    assert_eq!(
+        addresses(&rsp.answers),
+        vec![Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)]
+    );
+
+    // A CNAME inside the zone is followed
+    let rsp = zones.answer(&query("alias.example.com", Type::A)).unwrap();
+    assert_eq!(rsp.answers[0].resource.r#type(), Type::CNAME);
+    assert_eq!(addresses(&rsp.answers).len(), 2);
+
+    // Names outside every zone are left to recursion
+    assert!(zones.answer(&query("www.example.net", Type::A)).is_none());
+}
+
+#[test]
+fn zone_store_negative_answers_wildcards_and_referrals() {
+    let zones = store(EXAMPLE);
+
+    let rsp = zones
+        .answer(&query("nothing.example.com", Type::A))
+        .unwrap();
+    assert_eq!(rsp.rcode, Rcode::NXDomain);
+    // The SOA is capped at its minimum for negative caching
+    assert_eq!(rsp.authoritys[0].ttl, Duration::from_secs(300));
+
+    let rsp = zones.answer(&query("www.example.com", Type::MX)).unwrap();
+    assert_eq!(rsp.rcode, Rcode::NoError);
+    assert!(rsp.answers.is_empty());
+    assert_eq!(rsp.authoritys[0].resource.r#type(), Type::SOA);
+
+    // The empty non-terminal above the wildcard exists
+    let rsp = zones.answer(&query("wild.example.com", Type::A)).unwrap();
+    assert_eq!(rsp.rcode, Rcode::NoError);
+    assert!(rsp.answers.is_empty());
+
+    let rsp = zones.answer(&query("a.wild.example.com", Type::A)).unwrap();
+    assert_eq!(rsp.answers[0].name, "a.wild.example.com");
+    assert_eq!(addresses(&rsp.answers), vec![Ipv4Addr::new(192, 0, 2, 3)]);
+
+    let rsp = zones
+        .answer(&query("host.sub.example.com", Type::A))
+        .unwrap();
+    assert!(!rsp.aa);
+    assert!(rsp.answers.is_empty());
+    assert_eq!(
+        rsp.authoritys[0].resource,
+        Resource::NS("ns.sub.example.com".to_string())
+    );
+    assert_eq!(
+        addresses(&rsp.additionals),
+        vec![Ipv4Addr::new(192, 0, 2, 54)]
+    );
+}
+
+#[test]
+fn most_specific_zone_wins() {
+    let parent = parse_zone(EXAMPLE, "example.com").unwrap();
+    let child = parse_zone(
+        "$TTL 60\n@ IN SOA ns hostmaster 1 2 3 4 5\nwww IN A 198.51.100.1\n",
+        "sub.example.com",
+    )
+    .unwrap();
+    let zones = ZoneStore::new(vec![
+        Zone::new("example.com", parent).unwrap(),
+        Zone::new("sub.example.com", child).unwrap(),
+    ]);
+
+    assert_eq!(
+        zones.zone_for("www.sub.example.com").unwrap().origin(),
+        "sub.example.com"
+    );
+    let rsp = zones
+        .answer(&query("www.sub.example.com", Type::A))
+        .unwrap();
+    assert!(rsp.aa);
+    assert_eq!(
+        addresses(&rsp.answers),
+        vec![Ipv4Addr::new(198, 51, 100, 1)]
+    );
+}
+
+#[test]
+fn large_response_is_truncated_over_udp() {
+    let mut text = String::from("$TTL 300\n@ IN SOA ns1 hostmaster 1 2 3 4 5\n");
+    for i in 0..20 {
+        text.push_str(&format!(
+            "big IN TXT \"{}\"\n",
+            format!("{:02}", i).repeat(20)
+        ));
+    }
+    let zones = store(&text);
+    let query = query("big.example.com", Type::TXT);
+    let mut rsp = zones.answer(&query).unwrap();
+    rsp.id = query.id;
+    rsp.questions = query.questions.clone();
+    assert_eq!(rsp.answers.len(), 20);
+
+    let full = encode_response(&rsp, 4096, None).unwrap();
+    assert!(full.len() > MAX_UDP_PAYLOAD);
+    assert_eq!(decode(&full).unwrap().message.answers.len(), 20);
+
+    let truncated = encode_response(&rsp, MAX_UDP_PAYLOAD, None).unwrap();
+    assert!(truncated.len() <= MAX_UDP_PAYLOAD);
+    let decoded = decode(&truncated).unwrap().message;
+    assert!(decoded.tc);
+    assert!(decoded.answers.is_empty());
+    assert_eq!(decoded.questions, query.questions);
+}